@@ -1,6 +1,6 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 use grex_t0::{
-    common::{stokes_i, Payload, CHANNELS},
+    common::{stokes_i, Channel, Payload, CHANNELS},
     dumps::DumpRing,
     injection::inject,
 };
@@ -13,6 +13,21 @@ pub fn push_ring(c: &mut Criterion) {
             dr.push(black_box(&pl));
         })
     });
+    // Requantizing on the way in
+    for bits in [4, 2] {
+        let mut dr = DumpRing::heap(15, bits, 1);
+        let mut pl = Payload {
+            pol_a: [Channel::new(10, -7); CHANNELS],
+            pol_b: [Channel::new(-3, 12); CHANNELS],
+            ..Default::default()
+        };
+        c.bench_function(&format!("push ring ({bits} bit)"), |b| {
+            b.iter(|| {
+                dr.push(black_box(&pl));
+                pl.count += 1;
+            })
+        });
+    }
 }
 
 pub fn injection(c: &mut Criterion) {
@@ -30,4 +30,39 @@ CREATE TABLE IF NOT EXISTS trigger (
     cand_name TEXT,
     cluster INTEGER NOT NULL,
     FOREIGN KEY (cluster) REFERENCES cluster (id)
-);
\ No newline at end of file
+);
+CREATE TABLE IF NOT EXISTS blanked_channel (
+    channel INTEGER PRIMARY KEY
+) STRICT;
+CREATE TABLE IF NOT EXISTS observation (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    start_mjd REAL NOT NULL,
+    stop_mjd REAL NOT NULL,
+    captured INTEGER NOT NULL,
+    filled INTEGER NOT NULL,
+    shuffled INTEGER NOT NULL,
+    downsampled INTEGER NOT NULL,
+    dump_overflow INTEGER NOT NULL,
+    dump_flushed INTEGER NOT NULL,
+    dumped INTEGER NOT NULL,
+    written TEXT NOT NULL,
+    summary TEXT
+) STRICT;
+CREATE TABLE IF NOT EXISTS control_audit (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mjd REAL NOT NULL,
+    action TEXT NOT NULL,
+    requester TEXT NOT NULL,
+    outcome TEXT NOT NULL
+) STRICT;
+CREATE TABLE IF NOT EXISTS exfil_checksum (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mjd REAL NOT NULL,
+    sink TEXT NOT NULL,
+    target TEXT NOT NULL,
+    block INTEGER NOT NULL,
+    offset INTEGER NOT NULL,
+    bytes INTEGER NOT NULL,
+    crc32c INTEGER NOT NULL,
+    running_crc32c INTEGER NOT NULL
+) STRICT;
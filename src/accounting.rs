@@ -0,0 +1,81 @@
+//! Monotonic accounting of where every sample went over the course of a run
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+};
+
+/// Run-wide sample counters, only ever incremented
+#[derive(Debug, Default)]
+pub struct Accounting {
+    /// Payloads we received from the NIC and forwarded
+    pub captured: AtomicU64,
+    /// Zero payloads fabricated in place of dropped packets
+    pub filled: AtomicU64,
+    /// Payloads thrown away because they arrived out of order
+    pub shuffled: AtomicU64,
+    /// Payloads that made it through the downsample stage
+    pub downsampled: AtomicU64,
+    /// Payloads that never reached the voltage ringbuffer because its channel was full
+    pub dump_overflow: AtomicU64,
+    /// Payloads thrown away by the dump task while recovering from a dump
+    pub dump_flushed: AtomicU64,
+    /// Payloads written out to voltage dumps
+    pub dumped: AtomicU64,
+    /// Downsampled spectra written, per exfil output
+    written: RwLock<BTreeMap<String, Arc<AtomicU64>>>,
+}
+
+/// A point-in-time copy of the accounting counters
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountingSnapshot {
+    pub captured: u64,
+    pub filled: u64,
+    pub shuffled: u64,
+    pub downsampled: u64,
+    pub dump_overflow: u64,
+    pub dump_flushed: u64,
+    pub dumped: u64,
+    pub written: BTreeMap<String, u64>,
+}
+
+impl Accounting {
+    /// Get (or create) the counter of spectra written by the named exfil output.
+    /// Consumers should grab this once and increment it directly.
+    pub fn output(&self, name: &str) -> Arc<AtomicU64> {
+        self.written
+            .write()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    pub fn snapshot(&self) -> AccountingSnapshot {
+        AccountingSnapshot {
+            captured: self.captured.load(Ordering::Relaxed),
+            filled: self.filled.load(Ordering::Relaxed),
+            shuffled: self.shuffled.load(Ordering::Relaxed),
+            downsampled: self.downsampled.load(Ordering::Relaxed),
+            dump_overflow: self.dump_overflow.load(Ordering::Relaxed),
+            dump_flushed: self.dump_flushed.load(Ordering::Relaxed),
+            dumped: self.dumped.load(Ordering::Relaxed),
+            written: self
+                .written
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(k, v)| (k.clone(), v.load(Ordering::Relaxed)))
+                .collect(),
+        }
+    }
+}
+
+/// Get the global accounting state
+pub fn accounting() -> &'static Accounting {
+    static ACCOUNTING: OnceLock<Accounting> = OnceLock::new();
+    ACCOUNTING.get_or_init(Accounting::default)
+}
@@ -1,33 +1,186 @@
-use clap::{Parser, Subcommand};
+use crate::{
+    baseband::BasebandConfig,
+    calibration::TimeOfDay,
+    capture::{AllowedSource, PacketFormat, PollMode, PollStrategy, ReplayRate},
+    common::{CountUnit, HeaderClock, CHANNELS},
+    dumps::{format::DumpFileFormat, DumpWindow, RollingConfig, MAX_DUMP_SAMPLES},
+    exfil::{mirror::TeePolicy, stream::StreamTransport, ChannelOrder, FrequencyPlan, Sideband},
+    injection::BandShape,
+    latency::LatencyPolicy,
+    naming::TimeStandard,
+    placement::PlacementPolicy,
+    processing::{BlankFill, Products},
+    realtime::SchedPolicy,
+    timing::{PtpClock, TimeSources},
+    triggers,
+};
+use clap::{Args, Parser, Subcommand};
 use regex::Regex;
-use std::{net::SocketAddr, ops::RangeInclusive, path::PathBuf};
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    ops::{Range, RangeInclusive},
+    path::PathBuf,
+    time::Duration,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
+    /// Name of this station, labelling every metric, database row, and output file so they can be told apart centrally
+    #[arg(long, value_parser = parse_station_id)]
+    pub station_id: String,
     /// Path to save voltage dumps
     #[arg(long, default_value = ".")]
     pub dump_path: PathBuf,
+    /// Format of the voltage dump files
+    #[arg(long, value_enum, default_value_t = DumpFileFormat::Netcdf)]
+    pub dump_format: DumpFileFormat,
+    /// Deflate level (1-9) to compress voltage dumps with as they're written (netcdf formats only)
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(i32).range(1..=9))]
+    pub dump_compression: Option<i32>,
+    /// Only keep this range of channels (start:stop, exclusive) in voltage dumps after they're written
+    #[arg(long, value_parser = parse_channel_range)]
+    pub dump_channels: Option<Range<usize>>,
+    /// Path to save diagnostic bundles on fatal errors or stalls
+    #[arg(long, default_value = ".")]
+    pub diagnostics_path: PathBuf,
+    /// How long the pipeline can go without making progress before the watchdog collects diagnostics (seconds)
+    #[arg(long, default_value_t = 30)]
+    pub watchdog_timeout: u64,
     /// Path to save filterbanks
     #[arg(long, default_value = ".")]
     pub filterbank_path: PathBuf,
+    /// Path to save the end-of-run summary reports
+    #[arg(long, default_value = ".")]
+    pub summary_path: PathBuf,
     /// Path to the SQLite DB used for storing the injection record
     #[arg(long)]
     pub db_path: PathBuf,
     /// CPU cores to which we'll build tasks. They should share a NUMA node.
     #[arg(long, default_value = "0:7", value_parser = parse_core_range)]
     pub core_range: RangeInclusive<usize>,
+    /// How to place the pinned threads on the core range, for machines with fewer cores than threads
+    #[arg(long, value_enum, default_value_t = PlacementPolicy::Exclusive)]
+    pub placement: PlacementPolicy,
+    /// Scheduling policy for the capture and downsample threads (realtime policies need CAP_SYS_NICE)
+    #[arg(long, value_enum, default_value_t = SchedPolicy::Other)]
+    pub rt_policy: SchedPolicy,
+    /// Realtime priority of the capture and downsample threads
+    #[arg(long, default_value_t = 50)]
+    #[clap(value_parser = clap::value_parser!(i32).range(1..=99))]
+    pub rt_priority: i32,
+    /// Lock all of our memory into RAM (needs CAP_IPC_LOCK)
+    #[arg(long)]
+    pub mlock: bool,
     /// MAC address of the interface which data comes in on (used in ARP)
     #[arg(long, value_parser=parse_mac)]
     pub mac: [u8; 6],
-    /// Port which we expect packets to be directed to
-    #[arg(long, default_value_t = 60000)]
+    /// Ports which we expect packets to be directed to (comma separated), one per SNAP board.
+    /// Payloads are tagged with the index of the port they came in on as their stream ID.
+    #[arg(long, default_value = "60000", value_delimiter = ',')]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
-    pub cap_port: u16,
+    pub cap_port: Vec<u16>,
+    /// Datagrams to receive per syscall (with recvmmsg), fewer syscalls at the cost of bigger bursts into the channel
+    #[arg(long, default_value_t = 32)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..=1024))]
+    pub recv_batch: u64,
+    /// Jumps in packet count bigger than this (in payloads, ~1 s by default) resynchronize capture instead of being zero-filled
+    #[arg(long, default_value_t = 131072)]
+    pub max_gap: u64,
+    /// Record every payload of the exfil stream to rotating files in this directory (baseband, replayable with --replay)
+    #[arg(long)]
+    pub raw_record: Option<PathBuf>,
+    /// Size of each baseband recording file (GB)
+    #[arg(long, default_value_t = 4.0)]
+    pub raw_file_size: f64,
+    /// Space baseband recordings can take up (GB), the oldest files are deleted past it
+    #[arg(long, default_value_t = 100.0)]
+    pub raw_budget: f64,
+    /// Most we'll write of baseband recordings (MB/s), past which payloads are dropped from the recording
+    #[arg(long)]
+    pub raw_rate_limit: Option<f64>,
+    /// Payloads queued for the baseband writer to ride out slow writes
+    #[arg(long, default_value_t = 8192)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub raw_queue: u64,
+    /// Payloads that can arrive ahead of a missing one and still wait for it, instead of it being zero-filled (0 to not wait)
+    #[arg(long, default_value_t = 64)]
+    #[clap(value_parser = clap::value_parser!(u64).range(0..=1024))]
+    pub reorder_window: u64,
+    /// Replay a recording (pcap, or raw payloads back to back) as the exfil stream instead of capturing from the FPGA,
+    /// which is left alone. From a pcap, only datagrams to the exfil stream's port are replayed.
+    #[arg(long)]
+    pub replay: Option<PathBuf>,
+    /// How fast to replay the recording
+    #[arg(long, value_enum, default_value_t = ReplayRate::Native)]
+    pub replay_rate: ReplayRate,
+    /// Capture simulated noise as the exfil stream, this many times faster than real time, instead of capturing from the FPGA
+    #[arg(long, conflicts_with = "replay", value_parser = parse_overspeed)]
+    pub simulate: Option<f64>,
+    /// Stream that's downsampled for exfil and sets the observation's timing (index into the capture ports)
+    #[arg(long, default_value_t = 0)]
+    pub exfil_stream: u16,
+    /// Stream whose voltages go into the dump ringbuffer (index into the capture ports)
+    #[arg(long, default_value_t = 0)]
+    pub dump_stream: u16,
+    /// Capture the exfil stream on each of these interfaces (comma separated), as hot standbys for each other,
+    /// keeping whichever copy of each payload arrives and failing over when one stops delivering
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["replay", "simulate"])]
+    pub standby_nics: Vec<String>,
+    /// Multicast group the exfil stream is sent to, joined on each standby interface
+    #[arg(long, requires = "standby_nics")]
+    pub multicast_group: Option<Ipv4Addr>,
+    /// Time an interface can go without delivering before we stop waiting on it (ms). Has to be shorter than the
+    /// ~8 ms the merge holds payloads for, or a dead interface pushes everything through the window as gaps first.
+    #[arg(long, default_value_t = 4)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..=8))]
+    pub failover_timeout: u64,
+    /// Only accept data from these senders (address, address:port, or `fpga` for the 10 GbE address of the board we
+    /// control), rejecting everything else on every stream
+    #[arg(long, value_delimiter = ',')]
+    pub allowed_source: Vec<AllowedSource>,
     /// Port which we expect to receive trigger messages
     #[arg(long, default_value_t = 65432)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
     pub trig_port: u16,
+    /// Also subscribe to JSON triggers published on this ZeroMQ endpoint (e.g. tcp://t2:5555)
+    #[arg(long)]
+    pub trig_zmq: Option<String>,
+    /// File holding a shared secret UDP triggers must be signed with (`{"payload": <message>, "hmac": <HMAC-SHA256 of
+    /// it>}`, the message carrying a timestamp and nonce), rejecting any that aren't or are replays. Triggers posted to
+    /// the web API have to carry it as a bearer token.
+    #[arg(long)]
+    pub trigger_secret_file: Option<PathBuf>,
+    /// Take manual dumps from the web API (`POST /dump/trigger`). With a trigger secret, requests have to carry it as
+    /// a bearer token.
+    #[arg(long)]
+    pub manual_dumps: bool,
+    /// Hosts (comma separated, as callbacks name them) we'll send dump statuses to for unsigned triggers. Signed
+    /// triggers' callbacks are always honored, and without this nobody else's are.
+    #[arg(long, value_delimiter = ',')]
+    pub callback_hosts: Vec<String>,
+    /// Override the priorities of trigger sources (udp, zmq, http, internal) as source=priority, higher is dumped first
+    #[arg(long, value_delimiter = ',', value_parser = parse_priority)]
+    pub trigger_priority: Vec<(String, u8)>,
+    /// Samples (payloads) to shift every trigger by, to correct the skew between heimdall's specnums and the data
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    pub trigger_offset: i64,
+    /// Cluster raw (e.g. heimdall) candidates within this many spectra of each other into one trigger, for stations without T2
+    #[arg(long)]
+    pub cluster_window: Option<u64>,
+    /// Fractional DM difference within which candidates are clustered together
+    #[arg(long, default_value_t = 0.1)]
+    pub cluster_dm_tolerance: f64,
+    /// Time a cluster must go without new members before it triggers a dump (s)
+    #[arg(long, default_value_t = 1.0, value_parser = parse_seconds)]
+    pub cluster_hold: f64,
+    /// Trigger on spectra of the stokes stream brighter than this (sigma over the MAD-weighted channels)
+    #[arg(long)]
+    pub detect_threshold: Option<f64>,
+    /// Time after a bright pulse before the detector will trigger again (s)
+    #[arg(long, default_value_t = 1.0)]
+    pub detect_holdoff: f64,
     /// Port to respond to prometheus requests for metrics
     #[arg(long, default_value_t = 8083)]
     #[clap(value_parser = clap::value_parser!(u16).range(1..))]
@@ -36,24 +189,222 @@ pub struct Cli {
     #[clap(value_parser = clap::value_parser!(u32).range(1..=9))]
     #[arg(long, short, default_value_t = 2)]
     pub downsample_power: u32,
+    /// What the count in the gateware's payload header counts
+    #[arg(long, value_enum, default_value_t = CountUnit::Packets)]
+    pub count_unit: CountUnit,
+    /// Header count at the sync PPS, for gateware that doesn't reset the count on arm
+    #[arg(long, default_value_t = 0)]
+    pub epoch_offset: u64,
+    /// Layout of the gateware's packets
+    #[arg(long, value_enum, default_value_t = PacketFormat::V1)]
+    pub packet_format: PacketFormat,
+    /// How capture waits on an empty socket
+    #[arg(long, value_enum, default_value_t = PollMode::Spin)]
+    pub poll_mode: PollMode,
+    /// In hybrid poll mode, how long to spin on an empty socket before sleeping (us)
+    #[arg(long, default_value_t = 50)]
+    pub spin_us: u64,
+    /// Stamp each payload with how late it was received, carried through the ring into voltage dumps
+    #[arg(long)]
+    pub watermark: bool,
+    #[command(flatten)]
+    pub freq: FrequencyArgs,
+    /// What to fill blanked channels (and, with channel-noise, dropped packets) with
+    #[arg(long, value_enum, default_value_t = BlankFill::Zeros)]
+    pub blank_fill: BlankFill,
+    /// Channels (in gateware order) to always blank, as inclusive ranges like "0-250,1900-2047"
+    #[arg(long)]
+    pub mask_channels: Option<String>,
+    /// File of channels to always blank, as ranges like --mask-channels takes them (one or more per line, # comments)
+    #[arg(long)]
+    pub mask_file: Option<PathBuf>,
+    /// Polarization products to exfil. Full stokes (IQUV) and dual-pol (AA, BB) are written with NPOL=4 or 2 by the
+    /// filterbank and DADA exfils, every other exfil still gets Stokes I
+    #[arg(long, value_enum, default_value_t = Products::StokesI)]
+    pub products: Products,
+    /// Shorthand for `--products full-stokes`
+    #[arg(long, conflicts_with = "products")]
+    pub full_stokes: bool,
+    /// Address (host:port) of the central server to relay a decimated copy of the stokes stream to
+    #[arg(long)]
+    pub relay_addr: Option<String>,
+    /// Number of channels in the relayed stream (must divide the number of channels)
+    #[arg(long, default_value_t = 64)]
+    pub relay_channels: usize,
+    /// Integration time of the relayed stream (seconds)
+    #[arg(long, default_value_t = 1.0)]
+    pub relay_integration: f64,
+    /// JSON routing table sending each output stream (stokes, flags, total power, candidates, dumps) to any number of
+    /// sinks (file, DADA, network, null), in place of an exfil subcommand
+    #[arg(long)]
+    pub routes: Option<PathBuf>,
+    /// JSON object of extra keys (SOURCE, RA, DEC, TELESCOPE, ...) to add to every DADA header we write
+    #[arg(long)]
+    pub dada_header: Option<PathBuf>,
+    /// Another exfil to run alongside the subcommand, written as its own subcommand would be (e.g. "psrdada -k b0ba").
+    /// Can be given any number of times, every exfil getting every spectrum from its own queue
+    #[arg(long, value_parser = parse_exfil)]
+    pub tee: Vec<Exfil>,
+    /// What a teed exfil's queue does when that exfil falls behind
+    #[arg(long, value_enum, default_value_t = TeePolicy::Drop)]
+    pub tee_policy: TeePolicy,
+    /// Dedisperse the stokes stream at this DM (pc cm^-3) before it's exfilled, for monitoring a known source.
+    /// Overrides the routing table's dedisperse_dm
+    #[arg(long)]
+    pub dedisperse_dm: Option<f64>,
+    /// Address to serve a read-only copy of the full stokes stream on, for observer processes
+    #[arg(long)]
+    pub tap_addr: Option<SocketAddr>,
+    /// Keep this many minutes of the stokes stream in memory, to write out windows of on triggers or requests
+    #[arg(long)]
+    pub stokes_ring_minutes: Option<f64>,
+    /// Stokes spectra averaged together for each one the ring keeps
+    #[arg(long, default_value_t = 16)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub stokes_ring_decimation: u64,
+    /// Time to write out of the stokes ring before a candidate (s)
+    #[arg(long, default_value_t = 0.5)]
+    pub stokes_ring_before: f64,
+    /// Time to write out of the stokes ring after a candidate, long enough to catch the dispersion sweep (s)
+    #[arg(long, default_value_t = 1.5)]
+    pub stokes_ring_after: f64,
     /// Voltage buffer capacity, 30s default
     #[arg(long, short, default_value_t = 3662109)]
     pub vbuf_capacity: usize,
+    /// Payloads of voltages to dump around a candidate, unless its trigger asks for another window (just over 2 s).
+    /// No more than the voltage buffer holds
+    #[arg(long, default_value_t = 262144)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..=MAX_DUMP_SAMPLES))]
+    pub dump_samples: u64,
+    /// Payloads of the usual window to dump before a candidate, rather than half of --dump-samples
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(u64).range(..=MAX_DUMP_SAMPLES))]
+    pub dump_pre: Option<u64>,
+    /// Payloads of the usual window to dump from a candidate on (including its own), rather than half of
+    /// --dump-samples
+    #[arg(long)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..=MAX_DUMP_SAMPLES))]
+    pub dump_post: Option<u64>,
+    /// Continuously record the voltage ring to segments in this directory, in the dump format, regardless of triggers
+    #[arg(long)]
+    pub rolling_record: Option<PathBuf>,
+    /// Payloads in each rolling segment (just over 2 s, 2 GiB at full resolution)
+    #[arg(long, default_value_t = 262144)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub rolling_segment: u64,
+    /// Space rolling segments can take up (GB), the oldest segments are deleted past it
+    #[arg(long, default_value_t = 1000.0)]
+    pub rolling_budget: f64,
+    /// Capacity (payloads) of the channels between capture, injection, and downsampling
+    #[arg(long, default_value_t = 32_768)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub payload_capacity: u64,
+    /// Capacity (payloads) of the channel feeding the voltage ringbuffer, which covers the stream while dumping
+    #[arg(long, default_value_t = 32_768)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub dump_capacity: u64,
+    /// Capacity (downsampled spectra) of the channel feeding exfil
+    #[arg(long, default_value_t = 1024)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub exfil_capacity: u64,
+    /// Back the voltage buffer with this file (on tmpfs or hugetlbfs) so it survives restarts, instead of the heap
+    #[arg(long)]
+    pub vbuf_backing: Option<PathBuf>,
+    /// Bits to hold each voltage of the voltage buffer in. At 4 or 2 bits (requantized with a scale per polarization for
+    /// each block of payloads) the same memory holds 2 or 4 times the capacity, at the cost of what the dumps can show.
+    #[arg(long, default_value_t = 8, value_parser = parse_ring_bits)]
+    pub vbuf_bits: u8,
+    /// Allocate the voltage buffer in (transparent) hugepages instead of normal heap pages
+    #[arg(long, conflicts_with = "vbuf_backing")]
+    pub vbuf_hugepages: bool,
+    /// Threads to touch every page of a new voltage buffer with. The pages end up on the NUMA nodes of the cores these
+    /// run on, which the OS picks.
+    #[arg(long, default_value_t = 8)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..=256))]
+    pub vbuf_touch_threads: u64,
+    /// Bound the buffering at every stage (overriding bigger channel capacities, and committing DADA blocks early) so
+    /// triggers and spectra get through within the latency budget, at the cost of riding out fewer hiccups
+    #[arg(long)]
+    pub low_latency: bool,
+    /// Time a trigger has to reach the dump stage (and a spectrum heimdall) in (ms), checked against the stage profiles
+    #[arg(long, default_value_t = 100)]
+    #[clap(value_parser = clap::value_parser!(u64).range(1..))]
+    pub latency_budget: u64,
+    /// Open exfil files with O_DSYNC, so every write has hit the disk when it returns
+    #[arg(long)]
+    pub sync_writes: bool,
     /// Socket address of the SNAP Board
     #[arg(long, default_value = "192.168.0.3:69")]
     pub fpga_addr: SocketAddr,
-    /// NTP server to synchronize against
-    #[arg(long, default_value = "time.google.com")]
-    pub ntp_addr: String,
+    /// NTP servers to synchronize against, tried in order
+    #[arg(long, value_delimiter = ',', default_value = "time.google.com")]
+    pub ntp_addr: Vec<String>,
+    /// PTP hardware clock (disciplined by linuxptp) to synchronize against, preferred over NTP when given
+    #[arg(long)]
+    pub ptp_device: Option<PathBuf>,
+    /// Seconds the PTP hardware clock is ahead of UTC
+    #[arg(long, default_value_t = 37)]
+    pub ptp_utc_offset: i64,
+    /// Number of passes over the time sources before falling back to the system clock
+    #[arg(long, default_value_t = 3)]
+    pub ntp_retries: usize,
+    /// How often to recheck NTP during the run (seconds)
+    #[arg(long, default_value_t = 600)]
+    pub ntp_recheck_interval: u64,
+    /// How often to compare packet-count time against NTP (seconds)
+    #[arg(long, default_value_t = 60)]
+    pub drift_check_interval: u64,
+    /// Drift between packet-count time and NTP beyond which we raise an alarm (milliseconds)
+    #[arg(long, default_value_t = 5)]
+    pub drift_threshold: u64,
+    /// Nominal rate of the FPGA fabric clock (MHz)
+    #[arg(long, default_value_t = 250.0)]
+    pub fpga_clock_rate: f64,
+    /// Deviation of the FPGA fabric clock from nominal, as seen by the host, beyond which we raise an alarm (ppm)
+    #[arg(long, default_value_t = 50.0)]
+    pub clock_rate_tolerance: f64,
     /// Requantization gain
     #[arg(long)]
     pub requant_gain: u16,
+    /// Local times of day (HH:MM, comma separated) to re-level the requant gains at, i.e. dawn and dusk
+    #[arg(long, value_delimiter = ',')]
+    pub recal_at: Vec<TimeOfDay>,
+    /// Period of the noise source cal cycle (s), marking cal-on/cal-off boundaries in the outputs when set
+    #[arg(long)]
+    pub cal_period: Option<f64>,
+    /// Time the noise source is on for at the start of each cal cycle (s)
+    #[arg(long, default_value_t = 1.0)]
+    pub cal_on: f64,
+    /// Time after the sync PPS that the first cal cycle starts (s)
+    #[arg(long, default_value_t = 0.0)]
+    pub cal_phase: f64,
+    /// Equivalent temperature of the noise source (K), enabling online Tsys estimates with the cal cycle
+    #[arg(long, requires = "cal_period")]
+    pub tcal: Option<f64>,
+    /// Number of equal sub-bands to estimate Tsys in
+    #[arg(long, default_value_t = 8)]
+    pub tsys_subbands: usize,
+    /// Integration time of each Tsys estimate (s), which should span several cal cycles
+    #[arg(long, default_value_t = 60.0)]
+    pub tsys_interval: f64,
     /// Force a pps trigger
     #[arg(long)]
     pub trig: bool,
     /// Sync FPGA timing without NTP
     #[arg(long)]
     pub skip_ntp: bool,
+    /// Time standard for the MJDs and timestamps in every output (filenames, headers, dumps, and the database)
+    #[arg(long, value_enum, default_value_t = TimeStandard::Tai)]
+    pub time_standard: TimeStandard,
+    /// Format (hifitime's, like strftime) of the timestamps in output filenames
+    #[arg(long, default_value = "%Y%m%dT%H%M%S")]
+    pub filename_time_format: String,
+    /// Reattach to an FPGA that's already streaming (after a crash), reusing the saved sync epoch instead of re-arming
+    #[arg(long)]
+    pub warm_restart: bool,
+    /// Where the sync epoch is saved when we arm the FPGA, and read back from on a warm restart
+    #[arg(long, default_value = "grex_epoch.json")]
+    pub epoch_path: PathBuf,
     /// Pulse injection cadence (seconds)
     #[arg(short, long, default_value_t = 3600)]
     pub injection_cadence: u64,
@@ -65,7 +416,267 @@ pub struct Cli {
     pub exfil: Option<Exfil>,
 }
 
+impl Cli {
+    /// Every exfil asked for, the subcommand's first
+    pub fn exfils(&self) -> Vec<Exfil> {
+        self.exfil.iter().chain(&self.tee).cloned().collect()
+    }
+
+    /// The priority of a trigger source, as overridden or by default
+    pub fn trigger_priority(&self, source: &str) -> u8 {
+        self.trigger_priority
+            .iter()
+            .rev()
+            .find(|(s, _)| s == source)
+            .map_or_else(|| triggers::default_priority(source), |(_, p)| *p)
+    }
+
+    /// The polarization products to exfil, with the full stokes shorthand
+    pub fn products(&self) -> Products {
+        if self.full_stokes {
+            Products::FullStokes
+        } else {
+            self.products
+        }
+    }
+
+    /// Absolute time sources, in order of preference
+    pub fn time_sources(&self) -> TimeSources {
+        TimeSources {
+            ptp: self.ptp_device.clone().map(|device| PtpClock {
+                device,
+                utc_offset: self.ptp_utc_offset,
+            }),
+            ntp: self.ntp_addr.clone(),
+        }
+    }
+
+    /// How to interpret the gateware's payload header, as configured
+    pub fn header_clock(&self) -> HeaderClock {
+        HeaderClock {
+            unit: self.count_unit,
+            epoch_offset: self.epoch_offset,
+        }
+    }
+
+    /// Baseband recording, if we're asked for it
+    pub fn baseband_config(&self) -> Option<BasebandConfig> {
+        self.raw_record.clone().map(|path| BasebandConfig {
+            path,
+            file_bytes: (self.raw_file_size * 1e9) as u64,
+            budget_bytes: (self.raw_budget * 1e9) as u64,
+            rate_limit: self.raw_rate_limit.map(|mb| mb * 1e6),
+            format: self.packet_format,
+        })
+    }
+
+    /// The window dumped around a candidate, unless its trigger asks for another
+    pub fn dump_window(&self) -> DumpWindow {
+        let centered = DumpWindow::centered(self.dump_samples);
+        DumpWindow {
+            pre: self.dump_pre.unwrap_or(centered.pre),
+            post: self.dump_post.unwrap_or(centered.post),
+        }
+    }
+
+    /// Rolling voltage recording, if we're asked for it
+    pub fn rolling_config(&self) -> Option<RollingConfig> {
+        self.rolling_record.clone().map(|path| RollingConfig {
+            path,
+            segment_samples: self.rolling_segment,
+            budget_bytes: (self.rolling_budget * 1e9) as u64,
+        })
+    }
+
+    /// How capture waits for data, as configured
+    pub fn poll_strategy(&self) -> PollStrategy {
+        match self.poll_mode {
+            PollMode::Spin => PollStrategy::Spin,
+            PollMode::Hybrid => PollStrategy::Hybrid(Duration::from_micros(self.spin_us)),
+        }
+    }
+
+    /// How much latency we'll put up with, as configured
+    pub fn latency_policy(&self) -> LatencyPolicy {
+        LatencyPolicy {
+            low_latency: self.low_latency,
+            budget: Duration::from_millis(self.latency_budget),
+            sync_writes: self.sync_writes,
+        }
+    }
+
+    /// The channel to sky frequency mapping of the gateware, as configured
+    pub fn frequency_plan(&self) -> FrequencyPlan {
+        self.freq.plan()
+    }
+}
+
+/// The RF front end and gateware configuration that sets the frequency of each channel
+#[derive(Args, Debug, Clone)]
+pub struct FrequencyArgs {
+    /// Frequency ordering of the channels coming off the gateware
+    #[arg(long, value_enum, default_value_t = ChannelOrder::Descending)]
+    pub channel_order: ChannelOrder,
+    /// Local oscillator frequency of the RF front end (MHz)
+    #[arg(long, default_value_t = 1530.0)]
+    pub lo_freq: f64,
+    /// Which side of the LO the sky band sits on
+    #[arg(long, value_enum, default_value_t = Sideband::Lower)]
+    pub sideband: Sideband,
+    /// Total bandwidth of the channelized band (MHz)
+    #[arg(long, default_value_t = 250.0)]
+    pub bandwidth: f64,
+}
+
+impl FrequencyArgs {
+    pub fn plan(&self) -> FrequencyPlan {
+        FrequencyPlan {
+            lo: self.lo_freq,
+            bandwidth: self.bandwidth,
+            sideband: self.sideband,
+            order: self.channel_order,
+        }
+    }
+}
+
+/// Standalone utilities that don't start the pipeline
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct ToolCli {
+    #[command(subcommand)]
+    pub tool: Tool,
+}
+
 #[derive(Debug, Subcommand)]
+pub enum Tool {
+    /// Measure the throughput of each hot-path stage on this machine against the real-time rate
+    BenchStages {
+        /// How long to run each stage for (seconds)
+        #[clap(short, long, default_value_t = 3.0)]
+        seconds: f64,
+        /// Downsample power to compute the required exfil rate with
+        #[clap(value_parser = clap::value_parser!(u32).range(1..=9))]
+        #[arg(long, short, default_value_t = 2)]
+        downsample_power: u32,
+    },
+    /// Measure this host's processing throughput and disk bandwidth, and recommend the smallest safe downsample power and channel capacities
+    Recommend {
+        /// How long to run each measurement for (seconds)
+        #[clap(short, long, default_value_t = 5.0)]
+        seconds: f64,
+        /// Directory exfil and dumps will write to, whose disk we measure
+        #[arg(long, default_value = ".")]
+        path: PathBuf,
+        /// Voltage buffer capacity the pipeline will run with (payloads)
+        #[arg(long, default_value_t = 3662109)]
+        vbuf_capacity: usize,
+        /// Factor of margin to leave over the measured rates and stalls
+        #[arg(long, default_value_t = 2.0)]
+        headroom: f64,
+        /// Also write the recommendation to this file as JSON
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+    /// Re-send previously recorded triggers to a pipeline running on a replayed stream, optionally checking the dumps against a reference set
+    ReplayTriggers {
+        /// Candidate database holding the recorded triggers
+        db: PathBuf,
+        /// Trigger port of the pipeline to send to
+        #[arg(long, default_value = "127.0.0.1:65432")]
+        target: SocketAddr,
+        /// Time between triggers, to give each dump time to finish (seconds)
+        #[arg(long, default_value_t = 10.0, value_parser = parse_seconds)]
+        spacing: f64,
+        /// Only replay triggers from candidates at or after this MJD
+        #[arg(long, default_value_t = 0.0)]
+        since_mjd: f64,
+        /// Directory of reference dumps to compare the new dumps to
+        #[arg(long)]
+        compare: Option<PathBuf>,
+        /// Directory the pipeline writes its dumps to
+        #[arg(long, default_value = ".")]
+        dump_path: PathBuf,
+        /// Format the pipeline writes its dumps in (only netcdf dumps can be compared)
+        #[arg(long, value_enum, default_value_t = DumpFileFormat::Netcdf)]
+        dump_format: DumpFileFormat,
+        /// File holding the pipeline's trigger secret (its --trigger-secret-file), to sign the triggers with
+        #[arg(long)]
+        trigger_secret_file: Option<PathBuf>,
+    },
+    /// Attach read-only to a running pipeline's stokes tap, optionally writing what we receive to a filterbank
+    Observe {
+        /// Tap address of the pipeline (its --tap-addr)
+        addr: String,
+        /// Directory to write a filterbank of the tapped stream to
+        #[arg(long)]
+        filterbank_path: Option<PathBuf>,
+    },
+    /// Burn in this machine: run the pipeline on a simulated source faster than real time for hours, injecting pulses and
+    /// sending triggers, then check nothing was dropped and every output was written
+    Soak {
+        /// How long to run for (hours)
+        #[arg(long, default_value_t = 12.0)]
+        hours: f64,
+        /// How much faster than real time the simulated source runs
+        #[arg(long, default_value_t = 1.5, value_parser = parse_overspeed)]
+        overspeed: f64,
+        /// Time between triggers (seconds)
+        #[arg(long, default_value_t = 60.0)]
+        trigger_interval: f64,
+        /// Time between pulse injections (seconds)
+        #[arg(long, default_value_t = 600)]
+        injection_cadence: u64,
+        /// Directory every output of the run (dumps, filterbanks, summary, database) goes under
+        #[arg(long, default_value = "./soak")]
+        output: PathBuf,
+        /// Station ID to run the pipeline as
+        #[arg(long, default_value = "soak")]
+        station_id: String,
+        /// Port the pipeline serves metrics (and its accounting) on
+        #[arg(long, default_value_t = 8083)]
+        metrics_port: u16,
+        /// Port the pipeline listens for triggers on
+        #[arg(long, default_value_t = 65432)]
+        trig_port: u16,
+        /// Downsample power of 2 to run the pipeline with
+        #[clap(value_parser = clap::value_parser!(u32).range(1..=9))]
+        #[arg(long, default_value_t = 2)]
+        downsample_power: u32,
+        /// Any further options for the pipeline (after a --, e.g. -- --core-range 0:15)
+        #[arg(last = true)]
+        pipeline_args: Vec<String>,
+    },
+    /// Write a synthetic dispersed pulse as a .dat file for pulse injection
+    MakePulse {
+        /// File to write (should end in .dat and live in the pulse path)
+        output: PathBuf,
+        /// Dispersion measure (pc cm^-3)
+        #[arg(long, default_value_t = 100.0)]
+        dm: f64,
+        /// Intrinsic width (FWHM, ms)
+        #[arg(long, default_value_t = 1.0)]
+        width: f64,
+        /// Total power (sum of squared samples) deposited in each channel at the reference frequency
+        #[arg(long, default_value_t = 2000.0)]
+        fluence: f64,
+        /// Spectral shape of the pulse
+        #[arg(long, value_enum, default_value_t = BandShape::Flat)]
+        band_shape: BandShape,
+        /// Spectral index for power-law pulses
+        #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+        spectral_index: f64,
+        /// Center frequency of gaussian pulses and reference frequency of power-law pulses (MHz, defaults to the band center)
+        #[arg(long)]
+        band_center: Option<f64>,
+        /// FWHM of gaussian pulses (MHz)
+        #[arg(long, default_value_t = 50.0)]
+        band_fwhm: f64,
+        #[command(flatten)]
+        freq: FrequencyArgs,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
 pub enum Exfil {
     /// Use PSRDADA for exfil
     Psrdada {
@@ -75,14 +686,117 @@ pub enum Exfil {
         /// Window size in number of time samples
         #[clap(short, long, default_value_t = 65536)]
         samples: usize,
+        /// Hex key of an auxiliary buffer to stream run-length encoded data quality flags into
+        #[clap(long, value_parser = valid_dada_key)]
+        flag_key: Option<i32>,
+    },
+    /// Write a filterbank to the filterbank path
+    Filterbank {
+        /// Also write an identical filterbank here, so we survive either disk failing
+        #[clap(short, long)]
+        mirror: Option<PathBuf>,
+        /// Number of spectra each mirrored output can fall behind by before it starts dropping them
+        #[clap(short, long, default_value_t = 16384)]
+        backlog: usize,
+        /// Bits per sample (8, 16, or 32). Integer samples are requantized per channel against a running offset and
+        /// scale, recorded in the metadata sidecar
+        #[clap(long, default_value_t = 32, value_parser = parse_nbits)]
+        nbits: u8,
+    },
+    /// Write Stokes I to chunked HDF5 files in the filterbank path
+    Hdf5 {
+        /// Spectra per chunk, written together
+        #[clap(long, default_value_t = 1024)]
+        chunk: usize,
+        /// Deflate level (1-9) to compress chunks with, uncompressed if unset
+        #[clap(long, value_parser = clap::value_parser!(i32).range(1..=9))]
+        compression: Option<i32>,
+    },
+    /// Write Stokes I to PSRFITS search-mode files in the filterbank path
+    Psrfits {
+        /// Spectra per subint
+        #[clap(long, default_value_t = 4096)]
+        nsblk: usize,
+        /// Subints per file, before starting the next
+        #[clap(long, default_value_t = 256)]
+        subints_per_file: usize,
+    },
+    /// Write Stokes I to Parquet files in the filterbank path, for loading into pandas or polars
+    Parquet {
+        /// Spectra per row group, written together
+        #[clap(long, default_value_t = 4096)]
+        batch: usize,
+        /// Row groups per file, before starting the next
+        #[clap(long, default_value_t = 64)]
+        batches_per_file: usize,
+    },
+    /// Publish every spectrum over the network, for a detection pipeline elsewhere
+    Stream {
+        /// Address to publish on
+        #[clap(long, default_value = "0.0.0.0:5555")]
+        addr: SocketAddr,
+        #[clap(long, value_enum, default_value_t = StreamTransport::Zmq)]
+        transport: StreamTransport,
+    },
+    /// Send every spectrum as a SPEAD heap over UDP, for a central aggregator
+    Spead {
+        /// Address (host:port) to send heaps to
+        #[clap(long)]
+        addr: SocketAddr,
+        /// Bytes of the spectrum per packet (a multiple of 4, up to 8192)
+        #[clap(long, default_value_t = 1024)]
+        payload: usize,
     },
-    Filterbank,
 }
 
 fn valid_dada_key(s: &str) -> Result<i32, String> {
     i32::from_str_radix(s, 16).map_err(|_| "Invalid hex literal".to_string())
 }
 
+/// Just an exfil subcommand, for parsing [`Cli::tee`]
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct TeeArgs {
+    #[command(subcommand)]
+    exfil: Exfil,
+}
+
+pub fn parse_exfil(input: &str) -> Result<Exfil, String> {
+    TeeArgs::try_parse_from(input.split_whitespace())
+        .map(|t| t.exfil)
+        .map_err(|e| e.to_string())
+}
+
+pub fn parse_nbits(input: &str) -> Result<u8, String> {
+    let nbits = input
+        .parse()
+        .map_err(|_| "Invalid number of bits".to_owned())?;
+    crate::exfil::requant::valid_nbits(nbits).map_err(|e| e.to_string())
+}
+
+pub fn parse_ring_bits(input: &str) -> Result<u8, String> {
+    match input.parse() {
+        Ok(bits @ (2 | 4 | 8)) => Ok(bits),
+        _ => Err("The voltage buffer holds 2, 4, or 8 bits".to_owned()),
+    }
+}
+
+pub fn parse_overspeed(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(overspeed) if overspeed.is_finite() && overspeed > 0.0 => Ok(overspeed),
+        _ => Err(
+            "The simulated source has to run some positive number of times real time".to_owned(),
+        ),
+    }
+}
+
+pub fn parse_seconds(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(seconds) if Duration::try_from_secs_f64(seconds).is_ok() => Ok(seconds),
+        _ => Err("Not a length of time in seconds".to_owned()),
+    }
+}
+
 pub fn parse_core_range(input: &str) -> Result<RangeInclusive<usize>, String> {
     let re = Regex::new(r"(\d+):(\d+)").unwrap();
     let cap = re.captures(input).unwrap();
@@ -91,12 +805,41 @@ pub fn parse_core_range(input: &str) -> Result<RangeInclusive<usize>, String> {
     if stop < start {
         return Err("Invalid CPU range".to_owned());
     }
-    if stop - start + 1 < 8 {
-        return Err("Not enough CPU cores".to_owned());
-    }
     Ok(start..=stop)
 }
 
+pub fn parse_channel_range(input: &str) -> Result<Range<usize>, String> {
+    let (start, stop) = input
+        .split_once(':')
+        .ok_or_else(|| "Channel range should be start:stop".to_owned())?;
+    let start: usize = start.parse().map_err(|_| "Invalid channel range start")?;
+    let stop: usize = stop.parse().map_err(|_| "Invalid channel range stop")?;
+    if stop <= start || stop > CHANNELS {
+        return Err("Invalid channel range".to_owned());
+    }
+    Ok(start..stop)
+}
+
+pub fn parse_priority(input: &str) -> Result<(String, u8), String> {
+    let (source, priority) = input
+        .split_once('=')
+        .ok_or_else(|| "Trigger priority should be source=priority".to_owned())?;
+    let priority = priority.parse().map_err(|_| "Invalid trigger priority")?;
+    Ok((source.to_owned(), priority))
+}
+
+pub fn parse_station_id(input: &str) -> Result<String, String> {
+    if !input.is_empty()
+        && input
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(input.to_owned())
+    } else {
+        Err("Station ID should be letters, numbers, '-', and '_'".to_owned())
+    }
+}
+
 pub fn parse_mac(input: &str) -> Result<[u8; 6], String> {
     // Accepting a MAC address in the usual way (hex separated by colon)
     let mut mac = [0u8; 6];
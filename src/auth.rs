@@ -0,0 +1,80 @@
+//! HMAC-SHA256 (for authenticating triggers) and the hex digests and MACs are written out in
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn keyed(key: &[u8], message: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+/// HMAC-SHA256 (RFC 2104) of `message` under `key`
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    keyed(key, message).finalize().into_bytes().into()
+}
+
+/// Lowercase hex, how digests and MACs are written out
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether `given` is the shared secret, comparing MACs of the two so timing doesn't leak how much of it matched
+pub fn is_secret(secret: &[u8], given: &[u8]) -> bool {
+    keyed(secret, given)
+        .verify_slice(&hmac_sha256(secret, secret))
+        .is_ok()
+}
+
+/// Read a shared secret from a file, less any surrounding whitespace
+pub fn read_secret(path: &Path) -> eyre::Result<Vec<u8>> {
+    Ok(std::fs::read_to_string(path)?.trim().as_bytes().to_vec())
+}
+
+/// Check a hex MAC from the wire against `message`, without leaking how much of it matched through timing
+pub fn verify(key: &[u8], message: &[u8], mac: &str) -> bool {
+    from_hex(mac).is_some_and(|mac| keyed(key, message).verify_slice(&mac).is_ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hmac() {
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+        assert!(verify(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            "5BDCC146BF60754E6A042426089575C75A003F089D2739839DEC58B964EC3843"
+        ));
+        assert!(!verify(b"Jefe", b"what do ya want for nothing!", "5bdc"));
+        assert!(!verify(b"Jefe", b"what do ya want for nothing?", "not hex"));
+        assert!(is_secret(b"Jefe", b"Jefe"));
+        assert!(!is_secret(b"Jefe", b"Jeff"));
+        assert!(!is_secret(b"Jefe", b""));
+    }
+}
@@ -0,0 +1,262 @@
+//! Baseband recording: every payload the exfil stream captures, teed to rotating files on disk alongside the normal
+//! pipeline, for commissioning and offline reprocessing. Files are raw payloads back to back (in the configured packet
+//! format, and including the ones capture had to zero-fill), so they can be fed straight back in with `--replay`.
+use crate::{
+    capture::{PacketFormat, EXT_HEADER_SIZE},
+    common::{Payload, BLOCK_TIMEOUT},
+    naming::time_policy,
+    postprocess::lower_priority,
+};
+use hifitime::Epoch;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{
+    blocking::{Receiver, Sender},
+    errors::{RecvTimeoutError, TrySendError},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+const FILENAME_PREFIX: &str = "grex_raw-";
+const FILENAME_SUFFIX: &str = ".dat";
+/// Writes are buffered up into sequential chunks this big
+const WRITE_BUFFER: usize = 16 * 1024 * 1024;
+
+/// Where and how much to record
+#[derive(Debug, Clone)]
+pub struct BasebandConfig {
+    pub path: PathBuf,
+    /// Bytes per file before we move on to the next
+    pub file_bytes: u64,
+    /// Bytes of recordings we keep on disk, deleting the oldest files past it
+    pub budget_bytes: u64,
+    /// Most bytes per second we'll write, so recording doesn't starve dumps of disk bandwidth
+    pub rate_limit: Option<f64>,
+    /// Packet format to write payloads in, what capture is reading
+    pub format: PacketFormat,
+}
+
+/// A payload on its way to the recording, with the extended header it came with
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Recorded {
+    pub payload: Payload,
+    pub header: [u8; EXT_HEADER_SIZE],
+}
+
+/// Counters describing how well the recording is keeping up
+#[derive(Debug, Default)]
+pub struct BasebandStats {
+    /// Payloads written to disk
+    pub recorded: AtomicU64,
+    /// Payloads we couldn't queue for the writer (it was behind, or rate limited)
+    pub dropped: AtomicU64,
+    /// Files started
+    pub files: AtomicU64,
+    /// Files deleted to stay within the budget
+    pub expired: AtomicU64,
+}
+
+/// Get the global recording statistics
+pub fn baseband_stats() -> &'static BasebandStats {
+    static BASEBAND_STATS: OnceLock<BasebandStats> = OnceLock::new();
+    BASEBAND_STATS.get_or_init(BasebandStats::default)
+}
+
+static TEE: OnceLock<Sender<Recorded>> = OnceLock::new();
+
+/// Start teeing payloads to the recording writer, once at startup
+pub fn set_tee(sender: Sender<Recorded>) {
+    let _ = TEE.set(sender);
+}
+
+/// Queue a payload (and its extended header) to be recorded (if we're recording), never blocking capture
+pub fn record(payload: &Payload, header: &[u8; EXT_HEADER_SIZE]) {
+    if let Some(tee) = TEE.get() {
+        let recorded = Recorded {
+            payload: *payload,
+            header: *header,
+        };
+        match tee.try_send(recorded) {
+            Ok(_) | Err(TrySendError::Closed(_)) => (),
+            Err(_) => {
+                baseband_stats().dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// Our recordings in `path`, oldest first, with their sizes
+fn recordings(path: &Path) -> eyre::Result<Vec<(PathBuf, u64)>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(FILENAME_PREFIX) && name.ends_with(FILENAME_SUFFIX) {
+            let meta = entry.metadata()?;
+            files.push((entry.path(), meta.modified()?, meta.len()));
+        }
+    }
+    // Files started within the timestamp resolution of each other still sort by their sequence number
+    files.sort_by(|(a, a_modified, _), (b, b_modified, _)| (a_modified, a).cmp(&(b_modified, b)));
+    Ok(files
+        .into_iter()
+        .map(|(path, _, len)| (path, len))
+        .collect())
+}
+
+/// Delete the oldest recordings (other than `current`) until we're within the budget
+fn enforce_budget(config: &BasebandConfig, current: &Path) -> eyre::Result<()> {
+    let files = recordings(&config.path)?;
+    let mut total: u64 = files.iter().map(|(_, len)| len).sum();
+    for (path, len) in files {
+        if total <= config.budget_bytes {
+            break;
+        }
+        if path == current {
+            continue;
+        }
+        std::fs::remove_file(&path)?;
+        total -= len;
+        baseband_stats().expired.fetch_add(1, Ordering::Relaxed);
+        info!(path = %path.display(), "Deleted baseband recording to stay within the budget");
+    }
+    Ok(())
+}
+
+/// The file we're currently recording into
+struct RawFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+}
+
+impl RawFile {
+    fn create(dir: &Path, seq: u64) -> eyre::Result<Self> {
+        let path = dir.join(format!(
+            "{FILENAME_PREFIX}{}-{seq:04}{FILENAME_SUFFIX}",
+            time_policy().filename_stamp(Epoch::now()?)
+        ));
+        let writer = BufWriter::with_capacity(WRITE_BUFFER, File::create(&path)?);
+        baseband_stats().files.fetch_add(1, Ordering::Relaxed);
+        info!(path = %path.display(), "Recording baseband");
+        Ok(Self {
+            path,
+            writer,
+            written: 0,
+        })
+    }
+}
+
+/// Write payloads from the tee to rotating files until we're told to stop
+pub fn baseband_task(
+    config: BasebandConfig,
+    payloads: Receiver<Recorded>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting baseband recording task");
+    lower_priority();
+    std::fs::create_dir_all(&config.path)?;
+    let mut seq = 0;
+    let mut file = RawFile::create(&config.path, seq)?;
+    enforce_budget(&config, &file.path)?;
+    let start = Instant::now();
+    let mut total = 0u64;
+    let ext_size = config.format.ext_header_size();
+    let packet_size = config.format.payload_size() as u64;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Baseband recording task stopping");
+            break;
+        }
+        let recorded = match payloads.recv_ref_timeout(BLOCK_TIMEOUT) {
+            Ok(recorded) => recorded,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(_) => break,
+        };
+        // The packet as it came over the wire: count, extended header (if any), then the spectra
+        let payload = &recorded.payload;
+        // Safety: The two pols are contiguous, plain integers (as capture receives them from the socket)
+        let spectra = unsafe {
+            std::slice::from_raw_parts(
+                payload.pol_a.as_ptr().cast::<u8>(),
+                2 * std::mem::size_of_val(&payload.pol_a),
+            )
+        };
+        file.writer.write_all(&payload.count.to_le_bytes())?;
+        file.writer.write_all(&recorded.header[..ext_size])?;
+        file.writer.write_all(spectra)?;
+        drop(recorded);
+        file.written += packet_size;
+        total += packet_size;
+        baseband_stats().recorded.fetch_add(1, Ordering::Relaxed);
+        if file.written >= config.file_bytes {
+            file.writer.flush()?;
+            seq += 1;
+            file = RawFile::create(&config.path, seq)?;
+            if let Err(e) = enforce_budget(&config, &file.path) {
+                warn!("Couldn't clear out old baseband recordings - {e}");
+            }
+        }
+        // Hold back to the rate limit, letting the queue (and then drops) take up the slack
+        if let Some(rate) = config.rate_limit {
+            let due = start + Duration::from_secs_f64(total as f64 / rate);
+            let now = Instant::now();
+            if due > now {
+                std::thread::sleep(due - now);
+            }
+        }
+    }
+    file.writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::Channel;
+
+    #[test]
+    fn test_rotation() {
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        let packet = PacketFormat::V2.payload_size() as u64;
+        let config = BasebandConfig {
+            path: dir.to_path_buf(),
+            file_bytes: 3 * packet,
+            budget_bytes: 5 * packet,
+            rate_limit: None,
+            format: PacketFormat::V2,
+        };
+        let (sender, receiver) = thingbuf::mpsc::blocking::channel(16);
+        for count in 0..10 {
+            let mut payload = Payload {
+                count,
+                ..Default::default()
+            };
+            payload.pol_a[0] = Channel::new(count as i8, -1);
+            let header = [count as u8, 0, 1, 0, 0, 0, 0, 0];
+            sender.send(Recorded { payload, header }).unwrap();
+        }
+        drop(sender);
+        let (_sd_s, sd_r) = broadcast::channel(1);
+        baseband_task(config, receiver, sd_r).unwrap();
+        // Files of 3, 3, 3, and 1 payloads, the first two deleted to fit 5 payloads worth
+        let files = recordings(dir).unwrap();
+        let sizes: Vec<_> = files.iter().map(|(_, len)| len / packet).collect();
+        assert_eq!(sizes, [3, 1]);
+        // The last one is just as it came over the wire, extended header and all
+        let bytes = std::fs::read(&files[1].0).unwrap();
+        assert_eq!(u64::from_le_bytes(bytes[..8].try_into().unwrap()), 9);
+        assert_eq!(bytes[8..16], [9, 0, 1, 0, 0, 0, 0, 0]);
+        assert_eq!(bytes[16..18], [9, 0xFF]);
+    }
+}
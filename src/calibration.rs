@@ -0,0 +1,329 @@
+//! Leveling the requantization gains across the band (on demand or at scheduled local times), and the noise source cal cycle
+use crate::{
+    common::PACKET_CADENCE,
+    db::{AuditRecord, DbEvent},
+    exfil::dada,
+    fpga::Device,
+    monitoring::{DeviceCommand, DeviceRequest},
+    naming::time_policy,
+};
+use std::{
+    ops::Range,
+    str::FromStr,
+    sync::{mpsc::SyncSender, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, oneshot};
+use tracing::{info, warn};
+
+/// Accumulations of the pre-requant spectra we level against (around 8 seconds)
+const LEVEL_ACCUMULATIONS: u32 = 1048576;
+/// How long we'll wait for DADA exfil to reach a block boundary before leveling anyway
+const PAUSE_TIMEOUT: Duration = Duration::from_secs(20);
+const SECONDS_PER_DAY: u32 = 86400;
+
+/// Per-channel requant gains that bring every channel of `power` to the level the median channel has at `base`.
+/// Channels with no power at all (i.e. outside the analog band) are left at `base`.
+pub fn level_gains(power: &[f64], base: u16) -> Vec<u16> {
+    let mut live: Vec<f64> = power.iter().copied().filter(|p| *p > 0.0).collect();
+    if live.is_empty() {
+        return vec![base; power.len()];
+    }
+    live.sort_by(f64::total_cmp);
+    let median = live[live.len() / 2];
+    power
+        .iter()
+        .map(|p| {
+            if *p > 0.0 {
+                // Power goes with the square of the gain
+                (base as f64 * (median / p).sqrt())
+                    .round()
+                    .clamp(1.0, u16::MAX as f64) as u16
+            } else {
+                base
+            }
+        })
+        .collect()
+}
+
+fn level(device: &mut Device, base: u16) -> eyre::Result<String> {
+    let (a, b) = device.perform_spec_vacc(LEVEL_ACCUMULATIONS)?;
+    let power = |v: Vec<u64>| v.into_iter().map(|x| x as f64).collect::<Vec<_>>();
+    let gains_a = level_gains(&power(a), base);
+    let gains_b = level_gains(&power(b), base);
+    device.set_requant_gains(&gains_a, &gains_b)?;
+    let range = |g: &[u16]| {
+        (
+            g.iter().min().copied().unwrap_or(base),
+            g.iter().max().copied().unwrap_or(base),
+        )
+    };
+    Ok(format!(
+        "Leveled requant gains, pol a {:?}, pol b {:?}",
+        range(&gains_a),
+        range(&gains_b)
+    ))
+}
+
+/// Re-level the requant gains, pausing DADA exfil around it so the step in gain falls between transfers.
+/// The FPGA is only locked for the leveling itself, not while we wait on DADA.
+pub fn recalibrate(device: &Mutex<Device>, base: u16) -> eyre::Result<String> {
+    // Held rather than paused, so whatever the operator asks for in the meantime still stands afterwards
+    dada::hold(true);
+    let pause_start = Instant::now();
+    while dada::transferring() && pause_start.elapsed() < PAUSE_TIMEOUT {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    if dada::transferring() {
+        warn!("DADA exfil didn't pause in time, recalibrating anyway");
+    }
+    let outcome = level(&mut device.lock().unwrap(), base);
+    dada::hold(false);
+    outcome
+}
+
+/// A periodic noise source cycle, in payload counts since the sync PPS.
+/// The noise source controller runs the same PPS-aligned cycle, so we can work out exactly which samples had the cal on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CalSchedule {
+    /// Payloads per cycle
+    pub period: u64,
+    /// Payloads per cycle the noise source is on for, starting at the beginning of the cycle
+    pub on: u64,
+    /// Payload count (mod period) at which a cycle starts
+    pub phase: u64,
+}
+
+impl CalSchedule {
+    /// A schedule from durations in seconds, rounded to whole payloads
+    pub fn from_seconds(period: f64, on: f64, phase: f64) -> eyre::Result<Self> {
+        let payloads = |s: f64| (s / PACKET_CADENCE).round() as u64;
+        let schedule = Self {
+            period: payloads(period),
+            on: payloads(on),
+            phase: payloads(phase) % payloads(period).max(1),
+        };
+        if schedule.period == 0 || schedule.on == 0 || schedule.on >= schedule.period {
+            eyre::bail!(
+                "The cal on time must be shorter than the cal period (and neither can be zero)"
+            );
+        }
+        Ok(schedule)
+    }
+
+    /// Payloads since the start of the cycle `count` is in
+    fn cycle_offset(&self, count: u64) -> u64 {
+        (count % self.period + self.period - self.phase) % self.period
+    }
+
+    pub fn is_on(&self, count: u64) -> bool {
+        self.cycle_offset(count) < self.on
+    }
+
+    /// Every payload count in `counts` where the noise source switches, and whether it switches on
+    pub fn boundaries(&self, counts: Range<u64>) -> Vec<(u64, bool)> {
+        let mut edges = vec![];
+        for (edge_offset, on) in [(0, true), (self.on, false)] {
+            // First edge at or after the start of the range
+            let mut edge = counts.start
+                + (edge_offset + self.period - self.cycle_offset(counts.start)) % self.period;
+            while edge < counts.end {
+                edges.push((edge, on));
+                edge += self.period;
+            }
+        }
+        edges.sort_unstable();
+        edges
+    }
+
+    /// PSRFITS-style cal keys for a DADA header, plus the exact cycle in payloads relative to `first_packet`
+    pub fn dada_keys(&self, first_packet: u64) -> Vec<(String, String)> {
+        vec![
+            ("CAL_MODE".to_owned(), "SYNC".to_owned()),
+            (
+                "CAL_FREQ".to_owned(),
+                (1.0 / (self.period as f64 * PACKET_CADENCE)).to_string(),
+            ),
+            (
+                "CAL_DCYC".to_owned(),
+                (self.on as f64 / self.period as f64).to_string(),
+            ),
+            (
+                "CAL_PHS".to_owned(),
+                (self.cycle_offset(first_packet) as f64 / self.period as f64).to_string(),
+            ),
+            ("CAL_PERIOD_PAYLOADS".to_owned(), self.period.to_string()),
+            ("CAL_ON_PAYLOADS".to_owned(), self.on.to_string()),
+            (
+                "CAL_FIRST_ON_PAYLOAD".to_owned(),
+                ((self.period - self.cycle_offset(first_packet)) % self.period).to_string(),
+            ),
+        ]
+    }
+}
+
+static CAL_SCHEDULE: OnceLock<CalSchedule> = OnceLock::new();
+
+/// Set the noise source schedule, once at startup. Returns false if it was already set.
+pub fn set_cal_schedule(schedule: CalSchedule) -> bool {
+    CAL_SCHEDULE.set(schedule).is_ok()
+}
+
+/// The noise source schedule, if there is one
+pub fn cal_schedule() -> Option<CalSchedule> {
+    CAL_SCHEDULE.get().copied()
+}
+
+/// A local wall-clock time of day, as seconds after midnight
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimeOfDay(u32);
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    /// Parse from HH:MM
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (h, m) = s
+            .split_once(':')
+            .ok_or_else(|| "Time of day should be HH:MM".to_owned())?;
+        let h: u32 = h.parse().map_err(|_| "Invalid hour")?;
+        let m: u32 = m.parse().map_err(|_| "Invalid minute")?;
+        if h >= 24 || m >= 60 {
+            return Err("Time of day out of range".to_owned());
+        }
+        Ok(Self(h * 3600 + m * 60))
+    }
+}
+
+/// Seconds after local midnight, right now
+fn local_seconds_of_day() -> u32 {
+    // SAFETY: time and localtime_r only write into the memory we give them
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+    }
+}
+
+/// Time from `now` (seconds after midnight) until the next of `times`, strictly after now
+fn until_next(times: &[TimeOfDay], now: u32) -> Option<Duration> {
+    times
+        .iter()
+        .map(|t| {
+            if t.0 > now {
+                t.0 - now
+            } else {
+                t.0 + SECONDS_PER_DAY - now
+            }
+        })
+        .min()
+        .map(|s| Duration::from_secs(s.into()))
+}
+
+/// Ask the monitoring task (which owns the FPGA) to recalibrate at each of the scheduled local times.
+/// Every recalibration is a scan boundary, so we log and audit each one.
+pub async fn recal_task(
+    times: Vec<TimeOfDay>,
+    devices: SyncSender<DeviceRequest>,
+    db: SyncSender<DbEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let Some(mut wait) = until_next(&times, local_seconds_of_day()) else {
+        return Ok(());
+    };
+    info!("Starting scheduled recalibration task");
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Scheduled recalibration task stopping");
+                break;
+            }
+            _ = tokio::time::sleep(wait) => {
+                let (reply, outcome) = oneshot::channel();
+                let request = DeviceRequest {
+                    command: DeviceCommand::Recalibrate,
+                    reply,
+                };
+                let outcome = match devices.try_send(request) {
+                    Ok(_) => outcome
+                        .await
+                        .unwrap_or_else(|_| Err("Monitoring task went away".to_owned())),
+                    Err(_) => Err("Monitoring task is busy".to_owned()),
+                };
+                let outcome = match outcome {
+                    Ok(s) => {
+                        info!(target: "audit", "Scheduled recalibration, scan boundary - {s}");
+                        s
+                    }
+                    Err(e) => {
+                        warn!("Scheduled recalibration failed - {e}");
+                        format!("error: {e}")
+                    }
+                };
+                let record = AuditRecord {
+                    mjd: hifitime::Epoch::now()
+                        .map(|e| time_policy().mjd(e))
+                        .unwrap_or_default(),
+                    action: "Recalibrate".to_owned(),
+                    requester: "schedule".to_owned(),
+                    outcome,
+                };
+                if db.try_send(DbEvent::Audit(record)).is_err() {
+                    warn!("Couldn't record the recalibration in the audit log");
+                }
+                wait = until_next(&times, local_seconds_of_day()).unwrap();
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level_gains() {
+        let gains = level_gains(&[0.0, 1.0, 4.0, 4.0, 16.0], 100);
+        assert_eq!(gains, vec![100, 200, 100, 100, 50]);
+    }
+
+    #[test]
+    fn test_until_next() {
+        let times: Vec<TimeOfDay> = ["06:30", "18:00"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            until_next(&times, 6 * 3600),
+            Some(Duration::from_secs(1800))
+        );
+        // Right on (or past) the last time of the day wraps around to tomorrow
+        assert_eq!(
+            until_next(&times, 18 * 3600),
+            Some(Duration::from_secs(12 * 3600 + 1800))
+        );
+        assert!("24:00".parse::<TimeOfDay>().is_err());
+    }
+
+    #[test]
+    fn test_cal_schedule() {
+        let cal = CalSchedule {
+            period: 10,
+            on: 4,
+            phase: 3,
+        };
+        assert!(!cal.is_on(2));
+        assert!(cal.is_on(3));
+        assert!(cal.is_on(6));
+        assert!(!cal.is_on(7));
+        assert!(cal.is_on(13));
+        assert_eq!(
+            cal.boundaries(0..20),
+            vec![(3, true), (7, false), (13, true), (17, false)]
+        );
+        assert_eq!(cal.boundaries(4..7), vec![]);
+        assert_eq!(cal.boundaries(7..8), vec![(7, false)]);
+    }
+}
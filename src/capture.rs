@@ -1,55 +1,759 @@
 //! Logic for capturing raw packets from the NIC, parsing them into payloads, and sending them to other processing threads
 
-use crate::common::{Payload, FIRST_PACKET};
+use crate::{
+    accounting::accounting,
+    baseband,
+    common::{
+        header_clock, payload_start_time, Payload, FIRST_PACKET, LATEST_PACKET, NEVER_RECEIVED,
+        PACKET_CADENCE,
+    },
+    flags::{flag_marks, Flags},
+    fpga::Device,
+    profiling::payload_profile,
+};
+use clap::ValueEnum;
 use socket2::{Domain, Socket, Type};
-use std::net::UdpSocket;
-use std::sync::atomic::Ordering;
-use std::sync::mpsc::SyncSender;
+use std::fs::File;
+use std::io::{BufReader, ErrorKind, Read, Seek};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc::SyncSender, Mutex, OnceLock};
 use std::{
     net::SocketAddr,
     time::{Duration, Instant},
 };
-use thingbuf::mpsc::blocking::StaticSender;
+use thingbuf::mpsc::blocking::Sender;
 use tokio::sync::broadcast;
-use tracing::{error, info, warn};
+use tracing::{info, warn};
 
 /// Size of the packet count header
 const TIMESTAMP_SIZE: usize = 8;
 /// Total number of bytes in the spectra block of the UDP payload
 const SPECTRA_SIZE: usize = 8192;
-/// Total UDP payload size
+/// Total UDP payload size (of the original packet format)
 pub const PAYLOAD_SIZE: usize = SPECTRA_SIZE + TIMESTAMP_SIZE;
+/// Size of the extended header words between the count and the spectra, in the formats that have them
+pub const EXT_HEADER_SIZE: usize = 8;
 /// Polling interval for stats
-const STATS_POLL_DURATION: Duration = Duration::from_secs(20);
+pub(crate) const STATS_POLL_DURATION: Duration = Duration::from_secs(20);
+/// Room for one cmsg carrying a timespec (u64 words, for alignment)
+const CONTROL_WORDS: usize = 8;
+/// Smallest power of two (ns) the arrival histogram resolves, anything shorter lands in its first bucket
+const ARRIVAL_MIN_OCTAVE: usize = 7;
+/// Powers of two the arrival histogram covers, 128 ns up to ~134 ms
+const ARRIVAL_OCTAVES: usize = 20;
+const ARRIVAL_SUB_BUCKETS: usize = 4;
+/// Buckets in the arrival histogram (not counting the overflow)
+pub const ARRIVAL_BUCKETS: usize = ARRIVAL_OCTAVES * ARRIVAL_SUB_BUCKETS;
+/// Longest we sleep on an empty socket before checking again (ms)
+const PARK_TIMEOUT_MS: libc::c_int = 100;
+/// Resyncs we remember for the dump ring, which only asks about them when it sees a jump
+const RESYNC_MEMORY: usize = 16;
+/// pcap magic numbers, for microsecond and nanosecond timestamps (as read in our byte order when the file's matches)
+const PCAP_MAGIC_US: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NS: u32 = 0xa1b23c4d;
+/// pcap link types we can find UDP in
+const LINKTYPE_ETHERNET: u32 = 1;
+const LINKTYPE_RAW: u32 = 101;
+const LINKTYPE_IPV4: u32 = 228;
 
 #[derive(thiserror::Error, Debug)]
 /// Errors that can be produced from captures
 pub enum Error {
     #[error("We received a payload which wasn't the size we expected {0}")]
     SizeMismatch(usize),
+    #[error("We received a {size} byte payload, which is the {found:?} packet format, not the {expected:?} we're configured for")]
+    FormatMismatch {
+        size: usize,
+        expected: PacketFormat,
+        found: PacketFormat,
+    },
     #[error("Failed to set the recv buffer size. We tried to set {expected}, but found {found}. Check sysctl net.core.rmem_max")]
     SetRecvBufferFailed { expected: usize, found: usize },
+    #[error("The recording's pcap link type ({0}) isn't one we can find UDP in")]
+    UnsupportedLinkType(u32),
+}
+
+/// Layout of the packets the gateware sends, so a gateware upgrade is a flag rather than a new binary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PacketFormat {
+    /// 8-byte count, then the spectra
+    #[default]
+    V1,
+    /// 8-byte count, 2-byte stream ID, 2-byte FPGA status flags, 4 reserved bytes, then the spectra
+    V2,
+}
+
+impl PacketFormat {
+    const ALL: [Self; 2] = [Self::V1, Self::V2];
+
+    /// Bytes of header between the count and the spectra
+    pub fn ext_header_size(self) -> usize {
+        match self {
+            Self::V1 => 0,
+            Self::V2 => EXT_HEADER_SIZE,
+        }
+    }
+
+    /// Total UDP payload size
+    pub fn payload_size(self) -> usize {
+        TIMESTAMP_SIZE + self.ext_header_size() + SPECTRA_SIZE
+    }
+
+    /// The format whose packets are `size` bytes, if any
+    fn of_size(size: usize) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.payload_size() == size)
+    }
+
+    /// Pull the fields we know about out of the extended header
+    fn parse_ext_header(self, bytes: &[u8; EXT_HEADER_SIZE]) -> ExtHeader {
+        match self {
+            Self::V1 => ExtHeader::default(),
+            Self::V2 => ExtHeader {
+                stream_id: Some(u16::from_le_bytes([bytes[0], bytes[1]])),
+                status: u16::from_le_bytes([bytes[2], bytes[3]]),
+            },
+        }
+    }
+
+    /// The extended header as it would have come over the wire (with the reserved bytes zeroed)
+    fn ext_header_bytes(self, header: ExtHeader) -> [u8; EXT_HEADER_SIZE] {
+        let mut bytes = [0; EXT_HEADER_SIZE];
+        if self == Self::V2 {
+            bytes[..2].copy_from_slice(&header.stream_id.unwrap_or_default().to_le_bytes());
+            bytes[2..4].copy_from_slice(&header.status.to_le_bytes());
+        }
+        bytes
+    }
+}
+
+/// What an extended packet header tells us beyond the count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct ExtHeader {
+    /// Which board the gateware thinks it is
+    stream_id: Option<u16>,
+    /// FPGA fault bits, zero when all is well
+    status: u16,
+}
+
+/// The extended header of a payload on `stream` that didn't say anything else (as we record zero-filled payloads with)
+pub fn plain_ext_header(stream: u16) -> [u8; EXT_HEADER_SIZE] {
+    packet_format().ext_header_bytes(ExtHeader {
+        stream_id: Some(stream),
+        status: 0,
+    })
+}
+
+/// How capture waits for data, as chosen on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum PollMode {
+    /// Always spin (a whole core, even with nothing coming in)
+    #[default]
+    Spin,
+    /// Spin for a while, then sleep until data arrives (for low-power test benches)
+    Hybrid,
+}
+
+/// How capture waits on a socket with nothing in it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// Spin on the socket, for the lowest latency at the cost of a whole core
+    #[default]
+    Spin,
+    /// Spin for this long after the socket runs dry, then sleep in poll(2) until something arrives
+    Hybrid(Duration),
+}
+
+static PACKET_FORMAT: OnceLock<PacketFormat> = OnceLock::new();
+static POLL_STRATEGY: OnceLock<PollStrategy> = OnceLock::new();
+static WATERMARK: AtomicBool = AtomicBool::new(false);
+
+/// Stamp every payload with how late we received it, once at startup
+pub fn set_watermarking(on: bool) {
+    WATERMARK.store(on, Ordering::Relaxed);
+}
+
+/// Nanoseconds since the unix epoch right now
+fn unix_ns() -> i128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i128)
+        .unwrap_or_default()
+}
+
+/// Microseconds between `recv_ns` and when payload `count` was due (packet 0 being at `start_ns`), saturating
+fn recv_latency(start_ns: i128, count: u64, recv_ns: i128) -> i32 {
+    // The packet cadence is a whole number of nanoseconds
+    let due = start_ns + i128::from(count) * (PACKET_CADENCE * 1e9).round() as i128;
+    ((recv_ns - due) / 1000).clamp(i128::from(i32::MIN + 1), i128::from(i32::MAX)) as i32
+}
+
+/// Set the global packet format, returning false if it was already set
+pub fn set_packet_format(format: PacketFormat) -> bool {
+    PACKET_FORMAT.set(format).is_ok()
+}
+
+/// The packet format we're capturing
+pub fn packet_format() -> PacketFormat {
+    PACKET_FORMAT.get().copied().unwrap_or_default()
+}
+
+/// Set how capture waits for data, returning false if it was already set
+pub fn set_poll_strategy(strategy: PollStrategy) -> bool {
+    POLL_STRATEGY.set(strategy).is_ok()
+}
+
+/// How capture waits for data
+pub fn poll_strategy() -> PollStrategy {
+    POLL_STRATEGY.get().copied().unwrap_or_default()
+}
+
+/// How fast to replay a recording
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ReplayRate {
+    /// At the cadence it was recorded at (the packet cadence, for raw files)
+    #[default]
+    Native,
+    /// As fast as the pipeline will take it
+    Fast,
+}
+
+/// What a recording is laid out as
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RecordingKind {
+    /// A packet capture, of which we want the UDP payloads sent to `port`
+    Pcap {
+        /// Whether the file's byte order is the opposite of ours
+        swapped: bool,
+        nanos: bool,
+        linktype: u32,
+        port: u16,
+    },
+    /// Payloads back to back, as they came off the wire
+    Raw,
+}
+
+/// A recording of payloads, read back in place of the socket
+struct Recording {
+    reader: BufReader<File>,
+    kind: RecordingKind,
+    rate: ReplayRate,
+    /// Wall clock and recording time (s) of the first payload, to pace the rest against
+    origin: Option<(Instant, f64)>,
+    /// Payloads read so far
+    read: u64,
+    /// Reused for each record of a pcap
+    record: Vec<u8>,
+}
+
+impl Recording {
+    fn open(path: &Path, port: u16, rate: ReplayRate) -> eyre::Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 24];
+        // Anything without a pcap header is taken to be raw payloads
+        let kind = match reader.read_exact(&mut header[..4]) {
+            Ok(()) => {
+                let magic = u32::from_ne_bytes(header[..4].try_into().unwrap());
+                let is_pcap = |m: u32| m == PCAP_MAGIC_US || m == PCAP_MAGIC_NS;
+                let swapped = !is_pcap(magic) && is_pcap(magic.swap_bytes());
+                if is_pcap(magic) || swapped {
+                    reader.read_exact(&mut header[4..])?;
+                    let m = if swapped { magic.swap_bytes() } else { magic };
+                    let linktype = u32::from_ne_bytes(header[20..24].try_into().unwrap());
+                    let linktype = if swapped {
+                        linktype.swap_bytes()
+                    } else {
+                        linktype
+                    };
+                    if ![LINKTYPE_ETHERNET, LINKTYPE_RAW, LINKTYPE_IPV4].contains(&linktype) {
+                        return Err(Error::UnsupportedLinkType(linktype).into());
+                    }
+                    RecordingKind::Pcap {
+                        swapped,
+                        nanos: m == PCAP_MAGIC_NS,
+                        linktype,
+                        port,
+                    }
+                } else {
+                    // Start over, those bytes were the first payload's
+                    reader.rewind()?;
+                    RecordingKind::Raw
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => RecordingKind::Raw,
+            Err(e) => return Err(e.into()),
+        };
+        info!(path = %path.display(), ?kind, ?rate, "Opened recording to replay");
+        Ok(Self {
+            reader,
+            kind,
+            rate,
+            origin: None,
+            read: 0,
+            record: vec![],
+        })
+    }
+
+    /// Read the next payload's bytes into `buf` (sized for the packet format), returning its time in the recording (s),
+    /// or None at the end of the recording
+    fn next(&mut self, buf: &mut [u8]) -> eyre::Result<Option<f64>> {
+        let eof = |r: std::io::Result<()>| match r {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        };
+        match self.kind {
+            RecordingKind::Raw => {
+                if !eof(self.reader.read_exact(buf))? {
+                    return Ok(None);
+                }
+                self.read += 1;
+                Ok(Some((self.read - 1) as f64 * PACKET_CADENCE))
+            }
+            RecordingKind::Pcap {
+                swapped,
+                nanos,
+                linktype,
+                port,
+            } => loop {
+                let mut header = [0u8; 16];
+                if !eof(self.reader.read_exact(&mut header))? {
+                    return Ok(None);
+                }
+                let field = |i: usize| {
+                    let v = u32::from_ne_bytes(header[4 * i..4 * (i + 1)].try_into().unwrap());
+                    if swapped {
+                        v.swap_bytes()
+                    } else {
+                        v
+                    }
+                };
+                let time = field(0) as f64 + field(1) as f64 * if nanos { 1e-9 } else { 1e-6 };
+                self.record.resize(field(2) as usize, 0);
+                if !eof(self.reader.read_exact(&mut self.record))? {
+                    return Ok(None);
+                }
+                // Anything that isn't one of our payloads (other ports, ARP, truncated captures) is skipped
+                match udp_payload(linktype, &self.record, port) {
+                    Some(payload) if payload.len() == buf.len() => {
+                        buf.copy_from_slice(payload);
+                        self.read += 1;
+                        return Ok(Some(time));
+                    }
+                    _ => continue,
+                }
+            },
+        }
+    }
+
+    /// Wait until a payload at `time` into the recording is due
+    fn pace(&mut self, time: f64) {
+        if self.rate == ReplayRate::Fast {
+            return;
+        }
+        let (wall, start) = *self.origin.get_or_insert((Instant::now(), time));
+        let due = wall + Duration::from_secs_f64((time - start).max(0.0));
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+/// Distinct payloads of noise the simulator cycles through
+const SIMULATED_PAYLOADS: usize = 64;
+
+/// A stand-in for the FPGA, generating noise payloads at some multiple of the real-time rate (for soak tests)
+struct Simulator {
+    /// Spectra of noise, SIMULATED_PAYLOADS of them back to back
+    noise: Vec<u8>,
+    /// Count of the next payload
+    count: u64,
+    /// How much faster than real time we go
+    overspeed: f64,
+    /// When we started, so the pace doesn't drift
+    start: Option<Instant>,
+}
+
+impl Simulator {
+    fn new(overspeed: f64) -> Self {
+        // Cheap deterministic noise (xorshift), roughly gaussian by summing a few uniforms, well within the 8 bits
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut uniform = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 56) as i32 - 128
+        };
+        let noise = (0..SIMULATED_PAYLOADS * SPECTRA_SIZE)
+            .map(|_| ((uniform() + uniform() + uniform() + uniform()) / 32) as i8 as u8)
+            .collect();
+        Self {
+            noise,
+            count: 0,
+            overspeed,
+            start: None,
+        }
+    }
+
+    /// Fill in the next payload
+    fn next(&mut self, payload: &mut Payload) {
+        payload.count = self.count;
+        let i = self.count as usize % SIMULATED_PAYLOADS;
+        // Safety: The two pols are contiguous, SPECTRA_SIZE bytes of plain integers (as we receive into from the socket)
+        let spectra = unsafe {
+            std::slice::from_raw_parts_mut(payload.pol_a.as_mut_ptr().cast::<u8>(), SPECTRA_SIZE)
+        };
+        spectra.copy_from_slice(&self.noise[i * SPECTRA_SIZE..(i + 1) * SPECTRA_SIZE]);
+        self.count += 1;
+    }
+
+    /// Wait until the next payload is due
+    fn pace(&mut self) {
+        let start = *self.start.get_or_insert_with(Instant::now);
+        let due =
+            start + Duration::from_secs_f64(self.count as f64 * PACKET_CADENCE / self.overspeed);
+        let now = Instant::now();
+        if due > now {
+            std::thread::sleep(due - now);
+        }
+    }
+}
+
+/// The UDP payload of a datagram to `port` in a captured frame, if that's what it is
+fn udp_payload(linktype: u32, frame: &[u8], port: u16) -> Option<&[u8]> {
+    let be16 = |b: &[u8], i: usize| -> Option<u16> {
+        Some(u16::from_be_bytes([*b.get(i)?, *b.get(i + 1)?]))
+    };
+    let ip = match linktype {
+        LINKTYPE_ETHERNET => {
+            // Skip over any VLAN tags to the ethertype
+            let mut offset = 12;
+            while be16(frame, offset)? == 0x8100 {
+                offset += 4;
+            }
+            if be16(frame, offset)? != 0x0800 {
+                return None;
+            }
+            frame.get(offset + 2..)?
+        }
+        _ => frame,
+    };
+    // IPv4, UDP, and not a fragment
+    if ip.first()? >> 4 != 4 || *ip.get(9)? != 17 || be16(ip, 6)? & 0x3fff != 0 {
+        return None;
+    }
+    let ihl = usize::from(ip[0] & 0xf) * 4;
+    let udp = ip.get(ihl..)?;
+    if be16(udp, 2)? != port {
+        return None;
+    }
+    let len = usize::from(be16(udp, 4)?);
+    udp.get(8..len)
+}
+
+/// Where capture gets its datagrams from
+enum Input {
+    Socket(UdpSocket),
+    Recording(Recording),
+    Simulated(Simulator),
 }
 
 pub struct Capture {
-    /// The socket itself
-    sock: UdpSocket,
+    /// The socket (or recording or simulator standing in for it)
+    input: Input,
     /// How many packets we've dropped because the incoming one wasn't n+1
     pub drops: usize,
     /// How many packets from the past we've received (indicating there was a shuffle somewhere)
     pub shuffled: usize,
     /// The number of packets we've actually processed
     pub processed: usize,
+    /// How many datagrams we've thrown away for coming from a sender we weren't expecting
+    pub rejected: usize,
+    /// If any, only accept datagrams from these senders
+    sources: Vec<AllowedSource>,
     /// Marker bool for the first packet
     first_payload: bool,
     /// The next payload count we expect
     next_expected_count: u64,
+    /// Jumps in payload count (either way) bigger than this mean the FPGA started over, so we resync instead of filling
+    max_gap: u64,
+    /// How many times we've resynchronized to a new packet sequence
+    pub resyncs: usize,
+    /// How many payloads arrived early and waited in the reorder window
+    pub reordered: usize,
+    /// Payloads that arrived ahead of one we're missing, by count modulo the window, waiting for it to show up
+    reorder: Vec<Option<(Payload, ExtHeader)>>,
+    /// When watermarking, when packet 0 was due (ns since the unix epoch), and when the current batch arrived
+    watermark: Option<(i128, i128)>,
+    /// How many packets came with FPGA status bits set
+    pub faults: usize,
+    /// Time between datagrams, as the kernel saw them arrive
+    pub arrivals: ArrivalHistogram,
+    /// Layout of the packets we're receiving
+    format: PacketFormat,
+    /// How we wait when the socket runs dry
+    poll: PollStrategy,
+    /// Whether we've already complained about the stream ID in the header not matching ours
+    stream_id_warned: bool,
+    /// Tagged onto every payload we capture
+    stream: u16,
+    /// Whether this is the stream that sets the observation's timing and flags (and goes into the sample accounting)
+    primary: bool,
+    /// Payloads we receive straight into, as many as we ask for per syscall
+    batch: Vec<Payload>,
+    scratch: RecvScratch,
+}
+
+/// What recvmmsg needs alongside the batch, rebuilt to point at it before every call.
+/// Each datagram is scattered into the count and spectra of its payload, with any extended header in between set aside.
+struct RecvScratch {
+    addrs: Vec<libc::sockaddr_in>,
+    /// Count, extended header, spectra
+    iovecs: Vec<[libc::iovec; 3]>,
+    msgs: Vec<libc::mmsghdr>,
+    /// Extended headers, in step with the batch
+    headers: Vec<[u8; EXT_HEADER_SIZE]>,
+    /// Ancillary data (the kernel's receive timestamp), aligned for cmsghdr
+    controls: Vec<[u64; CONTROL_WORDS]>,
+}
+
+impl RecvScratch {
+    fn new(n: usize) -> Self {
+        // Safety: These are plain C structs, for which all zeros is valid (null pointers and zero lengths)
+        unsafe {
+            Self {
+                addrs: vec![std::mem::zeroed(); n],
+                iovecs: vec![std::mem::zeroed(); n],
+                msgs: vec![std::mem::zeroed(); n],
+                headers: vec![[0; EXT_HEADER_SIZE]; n],
+                controls: vec![[0; CONTROL_WORDS]; n],
+            }
+        }
+    }
+}
+
+/// A sender we accept datagrams from, on any port unless one is given
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllowedSource {
+    pub ip: IpAddr,
+    pub port: Option<u16>,
+}
+
+impl AllowedSource {
+    fn allows(&self, from: SocketAddr) -> bool {
+        from.ip() == self.ip && self.port.iter().all(|&port| from.port() == port)
+    }
+}
+
+impl From<IpAddr> for AllowedSource {
+    fn from(ip: IpAddr) -> Self {
+        Self { ip, port: None }
+    }
+}
+
+impl std::str::FromStr for AllowedSource {
+    type Err = String;
+
+    /// Either an address, or an address and port (`192.168.0.20` or `192.168.0.20:60000`), or `fpga` for the data
+    /// address we give the board we control
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "fpga" {
+            Ok(IpAddr::from(Device::DATA_IP).into())
+        } else if let Ok(addr) = s.parse::<SocketAddr>() {
+            Ok(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            })
+        } else {
+            s.parse::<IpAddr>()
+                .map(Self::from)
+                .map_err(|_| format!("{s} isn't an address or address:port"))
+        }
+    }
+}
+
+/// The kernel's receive time of a datagram (ns since the unix epoch), if it stamped it
+fn rx_timestamp(hdr: &libc::msghdr) -> Option<u64> {
+    // Safety: The control buffer is ours, and the kernel filled in msg_controllen of it with well-formed cmsgs
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(hdr);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_TIMESTAMPNS
+            {
+                let ts = std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<libc::timespec>());
+                return Some(ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64);
+            }
+            cmsg = libc::CMSG_NXTHDR(hdr, cmsg);
+        }
+    }
+    None
+}
+
+/// A cheap HDR-style histogram of the time between datagrams (ns): each power of two is split into
+/// ARRIVAL_SUB_BUCKETS linear buckets, so the resolution is ~25% of the value anywhere in the range
+#[derive(Debug, Clone)]
+pub struct ArrivalHistogram {
+    /// Counts per bucket, with one more at the end for anything past the range
+    pub counts: Vec<u64>,
+    /// Sum of every interval (ns)
+    pub sum_ns: u64,
+    /// Receive time of the last datagram
+    last: Option<u64>,
+}
+
+impl Default for ArrivalHistogram {
+    fn default() -> Self {
+        Self {
+            counts: vec![0; ARRIVAL_BUCKETS + 1],
+            sum_ns: 0,
+            last: None,
+        }
+    }
+}
+
+impl ArrivalHistogram {
+    fn bucket(ns: u64) -> usize {
+        let ns = ns.max(1 << ARRIVAL_MIN_OCTAVE);
+        let octave = (u64::BITS - 1 - ns.leading_zeros()) as usize;
+        if octave >= ARRIVAL_MIN_OCTAVE + ARRIVAL_OCTAVES {
+            return ARRIVAL_BUCKETS;
+        }
+        // The two bits under the leading one pick the sub-bucket
+        let sub = (ns >> (octave - 2)) as usize & (ARRIVAL_SUB_BUCKETS - 1);
+        (octave - ARRIVAL_MIN_OCTAVE) * ARRIVAL_SUB_BUCKETS + sub
+    }
+
+    /// Exclusive upper bound (ns) of a bucket (other than the overflow)
+    pub fn upper_bound(bucket: usize) -> u64 {
+        let octave = bucket / ARRIVAL_SUB_BUCKETS + ARRIVAL_MIN_OCTAVE;
+        let sub = bucket % ARRIVAL_SUB_BUCKETS;
+        ((ARRIVAL_SUB_BUCKETS + sub + 1) as u64) << (octave - 2)
+    }
+
+    fn record(&mut self, ns: u64) {
+        self.counts[Self::bucket(ns)] += 1;
+        self.sum_ns = self.sum_ns.saturating_add(ns);
+    }
+
+    /// Record the interval since the last datagram arrived (the kernel's clock is monotonic enough over this)
+    fn record_arrival(&mut self, ns: u64) {
+        if let Some(last) = self.last {
+            self.record(ns.saturating_sub(last));
+        }
+        self.last = Some(ns);
+    }
+
+    /// Add another stream's counts into this one
+    pub fn merge(&mut self, other: &Self) {
+        for (a, b) in self.counts.iter_mut().zip(&other.counts) {
+            *a += b;
+        }
+        self.sum_ns = self.sum_ns.saturating_add(other.sum_ns);
+    }
+}
+
+/// A network interface to capture on, rather than whichever one the kernel delivers the port from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nic {
+    pub name: String,
+    /// Multicast group the stream is sent to, joined on this interface
+    pub group: Option<Ipv4Addr>,
+}
+
+/// Set a socket option the socket2 API doesn't cover
+fn set_option<T>(
+    socket: &Socket,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: &T,
+) -> std::io::Result<()> {
+    // Safety: Passing a value of exactly the size we claim, on a socket we own
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (value as *const T).cast(),
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if set == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+/// Only receive from `nic` (so each of several sockets on the same port gets its own copy), joining its multicast group
+fn attach_to_nic(socket: &Socket, nic: &Nic) -> eyre::Result<()> {
+    let name = std::ffi::CString::new(nic.name.as_str())?;
+    // Safety: A valid, nul-terminated string
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        eyre::bail!("No network interface named {}", nic.name);
+    }
+    // The kernel wants the name itself, not a struct
+    let device = name.as_bytes_with_nul();
+    // Safety: Passing the name's bytes and their length, on a socket we own
+    let set = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_BINDTODEVICE,
+            device.as_ptr().cast(),
+            device.len() as libc::socklen_t,
+        )
+    };
+    if set != 0 {
+        return Err(eyre::eyre!(
+            "Couldn't bind capture to {} - {}",
+            nic.name,
+            std::io::Error::last_os_error()
+        ));
+    }
+    if let Some(group) = nic.group {
+        let request = libc::ip_mreqn {
+            imr_multiaddr: libc::in_addr {
+                s_addr: u32::from(group).to_be(),
+            },
+            imr_address: libc::in_addr { s_addr: 0 },
+            imr_ifindex: index as libc::c_int,
+        };
+        set_option(socket, libc::IPPROTO_IP, libc::IP_ADD_MEMBERSHIP, &request)
+            .map_err(|e| eyre::eyre!("Couldn't join {group} on {} - {e}", nic.name))?;
+    }
+    Ok(())
+}
+
+fn sockaddr_to_std(addr: &libc::sockaddr_in) -> SocketAddr {
+    SocketAddr::from((
+        Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+        u16::from_be(addr.sin_port),
+    ))
 }
 
 impl Capture {
-    pub fn new(port: u16) -> eyre::Result<Self> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        port: u16,
+        sources: Vec<AllowedSource>,
+        stream: u16,
+        primary: bool,
+        max_gap: u64,
+        batch: usize,
+        reorder_window: usize,
+        nic: Option<&Nic>,
+    ) -> eyre::Result<Self> {
         // Create UDP socket
         let socket = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+        // Sockets on different interfaces can share the port
+        if let Some(nic) = nic {
+            socket.set_reuse_address(true)?;
+            attach_to_nic(&socket, nic)?;
+        }
         // Bind our listening address
         let address = SocketAddr::from(([0, 0, 0, 0], port));
         socket.bind(&address.into())?;
@@ -67,125 +771,846 @@ impl Capture {
             }
             .into());
         }
+        // Have the kernel stamp every datagram as it arrives, for the inter-arrival histogram
+        let on: libc::c_int = 1;
+        if let Err(e) = set_option(&socket, libc::SOL_SOCKET, libc::SO_TIMESTAMPNS, &on) {
+            warn!("Couldn't enable receive timestamps, there won't be an inter-arrival histogram - {e}");
+        }
         // Set into nonblocking mode
         socket.set_nonblocking(true)?;
         // Replace the socket2 socket with a std socket
         let sock = socket.into();
-        Ok(Self {
-            sock,
+        Ok(Self::with_input(
+            Input::Socket(sock),
+            sources,
+            stream,
+            primary,
+            max_gap,
+            batch,
+            reorder_window,
+        ))
+    }
+
+    /// Capture from a recording instead of the network
+    pub fn replay(
+        path: &Path,
+        port: u16,
+        rate: ReplayRate,
+        stream: u16,
+        max_gap: u64,
+        batch: usize,
+        reorder_window: usize,
+    ) -> eyre::Result<Self> {
+        let recording = Recording::open(path, port, rate)?;
+        Ok(Self::with_input(
+            Input::Recording(recording),
+            vec![],
+            stream,
+            true,
+            max_gap,
+            batch,
+            reorder_window,
+        ))
+    }
+
+    /// Capture from a simulated source running `overspeed` times faster than real time
+    pub fn simulate(
+        overspeed: f64,
+        stream: u16,
+        max_gap: u64,
+        batch: usize,
+        reorder_window: usize,
+    ) -> Self {
+        Self::with_input(
+            Input::Simulated(Simulator::new(overspeed)),
+            vec![],
+            stream,
+            true,
+            max_gap,
+            batch,
+            reorder_window,
+        )
+    }
+
+    fn with_input(
+        input: Input,
+        sources: Vec<AllowedSource>,
+        stream: u16,
+        primary: bool,
+        max_gap: u64,
+        batch: usize,
+        reorder_window: usize,
+    ) -> Self {
+        Self {
+            input,
             drops: 0,
             processed: 0,
             shuffled: 0,
+            rejected: 0,
+            sources,
             first_payload: true,
             next_expected_count: 0,
-        })
+            max_gap,
+            resyncs: 0,
+            reordered: 0,
+            reorder: vec![None; reorder_window],
+            watermark: None,
+            faults: 0,
+            arrivals: ArrivalHistogram::default(),
+            format: packet_format(),
+            poll: poll_strategy(),
+            stream_id_warned: false,
+            stream,
+            primary,
+            batch: vec![Payload::default(); batch],
+            scratch: RecvScratch::new(batch),
+        }
     }
 
-    pub fn capture(&mut self, buf: &mut [u8]) -> eyre::Result<()> {
+    /// Read the next batch from the recording (pacing it if we're asked to), returning how many we got, 0 at the end
+    fn replay_batch(&mut self) -> eyre::Result<usize> {
+        let Input::Recording(recording) = &mut self.input else {
+            unreachable!()
+        };
+        let ext_size = self.format.ext_header_size();
+        let mut buf = vec![0u8; self.format.payload_size()];
+        let mut read = 0;
+        for (payload, header) in self.batch.iter_mut().zip(&mut self.scratch.headers) {
+            let Some(time) = recording.next(&mut buf)? else {
+                break;
+            };
+            recording.pace(time);
+            payload.count = u64::from_le_bytes(buf[..TIMESTAMP_SIZE].try_into().unwrap());
+            header[..ext_size].copy_from_slice(&buf[TIMESTAMP_SIZE..TIMESTAMP_SIZE + ext_size]);
+            // Safety: The two pols are contiguous, SPECTRA_SIZE bytes of plain integers (as we receive into from the socket)
+            let spectra = unsafe {
+                std::slice::from_raw_parts_mut(
+                    payload.pol_a.as_mut_ptr().cast::<u8>(),
+                    SPECTRA_SIZE,
+                )
+            };
+            spectra.copy_from_slice(&buf[TIMESTAMP_SIZE + ext_size..]);
+            read += 1;
+        }
+        Ok(read)
+    }
+
+    /// Generate the next batch from the simulator, once it's due
+    fn simulate_batch(&mut self) -> usize {
+        let Input::Simulated(simulator) = &mut self.input else {
+            unreachable!()
+        };
+        simulator.pace();
+        for (payload, header) in self.batch.iter_mut().zip(&mut self.scratch.headers) {
+            simulator.next(payload);
+            *header = [0; EXT_HEADER_SIZE];
+        }
+        self.batch.len()
+    }
+
+    /// Receive the next batch of datagrams (at least one), returning how many landed at the front of the batch
+    fn capture_batch(&mut self) -> eyre::Result<usize> {
+        let fd = match &self.input {
+            Input::Socket(sock) => sock.as_raw_fd(),
+            Input::Recording(_) => return self.replay_batch(),
+            Input::Simulated(_) => return Ok(self.simulate_batch()),
+        };
+        let n = self.batch.len();
+        let (ext_size, payload_size) = (self.format.ext_header_size(), self.format.payload_size());
+        // When the socket first ran dry, since the last batch
+        let mut dry_since = None;
         loop {
-            match self.sock.recv(buf) {
-                Ok(n) => {
-                    if n != buf.len() {
-                        return Err(Error::SizeMismatch(n).into());
-                    } else {
-                        return Ok(());
+            // Point the scratch space at the batch, it could have moved since last time
+            let scratch = &mut self.scratch;
+            for (((((payload, iovecs), msg), addr), header), control) in self
+                .batch
+                .iter_mut()
+                .zip(&mut scratch.iovecs)
+                .zip(&mut scratch.msgs)
+                .zip(&mut scratch.addrs)
+                .zip(&mut scratch.headers)
+                .zip(&mut scratch.controls)
+            {
+                *iovecs = [
+                    libc::iovec {
+                        iov_base: (&mut payload.count as *mut u64).cast(),
+                        iov_len: TIMESTAMP_SIZE,
+                    },
+                    libc::iovec {
+                        iov_base: header.as_mut_ptr().cast(),
+                        iov_len: ext_size,
+                    },
+                    libc::iovec {
+                        // The two pols are contiguous, and the stream tag after them is ours
+                        iov_base: payload.pol_a.as_mut_ptr().cast(),
+                        iov_len: SPECTRA_SIZE,
+                    },
+                ];
+                msg.msg_hdr.msg_iov = iovecs.as_mut_ptr();
+                msg.msg_hdr.msg_iovlen = iovecs.len() as _;
+                msg.msg_hdr.msg_name = (addr as *mut libc::sockaddr_in).cast();
+                msg.msg_hdr.msg_namelen =
+                    std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+                msg.msg_hdr.msg_control = control.as_mut_ptr().cast();
+                msg.msg_hdr.msg_controllen = std::mem::size_of_val(control) as _;
+                msg.msg_hdr.msg_flags = 0;
+            }
+            // Safety: Every message points at memory we own for the duration of the call, sized as we've said
+            let received = unsafe {
+                libc::recvmmsg(
+                    fd,
+                    self.scratch.msgs.as_mut_ptr(),
+                    n as libc::c_uint,
+                    0,
+                    std::ptr::null_mut(),
+                )
+            };
+            if received < 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() == std::io::ErrorKind::WouldBlock {
+                    self.wait_readable(fd, *dry_since.get_or_insert_with(Instant::now));
+                    continue;
+                }
+                return Err(err.into());
+            }
+            // Keep what we want at the front of the batch
+            let mut kept = 0;
+            let received = &self.scratch.msgs[..received as usize];
+            for (i, (msg, addr)) in received.iter().zip(&self.scratch.addrs).enumerate() {
+                // Whatever strangers send us, it's theirs and not the FPGA's that's the wrong size
+                if !self.sources.is_empty() {
+                    let from = sockaddr_to_std(addr);
+                    if !self.sources.iter().any(|s| s.allows(from)) {
+                        if self.rejected == 0 {
+                            warn!(%from, "Rejecting datagram from unexpected source");
+                        }
+                        self.rejected += 1;
+                        continue;
                     }
                 }
-                Err(ref err) if err.kind() == std::io::ErrorKind::WouldBlock => {
-                    continue;
+                if msg.msg_len as usize != payload_size
+                    || msg.msg_hdr.msg_flags & libc::MSG_TRUNC != 0
+                {
+                    return Err(self.size_error(msg.msg_len as usize).into());
+                }
+                if let Some(ns) = rx_timestamp(&msg.msg_hdr) {
+                    self.arrivals.record_arrival(ns);
+                }
+                if kept != i {
+                    self.batch.swap(kept, i);
+                    self.scratch.headers.swap(kept, i);
+                }
+                kept += 1;
+            }
+            // Stray datagrams, go around again
+            if kept > 0 {
+                return Ok(kept);
+            }
+        }
+    }
+
+    /// Back off from an empty socket, if we're not spinning (or have spun long enough)
+    fn wait_readable(&self, fd: RawFd, dry_since: Instant) {
+        let PollStrategy::Hybrid(spin) = self.poll else {
+            return;
+        };
+        if dry_since.elapsed() < spin {
+            std::hint::spin_loop();
+            return;
+        }
+        let mut pfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Safety: One pollfd, which we own. Whatever the outcome, the next recvmmsg finds out.
+        unsafe { libc::poll(&mut pfd, 1, PARK_TIMEOUT_MS) };
+    }
+
+    /// Why a datagram of `size` wasn't what we expected, pointing out when it's a format we know
+    fn size_error(&self, size: usize) -> Error {
+        match PacketFormat::of_size(size) {
+            Some(found) if found != self.format => Error::FormatMismatch {
+                size,
+                expected: self.format,
+                found,
+            },
+            _ => Error::SizeMismatch(size),
+        }
+    }
+
+    /// Act on what the extended header says about a payload (whose count has been normalized)
+    fn check_ext_header(&mut self, count: u64, header: ExtHeader) {
+        if let Some(id) = header.stream_id {
+            if id != self.stream && !self.stream_id_warned {
+                warn!(
+                    stream = self.stream,
+                    header = id,
+                    "Stream ID in the packet header doesn't match the port it came in on"
+                );
+                self.stream_id_warned = true;
+            }
+        }
+        if header.status != 0 {
+            if self.faults == 0 {
+                warn!(
+                    status = header.status,
+                    "FPGA reported a fault in a packet header"
+                );
+            }
+            self.faults += 1;
+            if self.primary {
+                flag_marks().mark(count..count + 1, Flags::FPGA_STATUS);
+            }
+        }
+    }
+
+    /// Add to one of the observation's counters, if we're the stream it's about
+    fn account(&self, counter: &AtomicU64, n: u64) {
+        // Only the primary stream counts towards the observation's accounting, the rest are in their stats
+        if self.primary {
+            counter.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Send on a payload at or after the next one we expect, filling in any that never came before it
+    fn emit(
+        &mut self,
+        payload: &Payload,
+        header: ExtHeader,
+        payload_sender: &Sender<Payload>,
+    ) -> eyre::Result<()> {
+        if payload.count > self.next_expected_count {
+            // Packets were dropped, fill in with zeros (hopefully not too many)
+            let drops = payload.count - self.next_expected_count;
+            warn!("Jump in packet count, dropping {} packets", drops);
+            if self.primary {
+                flag_marks().mark(self.next_expected_count..payload.count, Flags::ZERO_FILLED);
+            }
+            // Recordings get the fill too, so they stay contiguous
+            let filled_header = plain_ext_header(self.stream);
+            for d in 0..drops {
+                // Create the payload in it's place
+                let pl = Payload {
+                    count: self.next_expected_count + d,
+                    stream: self.stream,
+                    recv_latency: NEVER_RECEIVED,
+                    ..Default::default()
+                };
+                if self.primary {
+                    baseband::record(&pl, &filled_header);
+                }
+                // And send
+                payload_sender.send(pl)?;
+            }
+            self.account(&accounting().filled, drops);
+            // Increment our drops counter
+            self.drops += drops as usize;
+        }
+        payload_sender.send(*payload)?;
+        self.account(&accounting().captured, 1);
+        if self.primary {
+            baseband::record(payload, &self.format.ext_header_bytes(header));
+        }
+        self.next_expected_count = payload.count + 1;
+        Ok(())
+    }
+
+    /// Send on whatever is waiting in the reorder window that's now next in line
+    fn drain_reorder(&mut self, payload_sender: &Sender<Payload>) -> eyre::Result<()> {
+        while !self.reorder.is_empty() {
+            let slot = (self.next_expected_count % self.reorder.len() as u64) as usize;
+            match self.reorder[slot].take() {
+                Some((waiting, header)) if waiting.count == self.next_expected_count => {
+                    self.emit(&waiting, header, payload_sender)?;
                 }
-                Err(e) => return Err(e.into()),
+                other => {
+                    self.reorder[slot] = other;
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Give up on whatever's missing from the reorder window, sending on everything in it (in order)
+    fn flush_reorder(&mut self, payload_sender: &Sender<Payload>) -> eyre::Result<()> {
+        let window = self.reorder.len() as u64;
+        for count in self.next_expected_count..self.next_expected_count + window {
+            let slot = (count % window) as usize;
+            if let Some((waiting, header)) = self.reorder[slot].take() {
+                self.emit(&waiting, header, payload_sender)?;
             }
         }
+        Ok(())
+    }
+
+    /// Account for and send on one captured payload, holding it in the reorder window if some before it haven't arrived
+    fn process(
+        &mut self,
+        payload: &mut Payload,
+        header: ExtHeader,
+        payload_sender: &Sender<Payload>,
+        stats_send: &SyncSender<CaptureEvent>,
+    ) -> eyre::Result<()> {
+        // Normalize the header into packets since the sync PPS
+        payload.count = header_clock().packet_count(payload.count);
+        payload.stream = self.stream;
+        if let Some((start_ns, recv_ns)) = self.watermark {
+            payload.recv_latency = recv_latency(start_ns, payload.count, recv_ns);
+        }
+        self.check_ext_header(payload.count, header);
+        self.processed += 1;
+        let window = self.reorder.len() as u64;
+        // Check first payload
+        if self.first_payload {
+            self.first_payload = false;
+            // And send the first one
+            if self.primary {
+                FIRST_PACKET.swap(payload.count, Ordering::Acquire);
+            }
+            self.next_expected_count = payload.count;
+            self.emit(payload, header, payload_sender)?;
+        } else if payload.count.abs_diff(self.next_expected_count) > self.max_gap {
+            // The FPGA started over (or skipped far ahead), filling the gap would flood everything downstream
+            let resync = Resync {
+                stream: self.stream,
+                expected: self.next_expected_count,
+                count: payload.count,
+            };
+            warn!(
+                expected = resync.expected,
+                count = resync.count,
+                "Packet count jumped past the max gap, resynchronizing"
+            );
+            // What we were waiting on belongs to the old sequence
+            self.flush_reorder(payload_sender)?;
+            note_resync(self.stream, payload.count);
+            if stats_send.try_send(CaptureEvent::Resync(resync)).is_err() {
+                warn!("Monitoring isn't keeping up, couldn't report the resync");
+            }
+            self.resyncs += 1;
+            self.next_expected_count = payload.count;
+            self.emit(payload, header, payload_sender)?;
+        } else if payload.count < self.next_expected_count {
+            // If the packet is from the past (past even the reorder window), we drop it
+            warn!("Anachronistic payload, dropping packet");
+            self.shuffled += 1;
+            self.account(&accounting().shuffled, 1);
+        } else if payload.count == self.next_expected_count {
+            self.emit(payload, header, payload_sender)?;
+            // Which may be what the ones in the window were waiting for
+            self.drain_reorder(payload_sender)?;
+        } else if payload.count < self.next_expected_count + window {
+            // Ahead of what we're missing, but close enough to wait for it
+            let slot = (payload.count % window) as usize;
+            if self.reorder[slot].is_some() {
+                warn!("Duplicate payload in the reorder window, dropping packet");
+                self.shuffled += 1;
+                self.account(&accounting().shuffled, 1);
+            } else {
+                self.reorder[slot] = Some((*payload, header));
+                self.reordered += 1;
+            }
+        } else {
+            // Too far ahead to keep waiting on what's missing
+            self.flush_reorder(payload_sender)?;
+            self.emit(payload, header, payload_sender)?;
+        }
+        if self.primary {
+            LATEST_PACKET.store(self.next_expected_count - 1, Ordering::Relaxed);
+        }
+        Ok(())
     }
 
     pub fn start(
         &mut self,
-        payload_sender: StaticSender<Payload>,
-        stats_send: SyncSender<Stats>,
+        payload_sender: Sender<Payload>,
+        stats_send: SyncSender<CaptureEvent>,
         stats_polling_time: Duration,
         mut shutdown: broadcast::Receiver<()>,
     ) -> eyre::Result<()> {
         let mut last_stats = Instant::now();
-        let mut capture_buf = [0u8; PAYLOAD_SIZE];
+        // Packet 0 is set before capture starts (and doesn't move, short of a re-arm before any packets)
+        let watermark_start = if WATERMARK.load(Ordering::Relaxed) {
+            let start = payload_start_time().lock().unwrap().ok_or_else(|| {
+                eyre::eyre!("Can't watermark payloads without a packet start time")
+            })?;
+            Some((start.to_unix_seconds() * 1e9) as i128)
+        } else {
+            None
+        };
+        let profile = payload_profile(if self.primary {
+            "capture"
+        } else {
+            "capture_aux"
+        });
         loop {
             // Look for shutdown signal
             if shutdown.try_recv().is_ok() {
                 info!("Capture task stopping");
                 break;
             }
-            // Capture into buf
-            self.capture(&mut capture_buf[..])?;
-            // Transmute into a payload
-            // Safety: We will always own the bytes, and the FPGA code ensures this is a valid thing to do
-            // Also, we've checked that we've captured exactly 8200 bytes, which is the size of the payload
-            let payload = unsafe { &*(capture_buf.as_ptr() as *const Payload) };
-            self.processed += 1;
+            let received = self.capture_batch()?;
+            // One receive time for the whole batch, they came out of the socket together
+            self.watermark = watermark_start.map(|start| (start, unix_ns()));
+            // Only a recording runs out
+            if received == 0 {
+                info!("Replay finished, capture stopping");
+                break;
+            }
+            // Out of self while we work through it, so we can process payloads without copying them out
+            let mut batch = std::mem::take(&mut self.batch);
+            for (i, payload) in batch[..received].iter_mut().enumerate() {
+                let iter_start = Instant::now();
+                let header = self.format.parse_ext_header(&self.scratch.headers[i]);
+                self.process(payload, header, &payload_sender, &stats_send)?;
+                profile.record(iter_start.elapsed());
+            }
+            self.batch = batch;
             // Send away the stats if the time has come (non blocking)
             if last_stats.elapsed() >= stats_polling_time {
-                let _ = stats_send.try_send(Stats {
+                let _ = stats_send.try_send(CaptureEvent::Stats(Stats {
+                    stream: self.stream,
                     drops: self.drops,
                     processed: self.processed,
                     shuffled: self.shuffled,
-                });
+                    rejected: self.rejected,
+                    resyncs: self.resyncs,
+                    reordered: self.reordered,
+                    faults: self.faults,
+                    arrivals: self.arrivals.clone(),
+                }));
                 last_stats = Instant::now();
             }
-            // Check first payload
-            if self.first_payload {
-                self.first_payload = false;
-                // And send the first one
-                payload_sender.send(*payload)?;
-                FIRST_PACKET.swap(payload.count, Ordering::Acquire);
-                self.next_expected_count = payload.count + 1;
-            } else if payload.count == self.next_expected_count {
-                self.next_expected_count += 1;
-                // And send
-                payload_sender.send(*payload)?;
-            } else if payload.count < self.next_expected_count {
-                // If the packet is from the past, we drop it
-                warn!("Anachronistic payload, dropping packet");
-                self.shuffled += 1;
-            } else {
-                // payload.count > self.next_expected_count
-                // Packets were dropped, fill in with zeros (hopefully not too many)
-                let drops = payload.count - self.next_expected_count;
-                warn!("Jump in packet count, dropping {} packets", drops);
-                for d in 0..drops {
-                    // Create the payload in it's place
-                    let pl = Payload {
-                        count: self.next_expected_count + d,
-                        ..Default::default()
-                    };
-                    // And send
-                    payload_sender.send(pl)?;
-                }
-                // Don't forget to send *this* payload!!
-                payload_sender.send(*payload)?;
-                // Increment our drops counter
-                self.drops += drops as usize;
-                // And finally update the next expected
-                self.next_expected_count = payload.count + 1;
-            }
         }
         Ok(())
     }
 }
 
+/// A jump in payload count too big to fill, after which capture picked up the new sequence where it was
+#[derive(Debug, Clone, Copy)]
+pub struct Resync {
+    pub stream: u16,
+    /// The count we were expecting
+    pub expected: u64,
+    /// The count we got, which the stream carries on from
+    pub count: u64,
+}
+
+/// What capture tells the monitoring thread
+#[derive(Debug, Clone)]
+pub enum CaptureEvent {
+    Stats(Stats),
+    Resync(Resync),
+}
+
+/// Payload counts (by stream) that capture resynchronized to, for the dump ring to expect
+fn resync_points() -> &'static Mutex<Vec<(u16, u64)>> {
+    static RESYNC_POINTS: OnceLock<Mutex<Vec<(u16, u64)>>> = OnceLock::new();
+    RESYNC_POINTS.get_or_init(|| Mutex::new(vec![]))
+}
+
+fn note_resync(stream: u16, count: u64) {
+    let mut points = resync_points().lock().unwrap();
+    if points.len() == RESYNC_MEMORY {
+        points.remove(0);
+    }
+    points.push((stream, count));
+}
+
+/// Whether capture deliberately resynchronized `stream` to `count` (forgetting it if so, each resync is only taken once)
+pub fn take_resync(stream: u16, count: u64) -> bool {
+    let mut points = resync_points().lock().unwrap();
+    match points.iter().position(|p| *p == (stream, count)) {
+        Some(i) => {
+            points.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
 #[derive(Debug, Clone, Default)]
 /// Statistics we send to the monitoring thread
 pub struct Stats {
+    /// Which capture stream these are for
+    pub stream: u16,
     pub drops: usize,
     pub processed: usize,
     pub shuffled: usize,
+    pub rejected: usize,
+    pub resyncs: usize,
+    pub reordered: usize,
+    pub faults: usize,
+    pub arrivals: ArrivalHistogram,
 }
 
+/// Capture from `port` (on `nic`, if given), tagging everything with `stream`. Only the `primary` stream sets the observation's timing.
+#[allow(clippy::too_many_arguments)]
 pub fn cap_task(
+    nic: Option<Nic>,
     port: u16,
-    cap_send: StaticSender<Payload>,
-    stats_send: SyncSender<Stats>,
+    stream: u16,
+    primary: bool,
+    sources: Vec<AllowedSource>,
+    max_gap: u64,
+    batch: usize,
+    reorder_window: usize,
+    cap_send: Sender<Payload>,
+    stats_send: SyncSender<CaptureEvent>,
+    shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!(port, stream, primary, nic = nic.as_ref().map(|n| n.name.as_str()), format = ?packet_format(), poll = ?poll_strategy(), "Starting capture task!");
+    let mut cap = Capture::new(
+        port,
+        sources,
+        stream,
+        primary,
+        max_gap,
+        batch,
+        reorder_window,
+        nic.as_ref(),
+    )?;
+    cap.start(cap_send, stats_send, STATS_POLL_DURATION, shutdown)
+}
+
+/// Feed the payloads to `port` in a recording (pcap or raw payloads) through the pipeline as the primary `stream`,
+/// so the whole chain can be exercised without an FPGA
+#[allow(clippy::too_many_arguments)]
+pub fn replay_task(
+    path: &Path,
+    port: u16,
+    stream: u16,
+    rate: ReplayRate,
+    max_gap: u64,
+    batch: usize,
+    reorder_window: usize,
+    cap_send: Sender<Payload>,
+    stats_send: SyncSender<CaptureEvent>,
+    shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!(port, stream, format = ?packet_format(), "Starting replay task!");
+    let mut cap = Capture::replay(path, port, rate, stream, max_gap, batch, reorder_window)?;
+    cap.start(cap_send, stats_send, STATS_POLL_DURATION, shutdown)
+}
+
+/// Feed simulated noise through the pipeline as the primary `stream`, `overspeed` times faster than real time
+#[allow(clippy::too_many_arguments)]
+pub fn simulate_task(
+    overspeed: f64,
+    stream: u16,
+    max_gap: u64,
+    batch: usize,
+    reorder_window: usize,
+    cap_send: Sender<Payload>,
+    stats_send: SyncSender<CaptureEvent>,
     shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
-    info!("Starting capture task!");
-    let mut cap = Capture::new(port).unwrap();
+    info!(stream, overspeed, "Starting simulated capture task!");
+    let mut cap = Capture::simulate(overspeed, stream, max_gap, batch, reorder_window);
     cap.start(cap_send, stats_send, STATS_POLL_DURATION, shutdown)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_packet_formats() {
+        assert_eq!(PacketFormat::V1.payload_size(), PAYLOAD_SIZE);
+        assert_eq!(
+            PacketFormat::of_size(PAYLOAD_SIZE + 8),
+            Some(PacketFormat::V2)
+        );
+        assert_eq!(PacketFormat::of_size(100), None);
+        let header = PacketFormat::V2.parse_ext_header(&[1, 0, 4, 0, 0, 0, 0, 0]);
+        assert_eq!(header.stream_id, Some(1));
+        assert_eq!(header.status, 4);
+        assert_eq!(
+            PacketFormat::V1.parse_ext_header(&[1; EXT_HEADER_SIZE]),
+            ExtHeader::default()
+        );
+        // Which goes back the way it came, for recordings
+        assert_eq!(
+            PacketFormat::V2.ext_header_bytes(header),
+            [1, 0, 4, 0, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_arrival_histogram() {
+        // The nominal packet cadence lands in a bucket that brackets it
+        let bucket = ArrivalHistogram::bucket(8192);
+        assert!(ArrivalHistogram::upper_bound(bucket) > 8192);
+        assert!(bucket == 0 || ArrivalHistogram::upper_bound(bucket - 1) <= 8192);
+        // Bounds only ever increase
+        assert!((1..ARRIVAL_BUCKETS)
+            .all(|b| ArrivalHistogram::upper_bound(b) > ArrivalHistogram::upper_bound(b - 1)));
+        // Out of range either way
+        assert_eq!(ArrivalHistogram::bucket(0), 0);
+        assert_eq!(ArrivalHistogram::bucket(u64::MAX), ARRIVAL_BUCKETS);
+        let mut hist = ArrivalHistogram::default();
+        for t in [0, 8192, 16384, 40000] {
+            hist.record_arrival(t);
+        }
+        assert_eq!(hist.counts.iter().sum::<u64>(), 3);
+        assert_eq!(hist.sum_ns, 40000);
+    }
+
+    #[test]
+    fn test_simulator() {
+        let mut sim = Simulator::new(1.0);
+        let (mut a, mut b) = (Payload::default(), Payload::default());
+        sim.next(&mut a);
+        sim.next(&mut b);
+        assert_eq!((a.count, b.count), (0, 1));
+        // Noise, not silence, and not the same twice in a row
+        assert!(a.pol_a.iter().any(|c| c.0.re != 0));
+        assert!(a.pol_a.iter().zip(&b.pol_a).any(|(x, y)| x.0 != y.0));
+    }
+
+    #[test]
+    fn test_allowed_source() {
+        let board: AllowedSource = "192.168.0.20".parse().unwrap();
+        let port: AllowedSource = "192.168.0.21:60000".parse().unwrap();
+        assert!(board.allows("192.168.0.20:1234".parse().unwrap()));
+        assert!(!board.allows("192.168.0.99:1234".parse().unwrap()));
+        assert!(port.allows("192.168.0.21:60000".parse().unwrap()));
+        assert!(!port.allows("192.168.0.21:60001".parse().unwrap()));
+        assert!("192.168.0".parse::<AllowedSource>().is_err());
+        assert_eq!("fpga".parse::<AllowedSource>().unwrap(), board);
+    }
+
+    #[test]
+    fn test_rejected_source() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_nonblocking(true).unwrap();
+        let addr = sock.local_addr().unwrap();
+        let (fpga, stranger) = (
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        );
+        let allowed = fpga.local_addr().unwrap().to_string().parse().unwrap();
+        let mut cap = Capture::with_input(Input::Socket(sock), vec![allowed], 0, true, 1000, 4, 4);
+        let size = cap.format.payload_size();
+        // Strangers sending the wrong thing are turned away, rather than taken for a misconfigured FPGA
+        stranger.send_to(&vec![0; size + 100], addr).unwrap();
+        stranger.send_to(&[0; 10], addr).unwrap();
+        fpga.send_to(&vec![0; size], addr).unwrap();
+        assert_eq!(cap.capture_batch().unwrap(), 1);
+        assert_eq!(cap.rejected, 2);
+        // But the FPGA sending the wrong thing is fatal
+        fpga.send_to(&vec![0; size + 100], addr).unwrap();
+        assert!(cap.capture_batch().is_err());
+    }
+
+    #[test]
+    fn test_recv_latency() {
+        let start = 1_700_000_000_000_000_000i128;
+        // Packet 1000 is due 8.192 ms in
+        assert_eq!(recv_latency(start, 1000, start + 8_192_000 + 250_000), 250);
+        // Early (clock disagreements) comes out negative
+        assert_eq!(recv_latency(start, 1000, start + 8_192_000 - 3_000), -3);
+        // Way off saturates rather than wrapping into the fill value
+        assert_eq!(
+            recv_latency(start, 0, start - i128::from(u64::MAX)),
+            i32::MIN + 1
+        );
+    }
+
+    #[test]
+    fn test_reorder_window() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut cap = Capture::with_input(Input::Socket(sock), vec![], 0, false, 1000, 1, 4);
+        let (payload_send, payload_recv) = thingbuf::mpsc::blocking::channel(64);
+        let (stats_send, _stats_recv) = std::sync::mpsc::sync_channel(4);
+        for count in [0, 2, 1, 3, 10] {
+            let mut payload = Payload {
+                count,
+                ..Default::default()
+            };
+            cap.process(
+                &mut payload,
+                ExtHeader::default(),
+                &payload_send,
+                &stats_send,
+            )
+            .unwrap();
+        }
+        drop(payload_send);
+        let counts: Vec<_> = std::iter::from_fn(|| payload_recv.recv().map(|p| p.count)).collect();
+        // Put back in order, then too far ahead to wait for 4..10
+        assert_eq!(counts, (0..=10).collect::<Vec<_>>());
+        assert_eq!(cap.reordered, 1);
+        assert_eq!(cap.drops, 6);
+    }
+
+    #[test]
+    fn test_resync() {
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut cap = Capture::with_input(Input::Socket(sock), vec![], 7, false, 1000, 1, 4);
+        let (payload_send, payload_recv) = thingbuf::mpsc::blocking::channel(64);
+        let (stats_send, stats_recv) = std::sync::mpsc::sync_channel(4);
+        // Jumps past the max gap either way start a new sequence, without filling the gap
+        for count in [0, 1, 5000, 5001, 3, 13] {
+            let mut payload = Payload {
+                count,
+                ..Default::default()
+            };
+            cap.process(
+                &mut payload,
+                ExtHeader::default(),
+                &payload_send,
+                &stats_send,
+            )
+            .unwrap();
+        }
+        drop(payload_send);
+        let counts: Vec<_> = std::iter::from_fn(|| payload_recv.recv().map(|p| p.count)).collect();
+        // But gaps within it are still filled in
+        let mut expected = vec![0, 1, 5000, 5001];
+        expected.extend(3..=13);
+        assert_eq!(counts, expected);
+        assert_eq!(cap.resyncs, 2);
+        assert_eq!(cap.drops, 9);
+        let resyncs: Vec<_> = stats_recv
+            .try_iter()
+            .filter_map(|e| match e {
+                CaptureEvent::Resync(r) => Some((r.expected, r.count)),
+                CaptureEvent::Stats(_) => None,
+            })
+            .collect();
+        assert_eq!(resyncs, [(2, 5000), (5002, 3)]);
+        // The dump ring gets to hear about each one once
+        assert!(take_resync(7, 5000));
+        assert!(!take_resync(7, 5000));
+        assert!(take_resync(7, 3));
+    }
+
+    #[test]
+    fn test_udp_payload() {
+        let data = [1u8, 2, 3, 4];
+        let mut frame = vec![0u8; 12];
+        // VLAN tagged IPv4
+        frame.extend([0x81, 0x00, 0, 1, 0x08, 0x00]);
+        let mut ip = vec![0x45, 0, 0, 0, 0, 0, 0x40, 0, 64, 17];
+        ip.resize(20, 0);
+        frame.extend(ip);
+        frame.extend(1234u16.to_be_bytes());
+        frame.extend(60000u16.to_be_bytes());
+        frame.extend((8 + data.len() as u16).to_be_bytes());
+        frame.extend([0, 0]);
+        frame.extend(data);
+        assert_eq!(
+            udp_payload(LINKTYPE_ETHERNET, &frame, 60000),
+            Some(&data[..])
+        );
+        assert_eq!(udp_payload(LINKTYPE_ETHERNET, &frame, 60001), None);
+        assert_eq!(
+            udp_payload(LINKTYPE_RAW, &frame[18..], 60000),
+            Some(&data[..])
+        );
+        assert_eq!(udp_payload(LINKTYPE_ETHERNET, &frame[..30], 60000), None);
+    }
+}
@@ -1,10 +1,12 @@
 //! Common types shared between tasks
 
 use arrayvec::ArrayVec;
+use clap::ValueEnum;
 use hifitime::prelude::*;
 use ndarray::prelude::*;
 use num_complex::Complex;
-use pulp::{as_arrays, as_arrays_mut, cast, f32x8, i16x16, i32x8, x86::V3};
+use pulp::{as_arrays, as_arrays_mut, cast, f32x8, i16x16, i32x8, i8x32, x86::V3};
+use serde::{Deserialize, Serialize};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc, Mutex, OnceLock,
@@ -18,8 +20,149 @@ pub const PACKET_CADENCE: f64 = 8.192e-6;
 pub const BLOCK_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
 /// Global atomic to hold the payload count of the first packet
 pub static FIRST_PACKET: AtomicU64 = AtomicU64::new(0);
+/// Global atomic to hold the payload count of the most recent packet we forwarded (0 if none yet)
+pub static LATEST_PACKET: AtomicU64 = AtomicU64::new(0);
 
 pub type Stokes = ArrayVec<f32, CHANNELS>;
+/// All four Stokes parameters of a spectrum, as I, Q, U, then V, each [`CHANNELS`] long
+pub type StokesVec = ArrayVec<f32, { 4 * CHANNELS }>;
+
+/// A candidate we've been asked to act on, carried whole from the trigger socket (or the DB) through to the dump file.
+/// Only the name and spectrum number are required on the wire, everything else is filled in as it becomes known.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct CandidateEvent {
+    pub candname: String,
+    /// Assigned by the trigger task, unique within a run
+    #[serde(default)]
+    pub trigger_id: u64,
+    /// Downsampled spectrum the candidate was found in (heimdall's itime)
+    #[serde(alias = "itime")]
+    pub specnum: u64,
+    /// Additional samples (payloads) to shift this trigger by, on top of the configured offset
+    #[serde(default)]
+    pub offset: i64,
+    /// MJD (TAI) of the candidate
+    pub mjd: Option<f64>,
+    /// Dispersion measure (pc cm^-3)
+    pub dm: Option<f64>,
+    pub snr: Option<f64>,
+    /// Raw candidates this one stands for, when it's the best of a cluster
+    pub members: Option<u64>,
+    /// Where the event came from (the sender's address, or whatever made it)
+    pub source: Option<String>,
+    /// How much of the voltages to keep, so low-confidence candidates can take less disk
+    #[serde(default)]
+    pub resolution: DumpResolution,
+    /// Payloads of voltages to dump around the candidate, for wider or narrower windows than `--dump-samples`
+    pub ntime: Option<u64>,
+    /// Payloads to dump before the candidate, and from it on, when the window shouldn't be centered (scattering tails
+    /// want more after than before). These win over `ntime`, and either left out keeps its share of the usual window.
+    pub ntime_pre: Option<u64>,
+    pub ntime_post: Option<u64>,
+    /// Where to send the outcome of the dump, as `udp://host:port` or `http://host[:port]/path`.
+    /// Only honored for signed triggers, or hosts we're told to trust with `--callback-hosts`.
+    pub callback: Option<String>,
+    /// When we got it, to time how long it takes to reach the dump stage
+    #[serde(skip)]
+    pub received: Option<std::time::Instant>,
+    /// Made here (a manual dump or the internal detector) rather than by T2, so its sample is already ours and doesn't
+    /// take the offset that corrects T2's specnums
+    #[serde(skip)]
+    pub local: bool,
+}
+
+/// Resolution of the voltages in a dump, requested per trigger.
+/// On the wire, `"full"`, `{"decimated": <samples>}`, or `{"reduced": <bits>}`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DumpResolution {
+    /// Every sample, as it came off the gateware
+    #[default]
+    Full,
+    /// Consecutive samples (coherently) averaged together, shrinking the time axis
+    Decimated(u32),
+    /// Every sample, requantized to this many bits (stored compressed, at the original scale)
+    Reduced(u8),
+}
+
+impl DumpResolution {
+    /// Resolutions are copied out of the ring a slab at a time, which has to hold at least one decimated sample
+    pub fn validate(self) -> eyre::Result<Self> {
+        let max_decimation = crate::dumps::format::CHUNK_SAMPLES as u32;
+        match self {
+            Self::Decimated(0) => eyre::bail!("Can't decimate a dump by 0"),
+            Self::Decimated(n) if n > max_decimation => {
+                eyre::bail!("Can't decimate a dump by more than {max_decimation}")
+            }
+            Self::Reduced(bits) if !(1..=8).contains(&bits) => {
+                eyre::bail!("Can't reduce a dump to {bits} bits")
+            }
+            _ => Ok(self),
+        }
+    }
+}
+
+/// ADC samples that go into a single packet (one FFT)
+pub const SAMPLES_PER_PACKET: u64 = 4096;
+
+/// What the count in the payload header is counting
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CountUnit {
+    /// One count per packet
+    #[default]
+    Packets,
+    /// One count per ADC sample
+    Samples,
+}
+
+/// How to interpret the count in the payload header, set once at startup to match the gateware.
+/// Capture normalizes every header through this, so everything downstream sees packet counts since the sync PPS.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderClock {
+    pub unit: CountUnit,
+    /// Header count at the sync PPS, for gateware that doesn't reset the count on arm
+    pub epoch_offset: u64,
+}
+
+impl HeaderClock {
+    /// Packets since the sync PPS of the raw count from a payload header
+    pub fn packet_count(&self, raw: u64) -> u64 {
+        let count = raw.saturating_sub(self.epoch_offset);
+        match self.unit {
+            CountUnit::Packets => count,
+            CountUnit::Samples => count / SAMPLES_PER_PACKET,
+        }
+    }
+
+    /// Seconds since the sync PPS of a (normalized) packet count
+    pub fn seconds(&self, count: u64) -> f64 {
+        count as f64 * PACKET_CADENCE
+    }
+}
+
+static HEADER_CLOCK: OnceLock<HeaderClock> = OnceLock::new();
+
+/// Set the global header interpretation, returning false if it was already set
+pub fn set_header_clock(clock: HeaderClock) -> bool {
+    HEADER_CLOCK.set(clock).is_ok()
+}
+
+/// Get the global header interpretation (the default, if it was never set)
+pub fn header_clock() -> HeaderClock {
+    HEADER_CLOCK.get().copied().unwrap_or_default()
+}
+
+static STATION_ID: OnceLock<String> = OnceLock::new();
+
+/// Set which station we are, returning false if it was already set
+pub fn set_station_id(id: String) -> bool {
+    STATION_ID.set(id).is_ok()
+}
+
+/// Which station we are, labelling everything we export ("unknown" if it was never set, as in the tools)
+pub fn station_id() -> &'static str {
+    STATION_ID.get().map_or("unknown", String::as_str)
+}
 
 /// Get the global, true packet start time of payload 0, not necessarily the first one we processed
 pub fn payload_start_time() -> &'static Arc<Mutex<Option<Epoch>>> {
@@ -30,7 +173,7 @@ pub fn payload_start_time() -> &'static Arc<Mutex<Option<Epoch>>> {
 /// Get the true time of the data in a given payload count
 pub fn payload_time(count: u64) -> Epoch {
     let payload_zero_time = payload_start_time().lock().unwrap().unwrap();
-    payload_zero_time + Duration::from_seconds(count as f64 * PACKET_CADENCE)
+    payload_zero_time + Duration::from_seconds(header_clock().seconds(count))
 }
 
 /// Get the Epoch of the first payload we processed (not necessarily Payload 0)
@@ -58,8 +201,17 @@ pub struct Payload {
     pub count: u64,
     pub pol_a: Channels,
     pub pol_b: Channels,
+    /// Which capture stream (index into the capture ports) this came from.
+    /// Not part of the packet, capture tags it after the header and spectra.
+    pub stream: u16,
+    /// When watermarking, how long after it was due we received this (us), or [`NEVER_RECEIVED`] if capture filled it in.
+    /// Sits in what would otherwise be padding, so it costs nothing when we're not.
+    pub recv_latency: i32,
 }
 
+/// Receive latency of a payload capture had to fill in
+pub const NEVER_RECEIVED: i32 = i32::MIN;
+
 impl Default for Payload {
     fn default() -> Self {
         // Safety: Payload having a 0-bit pattern is valid
@@ -69,7 +221,7 @@ impl Default for Payload {
 
 impl Payload {
     /// Yields an [`ndarray::ArrayView3`] of dimensions (Polarization, Channel, Real/Imaginary)
-    pub fn as_ndarray_data_view(&self) -> ArrayView3<i8> {
+    pub fn as_ndarray_data_view(&self) -> ArrayView3<'_, i8> {
         // C-array format, so the pol_a, pol_b chunk is in memory as
         //        POL A               POL B
         //  CH1   CH2   CH3  ...  CH1   CH2   CH3
@@ -92,7 +244,11 @@ impl Payload {
     }
 }
 
-fn simd_stokes(dst: &mut [f32; CHANNELS], a: &[i8; 2 * CHANNELS], b: &[i8; 2 * CHANNELS]) {
+pub(crate) fn simd_stokes(
+    dst: &mut [f32; CHANNELS],
+    a: &[i8; 2 * CHANNELS],
+    b: &[i8; 2 * CHANNELS],
+) {
     if let Some(simd) = V3::try_new() {
         struct Impl<'a> {
             simd: V3,
@@ -138,8 +294,131 @@ fn simd_stokes(dst: &mut [f32; CHANNELS], a: &[i8; 2 * CHANNELS], b: &[i8; 2 * C
     }
 }
 
+/// Reference implementation of [`simd_stokes`], which the SIMD path must match bit-for-bit
+#[cfg(test)]
+pub(crate) fn scalar_stokes(
+    dst: &mut [f32; CHANNELS],
+    a: &[i8; 2 * CHANNELS],
+    b: &[i8; 2 * CHANNELS],
+) {
+    for (d, (a, b)) in dst.iter_mut().zip(a.chunks_exact(2).zip(b.chunks_exact(2))) {
+        let power: i32 = a.iter().chain(b).map(|x| i32::from(*x).pow(2)).sum();
+        *d = power as f32 / 16384.0;
+    }
+}
+
 pub fn stokes_i(out: &mut [f32; CHANNELS], pl: &Payload) {
     let a_slice = unsafe { std::mem::transmute::<&[Channel; 2048], &[i8; 4096]>(&pl.pol_a) };
     let b_slice = unsafe { std::mem::transmute::<&[Channel; 2048], &[i8; 4096]>(&pl.pol_b) };
     simd_stokes(out, a_slice, b_slice);
 }
+
+/// Full Stokes of every channel, as `[I, Q, U, V]` where (for pols a and b)
+/// I = |a|² + |b|², Q = |a|² - |b|², U = 2 Re(a* b), and V = 2 Im(a* b)
+pub(crate) fn simd_full_stokes(
+    dst: &mut [[f32; CHANNELS]; 4],
+    a: &[i8; 2 * CHANNELS],
+    b: &[i8; 2 * CHANNELS],
+) {
+    if let Some(simd) = V3::try_new() {
+        struct Impl<'a> {
+            simd: V3,
+            dst: &'a mut [[f32; CHANNELS]; 4],
+            a: &'a [i8],
+            b: &'a [i8],
+        }
+
+        impl pulp::NullaryFnOnce for Impl<'_> {
+            type Output = ();
+
+            #[inline(always)]
+            fn call(self) -> Self::Output {
+                let Self { simd, dst, a, b } = self;
+                let scale = cast([16384f32; 8]);
+                // Swaps the real and imaginary parts of each (i16) channel, within each 128 bit lane
+                let swap: i8x32 = cast([
+                    2i8, 3, 0, 1, 6, 7, 4, 5, 10, 11, 8, 9, 14, 15, 12, 13, 2, 3, 0, 1, 6, 7, 4, 5,
+                    10, 11, 8, 9, 14, 15, 12, 13,
+                ]);
+                let conj: i16x16 =
+                    cast([1i16, -1, 1, -1, 1, -1, 1, -1, 1, -1, 1, -1, 1, -1, 1, -1]);
+                let [i_dst, q_dst, u_dst, v_dst] = dst;
+                let (i_chunks, _) = as_arrays_mut::<8, _>(i_dst.as_mut_slice());
+                let (q_chunks, _) = as_arrays_mut::<8, _>(q_dst.as_mut_slice());
+                let (u_chunks, _) = as_arrays_mut::<8, _>(u_dst.as_mut_slice());
+                let (v_chunks, _) = as_arrays_mut::<8, _>(v_dst.as_mut_slice());
+                let (a_chunks, _) = as_arrays::<16, _>(a);
+                let (b_chunks, _) = as_arrays::<16, _>(b);
+                let to_float = |x: i32x8| -> [f32; 8] {
+                    let floats: f32x8 = cast(simd.avx._mm256_cvtepi32_ps(cast(x)));
+                    cast(simd.avx._mm256_div_ps(cast(floats), scale))
+                };
+                for (((((i, q), u), v), &a_chunk), &b_chunk) in i_chunks
+                    .iter_mut()
+                    .zip(q_chunks)
+                    .zip(u_chunks)
+                    .zip(v_chunks)
+                    .zip(a_chunks)
+                    .zip(b_chunks)
+                {
+                    let a_ext: i16x16 = cast(simd.avx2._mm256_cvtepi8_epi16(cast(a_chunk)));
+                    let b_ext: i16x16 = cast(simd.avx2._mm256_cvtepi8_epi16(cast(b_chunk)));
+                    // (im, -re) of b, so multiplying pairwise with a gives Im(a* b)
+                    let b_rot: i16x16 = cast(simd.avx2._mm256_sign_epi16(
+                        simd.avx2._mm256_shuffle_epi8(cast(b_ext), cast(swap)),
+                        cast(conj),
+                    ));
+                    let mag_a: i32x8 = cast(simd.avx2._mm256_madd_epi16(cast(a_ext), cast(a_ext)));
+                    let mag_b: i32x8 = cast(simd.avx2._mm256_madd_epi16(cast(b_ext), cast(b_ext)));
+                    let re: i32x8 = cast(simd.avx2._mm256_madd_epi16(cast(a_ext), cast(b_ext)));
+                    let im: i32x8 = cast(simd.avx2._mm256_madd_epi16(cast(a_ext), cast(b_rot)));
+                    i.clone_from_slice(&to_float(cast(
+                        simd.avx2._mm256_add_epi32(cast(mag_a), cast(mag_b)),
+                    )));
+                    q.clone_from_slice(&to_float(cast(
+                        simd.avx2._mm256_sub_epi32(cast(mag_a), cast(mag_b)),
+                    )));
+                    u.clone_from_slice(&to_float(cast(
+                        simd.avx2._mm256_add_epi32(cast(re), cast(re)),
+                    )));
+                    v.clone_from_slice(&to_float(cast(
+                        simd.avx2._mm256_add_epi32(cast(im), cast(im)),
+                    )));
+                }
+            }
+        }
+
+        simd.vectorize(Impl { simd, dst, a, b });
+    } else {
+        panic!("This hardware doesn't have support for x86_64_v3")
+    }
+}
+
+/// Reference implementation of [`simd_full_stokes`], which the SIMD path must match bit-for-bit
+#[cfg(test)]
+pub(crate) fn scalar_full_stokes(
+    dst: &mut [[f32; CHANNELS]; 4],
+    a: &[i8; 2 * CHANNELS],
+    b: &[i8; 2 * CHANNELS],
+) {
+    for (c, (a, b)) in a.chunks_exact(2).zip(b.chunks_exact(2)).enumerate() {
+        let [ar, ai, br, bi] = [a[0], a[1], b[0], b[1]].map(i32::from);
+        let mag_a = ar * ar + ai * ai;
+        let mag_b = br * br + bi * bi;
+        let params = [
+            mag_a + mag_b,
+            mag_a - mag_b,
+            2 * (ar * br + ai * bi),
+            2 * (ar * bi - ai * br),
+        ];
+        for (d, p) in dst.iter_mut().zip(params) {
+            d[c] = p as f32 / 16384.0;
+        }
+    }
+}
+
+pub fn full_stokes(out: &mut [[f32; CHANNELS]; 4], pl: &Payload) {
+    let a_slice = unsafe { std::mem::transmute::<&[Channel; 2048], &[i8; 4096]>(&pl.pol_a) };
+    let b_slice = unsafe { std::mem::transmute::<&[Channel; 2048], &[i8; 4096]>(&pl.pol_b) };
+    simd_full_stokes(out, a_slice, b_slice);
+}
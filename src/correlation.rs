@@ -0,0 +1,246 @@
+//! Slow-path cross-correlation of the two polarizations, for polarization calibration and spotting cable changes
+use crate::common::{Payload, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE};
+use crate::db::DbEvent;
+use crate::postprocess::lower_priority;
+use crate::tsys::{self, TsysEstimator};
+use num_complex::Complex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        mpsc::{Receiver, RecvTimeoutError, SyncSender},
+        Mutex, OnceLock,
+    },
+};
+use tokio::sync::broadcast;
+use tracing::info;
+
+/// Only every this many payloads are correlated, so the slow path never competes with the fast path
+pub const CORRELATION_STRIDE: u64 = 64;
+/// Number of (strided) payloads in each integration, around 8 seconds
+const CORRELATION_INTEGRATIONS: usize = 16384;
+/// Seconds of host-side bandpasses we keep around to integrate on request
+pub const BANDPASS_HISTORY_S: usize = 60;
+
+/// Integrated auto and cross power spectra of the two polarizations
+#[derive(Debug, Clone)]
+pub struct CrossPower {
+    /// Number of payloads integrated
+    pub integrations: usize,
+    /// |A|^2 per channel
+    pub auto_a: Vec<f64>,
+    /// |B|^2 per channel
+    pub auto_b: Vec<f64>,
+    /// A * conj(B) per channel
+    pub cross: Vec<Complex<f64>>,
+}
+
+impl Default for CrossPower {
+    fn default() -> Self {
+        Self {
+            integrations: 0,
+            auto_a: vec![0.0; CHANNELS],
+            auto_b: vec![0.0; CHANNELS],
+            cross: vec![Complex::new(0.0, 0.0); CHANNELS],
+        }
+    }
+}
+
+impl CrossPower {
+    /// Add a payload's voltages into the integration
+    pub fn accumulate(&mut self, payload: &Payload) {
+        for (i, (a, b)) in payload.pol_a.iter().zip(&payload.pol_b).enumerate() {
+            let a = Complex::new(f64::from(a.0.re), f64::from(a.0.im));
+            let b = Complex::new(f64::from(b.0.re), f64::from(b.0.im));
+            self.auto_a[i] += a.norm_sqr();
+            self.auto_b[i] += b.norm_sqr();
+            self.cross[i] += a * b.conj();
+        }
+        self.integrations += 1;
+    }
+
+    /// Normalized cross power |A conj(B)| / sqrt(|A|^2 |B|^2) per channel (0 if there was no power)
+    pub fn coherence(&self) -> Vec<f64> {
+        self.cross
+            .iter()
+            .zip(self.auto_a.iter().zip(&self.auto_b))
+            .map(|(x, (a, b))| {
+                let denom = (a * b).sqrt();
+                if denom > 0.0 {
+                    x.norm() / denom
+                } else {
+                    0.0
+                }
+            })
+            .collect()
+    }
+
+    /// Relative phase of A to B per channel (radians)
+    pub fn phase(&self) -> Vec<f64> {
+        self.cross.iter().map(|x| x.arg()).collect()
+    }
+}
+
+/// Which product of the polarizations a bandpass is of
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BandpassPol {
+    A,
+    B,
+    /// Stokes I, the sum of the two, as in the exfil stream
+    #[default]
+    I,
+}
+
+/// Power spectra of the two polarizations from the voltages we receive, in the gateware's channel order (like the FPGA vacc)
+#[derive(Debug, Clone)]
+pub struct Bandpass {
+    /// Number of payloads integrated
+    pub payloads: usize,
+    pub power_a: Vec<f64>,
+    pub power_b: Vec<f64>,
+}
+
+impl Default for Bandpass {
+    fn default() -> Self {
+        Self {
+            payloads: 0,
+            power_a: vec![0.0; CHANNELS],
+            power_b: vec![0.0; CHANNELS],
+        }
+    }
+}
+
+impl Bandpass {
+    pub fn accumulate(&mut self, payload: &Payload) {
+        for (i, (a, b)) in payload.pol_a.iter().zip(&payload.pol_b).enumerate() {
+            self.power_a[i] += f64::from(a.0.re).powi(2) + f64::from(a.0.im).powi(2);
+            self.power_b[i] += f64::from(b.0.re).powi(2) + f64::from(b.0.im).powi(2);
+        }
+        self.payloads += 1;
+    }
+
+    fn add(&mut self, other: &Self) {
+        for (x, y) in self.power_a.iter_mut().zip(&other.power_a) {
+            *x += y;
+        }
+        for (x, y) in self.power_b.iter_mut().zip(&other.power_b) {
+            *x += y;
+        }
+        self.payloads += other.payloads;
+    }
+
+    /// Average power per payload in each channel of `pol`
+    pub fn average(&self, pol: BandpassPol) -> Vec<f64> {
+        let n = self.payloads.max(1) as f64;
+        match pol {
+            BandpassPol::A => self.power_a.iter().map(|p| p / n).collect(),
+            BandpassPol::B => self.power_b.iter().map(|p| p / n).collect(),
+            BandpassPol::I => self
+                .power_a
+                .iter()
+                .zip(&self.power_b)
+                .map(|(a, b)| (a + b) / n)
+                .collect(),
+        }
+    }
+}
+
+/// One second bandpasses, newest last
+fn bandpass_history() -> &'static Mutex<VecDeque<Bandpass>> {
+    static BANDPASS_HISTORY: OnceLock<Mutex<VecDeque<Bandpass>>> = OnceLock::new();
+    BANDPASS_HISTORY.get_or_init(|| Mutex::new(VecDeque::with_capacity(BANDPASS_HISTORY_S)))
+}
+
+/// The bandpass integrated over the last `seconds` (whole seconds, up to [`BANDPASS_HISTORY_S`]) and how many seconds that really was
+pub fn integrated_bandpass(seconds: usize) -> Option<(Bandpass, usize)> {
+    let history = bandpass_history().lock().unwrap();
+    if history.is_empty() {
+        return None;
+    }
+    let mut total = Bandpass::default();
+    let mut used = 0;
+    for bandpass in history.iter().rev().take(seconds.max(1)) {
+        total.add(bandpass);
+        used += 1;
+    }
+    Some((total, used))
+}
+
+/// Get the most recently completed cross power integration
+pub fn latest_cross_power() -> &'static Mutex<Option<CrossPower>> {
+    static LATEST_CROSS_POWER: OnceLock<Mutex<Option<CrossPower>>> = OnceLock::new();
+    LATEST_CROSS_POWER.get_or_init(|| Mutex::new(None))
+}
+
+pub fn correlation_task(
+    payloads: Receiver<Payload>,
+    mut tsys: Option<TsysEstimator>,
+    db: SyncSender<DbEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting correlation task");
+    lower_priority();
+    let mut xpower = CrossPower::default();
+    let mut bandpass = Bandpass::default();
+    // Payloads in a second, going by their counts (as not every payload comes our way)
+    let bandpass_payloads = (1.0 / PACKET_CADENCE).round() as u64;
+    let mut bandpass_second = None;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Correlation task stopping");
+            break;
+        }
+        match payloads.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(payload) => {
+                xpower.accumulate(&payload);
+                let second = payload.count / bandpass_payloads;
+                if bandpass_second.is_some_and(|s| s != second) && bandpass.payloads > 0 {
+                    let mut history = bandpass_history().lock().unwrap();
+                    if history.len() == BANDPASS_HISTORY_S {
+                        history.pop_front();
+                    }
+                    history.push_back(std::mem::take(&mut bandpass));
+                }
+                bandpass_second = Some(second);
+                bandpass.accumulate(&payload);
+                if let Some(t) = tsys.as_mut() {
+                    if let Some(records) = t.accumulate(&payload) {
+                        tsys::publish(records, t.valid_for(), &db);
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        if xpower.integrations == CORRELATION_INTEGRATIONS {
+            *latest_cross_power().lock().unwrap() = Some(std::mem::take(&mut xpower));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::Channel;
+
+    #[test]
+    fn test_cross_power() {
+        // B lags A by 90 degrees in every channel
+        let payload = Payload {
+            pol_a: [Channel::new(3, 0); CHANNELS],
+            pol_b: [Channel::new(0, 3); CHANNELS],
+            ..Default::default()
+        };
+        let mut xpower = CrossPower::default();
+        xpower.accumulate(&payload);
+        xpower.accumulate(&payload);
+        assert_eq!(xpower.integrations, 2);
+        assert!(xpower.coherence().iter().all(|c| (c - 1.0).abs() < 1e-12));
+        assert!(xpower
+            .phase()
+            .iter()
+            .all(|p| (p + std::f64::consts::FRAC_PI_2).abs() < 1e-12));
+    }
+}
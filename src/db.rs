@@ -1,8 +1,14 @@
 //! Interactions with the sqlite candidate database
-use rusqlite::{Connection, Result};
-use std::path::PathBuf;
+use crate::{
+    accounting::AccountingSnapshot,
+    common::{station_id, CandidateEvent},
+    exfil::checksum::ChecksumRecord,
+};
+use rusqlite::{Connection, OpenFlags, Result};
+use serde::Serialize;
+use std::{path::PathBuf, time::Duration};
 
-fn create_table(conn: &Connection) -> Result<()> {
+fn create_tables(conn: &Connection) -> Result<()> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS injection (
         id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -12,13 +18,136 @@ fn create_table(conn: &Connection) -> Result<()> {
     ) STRICT",
         (),
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS blanked_channel (
+        channel INTEGER PRIMARY KEY
+    ) STRICT",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS observation (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        start_mjd REAL NOT NULL,
+        stop_mjd REAL NOT NULL,
+        captured INTEGER NOT NULL,
+        filled INTEGER NOT NULL,
+        shuffled INTEGER NOT NULL,
+        downsampled INTEGER NOT NULL,
+        dump_overflow INTEGER NOT NULL,
+        dump_flushed INTEGER NOT NULL,
+        dumped INTEGER NOT NULL,
+        written TEXT NOT NULL,
+        summary TEXT
+    ) STRICT",
+        (),
+    )?;
+    // Databases from before we kept run summaries won't have the column yet
+    add_missing_column(conn, "observation", "summary")?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS control_audit (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mjd REAL NOT NULL,
+        action TEXT NOT NULL,
+        requester TEXT NOT NULL,
+        outcome TEXT NOT NULL
+    ) STRICT",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS exfil_checksum (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mjd REAL NOT NULL,
+        sink TEXT NOT NULL,
+        target TEXT NOT NULL,
+        block INTEGER NOT NULL,
+        offset INTEGER NOT NULL,
+        bytes INTEGER NOT NULL,
+        crc32c INTEGER NOT NULL,
+        running_crc32c INTEGER NOT NULL
+    ) STRICT",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS tsys (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mjd REAL NOT NULL,
+        subband INTEGER NOT NULL,
+        freq REAL NOT NULL,
+        tsys REAL,
+        station TEXT
+    ) STRICT",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS dumps (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mjd REAL NOT NULL,
+        candname TEXT NOT NULL,
+        merged TEXT NOT NULL,
+        trigger_mjd REAL,
+        start_sample INTEGER NOT NULL,
+        stop_sample INTEGER NOT NULL,
+        samples INTEGER NOT NULL,
+        path TEXT NOT NULL,
+        truncated_start INTEGER NOT NULL,
+        truncated_end INTEGER NOT NULL,
+        duration REAL NOT NULL,
+        station TEXT
+    ) STRICT",
+        (),
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS received_trigger (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        mjd REAL NOT NULL,
+        candname TEXT NOT NULL,
+        trigger_id INTEGER NOT NULL,
+        specnum INTEGER NOT NULL,
+        offset INTEGER NOT NULL,
+        trigger_mjd REAL,
+        dm REAL,
+        snr REAL,
+        source TEXT,
+        station TEXT
+    ) STRICT",
+        (),
+    )?;
+    // Nor will ones from before rows were labelled with the station
+    for table in [
+        "injection",
+        "observation",
+        "control_audit",
+        "exfil_checksum",
+    ] {
+        add_missing_column(conn, table, "station")?;
+    }
+    Ok(())
+}
+
+/// Add a (nullable) text column to a table from an older database that doesn't have it yet
+fn add_missing_column(conn: &Connection, table: &str, column: &str) -> Result<()> {
+    let exists: bool = conn.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info(?1) WHERE name = ?2",
+        (table, column),
+        |row| row.get(0),
+    )?;
+    if !exists {
+        conn.execute(&format!("ALTER TABLE {table} ADD COLUMN {column} TEXT"), ())?;
+    }
     Ok(())
 }
 
-/// Connect to the database, and create the injection table if it doesn't already exist
+/// Connect to the database, and create the tables if they don't already exist
 pub fn connect_and_create(db_path: PathBuf) -> Result<Connection> {
     let conn = Connection::open(db_path)?;
-    create_table(&conn)?;
+    create_tables(&conn)?;
+    Ok(conn)
+}
+
+/// Open the database to read what the db task has written, waiting out its writes
+pub fn open_read_only(db_path: PathBuf) -> Result<Connection> {
+    let conn = Connection::open_with_flags(db_path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.busy_timeout(Duration::from_secs(1))?;
     Ok(conn)
 }
 
@@ -33,13 +162,310 @@ impl InjectionRecord {
     /// Insert an injection record into the connected database
     pub fn db_insert(&self, conn: &Connection) -> Result<()> {
         conn.execute(
-            "INSERT INTO injection (mjd, filename, sample) VALUES (?1, ?2, ?3)",
-            (&self.mjd, &self.filename, &self.sample),
+            "INSERT INTO injection (mjd, filename, sample, station) VALUES (?1, ?2, ?3, ?4)",
+            (&self.mjd, &self.filename, &self.sample, station_id()),
         )?;
         Ok(())
     }
 }
 
+/// A record of someone poking at the hardware through the control API
+#[derive(Debug)]
+pub struct AuditRecord {
+    pub mjd: f64,
+    pub action: String,
+    pub requester: String,
+    pub outcome: String,
+}
+
+impl AuditRecord {
+    pub fn db_insert(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            "INSERT INTO control_audit (mjd, action, requester, outcome, station) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                &self.mjd,
+                &self.action,
+                &self.requester,
+                &self.outcome,
+                station_id(),
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// An estimate of the system temperature in one sub-band
+#[derive(Debug, Clone)]
+pub struct TsysRecord {
+    pub mjd: f64,
+    pub subband: usize,
+    /// Center frequency of the sub-band (MHz)
+    pub freq: f64,
+    /// Kelvin, or None if the noise source didn't register
+    pub tsys: Option<f64>,
+}
+
+impl TsysRecord {
+    pub fn db_insert(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            "INSERT INTO tsys (mjd, subband, freq, tsys, station) VALUES (?1, ?2, ?3, ?4, ?5)",
+            (
+                &self.mjd,
+                &self.subband,
+                &self.freq,
+                &self.tsys,
+                station_id(),
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// A voltage dump that made it to disk
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DumpRecord {
+    /// When it finished
+    pub mjd: f64,
+    pub candname: String,
+    /// Other candidates coalesced into the dump
+    pub merged: Vec<String>,
+    /// MJD (TAI) of the candidate, if the trigger had one
+    pub trigger_mjd: Option<f64>,
+    /// First and last (inclusive) payload counts of the window
+    pub start_sample: u64,
+    pub stop_sample: u64,
+    /// Payloads of the window actually written
+    pub samples: u64,
+    pub path: String,
+    pub truncated_start: bool,
+    pub truncated_end: bool,
+    /// Time it took to write (s)
+    pub duration: f64,
+}
+
+impl DumpRecord {
+    pub fn db_insert(&self, conn: &Connection) -> Result<()> {
+        conn.execute(
+            "INSERT INTO dumps (mjd, candname, merged, trigger_mjd, start_sample, stop_sample, samples, path,
+            truncated_start, truncated_end, duration, station) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            (
+                &self.mjd,
+                &self.candname,
+                self.merged.join(","),
+                &self.trigger_mjd,
+                &self.start_sample,
+                &self.stop_sample,
+                &self.samples,
+                &self.path,
+                &self.truncated_start,
+                &self.truncated_end,
+                &self.duration,
+                station_id(),
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// A trigger that reached the dump task, whether or not it was dumped
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerRecord {
+    /// When it got to us
+    pub mjd: f64,
+    pub event: CandidateEvent,
+}
+
+impl TriggerRecord {
+    pub fn db_insert(&self, conn: &Connection) -> Result<()> {
+        let event = &self.event;
+        conn.execute(
+            "INSERT INTO received_trigger (mjd, candname, trigger_id, specnum, offset, trigger_mjd, dm, snr, source, station)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+            (
+                &self.mjd,
+                &event.candname,
+                &event.trigger_id,
+                &event.specnum,
+                &event.offset,
+                &event.mjd,
+                &event.dm,
+                &event.snr,
+                &event.source,
+                station_id(),
+            ),
+        )?;
+        Ok(())
+    }
+}
+
+/// Record the CRC32C of a block of exfilled data
+pub fn insert_checksum(conn: &Connection, checksum: &ChecksumRecord) -> Result<()> {
+    conn.execute(
+        "INSERT INTO exfil_checksum (mjd, sink, target, block, offset, bytes, crc32c, running_crc32c, station)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        (
+            checksum.mjd,
+            &checksum.sink,
+            &checksum.target,
+            checksum.block,
+            checksum.offset,
+            checksum.bytes,
+            checksum.crc32c,
+            checksum.running_crc32c,
+            station_id(),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Record the sample accounting for an observation spanning the two MJDs, along with its (JSON) run summary
+pub fn insert_observation(
+    conn: &Connection,
+    start_mjd: f64,
+    stop_mjd: f64,
+    acc: &AccountingSnapshot,
+    summary: &str,
+) -> Result<()> {
+    let written = serde_json::to_string(&acc.written).unwrap();
+    conn.execute(
+        "INSERT INTO observation (start_mjd, stop_mjd, captured, filled, shuffled, downsampled, dump_overflow, dump_flushed, dumped, written, summary, station)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        (
+            start_mjd,
+            stop_mjd,
+            acc.captured,
+            acc.filled,
+            acc.shuffled,
+            acc.downsampled,
+            acc.dump_overflow,
+            acc.dump_flushed,
+            acc.dumped,
+            written,
+            summary,
+            station_id(),
+        ),
+    )?;
+    Ok(())
+}
+
+/// Count the pulses injected since `since_mjd`
+pub fn injections_since(conn: &Connection, since_mjd: f64) -> Result<u64> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM injection WHERE mjd >= ?1",
+        (since_mjd,),
+        |row| row.get(0),
+    )
+}
+
+/// Get the list of persisted blanked channels
+pub fn blanked_channels(conn: &Connection) -> Result<Vec<usize>> {
+    let mut stmt = conn.prepare("SELECT channel FROM blanked_channel ORDER BY channel")?;
+    let channels = stmt.query_map((), |row| row.get(0))?.collect();
+    channels
+}
+
+/// Get every trigger (as the candidate that caused it) recorded since `since_mjd`, in the order they happened.
+/// These tables are written by the detection pipeline, so this only works against the full candidate database.
+pub fn recorded_triggers(conn: &Connection, since_mjd: f64) -> Result<Vec<CandidateEvent>> {
+    let mut stmt = conn.prepare(
+        "SELECT trigger.id, trigger.cand_name, candidate.sample, candidate.mjd, candidate.dm, candidate.snr FROM trigger
+        JOIN cluster ON trigger.cluster = cluster.id
+        JOIN candidate ON cluster.centroid = candidate.id
+        WHERE trigger.cand_name IS NOT NULL AND candidate.mjd >= ?1
+        ORDER BY candidate.mjd",
+    )?;
+    let triggers = stmt
+        .query_map((since_mjd,), |row| {
+            Ok(CandidateEvent {
+                candname: row.get(1)?,
+                trigger_id: row.get(0)?,
+                specnum: row.get(2)?,
+                offset: 0,
+                mjd: row.get(3)?,
+                dm: row.get(4)?,
+                snr: row.get(5)?,
+                members: None,
+                source: Some("candidate db".to_owned()),
+                resolution: Default::default(),
+                ntime: None,
+                ntime_pre: None,
+                ntime_post: None,
+                callback: None,
+                received: None,
+                local: false,
+            })
+        })?
+        .collect();
+    triggers
+}
+
+/// The most recent `limit` dumps, newest first
+pub fn recent_dumps(conn: &Connection, limit: usize) -> Result<Vec<DumpRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT mjd, candname, merged, trigger_mjd, start_sample, stop_sample, samples, path, truncated_start,
+        truncated_end, duration FROM dumps ORDER BY id DESC LIMIT ?1",
+    )?;
+    let dumps = stmt
+        .query_map((limit,), |row| {
+            let merged: String = row.get(2)?;
+            Ok(DumpRecord {
+                mjd: row.get(0)?,
+                candname: row.get(1)?,
+                merged: merged
+                    .split(',')
+                    .filter(|m| !m.is_empty())
+                    .map(str::to_owned)
+                    .collect(),
+                trigger_mjd: row.get(3)?,
+                start_sample: row.get(4)?,
+                stop_sample: row.get(5)?,
+                samples: row.get(6)?,
+                path: row.get(7)?,
+                truncated_start: row.get(8)?,
+                truncated_end: row.get(9)?,
+                duration: row.get(10)?,
+            })
+        })?
+        .collect();
+    dumps
+}
+
+/// Events sent to the db task to be recorded
+#[derive(Debug)]
+pub enum DbEvent {
+    Injection(InjectionRecord),
+    BlankChannel(usize),
+    UnblankChannel(usize),
+    Audit(AuditRecord),
+    Tsys(TsysRecord),
+    Dump(DumpRecord),
+    Trigger(TriggerRecord),
+}
+
+impl DbEvent {
+    /// Apply this event to the connected database
+    pub fn apply(&self, conn: &Connection) -> Result<()> {
+        match self {
+            DbEvent::Injection(ir) => ir.db_insert(conn),
+            DbEvent::BlankChannel(c) => {
+                conn.execute(
+                    "INSERT OR IGNORE INTO blanked_channel (channel) VALUES (?1)",
+                    (c,),
+                )?;
+                Ok(())
+            }
+            DbEvent::UnblankChannel(c) => {
+                conn.execute("DELETE FROM blanked_channel WHERE channel = ?1", (c,))?;
+                Ok(())
+            }
+            DbEvent::Audit(ar) => ar.db_insert(conn),
+            DbEvent::Tsys(tr) => tr.db_insert(conn),
+            DbEvent::Dump(dr) => dr.db_insert(conn),
+            DbEvent::Trigger(tr) => tr.db_insert(conn),
+        }
+    }
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -47,7 +473,7 @@ pub mod test {
     #[test]
     fn test_db() {
         let conn = Connection::open_in_memory().unwrap();
-        create_table(&conn).unwrap();
+        create_tables(&conn).unwrap();
         let ir = InjectionRecord {
             mjd: 123.456,
             filename: "foo".to_owned(),
@@ -55,4 +481,128 @@ pub mod test {
         };
         ir.db_insert(&conn).unwrap()
     }
+
+    #[test]
+    fn test_dump_record() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let dr = DumpRecord {
+            mjd: 60000.5,
+            candname: "cand".to_owned(),
+            merged: vec!["a".to_owned(), "b".to_owned()],
+            trigger_mjd: None,
+            start_sample: 1000,
+            stop_sample: 1999,
+            samples: 800,
+            path: "/dumps/grex_dump-cand.nc".to_owned(),
+            truncated_start: true,
+            truncated_end: false,
+            duration: 1.5,
+        };
+        DbEvent::Dump(dr.clone()).apply(&conn).unwrap();
+        let later = DumpRecord {
+            candname: "later".to_owned(),
+            merged: vec![],
+            ..dr.clone()
+        };
+        DbEvent::Dump(later.clone()).apply(&conn).unwrap();
+        assert_eq!(recent_dumps(&conn, 5).unwrap(), vec![later.clone(), dr]);
+        assert_eq!(recent_dumps(&conn, 1).unwrap(), vec![later]);
+    }
+
+    #[test]
+    fn test_trigger_record() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let tr = TriggerRecord {
+            mjd: 60000.5,
+            event: CandidateEvent {
+                candname: "cand".to_owned(),
+                trigger_id: 3,
+                specnum: 5000,
+                dm: Some(100.0),
+                source: Some("udp".to_owned()),
+                ..Default::default()
+            },
+        };
+        DbEvent::Trigger(tr).apply(&conn).unwrap();
+        let (candname, specnum, dm, snr): (String, u64, Option<f64>, Option<f64>) = conn
+            .query_row(
+                "SELECT candname, specnum, dm, snr FROM received_trigger",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!(
+            (candname.as_str(), specnum, dm, snr),
+            ("cand", 5000, Some(100.0), None)
+        );
+    }
+
+    #[test]
+    fn test_blanking() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        for c in [10, 5, 10, 300] {
+            DbEvent::BlankChannel(c).apply(&conn).unwrap();
+        }
+        DbEvent::UnblankChannel(300).apply(&conn).unwrap();
+        assert_eq!(blanked_channels(&conn).unwrap(), vec![5, 10]);
+    }
+
+    #[test]
+    fn test_observation() {
+        let conn = Connection::open_in_memory().unwrap();
+        create_tables(&conn).unwrap();
+        let acc = AccountingSnapshot {
+            captured: 100,
+            filled: 2,
+            shuffled: 1,
+            downsampled: 102,
+            dump_overflow: 0,
+            dump_flushed: 0,
+            dumped: 50,
+            written: [("filterbank".to_owned(), 25)].into(),
+        };
+        insert_observation(&conn, 60000.0, 60000.5, &acc, "{}").unwrap();
+        // Creating the tables again shouldn't try to add the summary column twice
+        create_tables(&conn).unwrap();
+        let (captured, dumped, written, summary): (u64, u64, String, String) = conn
+            .query_row(
+                "SELECT captured, dumped, written, summary FROM observation",
+                (),
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .unwrap();
+        assert_eq!((captured, dumped), (100, 50));
+        assert_eq!(written, r#"{"filterbank":25}"#);
+        assert_eq!(summary, "{}");
+    }
+
+    #[test]
+    fn test_recorded_triggers() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(include_str!("../candidate_db_schema.sql"))
+            .unwrap();
+        conn.execute_batch(
+            "INSERT INTO candidate (dm, snr, mjd, boxcar, sample) VALUES (100.0, 12.0, 60000.2, 4, 5000);
+            INSERT INTO candidate (dm, snr, mjd, boxcar, sample) VALUES (50.0, 9.0, 60000.1, 2, 1000);
+            INSERT INTO cluster (centroid) VALUES (1);
+            INSERT INTO cluster (centroid) VALUES (2);
+            INSERT INTO trigger (cand_name, cluster) VALUES ('late', 1);
+            INSERT INTO trigger (cand_name, cluster) VALUES ('early', 2);",
+        )
+        .unwrap();
+        let triggers = recorded_triggers(&conn, 0.0).unwrap();
+        assert_eq!(
+            triggers
+                .iter()
+                .map(|t| t.candname.as_str())
+                .collect::<Vec<_>>(),
+            vec!["early", "late"]
+        );
+        assert_eq!(triggers[1].specnum, 5000);
+        assert_eq!(triggers[1].dm, Some(100.0));
+        assert_eq!(recorded_triggers(&conn, 60000.15).unwrap().len(), 1);
+    }
 }
@@ -0,0 +1,179 @@
+//! Clustering raw candidates in DM and time, so one burst (which heimdall reports dozens of times over) makes one dump.
+//! This is the job T2 does for full stations, this is for the ones that run without it.
+use crate::common::CandidateEvent;
+use eyre::eyre;
+use std::time::{Duration, Instant};
+
+/// Raw candidates that belong to the same burst
+#[derive(Debug)]
+struct Cluster {
+    /// The highest-SNR member so far
+    best: CandidateEvent,
+    members: u64,
+    /// Spectra the members span
+    first_specnum: u64,
+    last_specnum: u64,
+    /// DMs the members span
+    dm_lo: f64,
+    dm_hi: f64,
+    /// When we last heard of a new member
+    updated: Instant,
+}
+
+impl Cluster {
+    fn new(event: CandidateEvent) -> Self {
+        let dm = event.dm.unwrap_or_default();
+        Self {
+            members: 1,
+            first_specnum: event.specnum,
+            last_specnum: event.specnum,
+            dm_lo: dm,
+            dm_hi: dm,
+            updated: Instant::now(),
+            best: event,
+        }
+    }
+
+    fn add(&mut self, event: CandidateEvent) {
+        self.members += 1;
+        self.first_specnum = self.first_specnum.min(event.specnum);
+        self.last_specnum = self.last_specnum.max(event.specnum);
+        if let Some(dm) = event.dm {
+            self.dm_lo = self.dm_lo.min(dm);
+            self.dm_hi = self.dm_hi.max(dm);
+        }
+        self.updated = Instant::now();
+        if event.snr.unwrap_or(f64::NEG_INFINITY) > self.best.snr.unwrap_or(f64::NEG_INFINITY) {
+            self.best = event;
+        }
+    }
+
+    /// The best member, standing in for the whole cluster
+    fn into_event(self) -> CandidateEvent {
+        CandidateEvent {
+            members: Some(self.members),
+            ..self.best
+        }
+    }
+}
+
+/// Groups candidates that are within `window` spectra and `dm_tolerance` (fractional) DM of an existing cluster,
+/// and hands back the best member of each cluster once it's gone `hold` without a new member.
+#[derive(Debug)]
+pub struct Clusterer {
+    window: u64,
+    dm_tolerance: f64,
+    hold: Duration,
+    open: Vec<Cluster>,
+}
+
+impl Clusterer {
+    pub fn new(window: u64, dm_tolerance: f64, hold: Duration) -> Self {
+        Self {
+            window,
+            dm_tolerance,
+            hold,
+            open: vec![],
+        }
+    }
+
+    fn matches(&self, cluster: &Cluster, event: &CandidateEvent) -> bool {
+        let near_in_time = event.specnum.saturating_add(self.window) >= cluster.first_specnum
+            && event.specnum <= cluster.last_specnum.saturating_add(self.window);
+        // Candidates without a DM can only be clustered in time
+        let near_in_dm = event.dm.is_none_or(|dm| {
+            dm >= cluster.dm_lo * (1.0 - self.dm_tolerance)
+                && dm <= cluster.dm_hi * (1.0 + self.dm_tolerance)
+        });
+        near_in_time && near_in_dm
+    }
+
+    pub fn push(&mut self, event: CandidateEvent) {
+        match self.open.iter().position(|c| self.matches(c, &event)) {
+            Some(i) => self.open[i].add(event),
+            None => self.open.push(Cluster::new(event)),
+        }
+    }
+
+    /// Clusters that have gone quiet, as their best members
+    pub fn closed(&mut self) -> Vec<CandidateEvent> {
+        let (closed, open) = std::mem::take(&mut self.open)
+            .into_iter()
+            .partition(|c| c.updated.elapsed() >= self.hold);
+        self.open = open;
+        closed.into_iter().map(Cluster::into_event).collect()
+    }
+
+    /// Every cluster, finished or not
+    pub fn flush(&mut self) -> Vec<CandidateEvent> {
+        self.open.drain(..).map(Cluster::into_event).collect()
+    }
+}
+
+/// Parse one of heimdall's candidate lines (`snr sample time filter dm_trial dm members begin end ...`)
+pub fn parse_heimdall(line: &str) -> eyre::Result<CandidateEvent> {
+    let fields: Vec<_> = line.split_whitespace().collect();
+    if fields.len() < 7 {
+        return Err(eyre!("Expected at least 7 fields, got {}", fields.len()));
+    }
+    let snr: f64 = fields[0].parse()?;
+    let specnum: u64 = fields[1].parse()?;
+    let dm: f64 = fields[5].parse()?;
+    let members: u64 = fields[6].parse()?;
+    Ok(CandidateEvent {
+        candname: format!("heimdall_{specnum}_{dm:.1}"),
+        specnum,
+        dm: Some(dm),
+        snr: Some(snr),
+        members: Some(members),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cand(specnum: u64, dm: f64, snr: f64) -> CandidateEvent {
+        CandidateEvent {
+            candname: format!("{specnum}_{dm}"),
+            specnum,
+            dm: Some(dm),
+            snr: Some(snr),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_clustering() {
+        let mut clusterer = Clusterer::new(10, 0.1, Duration::ZERO);
+        clusterer.push(cand(100, 500.0, 8.0));
+        clusterer.push(cand(105, 520.0, 12.0));
+        clusterer.push(cand(112, 530.0, 9.0));
+        // Same time, very different DM
+        clusterer.push(cand(105, 50.0, 7.0));
+        // Same DM, much later
+        clusterer.push(cand(500, 510.0, 7.0));
+        let closed = clusterer.closed();
+        assert_eq!(closed.len(), 3);
+        let burst = closed
+            .iter()
+            .find(|c| c.specnum == 105 && c.dm == Some(520.0));
+        assert_eq!(burst.unwrap().members, Some(3));
+        assert!(clusterer.flush().is_empty());
+        // Nonsense specnums are clustered, not overflowed
+        clusterer.push(cand(u64::MAX, 500.0, 8.0));
+        clusterer.push(cand(u64::MAX - 5, 500.0, 9.0));
+        assert_eq!(clusterer.closed().len(), 1);
+    }
+
+    #[test]
+    fn test_parse_heimdall() {
+        let event = parse_heimdall("10.5\t12345\t3.2\t2\t40\t512.3\t17\t12300\t12400").unwrap();
+        assert_eq!(event.specnum, 12345);
+        assert_eq!(event.dm, Some(512.3));
+        assert_eq!(event.snr, Some(10.5));
+        assert_eq!(event.members, Some(17));
+        assert!(parse_heimdall("not a candidate").is_err());
+    }
+}
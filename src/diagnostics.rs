@@ -0,0 +1,262 @@
+//! Collect everything we'd want to know about a sick station into one tarball
+use crate::{
+    accounting::accounting, naming::time_policy, processing::channel_mask, quality::quality_inputs,
+};
+use flate2::{write::GzEncoder, Compression};
+use hifitime::Epoch;
+use prometheus::TextEncoder;
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast;
+use tracing::{error, field::Field, info, warn, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
+
+/// How many log lines we keep around for the bundle
+const RECENT_LOG_LINES: usize = 4096;
+
+/// Where bundles go, the pipeline config, and the last FPGA register dump, all set once the pipeline starts
+#[derive(Debug, Default)]
+struct DiagnosticState {
+    path: Option<PathBuf>,
+    config: String,
+    registers: String,
+}
+
+fn state() -> &'static Mutex<DiagnosticState> {
+    static DIAGNOSTIC_STATE: OnceLock<Mutex<DiagnosticState>> = OnceLock::new();
+    DIAGNOSTIC_STATE.get_or_init(|| Mutex::new(DiagnosticState::default()))
+}
+
+fn recent_logs() -> &'static Mutex<VecDeque<String>> {
+    static RECENT_LOGS: OnceLock<Mutex<VecDeque<String>>> = OnceLock::new();
+    RECENT_LOGS.get_or_init(|| Mutex::new(VecDeque::with_capacity(RECENT_LOG_LINES)))
+}
+
+/// Warnings and errors logged so far this run
+#[derive(Debug, Default)]
+pub struct AlertCounts {
+    pub warnings: AtomicU64,
+    pub errors: AtomicU64,
+}
+
+/// Get the global count of warnings and errors
+pub fn alert_counts() -> &'static AlertCounts {
+    static ALERT_COUNTS: OnceLock<AlertCounts> = OnceLock::new();
+    ALERT_COUNTS.get_or_init(AlertCounts::default)
+}
+
+/// Set where bundles are written and the configuration to include in them
+pub fn configure(path: PathBuf, config: String) {
+    let mut state = state().lock().unwrap();
+    state.path = Some(path);
+    state.config = config;
+}
+
+/// The configuration we were started with
+pub fn config() -> String {
+    state().lock().unwrap().config.clone()
+}
+
+/// Record the latest FPGA register dump (the FPGA is owned by monitoring, so we can't read it ourselves)
+pub fn record_registers(registers: String) {
+    state().lock().unwrap().registers = registers;
+}
+
+/// A tracing layer that keeps the most recent log lines in memory
+pub struct RecentLogs;
+
+/// Flattens an event's fields into a single line
+struct LineVisitor<'a>(&'a mut String);
+
+impl tracing::field::Visit for LineVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.0, " {value:?}");
+        } else {
+            let _ = write!(self.0, " {}={value:?}", field.name());
+        }
+    }
+}
+
+impl<S: Subscriber> Layer<S> for RecentLogs {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        match *meta.level() {
+            Level::ERROR => alert_counts().errors.fetch_add(1, Ordering::Relaxed),
+            Level::WARN => alert_counts().warnings.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+        let mut line = format!(
+            "{} {} {}:",
+            Epoch::now().map(|e| e.to_string()).unwrap_or_default(),
+            meta.level(),
+            meta.target()
+        );
+        event.record(&mut LineVisitor(&mut line));
+        let mut logs = recent_logs().lock().unwrap();
+        if logs.len() == RECENT_LOG_LINES {
+            logs.pop_front();
+        }
+        logs.push_back(line);
+    }
+}
+
+/// Backtraces of every thread in this process, courtesy of whichever debugger is installed
+fn thread_backtraces() -> String {
+    let pid = std::process::id().to_string();
+    let attempts: [(&str, &[&str]); 2] = [
+        ("eu-stack", &["-p", &pid]),
+        ("gdb", &["-p", &pid, "-batch", "-ex", "thread apply all bt"]),
+    ];
+    for (cmd, args) in attempts {
+        if let Ok(output) = Command::new(cmd).args(args).output() {
+            if output.status.success() {
+                return String::from_utf8_lossy(&output.stdout).into_owned();
+            }
+        }
+    }
+    format!(
+        "No debugger available, backtrace of the reporting thread only:\n{}",
+        std::backtrace::Backtrace::force_capture()
+    )
+}
+
+/// Channel occupancy as we currently understand it
+fn channel_report() -> String {
+    let quality = *quality_inputs().lock().unwrap();
+    format!(
+        "blanked: {:?}\nrfi_occupancy: {:?}\nexfil_backlog: {:?}\n",
+        channel_mask().channels(),
+        quality.rfi_occupancy,
+        quality.exfil_backlog
+    )
+}
+
+fn append(
+    tar: &mut tar::Builder<GzEncoder<std::fs::File>>,
+    name: &str,
+    contents: &str,
+) -> std::io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+    );
+    header.set_cksum();
+    tar.append_data(&mut header, name, contents.as_bytes())
+}
+
+/// Write a diagnostic bundle for the given reason, returning its path.
+/// Does nothing (and returns None) if the pipeline hasn't told us where to put it.
+pub fn write_bundle(reason: &str) -> Option<PathBuf> {
+    let (path, config, registers) = {
+        let state = state().lock().unwrap();
+        (
+            state.path.clone()?,
+            state.config.clone(),
+            state.registers.clone(),
+        )
+    };
+    match write_bundle_to(&path, reason, &config, &registers) {
+        Ok(file) => {
+            info!(?file, "Wrote diagnostic bundle");
+            Some(file)
+        }
+        Err(e) => {
+            error!("Failed to write diagnostic bundle - {e}");
+            None
+        }
+    }
+}
+
+fn write_bundle_to(
+    path: &Path,
+    reason: &str,
+    config: &str,
+    registers: &str,
+) -> eyre::Result<PathBuf> {
+    let name = format!(
+        "grex-diagnostics-{}",
+        time_policy().filename_stamp(Epoch::now()?)
+    );
+    let file_path = path.join(format!("{name}.tar.gz"));
+    let file = std::fs::File::create(&file_path)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+    let logs: Vec<_> = recent_logs().lock().unwrap().iter().cloned().collect();
+    let metrics = TextEncoder::new().encode_to_string(&crate::monitoring::gather())?;
+    let accounting = serde_json::to_string_pretty(&accounting().snapshot())?;
+    for (file, contents) in [
+        ("reason.txt", reason.to_owned()),
+        ("logs.txt", logs.join("\n")),
+        ("metrics.txt", metrics),
+        ("accounting.json", accounting),
+        ("config.txt", config.to_owned()),
+        ("fpga_registers.txt", registers.to_owned()),
+        ("channels.txt", channel_report()),
+        ("backtraces.txt", thread_backtraces()),
+    ] {
+        append(&mut tar, &format!("{name}/{file}"), &contents)?;
+    }
+    tar.into_inner()?.finish()?;
+    Ok(file_path)
+}
+
+/// Write a bundle on any panic, before handing over to the existing panic handler
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        write_bundle(&format!("Panic: {info}"));
+        previous(info);
+    }));
+}
+
+/// Write a bundle whenever capture or processing stop making progress for longer than `timeout`
+pub async fn watchdog_task(
+    timeout: Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting watchdog task");
+    let mut ticker = tokio::time::interval(timeout);
+    let mut last = (0, 0);
+    let mut stalled = false;
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Watchdog task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                let now = (
+                    accounting().captured.load(Ordering::Relaxed),
+                    accounting().downsampled.load(Ordering::Relaxed),
+                );
+                // Only bundle once per stall, and not before data has started flowing
+                if now == last && now != (0, 0) {
+                    if !stalled {
+                        stalled = true;
+                        warn!("Pipeline has stalled, collecting diagnostics");
+                        let reason = format!("Watchdog: no progress in {timeout:?}");
+                        tokio::task::spawn_blocking(move || write_bundle(&reason)).await?;
+                    }
+                } else {
+                    stalled = false;
+                }
+                last = now;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,448 @@
+//! The files voltage dumps are written to. The ring and the writer thread only see a [`DumpFormat`], so a new layout
+//! is a new implementation here and a variant of [`DumpFileFormat`] to pick it with.
+use crate::common::{
+    payload_time, station_id, CandidateEvent, CHANNELS, NEVER_RECEIVED, PACKET_CADENCE,
+};
+use crate::exfil::{checksum::Crc32c, FrequencyPlan};
+use crate::naming::time_policy;
+use crate::timing;
+use byte_slice_cast::AsByteSlice;
+use clap::ValueEnum;
+use ndarray::prelude::*;
+use serde_json::json;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Time samples per chunk of the voltages, where the format has chunks (16 MiB)
+pub const CHUNK_SAMPLES: usize = 2048;
+
+/// Everything about a dump but its samples
+#[derive(Debug, Clone, Copy)]
+pub struct DumpHeader<'a> {
+    /// The candidate the dump is named after
+    pub event: &'a CandidateEvent,
+    /// Every other candidate coalesced into the dump
+    pub merged: &'a [String],
+    /// Payload count of the first sample
+    pub start_sample: u64,
+    /// Output samples, after decimation
+    pub samples: usize,
+    /// Payloads averaged into each output sample
+    pub decimation: u64,
+    /// Significant bits of each voltage
+    pub nbits: u8,
+    pub freq_plan: FrequencyPlan,
+    /// Whether each output sample comes with its receive latency
+    pub watermarked: bool,
+    /// Deflate level, for formats that compress
+    pub compression: Option<i32>,
+}
+
+impl DumpHeader<'_> {
+    /// MJD of each output sample. Decimated samples are stamped with the middle of what went into them.
+    pub fn mjds(&self) -> Array1<f64> {
+        let mjd_start = time_policy().mjd(payload_time(self.start_sample));
+        let decimation = self.decimation;
+        Array::from_shape_fn(self.samples, |i| {
+            let offset = (i as u64 * decimation) as f64 + (decimation - 1) as f64 / 2.0;
+            mjd_start + offset * PACKET_CADENCE / 86400.0
+        })
+    }
+}
+
+/// CRC32C of a dump's voltages (as int8, in time, pol, freq, reim order), fed as they're written so they can be
+/// checked against the data no matter what container they're in. The files themselves get a SHA-256 in their
+/// manifest once they're finished.
+#[derive(Debug, Clone, Default)]
+pub struct VoltageChecksum {
+    crc32c: Crc32c,
+}
+
+impl VoltageChecksum {
+    pub fn update<D: Dimension>(&mut self, voltages: ArrayView<i8, D>) {
+        let voltages = voltages.as_standard_layout();
+        let bytes = voltages
+            .as_slice()
+            .expect("Standard layout is contiguous")
+            .as_byte_slice();
+        self.crc32c.update(bytes);
+    }
+
+    pub fn crc32c(&self) -> u32 {
+        self.crc32c.value()
+    }
+}
+
+/// A way of laying voltage dumps out on disk
+pub trait DumpFormat: Send + Sync {
+    /// Extension of the (main) file of a dump, without the dot
+    fn extension(&self) -> &'static str;
+    /// Create the file(s) for a dump at `path`, with everything but the samples themselves
+    fn create(&self, path: &Path, header: &DumpHeader) -> eyre::Result<Box<dyn DumpWriter>>;
+}
+
+/// A dump whose file is open, filled in order
+pub trait DumpWriter: Send {
+    /// Write output samples starting at `start`, with their receive latencies if the dump is watermarked
+    fn write(
+        &mut self,
+        start: usize,
+        voltages: ArrayView4<i8>,
+        latencies: Option<ArrayView1<i32>>,
+    ) -> eyre::Result<()>;
+    /// Record the checksum of the voltages and make sure everything we wrote is on disk, returning the path of the main
+    /// file
+    fn finish(self: Box<Self>, checksum: &VoltageChecksum) -> eyre::Result<PathBuf>;
+}
+
+/// The dump formats we can write, as picked on the command line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum DumpFileFormat {
+    /// NetCDF-4, with the axes and candidate alongside the voltages
+    #[default]
+    Netcdf,
+    /// NetCDF-4 just the same, named .h5 for tooling that goes by the extension (netCDF-4 files are HDF5 underneath,
+    /// but with netCDF's conventions)
+    NetcdfH5,
+    /// Flat int8 voltages (time, pol, freq, reim) with a JSON sidecar of the metadata
+    Raw,
+}
+
+impl DumpFileFormat {
+    pub fn format(self) -> Arc<dyn DumpFormat> {
+        match self {
+            Self::Netcdf | Self::NetcdfH5 => Arc::new(Netcdf {
+                extension: self.extension(),
+            }),
+            Self::Raw => Arc::new(Raw),
+        }
+    }
+
+    /// Extension of the (main) file of a dump in this format, without the dot
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Netcdf => "nc",
+            Self::NetcdfH5 => "h5",
+            Self::Raw => "raw",
+        }
+    }
+
+    /// Whether dumps can be rewritten by post-processing, which reads them back as netCDF
+    pub fn rewritable(self) -> bool {
+        !matches!(self, Self::Raw)
+    }
+}
+
+/// NetCDF-4 (so HDF5) dumps, chunked in time
+pub struct Netcdf {
+    extension: &'static str,
+}
+
+struct NetcdfWriter {
+    file: netcdf::FileMut,
+    path: PathBuf,
+}
+
+impl DumpFormat for Netcdf {
+    fn extension(&self) -> &'static str {
+        self.extension
+    }
+
+    fn create(&self, path: &Path, header: &DumpHeader) -> eyre::Result<Box<dyn DumpWriter>> {
+        let event = header.event;
+        let mut file = netcdf::create(path)?;
+
+        // Flag files whose absolute timing can't be trusted
+        file.add_attribute("station", station_id())?;
+        file.add_attribute("timing_degraded", u8::from(timing::degraded()))?;
+
+        // Everything we know about the candidate that caused this dump
+        file.add_attribute("candname", event.candname.as_str())?;
+        file.add_attribute("trigger_id", event.trigger_id)?;
+        file.add_attribute("specnum", event.specnum)?;
+        file.add_attribute("offset", event.offset)?;
+        if let Some(mjd) = event.mjd {
+            file.add_attribute("cand_mjd", mjd)?;
+        }
+        if let Some(dm) = event.dm {
+            file.add_attribute("dm", dm)?;
+        }
+        if let Some(snr) = event.snr {
+            file.add_attribute("snr", snr)?;
+        }
+        if let Some(members) = event.members {
+            file.add_attribute("members", members)?;
+        }
+        if let Some(source) = &event.source {
+            file.add_attribute("source", source.as_str())?;
+        }
+        // Closely spaced candidates whose windows overlapped share this dump
+        if !header.merged.is_empty() {
+            file.add_attribute("merged_candnames", header.merged.join(",").as_str())?;
+        }
+
+        // Add the file dimensions
+        file.add_dimension("time", header.samples)?;
+        file.add_dimension("pol", 2)?;
+        file.add_dimension("freq", CHANNELS)?;
+        file.add_dimension("reim", 2)?;
+
+        // Describe the dimensions
+        let mut mjd = file.add_variable::<f64>("time", &["time"])?;
+        mjd.put_attribute("units", "Days")?;
+        mjd.put_attribute(
+            "long_name",
+            format!("{} days since the MJD Epoch", time_policy().mjd_standard()).as_str(),
+        )?;
+        mjd.put(.., header.mjds().view())?;
+
+        let mut pol =
+            file.add_variable_with_type("pol", &["pol"], &netcdf::types::NcVariableType::String)?;
+        pol.put_attribute("long_name", "Polarization")?;
+        pol.put_string("a", 0)?;
+        pol.put_string("b", 1)?;
+
+        let mut freq = file.add_variable::<f64>("freq", &["freq"])?;
+        freq.put_attribute("units", "Megahertz")?;
+        freq.put_attribute("long_name", "Frequency")?;
+        // Voltages are stored as they came off the gateware, so the axis follows its ordering
+        freq.put(.., header.freq_plan.freqs().view())?;
+
+        let mut reim =
+            file.add_variable_with_type("reim", &["reim"], &netcdf::types::NcVariableType::String)?;
+        reim.put_attribute("long_name", "Complex")?;
+        reim.put_string("real", 0)?;
+        reim.put_string("imaginary", 1)?;
+
+        // The receive latency of (the first sample of) each output sample, so timing anomalies can be traced to the network
+        if header.watermarked {
+            let mut recv = file.add_variable::<i32>("recv_latency", &["time"])?;
+            recv.put_attribute("units", "Microseconds")?;
+            recv.put_attribute("long_name", "Host receive time minus nominal payload time")?;
+            recv.put_attribute("_FillValue", NEVER_RECEIVED)?;
+        }
+
+        // Setup our data block
+        let mut voltages = file.add_variable::<i8>("voltages", &["time", "pol", "freq", "reim"])?;
+        voltages.put_attribute("long_name", "Channelized Voltages")?;
+        voltages.put_attribute("units", "Volts")?;
+        voltages.put_attribute("decimation", header.decimation as u32)?;
+        voltages.put_attribute("nbits", header.nbits)?;
+
+        // Write to the file, one timestep at a time (chunking in pols, channels, and reim)
+        // We want chunk sizes of 16MiB, which works out to 2048 time samples (less than the default window)
+        voltages.set_chunking(&[CHUNK_SAMPLES, 2, CHANNELS, 2])?;
+        // Raw voltages deflate well, and zeroed low bits don't save anything unless they're compressed away
+        match header.compression {
+            Some(level) => voltages.set_compression(level, true)?,
+            None if header.nbits < 8 => voltages.set_compression(1, true)?,
+            None => {}
+        }
+
+        Ok(Box::new(NetcdfWriter {
+            file,
+            path: path.to_owned(),
+        }))
+    }
+}
+
+impl DumpWriter for NetcdfWriter {
+    fn write(
+        &mut self,
+        start: usize,
+        voltages: ArrayView4<i8>,
+        latencies: Option<ArrayView1<i32>>,
+    ) -> eyre::Result<()> {
+        let len = voltages.len_of(Axis(0));
+        if let Some(latencies) = latencies {
+            self.file
+                .variable_mut("recv_latency")
+                .expect("Created with the dump when watermarking")
+                .put(start..start + len, latencies)?;
+        }
+        self.file
+            .variable_mut("voltages")
+            .expect("Created with the dump")
+            .put((start..start + len, .., .., ..), voltages)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>, checksum: &VoltageChecksum) -> eyre::Result<PathBuf> {
+        let mut voltages = self
+            .file
+            .variable_mut("voltages")
+            .expect("Created with the dump");
+        voltages.put_attribute("crc32c", checksum.crc32c())?;
+        self.file.sync()?;
+        Ok(self.path)
+    }
+}
+
+/// Voltages straight to disk, described by a JSON sidecar (`<dump>.json`) written when the dump finishes
+pub struct Raw;
+
+struct RawWriter {
+    file: BufWriter<File>,
+    path: PathBuf,
+    /// The sidecar, less the receive latencies
+    metadata: serde_json::Value,
+    latencies: Option<Vec<i32>>,
+    /// Output samples written so far
+    written: usize,
+}
+
+impl DumpFormat for Raw {
+    fn extension(&self) -> &'static str {
+        "raw"
+    }
+
+    fn create(&self, path: &Path, header: &DumpHeader) -> eyre::Result<Box<dyn DumpWriter>> {
+        let policy = time_policy();
+        let metadata = json!({
+            "station": station_id(),
+            "timing_degraded": timing::degraded(),
+            "candidate": header.event,
+            "merged_candnames": header.merged,
+            "dtype": "int8",
+            "nbits": header.nbits,
+            "axes": ["time", "pol", "freq", "reim"],
+            "shape": [header.samples, 2, CHANNELS, 2],
+            "pol": ["a", "b"],
+            "reim": ["real", "imaginary"],
+            "start_sample": header.start_sample,
+            "decimation": header.decimation,
+            "tsamp": PACKET_CADENCE * header.decimation as f64,
+            "mjd_standard": policy.mjd_standard(),
+            "mjd_start": header.mjds().first().copied(),
+            // Voltages are stored as they came off the gateware, so the axis follows its ordering
+            "freq_mhz": header.freq_plan.freqs().to_vec(),
+        });
+        Ok(Box::new(RawWriter {
+            file: BufWriter::new(File::create(path)?),
+            path: path.to_owned(),
+            metadata,
+            latencies: header
+                .watermarked
+                .then(|| Vec::with_capacity(header.samples)),
+            written: 0,
+        }))
+    }
+}
+
+impl DumpWriter for RawWriter {
+    fn write(
+        &mut self,
+        start: usize,
+        voltages: ArrayView4<i8>,
+        latencies: Option<ArrayView1<i32>>,
+    ) -> eyre::Result<()> {
+        if start != self.written {
+            eyre::bail!(
+                "Raw dumps are written in order, expected sample {} but got {start}",
+                self.written
+            );
+        }
+        // Standard layout is the on-disk layout, anything else gets copied into it first
+        let voltages = voltages.as_standard_layout();
+        self.file.write_all(
+            voltages
+                .as_slice()
+                .expect("Standard layout is contiguous")
+                .as_byte_slice(),
+        )?;
+        if let (Some(all), Some(latencies)) = (&mut self.latencies, latencies) {
+            all.extend(latencies.iter());
+        }
+        self.written += voltages.len_of(Axis(0));
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>, checksum: &VoltageChecksum) -> eyre::Result<PathBuf> {
+        let Self {
+            file,
+            path,
+            mut metadata,
+            latencies,
+            ..
+        } = *self;
+        file.into_inner()?.sync_all()?;
+        if let Some(latencies) = latencies {
+            metadata["recv_latency_us"] = json!(latencies);
+        }
+        metadata["crc32c"] = json!(checksum.crc32c());
+        let sidecar = File::create(path.with_extension("json"))?;
+        serde_json::to_writer_pretty(&sidecar, &metadata)?;
+        sidecar.sync_all()?;
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_raw_dump() {
+        testing::seed_start_time();
+        let dir = testing::scratch_dir();
+        let path = dir.path().join("grex_dump-raw.raw");
+        let event = CandidateEvent {
+            candname: "raw".to_owned(),
+            ..Default::default()
+        };
+        let header = DumpHeader {
+            event: &event,
+            merged: &["other".to_owned()],
+            start_sample: 0,
+            samples: 3,
+            decimation: 1,
+            nbits: 8,
+            freq_plan: FrequencyPlan::default(),
+            watermarked: true,
+            compression: None,
+        };
+        let mut writer = Raw.create(&path, &header).unwrap();
+        let voltages = Array4::from_shape_fn((3, 2, CHANNELS, 2), |(t, _, _, _)| t as i8 - 1);
+        let mut checksum = VoltageChecksum::default();
+        checksum.update(voltages.view());
+        let latencies = array![1, 2, 3];
+        writer
+            .write(
+                0,
+                voltages.slice(s![..2, .., .., ..]),
+                Some(latencies.slice(s![..2])),
+            )
+            .unwrap();
+        // Out of order
+        assert!(writer
+            .write(0, voltages.slice(s![2.., .., .., ..]), None)
+            .is_err());
+        writer
+            .write(
+                2,
+                voltages.slice(s![2.., .., .., ..]),
+                Some(latencies.slice(s![2..])),
+            )
+            .unwrap();
+        let path = writer.finish(&checksum).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(bytes.len(), 3 * 2 * CHANNELS * 2);
+        assert_eq!(bytes[0] as i8, -1);
+        assert_eq!(*bytes.last().unwrap() as i8, 1);
+        let sidecar: serde_json::Value =
+            serde_json::from_reader(File::open(path.with_extension("json")).unwrap()).unwrap();
+        assert_eq!(sidecar["candidate"]["candname"], "raw");
+        assert_eq!(sidecar["merged_candnames"][0], "other");
+        assert_eq!(sidecar["shape"][0], 3);
+        assert_eq!(sidecar["recv_latency_us"], json!([1, 2, 3]));
+        // Raw files are nothing but the voltages
+        let mut crc = Crc32c::default();
+        crc.update(&bytes);
+        assert_eq!(sidecar["crc32c"], crc.value());
+    }
+}
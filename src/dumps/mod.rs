@@ -0,0 +1,2627 @@
+//! Dumping voltage data
+
+use crate::accounting::accounting;
+use crate::common::{
+    payload_time, CandidateEvent, DumpResolution, Payload, BLOCK_TIMEOUT, CHANNELS, FIRST_PACKET,
+    NEVER_RECEIVED, PACKET_CADENCE,
+};
+use crate::db::{DbEvent, DumpRecord, TriggerRecord};
+use crate::exfil::FrequencyPlan;
+use crate::injection::DISPERSION_CONSTANT;
+use crate::latency::trigger_profile;
+use crate::naming::time_policy;
+use crate::profiling::payload_profile;
+use byte_slice_cast::{AsMutSliceOf, AsSliceOf};
+use eyre::{bail, eyre};
+use format::{DumpFormat, DumpHeader, DumpWriter, VoltageChecksum};
+use memmap2::{Advice, MmapMut};
+use ndarray::{prelude::*, Zip};
+use status::{DumpStatus, Reply};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::sync::{Arc, OnceLock};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::OpenOptions,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{blocking, errors::RecvTimeoutError};
+use tokio::sync::broadcast;
+use tracing::{error, info, trace, warn};
+
+pub mod format;
+pub mod status;
+
+const FILENAME_PREFIX: &str = "grex_dump";
+/// Output samples handed to a dump's file at a time, lined up with the chunking of its voltages so every chunk is
+/// written once, whole
+const SLAB_SAMPLES: usize = format::CHUNK_SAMPLES;
+
+/// Most payloads we'll dump around a candidate by default (just over a minute), which the ring has to hold as well
+pub const MAX_DUMP_SAMPLES: u64 = 1 << 23;
+
+/// Rolling segments are named for the time (and payload count) of their first sample
+const SEGMENT_PREFIX: &str = "grex_segment";
+
+/// Name of the dump file written for a candidate in `format`
+pub fn dump_filename(candname: &str, format: format::DumpFileFormat) -> String {
+    format!("{}.{}", dump_stem(candname), format.extension())
+}
+
+/// Name of the dump file written for a candidate, less the extension of the format
+fn dump_stem(candname: &str) -> String {
+    format!("{}-{}", FILENAME_PREFIX, candname)
+}
+
+/// Counters describing how well the voltage ringbuffer is keeping up
+#[derive(Debug, Default)]
+pub struct RingStats {
+    /// Payloads pushed into the ring
+    pub pushes: AtomicU64,
+    /// Times the ring started over because the payload counts weren't monotonic
+    pub resets: AtomicU64,
+    /// Times the ring started over because capture resynchronized to a new packet sequence
+    pub resyncs: AtomicU64,
+    /// Nanoseconds the ring couldn't accept payloads (while copying dump windows out of it)
+    pub blocked_ns: AtomicU64,
+    /// Nanoseconds spent copying rolling segments out of the ring, which holds it up just the same
+    pub rolling_copy_ns: AtomicU64,
+    /// Payloads currently held in the ring
+    pub occupancy: AtomicU64,
+    /// Payloads the ring can hold
+    pub capacity: AtomicU64,
+    /// Payload counts of the oldest and newest samples in the ring
+    pub oldest: AtomicU64,
+    pub newest: AtomicU64,
+    /// Dump files written
+    pub dumps: AtomicU64,
+    /// Nanoseconds from starting dumps to having them on disk, over every dump and for the latest one
+    pub dump_ns: AtomicU64,
+    pub last_dump_ns: AtomicU64,
+    /// Triggers whose window couldn't be dumped (it was gone from the ring)
+    pub failed_triggers: AtomicU64,
+    /// Triggers folded into the dump of an overlapping one
+    pub coalesced: AtomicU64,
+    /// Triggers dropped because too many were waiting on dumps
+    pub dropped_triggers: AtomicU64,
+    /// Rolling segments written
+    pub segments: AtomicU64,
+    /// Rolling segments deleted to stay within the budget
+    pub expired_segments: AtomicU64,
+    /// Payloads missing from the rolling recording, because its writer fell behind the ring
+    pub rolling_gaps: AtomicU64,
+    /// Dumps and segments we couldn't write a checksum manifest for
+    pub missing_manifests: AtomicU64,
+}
+
+/// Get the global ringbuffer statistics
+pub fn ring_stats() -> &'static RingStats {
+    static RING_STATS: OnceLock<RingStats> = OnceLock::new();
+    RING_STATS.get_or_init(RingStats::default)
+}
+
+/// Hugetlbfs files have to be sized in multiples of the huge page size, this covers both 2 MiB and 1 GiB pages
+const HUGEPAGE_ALIGN: u64 = 1 << 30;
+
+/// Write to every page of a fresh allocation so it's really backed by RAM before we need it, split over `threads`
+/// threads. Pages land on the NUMA node of the thread that touches them, so keep this to the cores near capture.
+fn first_touch(bytes: &mut [u8], threads: usize) {
+    let chunk = bytes.len().div_ceil(threads.max(1)).max(1);
+    std::thread::scope(|s| {
+        for part in bytes.chunks_mut(chunk) {
+            // We're going to write a non-zero value to do something convincingly non-trivial
+            // But this will be overwritten anyway
+            s.spawn(move || part.fill(0xDE));
+        }
+    });
+}
+
+/// Bytes a payload takes up in a ring holding each voltage in `bits`
+fn slot_bytes(bits: u8) -> usize {
+    2 * CHANNELS * 2 * bits as usize / 8
+}
+
+/// Payloads (by count) sharing each quantizer scale of a packed ring
+const SCALE_BLOCK: u64 = SLAB_SAMPLES as u64;
+
+/// Voltages requantized to fewer bits in the ring, so it holds more time in the same memory
+#[derive(Debug)]
+struct Packing {
+    bits: u8,
+    /// Quantization step (per polarization) of each block of [`SCALE_BLOCK`] payloads in the ring, oldest first
+    steps: VecDeque<[f32; 2]>,
+    /// Block the front of `steps` belongs to
+    first_block: u64,
+}
+
+impl Packing {
+    fn new(bits: u8) -> Self {
+        Self {
+            bits,
+            steps: VecDeque::new(),
+            first_block: 0,
+        }
+    }
+
+    /// The steps to pack payload `count` with, setting them from its voltages if it's the first of its block
+    fn steps_for(&mut self, count: u64, voltages: ArrayView3<i8>) -> [f32; 2] {
+        let block = count / SCALE_BLOCK;
+        if self.steps.is_empty() {
+            self.first_block = block;
+        }
+        if block >= self.first_block + self.steps.len() as u64 {
+            self.steps.push_back(block_steps(voltages, self.bits));
+        }
+        self.steps[(block - self.first_block) as usize]
+    }
+
+    /// The steps payload `count` (which must still be in the ring) was packed with
+    fn steps_of(&self, count: u64) -> [f32; 2] {
+        self.steps[(count / SCALE_BLOCK - self.first_block) as usize]
+    }
+
+    /// Forget the steps of blocks entirely older than payload `oldest`
+    fn expire(&mut self, oldest: u64) {
+        while self.first_block < oldest / SCALE_BLOCK && !self.steps.is_empty() {
+            self.steps.pop_front();
+            self.first_block += 1;
+        }
+    }
+}
+
+/// Uniform quantizer steps (in standard deviations) with the least error on gaussian voltages (Max, 1960)
+fn quantizer_step(bits: u8) -> f32 {
+    match bits {
+        2 => 0.9957,
+        4 => 0.3352,
+        _ => unreachable!("Rings are only packed to 2 or 4 bits"),
+    }
+}
+
+/// Quantizer steps for a block of payloads, scaled to each polarization's RMS in the first of them
+fn block_steps(voltages: ArrayView3<i8>, bits: u8) -> [f32; 2] {
+    let mut steps = [1.0; 2];
+    for (step, pol) in steps.iter_mut().zip(voltages.outer_iter()) {
+        let power = pol.iter().map(|&v| f32::from(v).powi(2)).sum::<f32>() / pol.len() as f32;
+        if power > 0.0 {
+            *step = power.sqrt() * quantizer_step(bits);
+        }
+    }
+    steps
+}
+
+/// Requantize a payload's voltages (polarization-major, as they come) to `bits` each with a step per polarization,
+/// in offset binary packed from the low bits of each byte up
+fn pack(voltages: &[i8], bits: u8, steps: [f32; 2], packed: &mut [u8]) {
+    match bits {
+        2 => pack_bits::<2>(voltages, steps, packed),
+        4 => pack_bits::<4>(voltages, steps, packed),
+        _ => unreachable!("Rings are only packed to 2 or 4 bits"),
+    }
+}
+
+fn pack_bits<const BITS: usize>(voltages: &[i8], steps: [f32; 2], packed: &mut [u8]) {
+    let per_byte = 8 / BITS;
+    let levels = (1 << (BITS - 1)) as f32;
+    let pols = voltages
+        .chunks_exact(voltages.len() / 2)
+        .zip(packed.chunks_exact_mut(packed.len() / 2));
+    for ((pol, out), step) in pols.zip(steps) {
+        let scale = 1.0 / step;
+        // Straight-line work per output byte, so this vectorizes
+        for (byte, vs) in out.iter_mut().zip(pol.chunks_exact(per_byte)) {
+            let mut b = 0u8;
+            for (k, &v) in vs.iter().enumerate() {
+                let q = (f32::from(v) * scale).floor().clamp(-levels, levels - 1.0) + levels;
+                b |= (q as u8) << (k * BITS);
+            }
+            *byte = b;
+        }
+    }
+}
+
+/// Undo [`pack`], back to 8-bit voltages at the original scale (each level at the middle of what went into it)
+fn unpack(packed: &[u8], bits: u8, steps: [f32; 2], mut voltages: ArrayViewMut3<i8>) {
+    let per_byte = 8 / bits as usize;
+    let per_pol = CHANNELS * 2;
+    let levels = 1i32 << (bits - 1);
+    let mask = (1u8 << bits) - 1;
+    for (p, mut pol) in voltages.outer_iter_mut().enumerate() {
+        for (i, v) in pol.iter_mut().enumerate() {
+            let idx = p * per_pol + i;
+            let q = ((packed[idx / per_byte] >> ((idx % per_byte) * bits as usize)) & mask) as i32
+                - levels;
+            *v = ((q as f32 + 0.5) * steps[p]).round().clamp(-128.0, 127.0) as i8;
+        }
+    }
+}
+
+/// Memory holding the samples of the ring
+#[derive(Debug)]
+enum RingStorage {
+    /// Private to this process
+    Heap(Vec<u8>),
+    /// A file on tmpfs or hugetlbfs that outlives us, so a restart doesn't have to allocate it again
+    Mapped(MmapMut),
+}
+
+impl RingStorage {
+    fn bytes(&self) -> &[u8] {
+        match self {
+            Self::Heap(buf) => buf,
+            Self::Mapped(map) => &map[..],
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Heap(buf) => buf,
+            Self::Mapped(map) => &mut map[..],
+        }
+    }
+}
+
+/// What the ring's memory holds for each payload
+#[derive(Debug)]
+enum Slots {
+    /// The 8-bit voltages as they came, which we can view in place
+    Voltages(VoltageSlots),
+    /// Voltages requantized to fewer bits, which have to be unpacked to be read
+    Packed(RingStorage, Packing),
+}
+
+impl Slots {
+    fn new(storage: RingStorage, capacity: usize, bits: u8) -> Self {
+        if bits < 8 {
+            Self::Packed(storage, Packing::new(bits))
+        } else {
+            Self::Voltages(VoltageSlots { storage, capacity })
+        }
+    }
+}
+
+/// Ring storage holding 8-bit voltages, the only kind we can view as voltages in place
+#[derive(Debug)]
+struct VoltageSlots {
+    storage: RingStorage,
+    capacity: usize,
+}
+
+impl VoltageSlots {
+    /// The whole ring as (time, polarization, channel, real/imaginary)
+    fn view(&self) -> ArrayView4<'_, i8> {
+        let bytes = &self.storage.bytes()[..self.capacity * slot_bytes(8)];
+        ArrayView4::from_shape(
+            (self.capacity, 2, CHANNELS, 2),
+            bytes.as_slice_of::<i8>().unwrap(),
+        )
+        .unwrap()
+    }
+
+    fn view_mut(&mut self) -> ArrayViewMut4<'_, i8> {
+        let bytes = &mut self.storage.bytes_mut()[..self.capacity * slot_bytes(8)];
+        ArrayViewMut4::from_shape(
+            (self.capacity, 2, CHANNELS, 2),
+            bytes.as_mut_slice_of::<i8>().unwrap(),
+        )
+        .unwrap()
+    }
+}
+
+/// A chunk-aligned run of a dump's output samples, filled by the writer and then written out by the dump's I/O thread
+struct Slab {
+    /// Output sample the slab starts at
+    start: usize,
+    /// Output samples filled so far
+    len: usize,
+    voltages: Array4<i8>,
+    latencies: Option<Array1<i32>>,
+}
+
+impl Slab {
+    fn new(start: usize, watermarked: bool) -> Self {
+        Self {
+            start,
+            len: 0,
+            voltages: Array4::zeros((SLAB_SAMPLES, 2, CHANNELS, 2)),
+            latencies: watermarked.then(|| Array1::from_elem(SLAB_SAMPLES, NEVER_RECEIVED)),
+        }
+    }
+}
+
+/// What a dump's I/O thread hands back when it's done: the writer, to finish the file with, and how writing went
+type SlabWriter = std::thread::JoinHandle<(Box<dyn DumpWriter>, VoltageChecksum, eyre::Result<()>)>;
+
+/// Write slabs to a dump's file as they're handed over, passing them back to be filled again. We stop at the first
+/// error, keeping what was written, and the checksum is of exactly that.
+fn write_slabs(
+    mut writer: Box<dyn DumpWriter>,
+    slabs: Receiver<Slab>,
+    spare: Sender<Slab>,
+) -> (Box<dyn DumpWriter>, VoltageChecksum, eyre::Result<()>) {
+    let mut checksum = VoltageChecksum::default();
+    for slab in slabs {
+        let voltages = slab.voltages.slice(s![..slab.len, .., .., ..]);
+        let written = writer.write(
+            slab.start,
+            voltages,
+            slab.latencies.as_ref().map(|l| l.slice(s![..slab.len])),
+        );
+        if written.is_err() {
+            return (writer, checksum, written);
+        }
+        checksum.update(voltages);
+        // The writer may have moved on without it, that's fine
+        let _ = spare.send(slab);
+    }
+    (writer, checksum, Ok(()))
+}
+
+/// A dump file that's been set up, and how far through its window we've written
+pub struct DumpFile {
+    /// Full slabs on their way to the I/O thread. The channel holds nothing, so one slab is filled while the last is
+    /// written.
+    slabs: SyncSender<Slab>,
+    /// Slabs the I/O thread is done with
+    spare: Receiver<Slab>,
+    io: SlabWriter,
+    /// The slab being filled
+    slab: Slab,
+    watermarked: bool,
+    path: PathBuf,
+    /// When the dump was started
+    created: Instant,
+    /// First payload count of the window
+    start: u64,
+    /// Next payload count to write
+    next: u64,
+    resolution: DumpResolution,
+    decimation: u64,
+    /// Who to tell how it went
+    reply: Reply,
+}
+
+impl std::fmt::Debug for DumpFile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DumpFile")
+            .field("path", &self.path)
+            .field("start", &self.start)
+            .field("next", &self.next)
+            .finish()
+    }
+}
+
+impl DumpFile {
+    /// Make room in the slab being filled, handing it to the I/O thread if it's full
+    fn make_room(&mut self) -> eyre::Result<()> {
+        if self.slab.len < SLAB_SAMPLES {
+            return Ok(());
+        }
+        let start = self.slab.start + SLAB_SAMPLES;
+        let next = match self.spare.try_recv() {
+            Ok(mut slab) => {
+                slab.start = start;
+                slab.len = 0;
+                slab
+            }
+            Err(_) => Slab::new(start, self.watermarked),
+        };
+        let full = std::mem::replace(&mut self.slab, next);
+        self.slabs
+            .send(full)
+            .map_err(|_| eyre!("The dump's I/O thread stopped early"))
+    }
+
+    /// Add output samples (and their receive latencies, if we have them) to the end of what's been written
+    fn append(
+        &mut self,
+        voltages: ArrayView4<i8>,
+        latencies: Option<ArrayView1<i32>>,
+    ) -> eyre::Result<()> {
+        let len = voltages.len_of(Axis(0));
+        let mut copied = 0;
+        while copied < len {
+            self.make_room()?;
+            let slab = &mut self.slab;
+            let n = (SLAB_SAMPLES - slab.len).min(len - copied);
+            let (from, to) = (copied..copied + n, slab.len..slab.len + n);
+            slab.voltages
+                .slice_mut(s![to.clone(), .., .., ..])
+                .assign(&voltages.slice(s![from.clone(), .., .., ..]));
+            if let Some(slab_latencies) = &mut slab.latencies {
+                let mut slab_latencies = slab_latencies.slice_mut(s![to]);
+                match latencies {
+                    Some(latencies) => slab_latencies.assign(&latencies.slice(s![from])),
+                    None => slab_latencies.fill(NEVER_RECEIVED),
+                }
+            }
+            slab.len += n;
+            copied += n;
+        }
+        Ok(())
+    }
+
+    /// Hand off the last slab and wait for everything to be written, then make sure it's on disk, returning where. If
+    /// writing failed partway, why is recorded in `status` and we keep what was written.
+    fn finish(self, status: &mut DumpStatus) -> eyre::Result<PathBuf> {
+        let Self {
+            slabs,
+            io,
+            slab,
+            start,
+            next,
+            ..
+        } = self;
+        if slab.len > 0 {
+            // If this fails, the I/O thread has already stopped and will tell us why
+            let _ = slabs.send(slab);
+        }
+        drop(slabs);
+        let (writer, checksum, written) = io
+            .join()
+            .map_err(|_| eyre!("The dump's I/O thread panicked"))?;
+        if let Err(e) = written {
+            warn!("Error writing dump, keeping what was written: {}", e);
+            status.error = Some(e.to_string());
+        }
+        let path = writer.finish(&checksum)?;
+        accounting()
+            .dumped
+            .fetch_add(next - start, Ordering::Relaxed);
+        Ok(path)
+    }
+}
+
+/// A dump whose window is still being copied out of the ring
+#[derive(Debug)]
+pub struct PendingDump {
+    /// First and last (inclusive) payload counts of the window
+    start: u64,
+    stop: u64,
+    /// Next payload count to copy
+    next: u64,
+    resolution: DumpResolution,
+    decimation: u64,
+}
+
+impl PendingDump {
+    fn is_complete(&self) -> bool {
+        self.next > self.stop
+    }
+
+    /// Take what we can of a new request into this dump. Returns whether it's all inside this window, otherwise the
+    /// request is trimmed to start after it if it started inside it.
+    fn absorb(&self, request: &mut DumpRequest) -> bool {
+        if request.start >= self.start && request.stop <= self.stop {
+            return true;
+        }
+        if (self.start..=self.stop).contains(&request.start) {
+            request.start = self.stop + 1;
+        }
+        false
+    }
+}
+
+/// Part of a dump's window, copied out of the ring for the writer
+#[derive(Debug)]
+pub struct Snapshot {
+    /// Payload count of the first sample
+    start: u64,
+    samples: Array4<i8>,
+    /// Receive latency of each sample, if capture is watermarking
+    latencies: Option<Vec<i32>>,
+}
+
+/// What the fill loop hands the dump writer, in order for each dump
+#[derive(Debug)]
+enum WriterRequest {
+    /// Start a new dump file, with everything but the samples themselves
+    Create {
+        /// Directory to write it in
+        dir: PathBuf,
+        /// Name of the file, less the extension of the format
+        name: String,
+        event: Box<CandidateEvent>,
+        /// Other candidates coalesced into the dump
+        merged: Vec<String>,
+        start: u64,
+        stop: u64,
+        resolution: DumpResolution,
+        decimation: u64,
+        freq_plan: FrequencyPlan,
+        watermarked: bool,
+        /// Bits the ring held each voltage in
+        ring_bits: u8,
+        /// Who to tell how it went
+        reply: Box<Reply>,
+    },
+    /// The next samples of the dump
+    Samples(Snapshot),
+    /// Another candidate whose window is inside the dump's, to hear how it goes
+    Merge(Box<Reply>),
+    /// That's everything for the dump
+    Finish,
+}
+
+/// Count a finished dump (that took `elapsed` to write), record it in the database, and hand it off for
+/// post-processing if we're doing any (or write its manifest if not). Called from the writer thread.
+fn hand_off(
+    file: PathBuf,
+    status: &DumpStatus,
+    elapsed: Duration,
+    db: &SyncSender<DbEvent>,
+    postprocess: &Option<SyncSender<PathBuf>>,
+) {
+    let stats = ring_stats();
+    stats.dumps.fetch_add(1, Ordering::Relaxed);
+    stats
+        .dump_ns
+        .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    stats
+        .last_dump_ns
+        .store(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    let record = DumpRecord {
+        mjd: hifitime::Epoch::now()
+            .map(|e| time_policy().mjd(e))
+            .unwrap_or_default(),
+        candname: status.candname.clone(),
+        merged: status.merged.clone(),
+        trigger_mjd: status.mjd,
+        start_sample: status.start,
+        stop_sample: status.stop,
+        samples: status.samples,
+        path: file.display().to_string(),
+        truncated_start: status.truncated_start,
+        truncated_end: status.truncated_end,
+        duration: elapsed.as_secs_f64(),
+    };
+    // We're on the writer thread, so waiting on a busy database only holds up the next dump, not the ring
+    if db.send(DbEvent::Dump(record)).is_err() {
+        warn!("Database task is gone, couldn't record dump");
+    }
+    match postprocess {
+        // Post-processing writes the manifest once it's done with the dump
+        Some(pp) => {
+            if let Err(std::sync::mpsc::SendError(file)) = pp.send(file) {
+                warn!("Dump post-processing has stopped, leaving dump as it was written");
+                write_manifest(&file);
+            }
+        }
+        None => write_manifest(&file),
+    }
+}
+
+/// Write the checksum manifest of a finished dump (or rolling segment)
+fn write_manifest(file: &Path) {
+    match crate::postprocess::write_manifest(file) {
+        Ok(manifest) => info!(?manifest, "Wrote dump manifest"),
+        Err(e) => {
+            ring_stats()
+                .missing_manifests
+                .fetch_add(1, Ordering::Relaxed);
+            warn!(?file, "Error writing dump manifest - {e}");
+        }
+    }
+}
+
+/// The voltage dump ringbuffer
+#[derive(Debug)]
+pub struct DumpRing {
+    /// The next time index we write into
+    write_ptr: usize,
+    /// The data itself (on the heap or in a shared memory file), as it came or requantized
+    buffer: Slots,
+    /// The number of time samples in this array
+    capacity: usize,
+    /// The timestamp (packet count) of the oldest sample (pointed to by read_ptr).
+    /// None if the buffer is empty
+    oldest: Option<u64>,
+    // If the buffer is completly full
+    full: bool,
+    /// Last pushed payload count
+    last: Option<u64>,
+    /// Receive latency of each slot, alongside the data, if capture is watermarking
+    watermarks: Option<Vec<i32>>,
+}
+
+impl DumpRing {
+    pub fn new(capacity: usize) -> Self {
+        Self::heap(capacity, 8, 1)
+    }
+
+    /// Allocate the ringbuffer on the heap (holding each voltage in `bits`), touching its pages from `touch_threads`
+    /// threads
+    pub fn heap(capacity: usize, bits: u8, touch_threads: usize) -> Self {
+        // Because (linux) uses overcommited memory, this just asks the OS for the pages, it doesn't actually back this by RAM
+        // This means we need to write actual values to every single slot to convince linux we're not dumb and we really really want like 100GB for our thread
+        let mut buffer = vec![0u8; capacity * slot_bytes(bits)];
+        info!(
+            "Creating voltage ringbuffer with a total capacity of {} seconds",
+            capacity as f64 * PACKET_CADENCE
+        );
+        first_touch(&mut buffer, touch_threads);
+        Self::with_storage(RingStorage::Heap(buffer), capacity, bits)
+    }
+
+    /// Allocate the ringbuffer from anonymous memory, asking for transparent hugepages so the TLB covers more of it
+    pub fn hugepages(capacity: usize, bits: u8, touch_threads: usize) -> eyre::Result<Self> {
+        let len = capacity * slot_bytes(bits);
+        let mut map = MmapMut::map_anon(len)?;
+        if let Err(e) = map.advise(Advice::HugePage) {
+            warn!(
+                "Couldn't ask for hugepages for the voltage ringbuffer, using normal pages - {e}"
+            );
+        }
+        info!(
+            "Creating voltage ringbuffer in hugepages with a total capacity of {} seconds",
+            capacity as f64 * PACKET_CADENCE
+        );
+        first_touch(&mut map[..], touch_threads);
+        Ok(Self::with_storage(RingStorage::Mapped(map), capacity, bits))
+    }
+
+    fn with_storage(buffer: RingStorage, capacity: usize, bits: u8) -> Self {
+        if bits < 8 {
+            info!(
+                bits,
+                "Requantizing voltages in the ringbuffer, dumps will only be as good"
+            );
+        }
+        Self {
+            capacity,
+            write_ptr: 0,
+            full: false,
+            oldest: None,
+            last: None,
+            watermarks: None,
+            buffer: Slots::new(buffer, capacity, bits),
+        }
+    }
+
+    /// Back the ringbuffer with a file on tmpfs (i.e. /dev/shm) or hugetlbfs.
+    /// If the file is left over from a previous run and big enough, its pages are already resident and we skip touching them.
+    pub fn mapped(
+        capacity: usize,
+        bits: u8,
+        path: &Path,
+        touch_threads: usize,
+    ) -> eyre::Result<Self> {
+        let len = (capacity * slot_bytes(bits)) as u64;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let warm = file.metadata()?.len() >= len;
+        if !warm {
+            file.set_len(len.div_ceil(HUGEPAGE_ALIGN) * HUGEPAGE_ALIGN)?;
+        }
+        // Safety: This file is ours, nobody else should be modifying it while we're running
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        if warm {
+            info!(
+                "Reusing the voltage ringbuffer at {} with a total capacity of {} seconds",
+                path.display(),
+                capacity as f64 * PACKET_CADENCE
+            );
+        } else {
+            info!(
+                "Creating voltage ringbuffer at {} with a total capacity of {} seconds",
+                path.display(),
+                capacity as f64 * PACKET_CADENCE
+            );
+            // Same as on the heap, the pages don't exist until we touch them
+            first_touch(&mut map[..len as usize], touch_threads);
+        }
+        Ok(Self::with_storage(RingStorage::Mapped(map), capacity, bits))
+    }
+
+    /// Also keep the receive latency capture stamped on each payload, to write out with the dumps
+    pub fn with_watermarks(mut self) -> Self {
+        self.watermarks = Some(vec![NEVER_RECEIVED; self.capacity]);
+        self
+    }
+
+    /// Reset the ring buffer state (empty)
+    pub fn reset(&mut self) {
+        self.write_ptr = 0;
+        self.full = false;
+        self.oldest = None;
+        self.last = None;
+        if let Slots::Packed(_, packing) = &mut self.buffer {
+            packing.steps.clear();
+        }
+    }
+
+    /// Number of payloads currently held
+    pub fn len(&self) -> usize {
+        match (self.oldest, self.full) {
+            (None, _) => 0,
+            (Some(_), true) => self.capacity,
+            (Some(_), false) => self.write_ptr,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.oldest.is_none()
+    }
+
+    pub fn push(&mut self, pl: &Payload) {
+        ring_stats().pushes.fetch_add(1, Ordering::Relaxed);
+        if let Some(last) = self.last {
+            // Check to see if the incoming payload is monotonic
+            if pl.count != last + 1 && crate::capture::take_resync(pl.stream, pl.count) {
+                // Capture told us this was coming, start over from here
+                info!(
+                    count = pl.count,
+                    last = last,
+                    "Capture resynchronized, starting the ring over"
+                );
+                ring_stats().resyncs.fetch_add(1, Ordering::Relaxed);
+                self.reset();
+            } else if pl.count != last + 1 {
+                error!(
+                    count = pl.count,
+                    last = last,
+                    "Not monotonic, clearing buffer and starting over"
+                );
+                ring_stats().resets.fetch_add(1, Ordering::Relaxed);
+                self.reset();
+                return;
+            } else {
+                self.last = Some(pl.count);
+            }
+        }
+
+        // Copy the data into the slice pointed to by the write_ptr
+        let data_view = pl.as_ndarray_data_view();
+        match &mut self.buffer {
+            Slots::Voltages(voltages) => voltages
+                .view_mut()
+                .slice_mut(s![self.write_ptr, .., .., ..])
+                .assign(&data_view),
+            Slots::Packed(storage, packing) => {
+                let len = slot_bytes(packing.bits);
+                let slot = &mut storage.bytes_mut()[self.write_ptr * len..][..len];
+                let steps = packing.steps_for(pl.count, data_view);
+                let voltages = data_view
+                    .to_slice()
+                    .expect("Payload voltages are contiguous");
+                pack(voltages, packing.bits, steps, slot);
+            }
+        }
+        if let Some(watermarks) = &mut self.watermarks {
+            watermarks[self.write_ptr] = pl.recv_latency;
+        }
+
+        // Move the pointer
+        self.write_ptr = (self.write_ptr + 1) % self.capacity;
+
+        // If there was no data update the timeslot of the oldest data and increment the write_ptr
+        if self.oldest.is_none() {
+            self.oldest = Some(pl.count);
+            self.last = Some(pl.count);
+            // Nothing left to do
+            return;
+        }
+
+        // If we're full, we overwrite old data
+        // which increments the payload count of old data by one
+        // as they are always monotonically increasing by one
+        if self.full {
+            self.oldest = Some(self.oldest.unwrap() + 1);
+            if let Slots::Packed(_, packing) = &mut self.buffer {
+                packing.expire(self.oldest.unwrap());
+            }
+        }
+
+        // If we wrapped around the first time, we are now full
+        if self.write_ptr == 0 && !self.full {
+            self.full = true;
+        }
+    }
+
+    /// Get the two array views that represent the time-ordered, consecutive memory chunks of the ringbuffer.
+    /// The first view will always have data in it, and the second view will be buffer_capacity - length(first_view)
+    fn consecutive_views<'a>(
+        &self,
+        voltages: &'a VoltageSlots,
+    ) -> (ArrayView4<'a, i8>, ArrayView4<'a, i8>) {
+        // There are four different cases
+        // 1. the buffer is empty or
+        // 2. The buffer has yet to be filled to capacity  (and we always start at index 0) so there's only really one chunk
+        let buffer = voltages.view();
+        if !self.full {
+            (
+                buffer.slice_move(s![..self.write_ptr, .., .., ..]),
+                ArrayView4::from_shape((0, 2, CHANNELS, 2), &[]).unwrap(),
+            )
+        } else {
+            // 3. The buffer is full and the write_ptr is at 0 (so the buffer is in order) or
+            // 4. The write_ptr is non zero and the buffer is full, meaning the write_ptr is the split where data at its value to the end is the oldest chunk
+            (
+                buffer.slice_move(s![self.write_ptr.., .., .., ..]),
+                buffer.slice_move(s![..self.write_ptr, .., .., ..]),
+            )
+        }
+    }
+
+    /// The payload count of the newest sample, if any
+    fn newest(&self) -> Option<u64> {
+        self.oldest.and(self.last)
+    }
+
+    /// Index of the slot holding payload `sample`, which must be in the ring
+    fn slot(&self, sample: u64) -> usize {
+        let oldest = self
+            .oldest
+            .expect("Only called on a ring with something in it");
+        let first = if self.full { self.write_ptr } else { 0 };
+        (first + (sample - oldest) as usize) % self.capacity
+    }
+
+    /// Bits each voltage is held in
+    pub fn bits(&self) -> u8 {
+        match &self.buffer {
+            Slots::Voltages(_) => 8,
+            Slots::Packed(_, packing) => packing.bits,
+        }
+    }
+
+    /// Time-ordered views of the ring covering `start_sample` to `stop_sample` (inclusive), which must be in it
+    fn range_views<'a>(
+        &self,
+        voltages: &'a VoltageSlots,
+        start_sample: u64,
+        stop_sample: u64,
+    ) -> Vec<ArrayView4<'a, i8>> {
+        let oldest = self
+            .oldest
+            .expect("Only called on a ring with something in it");
+        let (a, b) = self.consecutive_views(voltages);
+        let a_len = a.len_of(Axis(0));
+
+        // There are three situations:
+        // 1. The range is entirely in the first half
+        if oldest as usize + a_len > stop_sample as usize {
+            trace!("Dump is all in a chunk");
+            // Trim the chunk and write
+            let start_idx = (start_sample - oldest) as usize;
+            let stop_idx = (stop_sample - oldest) as usize;
+            vec![a.slice_move(s![start_idx..=stop_idx, .., .., ..])]
+        }
+        // 2. The range is between the two chunks
+        // Else branch implies that oldest + a_len <= stop_sample
+        else if oldest as usize + a_len > start_sample as usize {
+            trace!("Dump is between a and b chunk");
+            // stop idx for the first chunk is just the end of the chunk
+            let start_idx = (start_sample - oldest) as usize;
+            let a_slice = a.slice_move(s![start_idx.., .., .., ..]);
+            // start idx for the second chunk is the start of the chunk
+            let stop_idx = stop_sample as usize - oldest as usize - a_len;
+            let b_slice = b.slice_move(s![..=stop_idx, .., .., ..]);
+            // Sanity check
+            if a_slice.len_of(Axis(0)) + b_slice.len_of(Axis(0))
+                != (stop_sample - start_sample + 1) as usize
+            {
+                error!(
+                    "The size of the two slices doesn't match the total size we expected to dump"
+                );
+            }
+            vec![a_slice, b_slice]
+        }
+        // 3. The range is entirely in the second chunk
+        // Else branch implies that oldest + a_len <= stop_sample && oldest + a_len <= start_sample
+        else {
+            trace!("Dump is all in b chunk");
+            let oldest_b = oldest as usize + a_len;
+            let start_idx = start_sample as usize - oldest_b;
+            let stop_idx = stop_sample as usize - oldest_b;
+            vec![b.slice_move(s![start_idx..=stop_idx, .., .., ..])]
+        }
+    }
+
+    /// Copy `start_sample` to `stop_sample` (inclusive, and in the ring) out of the ring, for the writer
+    fn snapshot(&self, start_sample: u64, stop_sample: u64) -> Snapshot {
+        let samples = match &self.buffer {
+            Slots::Voltages(voltages) => ndarray::concatenate(
+                Axis(0),
+                &self.range_views(voltages, start_sample, stop_sample),
+            )
+            .expect("Every chunk of the ring has the same shape"),
+            Slots::Packed(storage, packing) => {
+                let len = slot_bytes(packing.bits);
+                let bytes = storage.bytes();
+                let n = (stop_sample - start_sample + 1) as usize;
+                let mut samples = Array4::zeros((n, 2, CHANNELS, 2));
+                for (s, row) in (start_sample..=stop_sample).zip(samples.outer_iter_mut()) {
+                    let slot = self.slot(s);
+                    unpack(
+                        &bytes[slot * len..][..len],
+                        packing.bits,
+                        packing.steps_of(s),
+                        row,
+                    );
+                }
+                samples
+            }
+        };
+        let latencies = self.watermarks.as_ref().map(|watermarks| {
+            (start_sample..=stop_sample)
+                .map(|s| watermarks[self.slot(s)])
+                .collect()
+        });
+        Snapshot {
+            start: start_sample,
+            samples,
+            latencies,
+        }
+    }
+
+    /// Copy the next block of a dump's window (or the end of it), if the ring holds it yet. Each call copies at most a
+    /// block, so a long window is copied over as many calls rather than all at once.
+    pub fn advance(&self, dump: &mut PendingDump) -> eyre::Result<Option<Snapshot>> {
+        let (Some(oldest), Some(newest)) = (self.oldest, self.newest()) else {
+            return Ok(None);
+        };
+        if dump.is_complete() {
+            return Ok(None);
+        }
+        if oldest > dump.next {
+            bail!("The ring moved on before a dump's window was copied, the rest of it is lost");
+        }
+        let block = (SLAB_SAMPLES as u64 / dump.decimation).max(1) * dump.decimation;
+        let until = (dump.next + block - 1).min(dump.stop);
+        if newest < until {
+            return Ok(None);
+        }
+        let snapshot = self.snapshot(dump.next, until);
+        dump.next = until + 1;
+        Ok(Some(snapshot))
+    }
+
+    /// Trim a requested window to what the ring holds. If it reaches past the newest sample, the rest is copied with
+    /// [`DumpRing::advance`] as it arrives.
+    #[tracing::instrument(level = "debug")]
+    pub fn trigger_dump(&self, request: &DumpRequest) -> eyre::Result<PendingDump> {
+        // As the ringbuffer will be in two segments, we need to deal with the possibility that the burst is across a ringbuffer boundary
+        let (Some(oldest), Some(newest)) = (self.oldest, self.newest()) else {
+            bail!("Tried to dump an empty ringbuffer")
+        };
+        let decimation = match request.resolution {
+            DumpResolution::Decimated(n) => n as u64,
+            _ => 1,
+        };
+        let mut begin_sample = request.start;
+        let end_sample = request.stop;
+
+        // Check if we totally missed the burst
+        if oldest > end_sample {
+            bail!("Ring buffer doesn't contain the requested sample, consider increasing the size of the buffer. The oldest sample in the buffer is {} and we wanted samples {}-{}", oldest, begin_sample, end_sample);
+        }
+        if newest < begin_sample {
+            bail!("Ring buffer doesn't contain the requested sample, but strangely we wanted a sample from the future, this shouldn't happen");
+        }
+
+        // At this point we know at least part of the burst is in the buffer, now we need to check if it is trimmed by the edges
+        if oldest > begin_sample {
+            warn!("The dump block we would write is being cut off at the beginning, consider increasing the size of the buffer");
+            begin_sample = oldest;
+        }
+        if newest < end_sample {
+            // Copy what we have before it can be overwritten, and the rest as it comes
+            info!(
+                waiting = end_sample - newest,
+                "Dump window reaches past the newest sample, copying the rest as it arrives"
+            );
+        }
+        Ok(PendingDump {
+            start: begin_sample,
+            stop: end_sample,
+            next: begin_sample,
+            resolution: request.resolution,
+            decimation,
+        })
+    }
+}
+
+/// How much to dump either side of a candidate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DumpWindow {
+    /// Payloads before the candidate
+    pub pre: u64,
+    /// Payloads from the candidate on (including its own)
+    pub post: u64,
+}
+
+impl DumpWindow {
+    /// A window of `ntime` payloads around the candidate. If it's even, it's biased one payload to the left.
+    pub fn centered(ntime: u64) -> Self {
+        let pre = ntime.saturating_sub(1) / 2;
+        Self {
+            pre,
+            post: ntime - pre,
+        }
+    }
+}
+
+/// The window one trigger (or several whose windows overlapped) asked to have dumped
+#[derive(Debug, Clone)]
+pub struct DumpRequest {
+    /// The candidate the dump is named after
+    pub event: CandidateEvent,
+    /// Every other candidate coalesced into this dump
+    pub merged: Vec<String>,
+    /// First and last (inclusive) payload counts of the window, before it's trimmed to the ring
+    pub start: u64,
+    pub stop: u64,
+    pub resolution: DumpResolution,
+    /// Where every candidate in the dump wants to hear how it went
+    pub callbacks: Vec<String>,
+}
+
+impl DumpRequest {
+    /// Work out the window to dump for a trigger, centered on the candidate, from a ring of `capacity` payloads. Fails if
+    /// the candidate's sample isn't one we could ever have (a nonsense specnum or offset).
+    pub fn new(
+        event: CandidateEvent,
+        downsample_factor: u32,
+        sample_offset: i64,
+        default_window: DumpWindow,
+        freq_plan: FrequencyPlan,
+        capacity: u64,
+    ) -> eyre::Result<Self> {
+        // Low-confidence candidates may have asked for less than everything
+        let resolution = match event.resolution.validate() {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{e}, dumping at full resolution instead");
+                DumpResolution::Full
+            }
+        };
+
+        // Wide or narrow candidates can ask for their own window, and scattered ones for more of it after the burst
+        let window = match (event.ntime, event.ntime_pre, event.ntime_post) {
+            (Some(ntime), None, None) if ntime > capacity => {
+                warn!(
+                    ntime,
+                    capacity,
+                    "Trigger asked for more than the ring holds, dumping as much as it can"
+                );
+                DumpWindow::centered(capacity)
+            }
+            (ntime, None, None) => ntime.map_or(default_window, DumpWindow::centered),
+            (_, pre, post) => DumpWindow {
+                pre: pre.unwrap_or(default_window.pre),
+                post: post.unwrap_or(default_window.post),
+            },
+        };
+        let ntime = window.pre.saturating_add(window.post);
+        let window = if ntime == 0 {
+            warn!("Can't dump an empty window, dumping the usual one instead");
+            default_window
+        } else if ntime > capacity {
+            warn!(
+                pre = window.pre,
+                post = window.post,
+                capacity,
+                "Trigger asked for more than the ring holds, dumping as much as it can"
+            );
+            // Keeping the candidate itself, and as much after it as we can
+            let post = window.post.clamp(1, capacity);
+            DumpWindow {
+                pre: window.pre.min(capacity - post),
+                post,
+            }
+        } else {
+            window
+        };
+        // Candidates are found at their arrival at the top of the band, so make room for the rest of the sweep (as
+        // much of it as the ring could ever hold)
+        let mut sweep = event.dm.map_or(0, |dm| dispersion_sweep(dm, freq_plan));
+        if sweep > capacity {
+            warn!(
+                dm = event.dm,
+                sweep, capacity, "Candidate's sweep is longer than the ring, dumping what it holds"
+            );
+            sweep = capacity;
+        }
+
+        // Goals: given tm.specnum, find the un-downsampled specnum in our block and write out a block centered at that point
+        // Specnum is which spectrum heimdall found the pulse in.
+        // So, the sample number of specnum 0 is the FIRST_PACKET that we processed and the sample number of specnum 1 is the downsample of samples FIRST_PACKET..=downsample_factor+FIRST_PACKET
+        // The constant and per-trigger offsets correct for any systematic skew in the specnums we're given
+        let sample_offset = if event.local { 0 } else { sample_offset };
+        let true_sample = event
+            .specnum
+            .checked_mul(downsample_factor as u64)
+            .and_then(|s| s.checked_add(FIRST_PACKET.load(Ordering::Acquire)))
+            .zip(sample_offset.checked_add(event.offset))
+            .map(|(s, offset)| s.saturating_add_signed(offset))
+            .ok_or_else(|| {
+                eyre!(
+                    "Candidate's sample is out of range (specnum {}, offset {})",
+                    event.specnum,
+                    event.offset
+                )
+            })?;
+
+        // Dispersed candidates get the window around both ends of the sweep
+        Ok(Self {
+            start: true_sample.saturating_sub(window.pre),
+            stop: true_sample
+                .saturating_add(sweep)
+                .saturating_add(window.post)
+                .saturating_sub(1)
+                .max(true_sample),
+            callbacks: event.callback.iter().cloned().collect(),
+            event,
+            merged: vec![],
+            resolution,
+        })
+    }
+
+    /// The status of this request's dump, before we know how it went
+    fn reply(&self) -> Reply {
+        Reply {
+            status: DumpStatus {
+                start: self.start,
+                stop: self.stop,
+                ..DumpStatus::new(&self.event, &self.merged)
+            },
+            callbacks: self.callbacks.clone(),
+        }
+    }
+
+    /// Whether the two windows share any payloads, or meet end to end
+    fn overlaps(&self, other: &Self) -> bool {
+        self.start <= other.stop.saturating_add(1) && other.start <= self.stop.saturating_add(1)
+    }
+
+    /// Widen this request to cover another's window as well, keeping everything at full resolution if they disagree
+    fn merge(&mut self, other: Self) {
+        self.start = self.start.min(other.start);
+        self.stop = self.stop.max(other.stop);
+        if self.resolution != other.resolution {
+            self.resolution = DumpResolution::Full;
+        }
+        self.merged.push(other.event.candname);
+        self.merged.extend(other.merged);
+        for callback in other.callbacks {
+            if !self.callbacks.contains(&callback) {
+                self.callbacks.push(callback);
+            }
+        }
+    }
+}
+
+/// What happened to a trigger handed to the [`DumpQueue`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    /// Waiting for its own dump
+    Alone,
+    /// Folded into the dump of an earlier trigger
+    Coalesced,
+    /// The queue was full
+    Dropped,
+}
+
+/// Triggers waiting for the dump in progress, with any whose windows overlap coalesced into one dump
+#[derive(Debug)]
+pub struct DumpQueue {
+    requests: VecDeque<DumpRequest>,
+    capacity: usize,
+    /// Longest window we'll merge requests into, as that's all the ring can hold
+    max_window: u64,
+}
+
+impl DumpQueue {
+    pub fn new(capacity: usize, max_window: u64) -> Self {
+        Self {
+            requests: VecDeque::with_capacity(capacity),
+            capacity,
+            max_window,
+        }
+    }
+
+    /// Whether two requests should share a dump, so long as it's no longer than we can hold
+    fn mergeable(&self, a: &DumpRequest, b: &DumpRequest) -> bool {
+        a.overlaps(b) && a.stop.max(b.stop) - a.start.min(b.start) < self.max_window
+    }
+
+    /// Queue a request, merging it with everything it overlaps (up to the longest window we'll dump)
+    pub fn push(&mut self, request: DumpRequest) -> Queued {
+        let Some(idx) = self
+            .requests
+            .iter()
+            .position(|r| self.mergeable(r, &request))
+        else {
+            if self.requests.len() >= self.capacity {
+                return Queued::Dropped;
+            }
+            self.requests.push_back(request);
+            return Queued::Alone;
+        };
+        let mut merged = self.requests.remove(idx).unwrap();
+        merged.merge(request);
+        // The wider window might now bridge the gap to later requests
+        while let Some(other) = self
+            .requests
+            .iter()
+            .position(|r| self.mergeable(r, &merged))
+        {
+            let other = self.requests.remove(other).unwrap();
+            merged.merge(other);
+        }
+        self.requests.insert(idx.min(self.requests.len()), merged);
+        Queued::Coalesced
+    }
+
+    /// The next dump to start
+    pub fn pop(&mut self) -> Option<DumpRequest> {
+        self.requests.pop_front()
+    }
+}
+
+/// Create the file for a dump of `start_sample` to `stop_sample` (inclusive), with everything but the samples themselves
+#[allow(clippy::too_many_arguments)]
+fn create_dump(
+    format: &dyn DumpFormat,
+    start_sample: u64,
+    stop_sample: u64,
+    dir: &Path,
+    name: &str,
+    event: &CandidateEvent,
+    merged: &[String],
+    resolution: DumpResolution,
+    decimation: u64,
+    freq_plan: FrequencyPlan,
+    watermarked: bool,
+    ring_bits: u8,
+    compression: Option<i32>,
+) -> eyre::Result<DumpFile> {
+    // The true dump size could have been modified by the caller to fit partial bursts into the window
+    let this_dump_size = stop_sample - start_sample + 1;
+    let path = dir.join(format!("{name}.{}", format.extension()));
+    let header = DumpHeader {
+        event,
+        merged,
+        start_sample,
+        samples: this_dump_size.div_ceil(decimation) as usize,
+        decimation,
+        // A requantized ring only held so much to begin with
+        nbits: match resolution {
+            DumpResolution::Reduced(bits) => bits,
+            _ => 8,
+        }
+        .min(ring_bits),
+        freq_plan,
+        watermarked,
+        compression,
+    };
+    let writer = format.create(&path, &header)?;
+    // A rendezvous, so the writer fills the next slab while the I/O thread writes the last
+    let (slabs, to_write) = std::sync::mpsc::sync_channel(0);
+    let (spent, spare) = std::sync::mpsc::channel();
+    let io = std::thread::Builder::new()
+        .name("dump_io".to_string())
+        .spawn(move || write_slabs(writer, to_write, spent))?;
+    Ok(DumpFile {
+        slabs,
+        spare,
+        io,
+        slab: Slab::new(0, watermarked),
+        watermarked,
+        path,
+        created: Instant::now(),
+        start: start_sample,
+        next: start_sample,
+        resolution,
+        decimation,
+        reply: Reply::default(),
+    })
+}
+
+/// Write the next samples of a dump. Unless they're the end of the dump, they should finish a decimated sample.
+fn write_snapshot(dump: &mut DumpFile, snapshot: &Snapshot) -> eyre::Result<()> {
+    if snapshot.start != dump.next {
+        bail!(
+            "Dump samples arrived out of order, expected {} but got {}",
+            dump.next,
+            snapshot.start
+        );
+    }
+    let len = snapshot.samples.len_of(Axis(0));
+    let out_len = (len as u64).div_ceil(dump.decimation) as usize;
+
+    let latencies: Option<Array1<i32>> = snapshot.latencies.as_ref().map(|latencies| {
+        latencies
+            .iter()
+            .step_by(dump.decimation as usize)
+            .copied()
+            .collect()
+    });
+
+    match dump.resolution {
+        DumpResolution::Full => {
+            // Straight out of the snapshot
+            dump.append(
+                snapshot.samples.view(),
+                latencies.as_ref().map(|l| l.view()),
+            )?;
+        }
+        resolution => {
+            // Transformed straight into the slab, a sample at a time, so we never hold the whole dump twice
+            let mut samples = snapshot.samples.outer_iter();
+            for i in 0..out_len {
+                dump.make_room()?;
+                let slab = &mut dump.slab;
+                let mut row = slab.voltages.index_axis_mut(Axis(0), slab.len);
+                transform(&mut row, &mut samples, resolution);
+                if let Some(slab_latencies) = &mut slab.latencies {
+                    slab_latencies[slab.len] = latencies.as_ref().map_or(NEVER_RECEIVED, |l| l[i]);
+                }
+                slab.len += 1;
+            }
+        }
+    }
+    dump.next += len as u64;
+    Ok(())
+}
+
+/// Payloads it takes a pulse at `dm` to sweep across the band, none for a DM that's nonsense
+fn dispersion_sweep(dm: f64, freq_plan: FrequencyPlan) -> u64 {
+    if !dm.is_finite() || dm <= 0.0 {
+        return 0;
+    }
+    let delay =
+        DISPERSION_CONSTANT * dm * (freq_plan.low_edge().powi(-2) - freq_plan.high_edge().powi(-2));
+    (delay / PACKET_CADENCE).ceil() as u64
+}
+
+/// Fill one time sample of a dump from the next sample(s) out of the ring, at the requested resolution
+fn transform<'a>(
+    row: &mut ArrayViewMut3<i8>,
+    samples: &mut impl Iterator<Item = ArrayView3<'a, i8>>,
+    resolution: DumpResolution,
+) {
+    match resolution {
+        DumpResolution::Full => {
+            if let Some(sample) = samples.next() {
+                row.assign(&sample);
+            }
+        }
+        DumpResolution::Decimated(n) => {
+            let mut acc = Array3::<i32>::zeros(row.raw_dim());
+            let mut taken = 0;
+            for sample in samples.take(n as usize) {
+                Zip::from(&mut acc)
+                    .and(&sample)
+                    .for_each(|a, &s| *a += i32::from(s));
+                taken += 1;
+            }
+            // The last one may be short, if the dump doesn't divide evenly
+            let taken = taken.max(1) as f32;
+            Zip::from(row)
+                .and(&acc)
+                .for_each(|r, &a| *r = (a as f32 / taken).round() as i8);
+        }
+        DumpResolution::Reduced(bits) => {
+            // Keep the top bits, at the original scale
+            let shift = 8 - u32::from(bits.clamp(1, 8));
+            if let Some(sample) = samples.next() {
+                Zip::from(row)
+                    .and(&sample)
+                    .for_each(|r, &s| *r = (s >> shift) << shift);
+            }
+        }
+    }
+}
+
+/// Dumps that can be waiting on the writer at once, each holding a copy of its window
+const MAX_QUEUED_DUMPS: usize = 2;
+/// Triggers (after coalescing) that can be waiting for their dump to start
+const MAX_QUEUED_TRIGGERS: usize = 16;
+
+/// Write dump files from the windows the fill loop copies out of the ring, so the ring never stops filling while they're
+/// written
+fn writer_loop(
+    requests: Receiver<WriterRequest>,
+    format: Arc<dyn DumpFormat>,
+    compression: Option<i32>,
+    queued: Arc<AtomicUsize>,
+    done: impl Fn(PathBuf, &DumpStatus, Duration),
+) {
+    // The dump being written, if it was created (and hasn't failed since)
+    let mut current: Option<DumpFile> = None;
+    let finish = |mut dump: DumpFile, error: Option<String>| {
+        let created = dump.created;
+        let mut reply = std::mem::take(&mut dump.reply);
+        reply.status.samples = dump.next - dump.start;
+        reply.status.truncated_end = dump.next <= reply.status.stop;
+        reply.status.error = error;
+        match dump.finish(&mut reply.status) {
+            Ok(file) => {
+                reply.status.path = Some(file.clone());
+                done(file, &reply.status, created.elapsed());
+            }
+            Err(e) => {
+                warn!("Error finishing dump: {}", e);
+                reply.status.error.get_or_insert(e.to_string());
+            }
+        }
+        reply.send();
+    };
+    for request in requests {
+        match request {
+            WriterRequest::Create {
+                dir,
+                name,
+                event,
+                merged,
+                start,
+                stop,
+                resolution,
+                decimation,
+                freq_plan,
+                watermarked,
+                ring_bits,
+                reply,
+            } => {
+                if let Some(dump) = current.take() {
+                    warn!("Starting a dump before the last one finished, keeping what was written");
+                    finish(dump, Some("Interrupted by the next dump".to_owned()));
+                }
+                match create_dump(
+                    format.as_ref(),
+                    start,
+                    stop,
+                    &dir,
+                    &name,
+                    &event,
+                    &merged,
+                    resolution,
+                    decimation,
+                    freq_plan,
+                    watermarked,
+                    ring_bits,
+                    compression,
+                ) {
+                    Ok(dump) => {
+                        current = Some(DumpFile {
+                            reply: *reply,
+                            ..dump
+                        })
+                    }
+                    Err(e) => {
+                        warn!("Error creating dump: {}", e);
+                        reply.fail(e).send();
+                    }
+                }
+            }
+            WriterRequest::Samples(snapshot) => {
+                if let Some(dump) = &mut current {
+                    if let Err(e) = write_snapshot(dump, &snapshot) {
+                        warn!("Error writing dump, keeping what was written: {}", e);
+                        finish(current.take().unwrap(), Some(e.to_string()));
+                    }
+                }
+            }
+            WriterRequest::Merge(merged) => match &mut current {
+                Some(dump) => {
+                    dump.reply.status.merged.push(merged.status.candname);
+                    dump.reply.callbacks.extend(merged.callbacks);
+                }
+                None => merged.fail("The dump it was coalesced into failed").send(),
+            },
+            WriterRequest::Finish => {
+                if let Some(dump) = current.take() {
+                    finish(dump, None);
+                }
+                queued.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+    }
+    if let Some(dump) = current.take() {
+        finish(dump, Some("The writer stopped partway through".to_owned()));
+    }
+}
+
+/// Copy whatever more of a pending dump the ring holds to the writer, returning whether it's all been copied
+fn copy_to_writer(
+    ring: &DumpRing,
+    dump: &mut PendingDump,
+    writer: &Sender<WriterRequest>,
+    spent_ns: &AtomicU64,
+) -> eyre::Result<bool> {
+    let copy_start = Instant::now();
+    let snapshot = ring.advance(dump);
+    spent_ns.fetch_add(copy_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    let complete = match snapshot {
+        Ok(Some(snapshot)) => {
+            writer.send(WriterRequest::Samples(snapshot))?;
+            dump.is_complete()
+        }
+        Ok(None) => false,
+        Err(e) => {
+            warn!("Error copying dump, keeping what was copied: {}", e);
+            true
+        }
+    };
+    if complete {
+        writer.send(WriterRequest::Finish)?;
+    }
+    Ok(complete)
+}
+
+/// Where and how to continuously record the ring
+#[derive(Debug, Clone)]
+pub struct RollingConfig {
+    pub path: PathBuf,
+    /// Payloads in each segment
+    pub segment_samples: u64,
+    /// Bytes of segments we keep on disk, deleting the oldest past it
+    pub budget_bytes: u64,
+}
+
+/// A rolling segment on disk, as every file sharing its name (the segment and any sidecars)
+#[derive(Debug, Default)]
+struct Segment {
+    files: Vec<PathBuf>,
+    /// Modification time of its newest file
+    modified: Option<std::time::SystemTime>,
+    bytes: u64,
+}
+
+/// Delete the oldest segments (and their sidecars) until we're within the budget
+fn expire_segments(config: &RollingConfig) -> eyre::Result<()> {
+    let mut segments: BTreeMap<String, Segment> = BTreeMap::new();
+    for entry in std::fs::read_dir(&config.path)? {
+        let entry = entry?;
+        let path = entry.path();
+        // A segment's sidecar and manifest are named after it, less its extension
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let name = name.strip_suffix(".sha256").unwrap_or(&name);
+        let Some(stem) = Path::new(name)
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+        else {
+            continue;
+        };
+        if !stem.starts_with(SEGMENT_PREFIX) {
+            continue;
+        }
+        let meta = entry.metadata()?;
+        let segment = segments.entry(stem).or_default();
+        segment.modified = segment.modified.max(Some(meta.modified()?));
+        segment.bytes += meta.len();
+        segment.files.push(path);
+    }
+    let mut segments: Vec<_> = segments.into_values().collect();
+    segments.sort_by_key(|segment| segment.modified);
+    let mut total: u64 = segments.iter().map(|segment| segment.bytes).sum();
+    for segment in segments {
+        if total <= config.budget_bytes {
+            break;
+        }
+        for path in &segment.files {
+            std::fs::remove_file(path)?;
+        }
+        total -= segment.bytes;
+        ring_stats()
+            .expired_segments
+            .fetch_add(1, Ordering::Relaxed);
+        info!(path = %segment.files[0].display(), "Deleted rolling segment to stay within the budget");
+    }
+    Ok(())
+}
+
+/// Records the ring back to back in fixed-length segments, through a writer of its own so triggered dumps aren't held up
+struct Rolling {
+    config: RollingConfig,
+    writer: Sender<WriterRequest>,
+    handle: std::thread::JoinHandle<()>,
+    /// Segments handed to the writer and not yet finished
+    queued: Arc<AtomicUsize>,
+    /// The segment being copied out of the ring
+    pending: Option<PendingDump>,
+    /// First payload count of the next segment, once we've started
+    next: Option<u64>,
+}
+
+impl Rolling {
+    fn start(
+        config: RollingConfig,
+        format: Arc<dyn DumpFormat>,
+        compression: Option<i32>,
+    ) -> eyre::Result<Self> {
+        std::fs::create_dir_all(&config.path)?;
+        info!(path = %config.path.display(), "Recording the voltage ring continuously");
+        let queued = Arc::new(AtomicUsize::new(0));
+        let (writer, requests) = std::sync::mpsc::channel();
+        let handle = std::thread::Builder::new()
+            .name("rolling_writer".to_string())
+            .spawn({
+                let (config, queued) = (config.clone(), queued.clone());
+                move || {
+                    writer_loop(requests, format, compression, queued, |file, _, _| {
+                        ring_stats().segments.fetch_add(1, Ordering::Relaxed);
+                        write_manifest(&file);
+                        if let Err(e) = expire_segments(&config) {
+                            warn!("Couldn't clear out old rolling segments - {e}");
+                        }
+                    })
+                }
+            })?;
+        Ok(Self {
+            config,
+            writer,
+            handle,
+            queued,
+            pending: None,
+            next: None,
+        })
+    }
+
+    /// Start the next segment if we can, and copy whatever more of the current one the ring holds
+    fn advance(&mut self, ring: &DumpRing, freq_plan: FrequencyPlan) -> eyre::Result<()> {
+        // If the ring started over behind us (the payload counts went backwards), so does the recording
+        if let (Some(segment), Some(newest)) = (&self.pending, ring.newest()) {
+            if segment.next > newest + 1 {
+                warn!("Voltage ring started over, ending the rolling segment early");
+                self.writer.send(WriterRequest::Finish)?;
+                self.pending = None;
+                self.next = None;
+            }
+        }
+        if self.pending.is_none() {
+            let Some(oldest) = ring.oldest else {
+                return Ok(());
+            };
+            // Every queued segment holds a copy of its window, so when the writer is behind we let the ring move on
+            if self.queued.load(Ordering::Relaxed) >= MAX_QUEUED_DUMPS {
+                return Ok(());
+            }
+            // Pick up where the last segment ended, unless that's already gone from the ring
+            let start = self.next.map_or(oldest, |next| next.max(oldest));
+            if let Some(next) = self.next.filter(|&next| next < start) {
+                warn!(
+                    missing = start - next,
+                    "Rolling recording fell behind the ring"
+                );
+                ring_stats()
+                    .rolling_gaps
+                    .fetch_add(start - next, Ordering::Relaxed);
+            }
+            let stop = start + self.config.segment_samples - 1;
+            let name = format!(
+                "{}-{}-{start}",
+                SEGMENT_PREFIX,
+                time_policy().filename_stamp(payload_time(start))
+            );
+            self.queued.fetch_add(1, Ordering::Relaxed);
+            self.writer.send(WriterRequest::Create {
+                dir: self.config.path.clone(),
+                event: Box::new(CandidateEvent {
+                    candname: name.clone(),
+                    ..Default::default()
+                }),
+                name,
+                merged: vec![],
+                start,
+                stop,
+                resolution: DumpResolution::Full,
+                decimation: 1,
+                freq_plan,
+                watermarked: ring.watermarks.is_some(),
+                ring_bits: ring.bits(),
+                reply: Box::default(),
+            })?;
+            self.pending = Some(PendingDump {
+                start,
+                stop,
+                next: start,
+                resolution: DumpResolution::Full,
+                decimation: 1,
+            });
+            self.next = Some(stop + 1);
+        }
+        if let Some(segment) = &mut self.pending {
+            if copy_to_writer(ring, segment, &self.writer, &ring_stats().rolling_copy_ns)? {
+                self.pending = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the segment we're in and wait for everything to be written
+    fn stop(self) -> eyre::Result<()> {
+        if self.pending.is_some() {
+            self.writer.send(WriterRequest::Finish)?;
+        }
+        drop(self.writer);
+        self.handle
+            .join()
+            .map_err(|_| eyre::eyre!("Rolling writer thread panicked"))
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn dump_task(
+    mut ring: DumpRing,
+    payload_reciever: blocking::Receiver<Payload>,
+    signal_receiver: Receiver<CandidateEvent>,
+    path: Option<PathBuf>,
+    downsample_power: u32,
+    sample_offset: i64,
+    window: DumpWindow,
+    format: Arc<dyn DumpFormat>,
+    compression: Option<i32>,
+    freq_plan: FrequencyPlan,
+    db: SyncSender<DbEvent>,
+    postprocess: Option<SyncSender<PathBuf>>,
+    rolling: Option<RollingConfig>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting voltage ringbuffer fill task!");
+    let profile = payload_profile("dump");
+    let trigger_latency = trigger_profile();
+    ring_stats()
+        .capacity
+        .store(ring.capacity as u64, Ordering::Relaxed);
+    let queued = Arc::new(AtomicUsize::new(0));
+    let (writer, requests) = std::sync::mpsc::channel();
+    let writer_handle = std::thread::Builder::new()
+        .name("dump_writer".to_string())
+        .spawn({
+            let (format, queued, db) = (format.clone(), queued.clone(), db.clone());
+            move || {
+                writer_loop(
+                    requests,
+                    format,
+                    compression,
+                    queued,
+                    |file, status, elapsed| hand_off(file, status, elapsed, &db, &postprocess),
+                )
+            }
+        })?;
+    let mut rolling = rolling
+        .map(|config| Rolling::start(config, format, compression))
+        .transpose()?;
+    // A dump still waiting on the end of its window, during which triggers wait their turn
+    let mut pending: Option<PendingDump> = None;
+    let mut triggers = DumpQueue::new(MAX_QUEUED_TRIGGERS, ring.capacity as u64);
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Dump task stopping");
+            break;
+        }
+        // Queue up every trigger that's come in, coalescing those that would dump the same samples
+        while let Ok(event) = signal_receiver.try_recv() {
+            let record = TriggerRecord {
+                mjd: hifitime::Epoch::now()
+                    .map(|e| time_policy().mjd(e))
+                    .unwrap_or_default(),
+                event: event.clone(),
+            };
+            if db.try_send(DbEvent::Trigger(record)).is_err() {
+                warn!(
+                    candname = event.candname,
+                    "Couldn't record trigger in the database"
+                );
+            }
+            // Dumps routed to null are dropped without disturbing the ring
+            if path.is_none() {
+                info!(
+                    candname = event.candname,
+                    "Dumps are routed to null, not dumping candidate"
+                );
+                Reply {
+                    status: DumpStatus::new(&event, &[]),
+                    callbacks: event.callback.iter().cloned().collect(),
+                }
+                .fail("Dumps are routed to null")
+                .send();
+                continue;
+            }
+            if let Some(received) = event.received {
+                trigger_latency.record(received.elapsed());
+            }
+            let candname = event.candname.clone();
+            let failed = Reply {
+                status: DumpStatus::new(&event, &[]),
+                callbacks: event.callback.iter().cloned().collect(),
+            };
+            let mut request = match DumpRequest::new(
+                event,
+                2u32.pow(downsample_power),
+                sample_offset,
+                window,
+                freq_plan,
+                ring.capacity as u64,
+            ) {
+                Ok(request) => request,
+                Err(e) => {
+                    warn!(candname, "Dropping trigger - {e}");
+                    ring_stats()
+                        .dropped_triggers
+                        .fetch_add(1, Ordering::Relaxed);
+                    failed.fail(e).send();
+                    continue;
+                }
+            };
+            // The dump still being copied can take it, or at least the part of it that's before its end
+            if let Some(dump) = &pending {
+                if dump.absorb(&mut request) {
+                    info!(candname, "Coalescing candidate into the dump in progress");
+                    ring_stats().coalesced.fetch_add(1, Ordering::Relaxed);
+                    writer.send(WriterRequest::Merge(Box::new(request.reply())))?;
+                    continue;
+                }
+            }
+            let reply = request.reply();
+            match triggers.push(request) {
+                Queued::Alone => {}
+                Queued::Coalesced => {
+                    info!(candname, "Coalescing candidate into an overlapping dump");
+                    ring_stats().coalesced.fetch_add(1, Ordering::Relaxed);
+                }
+                Queued::Dropped => {
+                    warn!(
+                        candname,
+                        "Too many triggers waiting on dumps, dropping candidate"
+                    );
+                    ring_stats()
+                        .dropped_triggers
+                        .fetch_add(1, Ordering::Relaxed);
+                    reply.fail("Too many triggers waiting on dumps").send();
+                }
+            }
+        }
+        // Then start the next dump, as that takes priority. Every queued dump holds a copy of its window, so we can only
+        // let so many pile up on the writer.
+        if pending.is_none() && queued.load(Ordering::Relaxed) < MAX_QUEUED_DUMPS {
+            if let (Some(path), Some(request)) = (&path, triggers.pop()) {
+                info!(
+                    candname = request.event.candname,
+                    trigger_id = request.event.trigger_id,
+                    merged = request.merged.len(),
+                    "Dumping candidate"
+                );
+                let mut reply = request.reply();
+                match ring.trigger_dump(&request) {
+                    Ok(mut dump) => {
+                        queued.fetch_add(1, Ordering::Relaxed);
+                        reply.status.truncated_start = dump.start > request.start;
+                        (reply.status.start, reply.status.stop) = (dump.start, dump.stop);
+                        writer.send(WriterRequest::Create {
+                            dir: path.clone(),
+                            name: dump_stem(&request.event.candname),
+                            event: Box::new(request.event),
+                            merged: request.merged,
+                            start: dump.start,
+                            stop: dump.stop,
+                            resolution: dump.resolution,
+                            decimation: dump.decimation,
+                            freq_plan,
+                            watermarked: ring.watermarks.is_some(),
+                            ring_bits: ring.bits(),
+                            reply: Box::new(reply),
+                        })?;
+                        // Copy what we have before it can be overwritten, and the rest as it comes
+                        if !copy_to_writer(&ring, &mut dump, &writer, &ring_stats().blocked_ns)? {
+                            pending = Some(dump);
+                        }
+                    }
+                    Err(e) => {
+                        warn!("Error in dumping buffer: {}", e);
+                        ring_stats().failed_triggers.fetch_add(1, Ordering::Relaxed);
+                        reply.fail(e).send();
+                    }
+                }
+                continue;
+            }
+        }
+        // Otherwise we're pushing data into the ringbuffer
+        match payload_reciever.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(pl) => {
+                let iter_start = Instant::now();
+                ring.push(&pl);
+                let stats = ring_stats();
+                stats.occupancy.store(ring.len() as u64, Ordering::Relaxed);
+                stats
+                    .oldest
+                    .store(ring.oldest.unwrap_or_default(), Ordering::Relaxed);
+                stats
+                    .newest
+                    .store(ring.newest().unwrap_or_default(), Ordering::Relaxed);
+                if let Some(dump) = &mut pending {
+                    if copy_to_writer(&ring, dump, &writer, &ring_stats().blocked_ns)? {
+                        pending = None;
+                    }
+                }
+                if let Some(rolling) = &mut rolling {
+                    rolling.advance(&ring, freq_plan)?;
+                }
+                profile.record(iter_start.elapsed());
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+    }
+    if pending.is_some() {
+        warn!("Stopping partway through a dump, the rest of its window is empty");
+        writer.send(WriterRequest::Finish)?;
+    }
+    if let Some(rolling) = rolling {
+        rolling.stop()?;
+    }
+    // Let the writer finish whatever it's been handed
+    drop(writer);
+    writer_handle
+        .join()
+        .map_err(|_| eyre::eyre!("Dump writer thread panicked"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dump_transforms() {
+        let ring = Array4::from_shape_fn((5, 2, 4, 2), |(t, _, _, _)| t as i8 * 10 - 7);
+        let mut row = Array3::<i8>::zeros((2, 4, 2));
+        let mut samples = ring.outer_iter();
+        transform(
+            &mut row.view_mut(),
+            &mut samples,
+            DumpResolution::Decimated(2),
+        );
+        assert!(row.iter().all(|&v| v == -2));
+        // The short block at the end takes what's left
+        transform(
+            &mut row.view_mut(),
+            &mut samples,
+            DumpResolution::Decimated(2),
+        );
+        transform(
+            &mut row.view_mut(),
+            &mut samples,
+            DumpResolution::Decimated(2),
+        );
+        assert!(row.iter().all(|&v| v == 33));
+        let mut samples = ring.outer_iter().skip(1);
+        transform(
+            &mut row.view_mut(),
+            &mut samples,
+            DumpResolution::Reduced(4),
+        );
+        // 3 (0b0000_0011) loses its low nibble
+        assert!(row.iter().all(|&v| v == 0));
+        // 23 (0b0001_0111) keeps its high nibble
+        let mut samples = ring.outer_iter().skip(3);
+        transform(
+            &mut row.view_mut(),
+            &mut samples,
+            DumpResolution::Reduced(4),
+        );
+        assert!(row.iter().all(|&v| v == 0b0001_0000));
+    }
+
+    #[test]
+    fn test_first_touch() {
+        let mut bytes = vec![0u8; 1001];
+        first_touch(&mut bytes, 3);
+        assert!(bytes.iter().all(|&b| b == 0xDE));
+        // A hugepage ring works like any other
+        let mut ring = DumpRing::hugepages(16, 8, 2).unwrap();
+        let pl = Payload::default();
+        ring.push(&pl);
+        assert_eq!(ring.len(), 1);
+    }
+
+    #[test]
+    fn test_advance() {
+        let mut ring = DumpRing::new(16).with_watermarks();
+        let mut pl = Payload::default();
+        for count in 0..10 {
+            pl.count = count;
+            pl.recv_latency = count as i32;
+            ring.push(&pl);
+        }
+        let mut dump = PendingDump {
+            start: 2,
+            stop: 12,
+            next: 2,
+            resolution: DumpResolution::Full,
+            decimation: 1,
+        };
+        // Less than a block, and not the end of the window
+        assert!(ring.advance(&mut dump).unwrap().is_none());
+        for count in 10..13 {
+            pl.count = count;
+            pl.recv_latency = count as i32;
+            ring.push(&pl);
+        }
+        let snapshot = ring.advance(&mut dump).unwrap().unwrap();
+        assert!(dump.is_complete());
+        assert_eq!(snapshot.start, 2);
+        assert_eq!(snapshot.samples.len_of(Axis(0)), 11);
+        assert_eq!(snapshot.latencies.unwrap(), (2..=12).collect::<Vec<_>>());
+        // The ring has moved on past the start of this one
+        for count in 13..30 {
+            pl.count = count;
+            ring.push(&pl);
+        }
+        let mut dump = PendingDump { next: 2, ..dump };
+        assert!(ring.advance(&mut dump).is_err());
+    }
+
+    #[test]
+    fn test_advance_blocks() {
+        let mut ring = DumpRing::new(3 * SLAB_SAMPLES);
+        let mut pl = Payload::default();
+        for count in 0..3 * SLAB_SAMPLES as u64 {
+            pl.count = count;
+            ring.push(&pl);
+        }
+        let stop = 5 * SLAB_SAMPLES as u64 / 2;
+        let mut dump = PendingDump {
+            start: 0,
+            stop,
+            next: 0,
+            resolution: DumpResolution::Full,
+            decimation: 1,
+        };
+        // Everything's in the ring, but it comes out a block at a time
+        let starts: Vec<_> = std::iter::from_fn(|| ring.advance(&mut dump).unwrap())
+            .map(|snapshot| (snapshot.start, snapshot.samples.len_of(Axis(0))))
+            .collect();
+        assert_eq!(
+            starts,
+            vec![
+                (0, SLAB_SAMPLES),
+                (SLAB_SAMPLES as u64, SLAB_SAMPLES),
+                (2 * SLAB_SAMPLES as u64, SLAB_SAMPLES / 2 + 1)
+            ]
+        );
+        assert!(dump.is_complete());
+    }
+
+    #[test]
+    fn test_range_views() {
+        use crate::common::Channel;
+        // Wrapped, so the oldest 6 samples are at the end of the buffer and the newest 10 at the start
+        let mut ring = DumpRing::new(16);
+        let mut pl = Payload::default();
+        for count in 0..26 {
+            pl.count = count;
+            pl.pol_a = [Channel::new(count as i8, 0); CHANNELS];
+            ring.push(&pl);
+        }
+        // Within either chunk, and across the split between them
+        for (start, stop) in [(11, 14), (17, 25), (12, 20), (10, 25)] {
+            let snapshot = ring.snapshot(start, stop);
+            let got: Vec<_> = snapshot
+                .samples
+                .outer_iter()
+                .map(|s| s[[0, 0, 0]] as u64)
+                .collect();
+            assert_eq!(got, (start..=stop).collect::<Vec<_>>(), "{start}..={stop}");
+        }
+    }
+
+    #[test]
+    fn test_slab_writing() {
+        crate::testing::seed_start_time();
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        // Spilling into a second slab, in pieces that don't line up with them
+        let samples = SLAB_SAMPLES as u64 + 3;
+        let mut dump = create_dump(
+            &format::Raw,
+            100,
+            100 + samples - 1,
+            dir,
+            "grex_dump-slabs",
+            &CandidateEvent::default(),
+            &[],
+            DumpResolution::Full,
+            1,
+            FrequencyPlan::default(),
+            true,
+            8,
+            None,
+        )
+        .unwrap();
+        let mut written = 0;
+        for len in [1000, 1000, samples - 2000] {
+            let snapshot = Snapshot {
+                start: 100 + written,
+                samples: Array4::from_shape_fn((len as usize, 2, CHANNELS, 2), |(t, _, _, _)| {
+                    ((written + t as u64) % 7) as i8
+                }),
+                latencies: Some((0..len).map(|t| (written + t) as i32).collect()),
+            };
+            write_snapshot(&mut dump, &snapshot).unwrap();
+            written += len;
+        }
+        let mut status = DumpStatus::default();
+        let path = dump.finish(&mut status).unwrap();
+        assert_eq!(status.error, None);
+        let bytes = std::fs::read(&path).unwrap();
+        let row = 2 * CHANNELS * 2;
+        assert_eq!(bytes.len() as u64, samples * row as u64);
+        assert!(bytes
+            .chunks_exact(row)
+            .enumerate()
+            .all(|(t, r)| r.iter().all(|&v| v as usize == t % 7)));
+        let sidecar: serde_json::Value =
+            serde_json::from_reader(std::fs::File::open(path.with_extension("json")).unwrap())
+                .unwrap();
+        assert_eq!(
+            sidecar["recv_latency_us"][SLAB_SAMPLES + 2],
+            SLAB_SAMPLES + 2
+        );
+        let mut crc = crate::exfil::checksum::Crc32c::default();
+        crc.update(&bytes);
+        assert_eq!(sidecar["crc32c"], crc.value());
+    }
+
+    #[test]
+    fn test_streaming_dump() {
+        use crate::common::Channel;
+        crate::testing::seed_start_time();
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        let (payload_s, payload_r) = blocking::channel(64);
+        let (trig_s, trig_r) = std::sync::mpsc::sync_channel(1);
+        let (db_s, db_r) = std::sync::mpsc::sync_channel(16);
+        let (sd_s, sd_r) = broadcast::channel(1);
+        let task = std::thread::spawn({
+            let dir = dir.to_path_buf();
+            move || {
+                dump_task(
+                    DumpRing::new(64),
+                    payload_r,
+                    trig_r,
+                    Some(dir),
+                    0,
+                    0,
+                    DumpWindow { pre: 5, post: 30 },
+                    format::DumpFileFormat::Raw.format(),
+                    None,
+                    FrequencyPlan::default(),
+                    db_s,
+                    None,
+                    None,
+                    sd_r,
+                )
+            }
+        });
+        let payload = |count: u64| {
+            let mut pl = Payload {
+                count,
+                ..Default::default()
+            };
+            pl.pol_a[0] = Channel::new(count as i8, 0);
+            pl
+        };
+        for count in 0..20 {
+            payload_s.send(payload(count)).unwrap();
+        }
+        while ring_stats().newest.load(Ordering::Relaxed) < 19 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        // Centered on 10, so the window (5 to 39) runs past what the ring holds
+        trig_s
+            .send(CandidateEvent {
+                candname: "streaming".to_owned(),
+                offset: 10,
+                ..Default::default()
+            })
+            .unwrap();
+        // The task only looks for triggers between payloads, and the rest of the window is appended as it arrives
+        for count in 20..60 {
+            payload_s.send(payload(count)).unwrap();
+        }
+        assert!(matches!(db_r.recv().unwrap(), DbEvent::Trigger(_)));
+        while ring_stats().newest.load(Ordering::Relaxed) < 59 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        sd_s.send(()).unwrap();
+        payload_s.send(payload(60)).unwrap();
+        task.join().unwrap().unwrap();
+        let DbEvent::Dump(record) = db_r.recv().unwrap() else {
+            panic!("Expected the dump's record")
+        };
+        assert_eq!((record.start_sample, record.stop_sample), (5, 39));
+        assert!(!record.truncated_end);
+        let bytes = std::fs::read(&record.path).unwrap();
+        let row = 2 * CHANNELS * 2;
+        let firsts: Vec<_> = bytes.chunks_exact(row).map(|r| r[0] as u64).collect();
+        assert_eq!(firsts, (5..=39).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_dump_window() {
+        let mut ring = DumpRing::new(64);
+        let mut pl = Payload::default();
+        for count in 0..40 {
+            pl.count = count;
+            ring.push(&pl);
+        }
+        let event = CandidateEvent {
+            candname: "window".to_owned(),
+            offset: 20,
+            ..Default::default()
+        };
+        // Even windows are biased one sample to the left
+        let dump = ring
+            .trigger_dump(
+                &DumpRequest::new(
+                    event.clone(),
+                    1,
+                    0,
+                    DumpWindow::centered(8),
+                    FrequencyPlan::default(),
+                    64,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!((dump.start, dump.stop), (17, 24));
+        // The trigger's own window wins
+        let event = CandidateEvent {
+            ntime: Some(5),
+            ..event
+        };
+        let dump = ring
+            .trigger_dump(
+                &DumpRequest::new(
+                    event.clone(),
+                    1,
+                    0,
+                    DumpWindow::centered(8),
+                    FrequencyPlan::default(),
+                    64,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!((dump.start, dump.stop), (18, 22));
+        // Anything the ring can't hold is cut down to what it can, which is copied as it arrives
+        let event = CandidateEvent {
+            ntime: Some(100),
+            ..event
+        };
+        let dump = ring
+            .trigger_dump(
+                &DumpRequest::new(
+                    event.clone(),
+                    1,
+                    0,
+                    DumpWindow::centered(8),
+                    FrequencyPlan::default(),
+                    64,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!((dump.start, dump.stop), (0, 52));
+        // A dispersed candidate gets the whole sweep, a tiny DM sweeping a payload or so across our band
+        let sweep = dispersion_sweep(0.0005, FrequencyPlan::default());
+        assert!((1..10).contains(&sweep));
+        let event = CandidateEvent {
+            ntime: Some(5),
+            dm: Some(0.0005),
+            ..event
+        };
+        let dump = ring
+            .trigger_dump(
+                &DumpRequest::new(
+                    event.clone(),
+                    1,
+                    0,
+                    DumpWindow::centered(8),
+                    FrequencyPlan::default(),
+                    64,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!((dump.start, dump.stop), (18, 22 + sweep));
+        // Scattering tails get more after the burst than before it
+        let event = CandidateEvent {
+            ntime_pre: Some(2),
+            ntime_post: Some(6),
+            dm: None,
+            ..event
+        };
+        let dump = ring
+            .trigger_dump(
+                &DumpRequest::new(
+                    event.clone(),
+                    1,
+                    0,
+                    DumpWindow::centered(8),
+                    FrequencyPlan::default(),
+                    64,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        assert_eq!((dump.start, dump.stop), (18, 25));
+        // Or keep the configured share of one side
+        let event = CandidateEvent {
+            ntime_pre: None,
+            ..event
+        };
+        let window = DumpWindow { pre: 1, post: 3 };
+        let request =
+            DumpRequest::new(event.clone(), 1, 0, window, FrequencyPlan::default(), 64).unwrap();
+        assert_eq!((request.start, request.stop), (19, 25));
+        // Only T2's candidates take the configured offset, ours are already where they are
+        let shifted = |local| {
+            let event = CandidateEvent {
+                local,
+                ..event.clone()
+            };
+            DumpRequest::new(event, 1, 5, window, FrequencyPlan::default(), 64)
+                .unwrap()
+                .start
+        };
+        assert_eq!((shifted(false), shifted(true)), (24, 19));
+    }
+
+    #[test]
+    fn test_request_overflow() {
+        let window = DumpWindow::centered(8);
+        let request = |specnum, offset, sample_offset| {
+            let event = CandidateEvent {
+                candname: "overflow".to_owned(),
+                specnum,
+                offset,
+                ..Default::default()
+            };
+            DumpRequest::new(
+                event,
+                512,
+                sample_offset,
+                window,
+                FrequencyPlan::default(),
+                64,
+            )
+        };
+        // Nonsense specnums and offsets are dropped rather than panicking
+        assert!(request(u64::MAX / 2, 0, 0).is_err());
+        // As are windows longer than the ring, on either side
+        let lopsided = |pre, post| {
+            let event = CandidateEvent {
+                candname: "lopsided".to_owned(),
+                specnum: 1000,
+                ntime_pre: pre,
+                ntime_post: post,
+                ..Default::default()
+            };
+            let request =
+                DumpRequest::new(event, 1, 0, window, FrequencyPlan::default(), 64).unwrap();
+            (request.start, request.stop)
+        };
+        assert_eq!(lopsided(Some(u64::MAX), Some(u64::MAX)), (1000, 1063));
+        assert_eq!(lopsided(Some(u64::MAX), None), (1000 - 59, 1004));
+        assert_eq!(lopsided(Some(0), Some(u64::MAX)), (1000, 1063));
+        assert_eq!(lopsided(Some(0), Some(0)), (997, 1004));
+        // Decimating by more than a slab would have us copy the whole window at once
+        let decimated = |n| {
+            let event = CandidateEvent {
+                candname: "decimated".to_owned(),
+                specnum: 1000,
+                resolution: DumpResolution::Decimated(n),
+                ..Default::default()
+            };
+            DumpRequest::new(event, 1, 0, window, FrequencyPlan::default(), 64)
+                .unwrap()
+                .resolution
+        };
+        let most = SLAB_SAMPLES as u32;
+        assert_eq!(decimated(most), DumpResolution::Decimated(most));
+        assert_eq!(decimated(most + 1), DumpResolution::Full);
+        assert_eq!(decimated(u32::MAX), DumpResolution::Full);
+        assert!(request(0, i64::MIN, -1).is_err());
+        // Candidates shifted to before the start of the observation start there
+        assert_eq!(request(0, -100, 0).unwrap().start, 0);
+        // Nor does a nonsense DM or a sweep longer than the ring
+        let swept = |dm| {
+            let event = CandidateEvent {
+                candname: "swept".to_owned(),
+                specnum: u64::MAX / 1024,
+                dm: Some(dm),
+                ..Default::default()
+            };
+            DumpRequest::new(event, 512, 0, window, FrequencyPlan::default(), 64).unwrap()
+        };
+        assert_eq!(dispersion_sweep(f64::INFINITY, FrequencyPlan::default()), 0);
+        let request = swept(1e6);
+        assert_eq!(request.stop - request.start + 1, 4 + 64 + 4);
+        let request = swept(f64::MAX);
+        assert_eq!(request.stop - request.start + 1, 4 + 64 + 4);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let request = |candname: &str, start, stop| DumpRequest {
+            event: CandidateEvent {
+                candname: candname.to_owned(),
+                ..Default::default()
+            },
+            merged: vec![],
+            start,
+            stop,
+            resolution: DumpResolution::Full,
+            callbacks: vec![],
+        };
+        let mut queue = DumpQueue::new(2, 64);
+        assert_eq!(queue.push(request("a", 0, 9)), Queued::Alone);
+        assert_eq!(queue.push(request("b", 20, 29)), Queued::Alone);
+        // Back to back windows share a dump
+        assert_eq!(queue.push(request("c", 10, 14)), Queued::Coalesced);
+        assert_eq!(queue.push(request("d", 40, 49)), Queued::Dropped);
+        // Bridging the gap folds everything into one
+        assert_eq!(queue.push(request("e", 12, 22)), Queued::Coalesced);
+        let dump = queue.pop().unwrap();
+        assert_eq!(dump.event.candname, "a");
+        assert_eq!((dump.start, dump.stop), (0, 29));
+        assert_eq!(dump.merged, vec!["c", "e", "b"]);
+        assert!(queue.pop().is_none());
+        // Disagreeing resolutions are dumped at full resolution
+        assert_eq!(
+            queue.push(DumpRequest {
+                resolution: DumpResolution::Decimated(4),
+                ..request("f", 0, 9)
+            }),
+            Queued::Alone
+        );
+        assert_eq!(queue.push(request("g", 5, 15)), Queued::Coalesced);
+        assert_eq!(queue.pop().unwrap().resolution, DumpResolution::Full);
+        // Nothing's merged into a window longer than the ring
+        assert_eq!(queue.push(request("h", 0, 39)), Queued::Alone);
+        assert_eq!(queue.push(request("i", 30, 69)), Queued::Alone);
+        assert_eq!(queue.pop().unwrap().stop, 39);
+        // And the dump in progress takes what it can
+        let dump = PendingDump {
+            start: 100,
+            stop: 120,
+            next: 110,
+            resolution: DumpResolution::Full,
+            decimation: 1,
+        };
+        assert!(dump.absorb(&mut request("j", 105, 115)));
+        let mut later = request("k", 110, 130);
+        assert!(!dump.absorb(&mut later));
+        assert_eq!((later.start, later.stop), (121, 130));
+        let mut earlier = request("l", 90, 110);
+        assert!(!dump.absorb(&mut earlier));
+        assert_eq!((earlier.start, earlier.stop), (90, 110));
+    }
+
+    #[test]
+    fn test_rolling() {
+        crate::testing::seed_start_time();
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        let config = RollingConfig {
+            path: dir.to_path_buf(),
+            segment_samples: 8,
+            budget_bytes: u64::MAX,
+        };
+        let mut rolling =
+            Rolling::start(config, format::DumpFileFormat::Raw.format(), None).unwrap();
+        let mut ring = DumpRing::new(64);
+        let mut pl = Payload::default();
+        for count in 0..20 {
+            pl.count = count;
+            ring.push(&pl);
+            // Give the writer time to catch up, so no segment's skipped for it
+            while rolling.queued.load(Ordering::Relaxed) >= MAX_QUEUED_DUMPS {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            rolling.advance(&ring, FrequencyPlan::default()).unwrap();
+        }
+        // Two whole segments, and the start of a third
+        assert_eq!(rolling.next, Some(24));
+        rolling.stop().unwrap();
+        let segments = || {
+            std::fs::read_dir(dir)
+                .unwrap()
+                .filter(|e| e.as_ref().unwrap().path().extension().unwrap() == "raw")
+                .count()
+        };
+        assert_eq!(segments(), 3);
+        // Every segment has its manifest
+        for segment in 0..3u64 {
+            let name = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+                .find(|n| n.ends_with(&format!("-{}.raw", segment * 8)))
+                .unwrap();
+            assert!(dir.join(format!("{name}.sha256")).exists());
+        }
+        // Expiring takes the oldest segment along with its sidecar and manifest, counted once
+        let bytes: u64 = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().metadata().unwrap().len())
+            .sum();
+        let expired = ring_stats().expired_segments.load(Ordering::Relaxed);
+        let config = RollingConfig {
+            path: dir.to_path_buf(),
+            segment_samples: 8,
+            budget_bytes: bytes - 1,
+        };
+        expire_segments(&config).unwrap();
+        assert_eq!(segments(), 2);
+        assert_eq!(std::fs::read_dir(dir).unwrap().count(), 6);
+        assert_eq!(
+            ring_stats().expired_segments.load(Ordering::Relaxed),
+            expired + 1
+        );
+    }
+
+    #[test]
+    fn test_packed_ring() {
+        use crate::common::Channel;
+        for bits in [2, 4] {
+            let mut ring = DumpRing::heap(8, bits, 1);
+            let mut pl = Payload::default();
+            for count in 0..10 {
+                pl.count = count;
+                // Opposite signs on each part so there's power, and louder in the second polarization
+                pl.pol_a = [Channel::new(10, -10); CHANNELS];
+                pl.pol_b = [Channel::new(40, -40); CHANNELS];
+                ring.push(&pl);
+            }
+            assert_eq!(ring.bits(), bits);
+            let snapshot = ring.snapshot(4, 9);
+            assert_eq!(snapshot.samples.dim(), (6, 2, CHANNELS, 2));
+            // Constant magnitudes come back close to where they were, at each polarization's scale
+            for (pol, amp) in [(0, 10.0), (1, 40.0)] {
+                let got = f32::from(snapshot.samples[[0, pol, 0, 0]]);
+                assert!(
+                    (got - amp).abs() <= amp * 0.6,
+                    "{bits} bits: {got} for {amp}"
+                );
+                assert_eq!(
+                    snapshot.samples[[5, pol, 7, 1]],
+                    -snapshot.samples[[5, pol, 7, 0]]
+                );
+            }
+            // The block's scale was set by its first payload, so louder ones later on clip
+            pl.count = 10;
+            pl.pol_a = [Channel::new(100, -100); CHANNELS];
+            ring.push(&pl);
+            let snapshot = ring.snapshot(9, 10);
+            let (before, louder) = (
+                snapshot.samples[[0, 0, 0, 0]],
+                snapshot.samples[[1, 0, 0, 0]],
+            );
+            assert!(
+                louder >= before && louder < 50,
+                "{bits} bits: {louder} for 100"
+            );
+            // And the next block gets a scale of its own
+            let mut ring = DumpRing::heap(8, bits, 1);
+            pl.count = SCALE_BLOCK - 1;
+            pl.pol_a = [Channel::new(10, -10); CHANNELS];
+            ring.push(&pl);
+            pl.count = SCALE_BLOCK;
+            pl.pol_a = [Channel::new(100, -100); CHANNELS];
+            ring.push(&pl);
+            let snapshot = ring.snapshot(SCALE_BLOCK, SCALE_BLOCK);
+            let got = f32::from(snapshot.samples[[0, 0, 0, 0]]);
+            assert!((got - 100.0).abs() <= 60.0, "{bits} bits: {got} for 100");
+        }
+    }
+}
@@ -0,0 +1,254 @@
+//! Telling whoever asked for a dump how it went, so T2/T3 can tie voltage files to their candidates
+use crate::common::CandidateEvent;
+use crate::triggers::PROTOCOL_VERSION;
+use eyre::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    net::UdpSocket,
+    path::PathBuf,
+    sync::{
+        mpsc::{sync_channel, SyncSender, TrySendError},
+        OnceLock,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+/// How long we'll wait on an HTTP callback before giving up on it
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Statuses that can be waiting on delivery, past which we drop them rather than hold up dumping
+const MAX_QUEUED_REPLIES: usize = 64;
+
+static CALLBACK_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Set the hosts unauthenticated triggers may ask us to send dump statuses to
+pub fn set_callback_hosts(hosts: Vec<String>) {
+    let _ = CALLBACK_HOSTS.set(hosts);
+}
+
+/// The host a `udp://host:port` or `http://host[:port]/path` callback points at
+fn callback_host(callback: &str) -> Option<&str> {
+    let rest = callback
+        .strip_prefix("udp://")
+        .or_else(|| callback.strip_prefix("http://"))?;
+    // URL parsers end the authority at any of these (backslashes count as slashes in http URLs)
+    let authority = &rest[..rest.find(['/', '\\', '?', '#']).unwrap_or(rest.len())];
+    // Userinfo would have us vet one host and send to another
+    if authority.contains('@') {
+        return None;
+    }
+    match authority.strip_prefix('[') {
+        // IPv6 literals are bracketed, with the port after
+        Some(v6) => v6.split(']').next(),
+        None => authority.split(':').next(),
+    }
+}
+
+/// Whether we'll send a dump status to `callback`. We'd otherwise send requests anywhere anyone on the trigger
+/// network liked, so that's only for triggers signed with the shared secret, or to the hosts we're told are fine.
+pub fn callback_allowed(callback: &str, authenticated: bool) -> bool {
+    let Some(host) = callback_host(callback) else {
+        return false;
+    };
+    authenticated
+        || CALLBACK_HOSTS
+            .get()
+            .is_some_and(|hosts| hosts.iter().any(|h| h == host))
+}
+
+/// The one thread delivering statuses, in the order they're sent
+fn replies() -> &'static SyncSender<(String, Vec<u8>)> {
+    static REPLIES: OnceLock<SyncSender<(String, Vec<u8>)>> = OnceLock::new();
+    REPLIES.get_or_init(|| {
+        let (sender, receiver) = sync_channel::<(String, Vec<u8>)>(MAX_QUEUED_REPLIES);
+        let spawned = std::thread::Builder::new()
+            .name("dump_reply".to_string())
+            .spawn(move || {
+                let agent = ureq::AgentBuilder::new().timeout(CALLBACK_TIMEOUT).build();
+                for (callback, body) in receiver {
+                    if let Err(e) = deliver(&agent, &callback, &body) {
+                        warn!(callback, "Couldn't report dump status - {e}");
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            warn!("Couldn't start dump status replies - {e}");
+        }
+        sender
+    })
+}
+
+/// What became of a trigger's dump
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DumpStatus {
+    pub version: u32,
+    /// The candidate the dump is named after
+    pub candname: String,
+    pub trigger_id: u64,
+    /// MJD (TAI) of the candidate, if the trigger had one
+    pub mjd: Option<f64>,
+    /// Other candidates coalesced into the same dump
+    pub merged: Vec<String>,
+    /// The dump file, if we wrote one
+    pub path: Option<PathBuf>,
+    /// First and last (inclusive) payload counts of the window we dumped
+    pub start: u64,
+    pub stop: u64,
+    /// Payloads of the window that made it into the file (before any decimation)
+    pub samples: u64,
+    /// The start of the requested window had already left the ring
+    pub truncated_start: bool,
+    /// The file stops short of the end of the window
+    pub truncated_end: bool,
+    /// Why the dump failed (or was cut short)
+    pub error: Option<String>,
+}
+
+impl DumpStatus {
+    pub fn new(event: &CandidateEvent, merged: &[String]) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            candname: event.candname.clone(),
+            trigger_id: event.trigger_id,
+            mjd: event.mjd,
+            merged: merged.to_vec(),
+            ..Default::default()
+        }
+    }
+}
+
+/// A dump's status, and every callback waiting to hear it
+#[derive(Debug, Default)]
+pub struct Reply {
+    pub status: DumpStatus,
+    pub callbacks: Vec<String>,
+}
+
+impl Reply {
+    /// Report a dump that never happened
+    pub fn fail(mut self, error: impl ToString) -> Self {
+        self.status.error = Some(error.to_string());
+        self
+    }
+
+    /// Queue the status for every callback, delivered from a thread of its own so a slow listener can't hold up dumping
+    pub fn send(self) {
+        if self.callbacks.is_empty() {
+            return;
+        }
+        let body = match serde_json::to_vec(&self.status) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!("Couldn't serialize dump status - {e}");
+                return;
+            }
+        };
+        for callback in self.callbacks {
+            match replies().try_send((callback, body.clone())) {
+                Ok(()) => (),
+                Err(TrySendError::Full((callback, _))) => {
+                    warn!(callback, "Too many dump statuses waiting, dropping one");
+                }
+                Err(TrySendError::Disconnected((callback, _))) => {
+                    warn!(
+                        callback,
+                        "Nothing is delivering dump statuses, dropping one"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Send a status to a `udp://host:port` or `http://host[:port]/path` callback
+fn deliver(agent: &ureq::Agent, callback: &str, body: &[u8]) -> eyre::Result<()> {
+    if let Some(addr) = callback.strip_prefix("udp://") {
+        let sock = UdpSocket::bind(("0.0.0.0", 0))?;
+        sock.send_to(body, addr)?;
+        return Ok(());
+    }
+    if !callback.starts_with("http://") {
+        bail!("Callbacks must be udp://host:port or http://host[:port]/path");
+    }
+    // Anything but a 2xx comes back as an error
+    agent
+        .post(callback)
+        .set("Content-Type", "application/json")
+        .send_bytes(body)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    #[test]
+    fn test_deliver() {
+        let status = DumpStatus {
+            candname: "cand".to_owned(),
+            path: Some("/dumps/grex_dump-cand.nc".into()),
+            samples: 10,
+            truncated_start: true,
+            ..DumpStatus::new(&CandidateEvent::default(), &[])
+        };
+        let body = serde_json::to_vec(&status).unwrap();
+
+        let agent = ureq::agent();
+        let sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        deliver(
+            &agent,
+            &format!("udp://{}", sock.local_addr().unwrap()),
+            &body,
+        )
+        .unwrap();
+        let mut buf = [0; 1024];
+        let n = sock.recv(&mut buf).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<DumpStatus>(&buf[..n]).unwrap(),
+            status
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dumps", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with('}') {
+                let n = stream.read(&mut buf).unwrap();
+                request.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            request
+        });
+        deliver(&agent, &url, &body).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /dumps HTTP/1.1"));
+        assert!(request.contains("\"candname\":\"cand\""));
+
+        assert!(deliver(&agent, "ftp://nowhere", &body).is_err());
+    }
+
+    #[test]
+    fn test_callback_allowed() {
+        set_callback_hosts(vec!["t2".to_owned(), "::1".to_owned()]);
+        assert!(callback_allowed("udp://t2:5000", false));
+        assert!(callback_allowed("http://t2/dumps", false));
+        assert!(callback_allowed("http://[::1]:8080/dumps", false));
+        assert!(!callback_allowed("http://169.254.169.254/latest", false));
+        assert!(!callback_allowed("http://t2.evil.example/dumps", false));
+        assert!(!callback_allowed("http://t2:80@evil.example/x", false));
+        assert!(!callback_allowed("udp://t2@evil.example:5000", false));
+        assert!(!callback_allowed("http://t2:80@evil.example/x", true));
+        assert!(callback_allowed("http://t2?from=evil.example", false));
+        // Signed triggers can send their statuses anywhere they like, as long as it's somewhere we can send to
+        assert!(callback_allowed("http://t3:8080/dumps", true));
+        assert!(!callback_allowed("ftp://t2", true));
+    }
+}
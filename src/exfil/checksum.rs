@@ -0,0 +1,138 @@
+//! CRC32C (Castagnoli) over the exfil stream, recorded per committed block or chunk of a file,
+//! so corruption from flaky RAM or exfil paths can be caught when the data are read back
+use crate::naming::time_policy;
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+
+/// A CRC32C we can keep feeding bytes into
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Crc32c {
+    state: u32,
+}
+
+impl Crc32c {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.state = crc32c::crc32c_append(self.state, bytes);
+    }
+
+    /// The CRC of everything so far
+    pub fn value(&self) -> u32 {
+        self.state
+    }
+}
+
+/// The checksum of one block (or chunk) of an exfil sink's output
+#[derive(Debug, Clone, Serialize)]
+pub struct ChecksumRecord {
+    pub sink: String,
+    /// What the sink was writing to (a file, or a DADA key)
+    pub target: String,
+    /// Block number within the target
+    pub block: u64,
+    /// Bytes into the target the block starts at
+    pub offset: u64,
+    pub bytes: u64,
+    pub crc32c: u32,
+    /// CRC of everything written to the target up to the end of this block
+    pub running_crc32c: u32,
+    pub mjd: f64,
+}
+
+/// Checksums that haven't made it into the database yet
+fn pending() -> &'static Mutex<Vec<ChecksumRecord>> {
+    static PENDING: OnceLock<Mutex<Vec<ChecksumRecord>>> = OnceLock::new();
+    PENDING.get_or_init(|| Mutex::new(vec![]))
+}
+
+/// Queue a checksum for the database
+pub fn record(record: ChecksumRecord) {
+    pending().lock().unwrap().push(record);
+}
+
+/// Take every checksum queued so far
+pub fn take() -> Vec<ChecksumRecord> {
+    std::mem::take(&mut *pending().lock().unwrap())
+}
+
+/// Keeps the per-block and running CRCs of one target, and records each block as it's finished
+#[derive(Debug)]
+pub struct BlockChecksums {
+    sink: String,
+    target: String,
+    block: Crc32c,
+    running: Crc32c,
+    blocks: u64,
+    block_start: u64,
+    bytes: u64,
+}
+
+impl BlockChecksums {
+    pub fn new(sink: &str, target: &str) -> Self {
+        Self {
+            sink: sink.to_owned(),
+            target: target.to_owned(),
+            block: Crc32c::default(),
+            running: Crc32c::default(),
+            blocks: 0,
+            block_start: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.block.update(bytes);
+        self.running.update(bytes);
+        self.bytes += bytes.len() as u64;
+    }
+
+    /// Running CRC of everything so far
+    pub fn running(&self) -> u32 {
+        self.running.value()
+    }
+
+    /// Bytes checksummed so far
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Finish the current block (if there's anything in it), recording it and returning the record
+    pub fn finish_block(&mut self) -> Option<ChecksumRecord> {
+        if self.bytes == self.block_start {
+            return None;
+        }
+        let finished = ChecksumRecord {
+            sink: self.sink.clone(),
+            target: self.target.clone(),
+            block: self.blocks,
+            offset: self.block_start,
+            bytes: self.bytes - self.block_start,
+            crc32c: self.block.value(),
+            running_crc32c: self.running.value(),
+            mjd: hifitime::Epoch::now()
+                .map(|e| time_policy().mjd(e))
+                .unwrap_or_default(),
+        };
+        self.blocks += 1;
+        self.block_start = self.bytes;
+        self.block = Crc32c::default();
+        record(finished.clone());
+        Some(finished)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        let mut crc = Crc32c::default();
+        crc.update(b"123456789");
+        assert_eq!(crc.value(), 0xE306_9283);
+        // Feeding it in pieces gives the same answer
+        let mut crc = Crc32c::default();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.value(), 0xE306_9283);
+    }
+}
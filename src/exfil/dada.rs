@@ -1,94 +1,699 @@
-use super::BANDWIDTH;
-use crate::common::{processed_payload_start_time, Stokes, CHANNELS, PACKET_CADENCE};
-use byte_slice_cast::AsByteSlice;
-use eyre::eyre;
-use hifitime::{
-    efmt::{Format, Formatter},
-    Epoch,
+use super::{
+    checksum::BlockChecksums,
+    exfil_first_packet, exfil_start_time,
+    stats::{sink_stats, SinkStats},
+    FrequencyPlan, StokesConsumer,
+};
+use crate::common::{station_id, Stokes, StokesVec, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE};
+use crate::{
+    accounting::accounting,
+    calibration::cal_schedule,
+    flags::FlagRun,
+    latency::{latency_policy, LatencyPolicy},
+    naming::{time_policy, TimePolicy, TimeStandard},
+    processing::channel_mask,
+    timing,
 };
+use byte_slice_cast::AsByteSlice;
+use eyre::{bail, eyre};
+use hifitime::Epoch;
 use psrdada::prelude::*;
-use std::{collections::HashMap, io::Write, str::FromStr};
-use thingbuf::mpsc::blocking::Receiver;
-use tokio::sync::broadcast;
-use tracing::{debug, info};
+use std::{
+    collections::HashMap,
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc, Arc, OnceLock,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{
+    blocking::{channel, Receiver, Sender},
+    errors::{RecvTimeoutError, TrySendError},
+};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tracing::{debug, info, warn};
+
+/// Number of spectra we'll queue up for the writer thread
+const WRITER_QUEUE_LEN: usize = 1024;
+/// How often to check for shutdown while the writer's queue is full
+const FULL_POLL: Duration = Duration::from_millis(1);
+
+/// Header keys we work out ourselves, which a template can't set (along with any starting with `CAL_`)
+const COMPUTED_KEYS: &[&str] = &[
+    "STATION",
+    "NCHAN",
+    "BW",
+    "FREQ",
+    "NPOL",
+    "NBIT",
+    "TSAMP",
+    "FLAG_KEY",
+    "UTC_START",
+    "OBS_OFFSET",
+    "MJD_START",
+    "MJD_STANDARD",
+    "BLANKED_CHANNELS",
+    "CRC32C_BLOCK_BYTES",
+    "CRC32C_PREV_TRANSFER",
+    "CRC32C_PREV_TRANSFER_BYTES",
+    "TIMING_DEGRADED",
+];
+
+static HEADER_TEMPLATE: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// Set the site-specific keys (SOURCE, RA, DEC, TELESCOPE, ...) added to every DADA header, once at startup.
+/// Returns false if it was already set.
+pub fn set_header_template(template: HashMap<String, String>) -> bool {
+    HEADER_TEMPLATE.set(template).is_ok()
+}
+
+/// Parse a header template, a JSON object of keys to string, number, or boolean values.
+/// Keys we compute ourselves are left out (and returned separately, so they can be reported).
+pub fn parse_header_template(json: &str) -> eyre::Result<(HashMap<String, String>, Vec<String>)> {
+    let fields: HashMap<String, serde_json::Value> = serde_json::from_str(json)
+        .map_err(|e| eyre!("DADA header templates are a JSON object - {e}"))?;
+    let mut template = HashMap::new();
+    let mut ignored = vec![];
+    for (key, value) in fields {
+        if key.is_empty() || key.chars().any(char::is_whitespace) {
+            bail!("Invalid DADA header key {key:?}");
+        }
+        let value = match value {
+            serde_json::Value::String(s) => s,
+            serde_json::Value::Number(n) => n.to_string(),
+            serde_json::Value::Bool(b) => u8::from(b).to_string(),
+            _ => bail!("DADA header value for {key} isn't a string, number, or boolean"),
+        };
+        if value.contains('\n') {
+            bail!("DADA header value for {key} spans lines");
+        }
+        if COMPUTED_KEYS.contains(&key.as_str()) || key.starts_with("CAL_") {
+            ignored.push(key);
+        } else {
+            template.insert(key, value);
+        }
+    }
+    ignored.sort();
+    Ok((template, ignored))
+}
 
-/// Convert a chronno `DateTime` into a heimdall-compatible timestamp string
+/// Read a header template from a file, warning about any keys it can't set
+pub fn load_header_template(path: &Path) -> eyre::Result<HashMap<String, String>> {
+    let (template, ignored) = parse_header_template(&std::fs::read_to_string(path)?)?;
+    if !ignored.is_empty() {
+        warn!(
+            ?ignored,
+            "DADA header template sets keys we compute ourselves, ignoring them"
+        );
+    }
+    Ok(template)
+}
+
+/// Convert an `Epoch` into a heimdall-compatible timestamp string, which is always UTC whatever our time policy
 fn heimdall_timestamp(time: &Epoch) -> String {
-    let fmt = Format::from_str("%Y-%m-%d-%H:%M:%S").unwrap();
-    format!("{}", Formatter::new(*time, fmt))
+    TimePolicy::format_in(TimeStandard::Utc, *time, "%Y-%m-%d-%H:%M:%S")
+}
+
+/// Spectra to commit each DADA block at. Heimdall can't see a spectrum until the block it's in is committed, so in
+/// low-latency mode we commit (partial) blocks as often as the budget needs.
+fn commit_window(policy: &LatencyPolicy, window_size: usize, downsample_factor: usize) -> usize {
+    let tsamp = downsample_factor as f64 * PACKET_CADENCE;
+    let block_time = Duration::from_secs_f64(window_size as f64 * tsamp);
+    if !policy.low_latency || block_time <= policy.budget {
+        return window_size;
+    }
+    let committed = ((policy.budget.as_secs_f64() / tsamp) as usize).clamp(1, window_size);
+    info!(
+        block_ms = block_time.as_secs_f64() * 1e3,
+        budget_ms = policy.budget.as_secs_f64() * 1e3,
+        spectra = committed,
+        "DADA blocks hold more time than the latency budget, committing them early"
+    );
+    committed
+}
+
+/// Streams stokes into a PSRDADA buffer for heimdall.
+/// The PSRDADA client borrows itself into the header, writer, and block handles, so it lives on its own writer thread.
+/// Acquiring a block can't time out, so that thread may be stuck waiting on the reader. We never wait on it for longer
+/// than [`BLOCK_TIMEOUT`] at shutdown, leaving it behind if it's still stuck.
+pub struct DadaConsumer {
+    /// Stokes I or other polarization products, the writer works out which from the length
+    sender: Option<Sender<StokesVec>>,
+    writer: Option<JoinHandle<eyre::Result<()>>>,
+    /// So a backed up writer doesn't hold up shutting down
+    shutdown: broadcast::Receiver<()>,
+    /// Latched once we've seen the shutdown, as the broadcast only tells us once
+    shutting_down: bool,
+    /// Flag runs go to their own buffer (if we were given one) from yet another thread
+    flag_sender: Option<mpsc::Sender<FlagRun>>,
+    flag_writer: Option<JoinHandle<eyre::Result<()>>>,
+    /// Time spent waiting on a full writer queue counts as a stall of the sink
+    stats: Arc<SinkStats>,
+}
+
+impl DadaConsumer {
+    pub fn new(
+        key: i32,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        window_size: usize,
+        flag_key: Option<i32>,
+        shutdown: broadcast::Receiver<()>,
+    ) -> eyre::Result<Self> {
+        let policy = latency_policy();
+        let window_size = commit_window(policy, window_size, downsample_factor);
+        let (sender, receiver) = channel(policy.spectra(WRITER_QUEUE_LEN));
+        let writer = std::thread::Builder::new()
+            .name("dada_writer".to_owned())
+            .spawn(move || {
+                writer_loop(
+                    key,
+                    receiver,
+                    downsample_factor,
+                    freq_plan,
+                    window_size,
+                    flag_key,
+                )
+            })?;
+        let (flag_sender, flag_writer) = match flag_key {
+            Some(flag_key) => {
+                let (flag_sender, flag_receiver) = mpsc::channel();
+                let flag_writer = std::thread::Builder::new()
+                    .name("dada_flags".to_owned())
+                    .spawn(move || flag_writer_loop(flag_key, flag_receiver))?;
+                (Some(flag_sender), Some(flag_writer))
+            }
+            None => (None, None),
+        };
+        Ok(Self {
+            sender: Some(sender),
+            writer: Some(writer),
+            shutdown,
+            shutting_down: false,
+            flag_sender,
+            flag_writer,
+            stats: sink_stats(
+                "psrdada",
+                Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64),
+            ),
+        })
+    }
 }
 
-pub fn consumer(
+impl DadaConsumer {
+    fn send(&mut self, stokes: &[f32]) -> eyre::Result<()> {
+        let sender = self
+            .sender
+            .as_ref()
+            .ok_or_else(|| eyre!("DADA consumer already finished"))?;
+        let mut full_since: Option<Instant> = None;
+        loop {
+            match sender.try_send_ref() {
+                Ok(mut slot) => {
+                    slot.clear();
+                    slot.try_extend_from_slice(stokes)?;
+                    if let Some(since) = full_since {
+                        self.stats.record_stall(since.elapsed());
+                    }
+                    return Ok(());
+                }
+                // The reader isn't taking blocks, which we wait out unless we're shutting down
+                Err(TrySendError::Full(_)) => {
+                    full_since.get_or_insert_with(Instant::now);
+                    if !self.shutting_down
+                        && !matches!(self.shutdown.try_recv(), Err(TryRecvError::Empty))
+                    {
+                        warn!("Shutting down while the DADA writer is backed up, dropping what's queued");
+                        self.shutting_down = true;
+                    }
+                    if self.shutting_down {
+                        return Ok(());
+                    }
+                    std::thread::sleep(FULL_POLL);
+                }
+                Err(_) => bail!("DADA writer thread stopped"),
+            }
+        }
+    }
+}
+
+impl StokesConsumer for DadaConsumer {
+    fn name(&self) -> &str {
+        "psrdada"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        self.send(stokes)
+    }
+
+    fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+        self.send(stokes)
+    }
+
+    fn flags(&mut self, run: &FlagRun) -> eyre::Result<()> {
+        if let Some(flag_sender) = &self.flag_sender {
+            // Losing the flags shouldn't take down exfil
+            if flag_sender.send(*run).is_err() {
+                warn!("DADA flag writer stopped, no longer streaming flags");
+                self.flag_sender = None;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        // Closing the channels stops the writers
+        drop(self.flag_sender.take());
+        if let Some(flag_writer) = self.flag_writer.take() {
+            match flag_writer.join() {
+                Ok(Err(e)) => warn!("DADA flag writer failed - {e}"),
+                Err(_) => warn!("DADA flag writer thread panicked"),
+                Ok(Ok(_)) => (),
+            }
+        }
+        drop(self.sender.take());
+        if let Some(writer) = self.writer.take() {
+            let start = Instant::now();
+            while !writer.is_finished() {
+                if start.elapsed() >= BLOCK_TIMEOUT {
+                    warn!("DADA writer is still waiting on a block from the reader, leaving it behind");
+                    return Ok(());
+                }
+                std::thread::sleep(FULL_POLL);
+            }
+            writer
+                .join()
+                .map_err(|_| eyre!("DADA writer thread panicked"))??;
+        }
+        Ok(())
+    }
+}
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Pause or resume DADA exfil. Pauses take effect at the next block boundary, and spectra are discarded while paused.
+pub fn set_paused(paused: bool) {
+    PAUSED.store(paused, Ordering::Release);
+}
+
+/// Held paused by something other than the operator (like a recalibration), whatever they've asked for
+static HELD: AtomicBool = AtomicBool::new(false);
+
+/// Hold DADA exfil paused (or let it go), without touching whether the operator has paused it
+pub fn hold(held: bool) {
+    HELD.store(held, Ordering::Release);
+}
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Acquire) || HELD.load(Ordering::Acquire)
+}
+
+static TRANSFERRING: AtomicBool = AtomicBool::new(false);
+
+/// Whether we're in the middle of a DADA transfer, i.e. a pause hasn't taken effect yet
+pub fn transferring() -> bool {
+    TRANSFERRING.load(Ordering::Acquire)
+}
+
+/// How long to wait between attempts to reconnect to the DADA buffer
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Why a connection to the DADA buffer ended
+enum SessionEnd {
+    /// The consumer closed the channel, we're done
+    Finished,
+    /// Something went wrong with the buffer (i.e. heimdall went away), so we should reconnect
+    Lost(eyre::Report),
+}
+
+/// Channels (in gateware order) that are currently blanked, in DADA header form
+fn blanked_header() -> String {
+    let blanked: Vec<_> = channel_mask()
+        .channels()
+        .iter()
+        .map(|c| c.to_string())
+        .collect();
+    if blanked.is_empty() {
+        "NONE".to_owned()
+    } else {
+        blanked.join(",")
+    }
+}
+
+fn writer_loop(
     key: i32,
-    stokes_rcv: Receiver<Stokes>,
+    stokes_rcv: Receiver<StokesVec>,
     downsample_factor: usize,
+    freq_plan: FrequencyPlan,
     window_size: usize,
-    mut shutdown: broadcast::Receiver<()>,
+    flag_key: Option<i32>,
 ) -> eyre::Result<()> {
     info!("Starting DADA consumer");
-    // DADA window
-    let mut stokes_cnt = 0usize;
-    // We will capture the timestamp on the first packet
-    let mut first_payload = true;
-    // Send the header (heimdall only wants one)
+    let written = accounting().output("psrdada");
+    let stats = sink_stats(
+        "psrdada",
+        Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64),
+    );
+    stats.set_target(format!("dada:{key:x}"));
     let mut header = HashMap::from([
+        ("STATION".to_owned(), station_id().to_owned()),
         ("NCHAN".to_owned(), CHANNELS.to_string()),
-        ("BW".to_owned(), (-BANDWIDTH).to_string()),
-        ("FREQ".to_owned(), "1405".to_owned()),
-        ("NPOL".to_owned(), "1".to_owned()),
+        ("BW".to_owned(), freq_plan.signed_bandwidth().to_string()),
+        ("FREQ".to_owned(), freq_plan.center().to_string()),
         ("NBIT".to_owned(), "32".to_owned()),
-        ("OBS_OFFSET".to_owned(), 0.to_string()),
         (
             "TSAMP".to_owned(),
             (PACKET_CADENCE * downsample_factor as f64 * 1e6).to_string(),
         ),
     ]);
-    // Grab PSRDADA writing context
-    let mut client = HduClient::connect(key).expect("Could not connect to PSRDADA buffer");
+    // Site-specific keys ride along, never replacing any of ours
+    if let Some(template) = HEADER_TEMPLATE.get() {
+        for (key, value) in template {
+            header.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+    // Let flag-aware readers know where to find them
+    if let Some(flag_key) = flag_key {
+        header.insert("FLAG_KEY".to_owned(), format!("{flag_key:x}"));
+    }
+    // Number of spectra since the start of the observation, including any we threw away while disconnected
+    let mut spectra = 0u64;
+    loop {
+        let end = session(
+            key,
+            &stokes_rcv,
+            &mut header,
+            &mut spectra,
+            downsample_factor,
+            window_size,
+            &written,
+            &stats,
+        );
+        TRANSFERRING.store(false, Ordering::Release);
+        match end {
+            SessionEnd::Finished => {
+                info!("Exfil task stopping");
+                return Ok(());
+            }
+            SessionEnd::Lost(e) => {
+                warn!("Lost the DADA buffer, reconnecting - {e}");
+                stats.record_error(&e);
+            }
+        }
+        // Keep draining (and counting) spectra until the buffer comes back, so time keeps moving
+        let mut last_attempt = Instant::now();
+        loop {
+            match stokes_rcv.recv_ref_timeout(RECONNECT_INTERVAL) {
+                Ok(_) => spectra += 1,
+                Err(RecvTimeoutError::Closed) => return Ok(()),
+                Err(_) => (),
+            }
+            if last_attempt.elapsed() >= RECONNECT_INTERVAL {
+                last_attempt = Instant::now();
+                if HduClient::connect(key).is_ok() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Stream flag runs into their own DADA buffer, one fixed-size (24 byte) record per block
+fn flag_writer_loop(key: i32, runs: mpsc::Receiver<FlagRun>) -> eyre::Result<()> {
+    info!("Starting DADA flag writer");
+    let mut client = HduClient::connect(key)
+        .map_err(|e| eyre!("Could not connect to PSRDADA flag buffer - {e:?}"))?;
     let (mut hc, mut dc) = client.split();
     let mut data_writer = dc
         .writer()
-        .expect("Couldn't lock the DADA buffer for writing");
-    info!("DADA header pushed, starting exfil to Heimdall");
-    // Start the main consumer loop
-    // FIXME FIXME How do we timeout of grabbing a dada block?
+        .map_err(|e| eyre!("Couldn't lock the DADA flag buffer for writing - {e:?}"))?;
+    let mut header_written = false;
+    while let Ok(run) = runs.recv() {
+        if !header_written {
+            header_written = true;
+            let header = HashMap::from([
+                (
+                    "UTC_START".to_owned(),
+                    heimdall_timestamp(&exfil_start_time()),
+                ),
+                (
+                    "FLAG_RECORD".to_owned(),
+                    "START:U64LE,LEN:U64LE,FLAGS:U64LE".to_owned(),
+                ),
+                (
+                    "FLAG_BITS".to_owned(),
+                    "RFI=1,ZERO_FILLED=2,INJECTED=4,CAL_ON=8,FPGA_STATUS=16".to_owned(),
+                ),
+            ]);
+            // Safety: All these header keys and values are valid
+            unsafe { hc.write_header(&header) }
+                .map_err(|e| eyre!("Couldn't write the DADA flag header - {e:?}"))?;
+        }
+        let Some(mut block) = data_writer.next() else {
+            bail!("DADA flag buffer stopped giving us blocks");
+        };
+        block.write_all(&run.to_bytes())?;
+        block.commit();
+    }
+    Ok(())
+}
+
+/// Write spectra to one connection of the DADA buffer, as one or more transfers (split by pauses).
+/// Each transfer starts with a header that carries on the observation.
+#[allow(clippy::too_many_arguments)]
+fn session(
+    key: i32,
+    stokes_rcv: &Receiver<StokesVec>,
+    header: &mut HashMap<String, String>,
+    spectra: &mut u64,
+    downsample_factor: usize,
+    window_size: usize,
+    written: &AtomicU64,
+    stats: &SinkStats,
+) -> SessionEnd {
+    // Grab PSRDADA writing context
+    let mut client = match HduClient::connect(key) {
+        Ok(c) => c,
+        Err(e) => return SessionEnd::Lost(eyre!("Could not connect to PSRDADA buffer - {e:?}")),
+    };
+    let (mut hc, mut dc) = client.split();
     loop {
-        // Grab the next psrdada block we can write to (BLOCKING)
-        let mut block = data_writer.next().unwrap();
+        let mut data_writer = match dc.writer() {
+            Ok(w) => w,
+            Err(e) => {
+                return SessionEnd::Lost(eyre!("Couldn't lock the DADA buffer for writing - {e:?}"))
+            }
+        };
+        TRANSFERRING.store(true, Ordering::Release);
+        // The header is written with the first spectrum of every transfer (heimdall only wants one per transfer)
+        let mut header_written = false;
+        // Checksums of each block of this transfer, which we name by where it starts in the observation
+        let mut checksums = BlockChecksums::new("psrdada", &format!("dada:{key:x}+{}", *spectra));
+        // DADA window
+        let mut stokes_cnt = 0usize;
+        // Start the main consumer loop
         loop {
-            if shutdown.try_recv().is_ok() {
-                info!("Exfil task stopping");
-                return Ok(());
+            // Pauses only take effect on block boundaries, so every transfer is made of whole windows
+            if paused() {
+                info!("Pausing DADA exfil");
+                break;
             }
-            // Grab the next stokes parameters (already downsampled)
-            let stokes = stokes_rcv
-                .recv_ref()
-                .ok_or_else(|| eyre!("Channel closed"))?;
-            debug_assert_eq!(stokes.len(), CHANNELS);
-            // Timestamp first one
-            if first_payload {
-                first_payload = false;
-                let time = processed_payload_start_time();
-                let timestamp_str = heimdall_timestamp(&time);
-                header.insert("UTC_START".to_owned(), timestamp_str);
-                // Write the single header
-                // Safety: All these header keys and values are valid
-                unsafe { hc.write_header(&header).unwrap() };
+            // Grab the next psrdada block we can write to (BLOCKING, with no way to time out, see [`DadaConsumer`])
+            let block_start = Instant::now();
+            let Some(mut block) = data_writer.next() else {
+                return SessionEnd::Lost(eyre!("DADA buffer stopped giving us blocks"));
+            };
+            // Waiting on the block is the reader holding us up, not the write
+            stats.record_wait(block_start.elapsed());
+            loop {
+                // Grab the next stokes parameters (already downsampled), stopping once the consumer closes the channel
+                let stokes = match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
+                    Ok(stokes) => stokes,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(_) => {
+                        // Whatever made it into this block is the end of the data
+                        if stokes_cnt > 0 {
+                            debug!(
+                                spectra = stokes_cnt,
+                                "Committing final partial block as EOD"
+                            );
+                            block.mark_eod();
+                            block.commit();
+                            checksums.finish_block();
+                        }
+                        return SessionEnd::Finished;
+                    }
+                };
+                if !header_written {
+                    header_written = true;
+                    // Polarization products go one after the other within each spectrum
+                    let npol = stokes.len() / CHANNELS;
+                    let spectrum_bytes = stokes.len() * std::mem::size_of::<f32>();
+                    header.insert("NPOL".to_owned(), npol.to_string());
+                    // UTC_START is always the start of the observation, later transfers are offset from it
+                    header
+                        .entry("UTC_START".to_owned())
+                        .or_insert_with(|| heimdall_timestamp(&exfil_start_time()));
+                    header.insert(
+                        "OBS_OFFSET".to_owned(),
+                        (*spectra * spectrum_bytes as u64).to_string(),
+                    );
+                    // The same start in our time policy's standard, which may not be UTC
+                    header.entry("MJD_START".to_owned()).or_insert_with(|| {
+                        format!("{:.12}", time_policy().mjd(exfil_start_time()))
+                    });
+                    header.insert(
+                        "MJD_STANDARD".to_owned(),
+                        time_policy().mjd_standard().to_owned(),
+                    );
+                    // Channels that were blanked at the start of this stretch of the observation
+                    header.insert("BLANKED_CHANNELS".to_owned(), blanked_header());
+                    // Each block's CRC goes in the database, and the CRC of a whole transfer goes in the next header
+                    header.insert(
+                        "CRC32C_BLOCK_BYTES".to_owned(),
+                        (window_size * spectrum_bytes).to_string(),
+                    );
+                    header.insert(
+                        "TIMING_DEGRADED".to_owned(),
+                        u8::from(timing::degraded()).to_string(),
+                    );
+                    // Noise source cycle, as of the first payload of this transfer
+                    if let Some(cal) = cal_schedule() {
+                        let first_count =
+                            exfil_first_packet() + *spectra * downsample_factor as u64;
+                        header.extend(cal.dada_keys(first_count));
+                    }
+                    // Safety: All these header keys and values are valid
+                    if let Err(e) = unsafe { hc.write_header(header) } {
+                        return SessionEnd::Lost(eyre!("Couldn't write the DADA header - {e:?}"));
+                    }
+                    info!(
+                        obs_offset = %header["OBS_OFFSET"],
+                        "DADA header pushed, starting exfil to Heimdall"
+                    );
+                }
+                // Write the block
+                let write_start = Instant::now();
+                let bytes = stokes.as_byte_slice();
+                if let Err(e) = block.write_all(bytes) {
+                    return SessionEnd::Lost(e.into());
+                }
+                checksums.update(bytes);
+                stats.record_write(bytes.len(), write_start.elapsed());
+                // Increase our count
+                stokes_cnt += 1;
+                *spectra += 1;
+                written.fetch_add(1, Ordering::Relaxed);
+                // If we've filled the window, commit it to PSRDADA
+                if stokes_cnt == window_size {
+                    debug!("Committing window to PSRDADA");
+                    // Reset the stokes counter
+                    stokes_cnt = 0;
+                    // Commit data and update
+                    block.commit();
+                    stats.record_block();
+                    checksums.finish_block();
+                    //Break to finish the write
+                    break;
+                }
             }
-            // Write the block
-            block.write_all(stokes.as_byte_slice()).unwrap();
-            // Increase our count
-            stokes_cnt += 1;
-            // If we've filled the window, commit it to PSRDADA
-            if stokes_cnt == window_size {
-                debug!("Committing window to PSRDADA");
-                // Reset the stokes counter
-                stokes_cnt = 0;
-                // Commit data and update
-                block.commit();
-                //Break to finish the write
-                break;
+        }
+        // Dropping the writer ends this transfer, keeping our connection to the buffer
+        drop(data_writer);
+        header.insert(
+            "CRC32C_PREV_TRANSFER".to_owned(),
+            format!("{:08x}", checksums.running()),
+        );
+        header.insert(
+            "CRC32C_PREV_TRANSFER_BYTES".to_owned(),
+            checksums.bytes().to_string(),
+        );
+        TRANSFERRING.store(false, Ordering::Release);
+        // Throw away (but count, so time keeps moving) spectra until we're resumed
+        while paused() {
+            match stokes_rcv.recv_ref_timeout(RECONNECT_INTERVAL) {
+                Ok(_) => *spectra += 1,
+                Err(RecvTimeoutError::Closed) => return SessionEnd::Finished,
+                Err(_) => (),
             }
         }
+        info!("Resuming DADA exfil");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hold() {
+        // An operator's pause outlasts a hold, whichever comes first
+        set_paused(true);
+        hold(true);
+        hold(false);
+        assert!(paused());
+        hold(true);
+        set_paused(false);
+        assert!(paused());
+        hold(false);
+        assert!(!paused());
+    }
+
+    #[test]
+    fn test_header_template() {
+        let (template, ignored) = parse_header_template(
+            r#"{"SOURCE": "B0329+54", "RA": "03:32:59.4", "DEC": 54.58, "TSAMP": 1, "CAL_MODE": "OFF", "SITE_FLAG": true}"#,
+        )
+        .unwrap();
+        assert_eq!(template["SOURCE"], "B0329+54");
+        assert_eq!(template["DEC"], "54.58");
+        assert_eq!(template["SITE_FLAG"], "1");
+        assert_eq!(ignored, vec!["CAL_MODE", "TSAMP"]);
+        assert!(parse_header_template(r#"{"BAD KEY": "x"}"#).is_err());
+        assert!(parse_header_template(r#"{"NOTES": ["a"]}"#).is_err());
+        assert!(parse_header_template("[]").is_err());
+    }
+
+    #[test]
+    fn test_commit_window() {
+        let mut policy = LatencyPolicy {
+            budget: Duration::from_millis(10),
+            ..Default::default()
+        };
+        // A 65536 spectrum window at ~1 ms each is ~67 s of data
+        assert_eq!(commit_window(&policy, 65536, 128), 65536);
+        policy.low_latency = true;
+        assert_eq!(commit_window(&policy, 65536, 128), 9);
+        assert_eq!(commit_window(&policy, 4, 128), 4);
+        // And never less than a spectrum at a time
+        assert_eq!(commit_window(&policy, 65536, 1 << 20), 1);
+    }
+
+    #[test]
+    fn test_shutdown_latch() {
+        let (sender, _receiver) = channel(1);
+        let (sd_s, shutdown) = broadcast::channel(1);
+        let mut consumer = DadaConsumer {
+            sender: Some(sender),
+            writer: None,
+            shutdown,
+            shutting_down: false,
+            flag_sender: None,
+            flag_writer: None,
+            stats: sink_stats("psrdada-test", FULL_POLL),
+        };
+        let stokes = [0.0; CHANNELS];
+        consumer.send(&stokes).unwrap();
+        sd_s.send(()).unwrap();
+        // The writer is backed up, and every spectrum after the shutdown is dropped rather than waiting on it
+        let start = Instant::now();
+        for _ in 0..3 {
+            consumer.send(&stokes).unwrap();
+        }
+        assert!(consumer.shutting_down);
+        assert!(start.elapsed() < BLOCK_TIMEOUT);
     }
 }
@@ -0,0 +1,224 @@
+//! Incoherent dedispersion of the stokes stream at a single DM, for monitoring a known source without a search.
+//! Each channel is delayed by its dispersion delay (rounded to the nearest spectrum) relative to the top of the band,
+//! so a pulse at that DM lands in the same spectrum across the band. Spectra keep the time of their top-of-band
+//! arrival, so everything downstream is still stamped from the first processed payload, but the last
+//! spectra (as many as the sweep across the band is long) are never written. Every polarization product is dedispersed
+//! alike, and the sweep is capped at [`MAX_SWEEP`] spectra, as we hold that many in memory.
+use super::{FrequencyPlan, StokesConsumer};
+use crate::{
+    common::{Stokes, StokesVec, CHANNELS, PACKET_CADENCE},
+    flags::FlagRun,
+    injection::DISPERSION_CONSTANT,
+};
+use eyre::bail;
+use std::collections::VecDeque;
+use tracing::info;
+
+/// Most spectra a sweep across the band can take, which is ~128 MiB of Stokes I history
+pub const MAX_SWEEP: usize = 16384;
+
+/// Delay (in spectra, of `tsamp` seconds) of every channel at `dm`, relative to the highest frequency
+fn channel_delays(dm: f64, freq_plan: FrequencyPlan, tsamp: f64) -> Vec<usize> {
+    let freqs = freq_plan.freqs();
+    let f_top = freqs.iter().copied().fold(f64::MIN, f64::max);
+    freqs
+        .iter()
+        .map(|f| {
+            (DISPERSION_CONSTANT * dm * (f.powi(-2) - f_top.powi(-2)) / tsamp).round() as usize
+        })
+        .collect()
+}
+
+/// Dedisperses the stokes stream at a fixed DM before handing it to the inner consumer
+pub struct DedisperseConsumer {
+    inner: Box<dyn StokesConsumer>,
+    /// Delay of every channel, in spectra
+    delays: Vec<usize>,
+    /// Longest delay, which is how many spectra the output runs behind the input
+    sweep: usize,
+    /// The last spectra (of whatever polarization products) we've seen, oldest first, up to one more than the sweep
+    history: VecDeque<Vec<f32>>,
+}
+
+impl DedisperseConsumer {
+    pub fn new(
+        inner: Box<dyn StokesConsumer>,
+        dm: f64,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+    ) -> eyre::Result<Self> {
+        if !dm.is_finite() || dm < 0.0 {
+            bail!("Can't dedisperse at a DM of {dm}");
+        }
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        let delays = channel_delays(dm, freq_plan, tsamp);
+        let sweep = delays.iter().copied().max().unwrap_or(0);
+        if sweep > MAX_SWEEP {
+            bail!(
+                "DM {dm} sweeps across the band in {sweep} spectra, more than the {MAX_SWEEP} we'll hold"
+            );
+        }
+        info!(
+            "Dedispersing {} exfil at DM {dm}, sweeping {sweep} spectra ({:.3} s)",
+            inner.name(),
+            sweep as f64 * tsamp
+        );
+        Ok(Self {
+            inner,
+            delays,
+            sweep,
+            history: VecDeque::with_capacity(sweep + 1),
+        })
+    }
+
+    /// Take the next spectrum, handing on the one that's now a sweep old with every channel taken from when it would
+    /// have arrived
+    fn push(&mut self, stokes: &[f32]) -> eyre::Result<()> {
+        if self
+            .history
+            .front()
+            .is_some_and(|oldest| oldest.len() != stokes.len())
+        {
+            bail!("Can't dedisperse across a change in polarization products");
+        }
+        // Reusing the oldest spectrum's allocation once we're a whole sweep in
+        let mut spectrum = if self.history.len() > self.sweep {
+            self.history
+                .pop_front()
+                .expect("History is longer than the sweep")
+        } else {
+            Vec::with_capacity(stokes.len())
+        };
+        spectrum.clear();
+        spectrum.extend_from_slice(stokes);
+        self.history.push_back(spectrum);
+        if self.history.len() <= self.sweep {
+            return Ok(());
+        }
+        let dedispersed: StokesVec = (0..stokes.len())
+            .map(|i| self.history[self.delays[i % CHANNELS]][i])
+            .collect();
+        if dedispersed.len() == CHANNELS {
+            self.inner.consume(&dedispersed.into_iter().collect())
+        } else {
+            self.inner.consume_full(&dedispersed)
+        }
+    }
+}
+
+impl StokesConsumer for DedisperseConsumer {
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        self.push(stokes)
+    }
+
+    fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+        self.push(stokes)
+    }
+
+    fn flags(&mut self, run: &FlagRun) -> eyre::Result<()> {
+        self.inner.flags(run)
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        self.inner.finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::exfil::STOKES_ORDER;
+    use std::sync::{Arc, Mutex};
+
+    struct Collect(Arc<Mutex<Vec<StokesVec>>>);
+
+    impl StokesConsumer for Collect {
+        fn name(&self) -> &str {
+            "collect"
+        }
+
+        fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+            self.0
+                .lock()
+                .unwrap()
+                .push(stokes.iter().copied().collect());
+            Ok(())
+        }
+
+        fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+            self.0.lock().unwrap().push(stokes.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dedisperse() {
+        let freq_plan = FrequencyPlan::default().reordered(STOKES_ORDER);
+        let tsamp = PACKET_CADENCE * 4.0;
+        let delays = channel_delays(500.0, freq_plan, tsamp);
+        let sweep = *delays.iter().max().unwrap();
+        assert!(sweep > 0);
+        let out = Arc::new(Mutex::new(vec![]));
+        let mut dd =
+            DedisperseConsumer::new(Box::new(Collect(out.clone())), 500.0, 4, freq_plan).unwrap();
+        assert_eq!(dd.sweep, sweep);
+        // A pulse at t = 2 at the top of the band, swept across it at this DM
+        let total = sweep + 8;
+        for t in 0..total {
+            let spectrum: Stokes = (0..CHANNELS)
+                .map(|c| if t == 2 + delays[c] { 1.0 } else { 0.0 })
+                .collect();
+            dd.consume(&spectrum).unwrap();
+        }
+        let out = out.lock().unwrap();
+        assert_eq!(out.len(), total - sweep);
+        for (t, spectrum) in out.iter().enumerate() {
+            let expected = if t == 2 { 1.0 } else { 0.0 };
+            assert!(spectrum.iter().all(|&v| v == expected));
+        }
+        assert!(
+            DedisperseConsumer::new(Box::new(Collect(Arc::default())), -1.0, 4, freq_plan).is_err()
+        );
+        // Nor will we hold more than so many spectra to do it
+        assert!(
+            DedisperseConsumer::new(Box::new(Collect(Arc::default())), 5000.0, 1, freq_plan)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_dedisperse_full() {
+        let freq_plan = FrequencyPlan::default().reordered(STOKES_ORDER);
+        let delays = channel_delays(100.0, freq_plan, PACKET_CADENCE * 4.0);
+        let sweep = *delays.iter().max().unwrap();
+        let out = Arc::new(Mutex::new(vec![]));
+        let mut dd =
+            DedisperseConsumer::new(Box::new(Collect(out.clone())), 100.0, 4, freq_plan).unwrap();
+        // A pulse at t = 1 in every one of the four stokes parameters, each a different brightness
+        for t in 0..sweep + 4 {
+            let spectrum: StokesVec = (0..4 * CHANNELS)
+                .map(|i| {
+                    if t == 1 + delays[i % CHANNELS] {
+                        (1 + i / CHANNELS) as f32
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            dd.consume_full(&spectrum).unwrap();
+        }
+        let out = out.lock().unwrap();
+        assert_eq!(out.len(), 4);
+        assert_eq!(out[1].len(), 4 * CHANNELS);
+        for (i, v) in out[1].iter().enumerate() {
+            assert_eq!(*v, (1 + i / CHANNELS) as f32);
+        }
+        assert!(out[0].iter().chain(&out[2]).all(|&v| v == 0.0));
+        // And the products can't change underneath it
+        assert!(dd.consume(&Stokes::from([0.0; CHANNELS])).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+//! A bright-pulse detector on the stokes stream, so the station can trigger on anything loud enough by itself.
+//! Every channel is normalized by its own median and MAD, and channels noisier than is typical are weighted down, so
+//! persistent narrowband RFI can't dominate the automatic trigger rate.
+use crate::{
+    common::{CandidateEvent, Stokes, CHANNELS, PACKET_CADENCE},
+    triggers,
+};
+use tracing::{info, warn};
+
+/// Spectra in each block of noise statistics, the block before is what we normalize against
+const NOISE_BLOCK: usize = 1024;
+/// Scales a MAD to the standard deviation of gaussian noise
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Per-channel noise estimates from the last block of spectra, and the weights they give
+#[derive(Debug)]
+struct Noise {
+    median: Vec<f32>,
+    sigma: Vec<f32>,
+    /// Inverse variance relative to the typical channel, capped at 1 so quiet channels can't dominate either
+    weights: Vec<f32>,
+    /// Norm of the weights, so the statistic is in units of sigma
+    norm: f32,
+}
+
+impl Noise {
+    /// Estimate the noise of every channel from a block of spectra stored channel-major
+    fn estimate(block: &mut [f32]) -> Option<Self> {
+        let mut median = Vec::with_capacity(CHANNELS);
+        let mut sigma = Vec::with_capacity(CHANNELS);
+        for channel in block.chunks_exact_mut(NOISE_BLOCK) {
+            let med = median_of(channel);
+            for x in channel.iter_mut() {
+                *x = (*x - med).abs();
+            }
+            median.push(med);
+            sigma.push(MAD_TO_SIGMA * median_of(channel));
+        }
+        // Channels that are flat (blanked or dead) or broken say nothing
+        let mut usable: Vec<_> = sigma
+            .iter()
+            .copied()
+            .filter(|s| s.is_finite() && *s > 0.0)
+            .collect();
+        if usable.is_empty() {
+            return None;
+        }
+        let typical = median_of(&mut usable);
+        let weights: Vec<_> = sigma
+            .iter()
+            .map(|s| {
+                if s.is_finite() && *s > 0.0 {
+                    (typical / s).powi(2).min(1.0)
+                } else {
+                    0.0
+                }
+            })
+            .collect();
+        let norm = weights.iter().map(|w| w * w).sum::<f32>().sqrt();
+        Some(Self {
+            median,
+            sigma,
+            weights,
+            norm,
+        })
+    }
+
+    /// Weighted sum of every channel's deviation (in its own sigmas), in units of sigma for gaussian noise
+    fn statistic(&self, stokes: &[f32]) -> f32 {
+        let sum: f32 = stokes
+            .iter()
+            .zip(&self.median)
+            .zip(&self.sigma)
+            .zip(&self.weights)
+            .filter(|(_, w)| **w > 0.0)
+            .map(|(((x, m), s), w)| w * (x - m) / s)
+            .sum();
+        sum / self.norm
+    }
+}
+
+fn median_of(v: &mut [f32]) -> f32 {
+    let mid = v.len() / 2;
+    *v.select_nth_unstable_by(mid, f32::total_cmp).1
+}
+
+/// Triggers on single spectra that stand out from the noise, handing them to the internal trigger source
+#[derive(Debug)]
+pub struct PulseDetector {
+    /// Detection threshold (sigma)
+    threshold: f32,
+    /// Spectra after a detection before we'll trigger again
+    holdoff: u64,
+    /// Spectra since the start of the observation
+    spectra: u64,
+    /// Spectra of the block being collected, channel-major
+    block: Vec<f32>,
+    filled: usize,
+    noise: Option<Noise>,
+    last_trigger: Option<u64>,
+}
+
+impl PulseDetector {
+    /// Trigger on spectra more than `threshold` sigma bright, at most once every `holdoff` seconds
+    pub fn new(threshold: f64, holdoff: f64, downsample_factor: usize) -> Self {
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        info!(
+            threshold,
+            holdoff, "Detecting bright pulses in the stokes stream"
+        );
+        Self {
+            threshold: threshold as f32,
+            holdoff: (holdoff / tsamp).ceil() as u64,
+            spectra: 0,
+            block: vec![0.0; CHANNELS * NOISE_BLOCK],
+            filled: 0,
+            noise: None,
+            last_trigger: None,
+        }
+    }
+
+    /// Look at the next spectrum, returning its statistic if it's a detection
+    fn detect(&mut self, stokes: &[f32]) -> Option<f32> {
+        let specnum = self.spectra;
+        self.spectra += 1;
+        for (c, x) in stokes.iter().enumerate() {
+            self.block[c * NOISE_BLOCK + self.filled] = *x;
+        }
+        self.filled += 1;
+        let statistic = self.noise.as_ref().map(|n| n.statistic(stokes));
+        if self.filled == NOISE_BLOCK {
+            self.noise = Noise::estimate(&mut self.block);
+            self.filled = 0;
+        }
+        let statistic = statistic.filter(|s| *s >= self.threshold)?;
+        if self
+            .last_trigger
+            .is_some_and(|last| specnum < last + self.holdoff)
+        {
+            return None;
+        }
+        self.last_trigger = Some(specnum);
+        Some(statistic)
+    }
+
+    pub fn push(&mut self, stokes: &Stokes) {
+        let specnum = self.spectra;
+        let Some(snr) = self.detect(stokes) else {
+            return;
+        };
+        info!(specnum, snr, "Detected a bright pulse");
+        let event = CandidateEvent {
+            candname: format!("internal-{specnum}"),
+            specnum,
+            dm: Some(0.0),
+            snr: Some(snr.into()),
+            local: true,
+            ..Default::default()
+        };
+        match triggers::internal() {
+            Some(sender) => {
+                if sender.try_send(event).is_err() {
+                    warn!("Internal trigger source is busy, dropping a detection");
+                }
+            }
+            None => warn!("Nowhere to send internal triggers"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    /// A standard normal deviate, by Box-Muller
+    fn normal(rng: &mut StdRng) -> f32 {
+        let (u, v): (f32, f32) = (rng.gen_range(f32::EPSILON..1.0), rng.gen());
+        (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+    }
+
+    /// Gaussian noise, with one channel of loud, wildly variable RFI
+    fn spectrum(rng: &mut StdRng) -> Vec<f32> {
+        let mut spectrum: Vec<f32> = (0..CHANNELS).map(|_| 10.0 + normal(rng)).collect();
+        spectrum[100] = 1000.0 + 500.0 * normal(rng);
+        spectrum
+    }
+
+    #[test]
+    fn test_detect() {
+        let mut rng = StdRng::seed_from_u64(2976);
+        let mut detector = PulseDetector::new(8.0, 0.0, 1);
+        // Nothing until there's a block of statistics, and then nothing from the RFI
+        for _ in 0..2 * NOISE_BLOCK {
+            assert!(detector.detect(&spectrum(&mut rng)).is_none());
+        }
+        // A broadband pulse of half a sigma per channel is ~22 sigma over all of them
+        let mut pulse = spectrum(&mut rng);
+        for x in pulse.iter_mut() {
+            *x += 0.5;
+        }
+        let snr = detector.detect(&pulse).unwrap();
+        assert!((snr - 0.5 * (CHANNELS as f32 - 1.0).sqrt()).abs() < 2.0);
+        // Where the RFI channel is excised rather than followed
+        let noise = detector.noise.as_ref().unwrap();
+        assert!(noise.weights[100] < 1e-3);
+    }
+
+    #[test]
+    fn test_holdoff() {
+        let mut rng = StdRng::seed_from_u64(2976);
+        let mut detector = PulseDetector::new(8.0, 1.0, 1);
+        for _ in 0..NOISE_BLOCK {
+            detector.detect(&spectrum(&mut rng));
+        }
+        let bright = |rng: &mut StdRng| {
+            spectrum(rng)
+                .into_iter()
+                .map(|x| x + 1.0)
+                .collect::<Vec<_>>()
+        };
+        assert!(detector.detect(&bright(&mut rng)).is_some());
+        assert!(detector.detect(&bright(&mut rng)).is_none());
+    }
+}
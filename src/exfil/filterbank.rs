@@ -1,61 +1,374 @@
-use crate::common::{
-    processed_payload_start_time, Stokes, BLOCK_TIMEOUT, CHANNELS, PACKET_CADENCE,
+use super::{
+    checksum::BlockChecksums,
+    exfil_first_packet, exfil_start_time,
+    requant::{valid_nbits, Requantizer, REQUANT_BLOCK},
+    stats::{sink_stats, SinkStats},
+    FrequencyPlan, StokesConsumer,
+};
+use crate::common::{station_id, Stokes, StokesVec, CHANNELS, PACKET_CADENCE};
+use crate::{
+    accounting::accounting, calibration::cal_schedule, flags::FlagRun, latency::latency_policy,
+    naming::time_policy, processing::channel_mask, timing,
 };
 use hifitime::prelude::*;
 use sigproc_filterbank::write::WriteFilterbank;
 use std::fs::File;
-use std::path::Path;
-use std::{io::Write, str::FromStr};
-use thingbuf::mpsc::blocking::Receiver;
-use thingbuf::mpsc::errors::RecvTimeoutError;
-use tokio::sync::broadcast;
-use tracing::info;
+use std::path::{Path, PathBuf};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
+
+/// Spectra per checksummed chunk of a filterbank
+const CHECKSUM_SPECTRA: u64 = 16384;
+
+/// How samples go into the file
+enum Packer {
+    /// Requantized, with the spectra held back until the first block of statistics is in
+    Int {
+        fb8: Option<SendFilterbank<u8>>,
+        fb16: Option<SendFilterbank<u16>>,
+        requant: Requantizer,
+        pending: Vec<Stokes>,
+    },
+}
+
+/// A [`WriteFilterbank`] we can hand to the consumer's thread. It's only `!Send` for the `PhantomData<*const T>` it
+/// keeps to remember its sample type.
+struct SendFilterbank<T>(WriteFilterbank<T>);
+
+// SAFETY: WriteFilterbank holds nothing but header fields, its pack buffer, and that marker
+unsafe impl<T> Send for SendFilterbank<T> {}
+
+impl<T> std::ops::Deref for SendFilterbank<T> {
+    type Target = WriteFilterbank<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> std::ops::DerefMut for SendFilterbank<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
 
 /// Basically the same as the dada consumer, except write to a filterbank instead with no chunking
-pub fn consumer(
-    stokes_rcv: Receiver<Stokes>,
+pub struct FilterbankConsumer {
+    name: String,
+    file: File,
+    file_path: PathBuf,
+    /// Sidecar of "<spectrum> <key> <value>" lines, for the metadata the filterbank header has nowhere to put
+    meta_file: File,
+    /// Sidecar of "<start spectrum> <length> <flags>" runs of data quality flags
+    flag_file: File,
+    fb: SendFilterbank<f32>,
+    packer: Option<Packer>,
+    /// Polarization products (as IFs) in every spectrum, which we find out from the first one
+    nifs: usize,
+    /// We will capture the timestamp on the first packet
+    first_payload: bool,
+    spectra_written: u64,
     downsample_factor: usize,
-    path: &Path,
-    mut shutdown: broadcast::Receiver<()>,
-) -> eyre::Result<()> {
-    info!("Starting filterbank consumer");
-    // Filename with ISO 8610 standard format
-    let fmt = Format::from_str("%Y%m%dT%H%M%S").unwrap();
-    let filename = format!("grex-{}.fil", Formatter::new(Epoch::now()?, fmt));
-    let file_path = path.join(filename);
-    // Create the file
-    let mut file = File::create(file_path)?;
-    // Create the filterbank context
-    let mut fb = WriteFilterbank::new(CHANNELS, 1);
-    // Setup the header stuff
-    fb.fch1 = Some(super::HIGHBAND_MID_FREQ); // End of band + half the step size
-    fb.foff = Some(-(super::BANDWIDTH / CHANNELS as f64));
-    fb.tsamp = Some(PACKET_CADENCE * downsample_factor as f64);
-    // We will capture the timestamp on the first packet
-    let mut first_payload = true;
-    loop {
-        if shutdown.try_recv().is_ok() {
-            info!("Exfil task stopping");
-            break;
-        }
-        // Grab next stokes
-        match stokes_rcv.recv_ref_timeout(BLOCK_TIMEOUT) {
-            Ok(stokes) => {
-                // Timestamp first one
-                if first_payload {
-                    first_payload = false;
-                    let time = processed_payload_start_time();
-                    fb.tstart = Some(time.to_mjd_tai_days());
-                    // Write out the header
-                    file.write_all(&fb.header_bytes()).unwrap();
+    /// Payload count of the start of the first spectrum
+    first_count: u64,
+    mask_generation: Option<u64>,
+    /// CRC32Cs of the data (after the header) in chunks of [`CHECKSUM_SPECTRA`]
+    checksums: BlockChecksums,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+    /// Time a spectrum can take to write before we fall behind
+    budget: Duration,
+}
+
+impl FilterbankConsumer {
+    pub fn new(
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        path: &Path,
+    ) -> eyre::Result<Self> {
+        let filename = format!("grex-{}.fil", time_policy().filename_stamp(Epoch::now()?));
+        let file_path = path.join(&filename);
+        // Create the file
+        let latency = latency_policy();
+        let file = latency.create(&file_path)?;
+        let meta_file = latency.create(&path.join(format!("{filename}.meta")))?;
+        let flag_file = latency.create(&path.join(format!("{filename}.flags")))?;
+        // Create the filterbank context
+        let mut fb = WriteFilterbank::new(CHANNELS, 1);
+        // Setup the header stuff
+        fb.fch1 = Some(freq_plan.fch1());
+        fb.foff = Some(freq_plan.foff());
+        fb.tsamp = Some(PACKET_CADENCE * downsample_factor as f64);
+        let budget = Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64);
+        let checksums = BlockChecksums::new("filterbank", &file_path.display().to_string());
+        Ok(Self {
+            name: "filterbank".to_owned(),
+            file,
+            file_path,
+            meta_file,
+            flag_file,
+            fb: SendFilterbank(fb),
+            packer: None,
+            nifs: 1,
+            first_payload: true,
+            spectra_written: 0,
+            downsample_factor,
+            first_count: 0,
+            mask_generation: None,
+            checksums,
+            written: accounting().output("filterbank"),
+            stats: sink_stats("filterbank", budget),
+            budget,
+        })
+    }
+}
+
+impl FilterbankConsumer {
+    /// Rename this consumer (and the output it's accounted under), to tell several filterbanks apart
+    pub fn named(self, name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            written: accounting().output(name),
+            stats: sink_stats(name, self.budget),
+            checksums: BlockChecksums::new(name, &self.file_path.display().to_string()),
+            ..self
+        }
+    }
+}
+
+impl FilterbankConsumer {
+    /// Write `nbits` (8, 16, or 32) samples, requantizing the integer ones against a running per-channel offset and scale
+    /// (recorded in the metadata sidecar whenever they change)
+    pub fn with_nbits(self, nbits: u8) -> eyre::Result<Self> {
+        let packer = match valid_nbits(nbits)? {
+            32 => None,
+            n => {
+                let mut fb8 =
+                    (n == 8).then(|| SendFilterbank(WriteFilterbank::<u8>::new(CHANNELS, 1)));
+                let mut fb16 =
+                    (n == 16).then(|| SendFilterbank(WriteFilterbank::<u16>::new(CHANNELS, 1)));
+                if let Some(fb) = &mut fb8 {
+                    (fb.fch1, fb.foff, fb.tsamp) = (self.fb.fch1, self.fb.foff, self.fb.tsamp);
+                }
+                if let Some(fb) = &mut fb16 {
+                    (fb.fch1, fb.foff, fb.tsamp) = (self.fb.fch1, self.fb.foff, self.fb.tsamp);
+                }
+                Some(Packer::Int {
+                    fb8,
+                    fb16,
+                    requant: Requantizer::new(n),
+                    pending: vec![],
+                })
+            }
+        };
+        Ok(Self { packer, ..self })
+    }
+
+    fn header_bytes(&mut self) -> Vec<u8> {
+        match &mut self.packer {
+            Some(Packer::Int { fb8: Some(fb), .. }) => {
+                fb.tstart = self.fb.tstart;
+                fb.header_bytes()
+            }
+            Some(Packer::Int { fb16: Some(fb), .. }) => {
+                fb.tstart = self.fb.tstart;
+                fb.header_bytes()
+            }
+            _ => self.fb.header_bytes(),
+        }
+    }
+
+    /// Write packed spectra to the file, accounting for them
+    fn write_packed(&mut self, packed: &[u8], spectra: u64) -> eyre::Result<()> {
+        let write_start = Instant::now();
+        if let Err(e) = self.file.write_all(packed) {
+            let e = eyre::Report::from(e);
+            self.stats.record_error(&e);
+            return Err(e);
+        }
+        self.stats.record_write(packed.len(), write_start.elapsed());
+        self.checksums.update(packed);
+        for _ in 0..spectra {
+            self.spectra_written += 1;
+            if self.spectra_written.is_multiple_of(CHECKSUM_SPECTRA) {
+                self.write_checksum()?;
+            }
+        }
+        self.written.fetch_add(spectra, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Pack a spectrum as this filterbank stores them (or with `None`, whatever's being held back), returning the bytes
+    /// and how many spectra they hold. Requantized spectra are held back until the first block's statistics are in.
+    fn pack(&mut self, stokes: Option<&[f32]>) -> eyre::Result<(Vec<u8>, u64)> {
+        let Some(Packer::Int {
+            fb8,
+            fb16,
+            requant,
+            pending,
+        }) = &mut self.packer
+        else {
+            return Ok(stokes.map_or((vec![], 0), |s| (self.fb.pack(s), 1)));
+        };
+        if let Some(stokes) = stokes {
+            requant.observe(stokes);
+            pending.push(Stokes::try_from(stokes)?);
+        }
+        let first_block = self.spectra_written == 0;
+        if first_block {
+            if pending.len() < REQUANT_BLOCK as usize && stokes.is_some() {
+                return Ok((vec![], 0));
+            }
+            requant.update();
+            writeln!(self.meta_file, "0 requant_offsets {:?}", requant.offsets)?;
+            writeln!(self.meta_file, "0 requant_scales {:?}", requant.scales)?;
+        }
+        let mut packed = vec![];
+        for spectrum in pending.iter() {
+            let samples = requant.quantize(spectrum);
+            match (fb8.as_mut(), fb16.as_mut()) {
+                (Some(fb), _) => {
+                    packed.extend(fb.pack(&samples.map(|s| s as u8).collect::<Vec<_>>()))
+                }
+                (_, Some(fb)) => packed.extend(fb.pack(&samples.collect::<Vec<_>>())),
+                _ => unreachable!("Integer filterbanks have one of the two"),
+            }
+        }
+        let spectra = pending.len() as u64;
+        pending.clear();
+        // Every block after the first is quantized against what we knew at the end of the one before
+        let next = self.spectra_written + spectra;
+        if !first_block && next.is_multiple_of(REQUANT_BLOCK) {
+            requant.update();
+            writeln!(
+                self.meta_file,
+                "{next} requant_offsets {:?}",
+                requant.offsets
+            )?;
+            writeln!(self.meta_file, "{next} requant_scales {:?}", requant.scales)?;
+        }
+        Ok((packed, spectra))
+    }
+
+    /// Finish the current chunk's checksum, as a "<spectrum after the chunk> crc32c <chunk crc> <running crc>" line
+    fn write_checksum(&mut self) -> eyre::Result<()> {
+        if let Some(checksum) = self.checksums.finish_block() {
+            writeln!(
+                self.meta_file,
+                "{} crc32c {:08x} {:08x}",
+                self.spectra_written, checksum.crc32c, checksum.running_crc32c
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl FilterbankConsumer {
+    /// Write the next spectrum, of Stokes I or (as several IFs) other polarization products
+    fn write_spectrum(&mut self, stokes: &[f32]) -> eyre::Result<()> {
+        let nifs = stokes.len() / CHANNELS;
+        if !self.first_payload && nifs != self.nifs {
+            eyre::bail!("Filterbank spectra can't switch polarization products");
+        }
+        // Timestamp first one
+        if self.first_payload {
+            self.first_payload = false;
+            if nifs > 1 {
+                if self.packer.is_some() {
+                    eyre::bail!("Multi-IF filterbanks can't be requantized, use 32 bits");
                 }
-                // Stream to FB
-                file.write_all(&fb.pack(&stokes))?;
+                let mut fb = WriteFilterbank::new(CHANNELS, nifs);
+                (fb.fch1, fb.foff, fb.tsamp) = (self.fb.fch1, self.fb.foff, self.fb.tsamp);
+                self.fb = SendFilterbank(fb);
+                self.nifs = nifs;
+            }
+            self.stats.set_target(self.file_path.display().to_string());
+            let time = exfil_start_time();
+            self.fb.tstart = Some(time_policy().mjd(time));
+            // Write out the header
+            let header = self.header_bytes();
+            self.file.write_all(&header).unwrap();
+            writeln!(self.meta_file, "0 station {}", station_id())?;
+            writeln!(self.meta_file, "0 timing_degraded {}", timing::degraded())?;
+            writeln!(
+                self.meta_file,
+                "0 tstart_standard {}",
+                time_policy().mjd_standard()
+            )?;
+            self.first_count = exfil_first_packet();
+            // Where the noise source cycle was when we started, later lines are "<spectrum> cal_on|cal_off <payload count>"
+            if let Some(cal) = cal_schedule() {
+                writeln!(self.meta_file, "0 first_payload {}", self.first_count)?;
+                writeln!(
+                    self.meta_file,
+                    "0 cal_initially_on {}",
+                    cal.is_on(self.first_count)
+                )?;
+            }
+        }
+        // Spectra held back for requantization still count
+        let index = self.spectra_written
+            + match &self.packer {
+                Some(Packer::Int { pending, .. }) => pending.len() as u64,
+                _ => 0,
+            };
+        // Noise source switches within this spectrum, to the payload
+        if let Some(cal) = cal_schedule() {
+            let ds = self.downsample_factor as u64;
+            let start = self.first_count + index * ds;
+            for (count, on) in cal.boundaries(start..start + ds) {
+                let state = if on { "cal_on" } else { "cal_off" };
+                writeln!(self.meta_file, "{index} {state} {count}")?;
             }
-            Err(RecvTimeoutError::Timeout) => continue,
-            Err(RecvTimeoutError::Closed) => break,
-            Err(_) => unreachable!(),
         }
+        // Record any change to the channel mask (to within the depth of the exfil channel)
+        let generation = channel_mask().generation();
+        if self.mask_generation != Some(generation) {
+            self.mask_generation = Some(generation);
+            writeln!(
+                self.meta_file,
+                "{index} blanked {:?}",
+                channel_mask().channels()
+            )?;
+        }
+        // Stream to FB
+        let (packed, spectra) = self.pack(Some(stokes))?;
+        self.write_packed(&packed, spectra)
+    }
+}
+
+impl StokesConsumer for FilterbankConsumer {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        self.write_spectrum(stokes)
+    }
+
+    fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+        self.write_spectrum(stokes)
+    }
+
+    fn flags(&mut self, run: &FlagRun) -> eyre::Result<()> {
+        writeln!(self.flag_file, "{} {} {}", run.start, run.len, run.flags)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        // Anything still held back for requantization
+        let (packed, spectra) = self.pack(None)?;
+        self.write_packed(&packed, spectra)?;
+        self.file.flush()?;
+        self.write_checksum()?;
+        self.meta_file.flush()?;
+        self.flag_file.flush()?;
+        Ok(())
     }
-    Ok(())
 }
@@ -0,0 +1,39 @@
+//! Data quality flags on their own, for when they're routed somewhere the stokes data isn't
+use super::StokesConsumer;
+use crate::{common::Stokes, flags::FlagRun, latency::latency_policy, naming::time_policy};
+use hifitime::prelude::*;
+use std::{fs::File, io::Write, path::Path};
+
+/// Writes "<start spectrum> <length> <flags>" runs to a text file, ignoring the spectra themselves
+pub struct FlagLog {
+    file: File,
+}
+
+impl FlagLog {
+    pub fn new(path: &Path) -> eyre::Result<Self> {
+        let filename = format!("grex-{}.flags", time_policy().filename_stamp(Epoch::now()?));
+        Ok(Self {
+            file: latency_policy().create(&path.join(filename))?,
+        })
+    }
+}
+
+impl StokesConsumer for FlagLog {
+    fn name(&self) -> &str {
+        "flags"
+    }
+
+    fn consume(&mut self, _stokes: &Stokes) -> eyre::Result<()> {
+        Ok(())
+    }
+
+    fn flags(&mut self, run: &FlagRun) -> eyre::Result<()> {
+        writeln!(self.file, "{} {} {}", run.start, run.len, run.flags)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
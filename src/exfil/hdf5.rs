@@ -0,0 +1,170 @@
+//! Stokes I in chunked (and optionally compressed) HDF5, with the axes and observation metadata alongside.
+//! Written through netCDF-4, whose files are HDF5 underneath, so anything that reads one reads the other.
+use super::{
+    exfil_start_time,
+    stats::{sink_stats, SinkStats},
+    FrequencyPlan, StokesConsumer,
+};
+use crate::{
+    accounting::accounting,
+    common::{station_id, Stokes, CHANNELS, PACKET_CADENCE},
+    naming::time_policy,
+    processing::channel_mask,
+    timing,
+};
+use hifitime::prelude::*;
+use ndarray::{s, Array1, Array2};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+/// Writes each chunk of spectra as it fills, so a chunk is the most we lose if we go down uncleanly
+pub struct Hdf5Consumer {
+    file: netcdf::FileMut,
+    file_path: PathBuf,
+    /// Spectra waiting for their chunk to fill
+    buffer: Array2<f32>,
+    buffered: usize,
+    spectra_written: usize,
+    /// Time per spectrum (s)
+    tsamp: f64,
+    /// MJD of the first spectrum, once we've seen it
+    tstart: Option<f64>,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+}
+
+impl Hdf5Consumer {
+    /// Write to a new file in `path`, in chunks of `chunk` spectra, deflated at `compression` (1-9) if given
+    pub fn new(
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        path: &Path,
+        chunk: usize,
+        compression: Option<i32>,
+    ) -> eyre::Result<Self> {
+        if chunk == 0 {
+            eyre::bail!("HDF5 chunks need at least one spectrum");
+        }
+        let filename = format!("grex-{}.h5", time_policy().filename_stamp(Epoch::now()?));
+        let file_path = path.join(filename);
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        let mut file = netcdf::create(&file_path)?;
+        file.add_attribute("station", station_id())?;
+        file.add_attribute("software_version", env!("CARGO_PKG_VERSION"))?;
+        file.add_attribute("tsamp", tsamp)?;
+        file.add_attribute("downsample_factor", downsample_factor as u64)?;
+        file.add_attribute("fch1", freq_plan.fch1())?;
+        file.add_attribute("foff", freq_plan.foff())?;
+        file.add_attribute("nchans", CHANNELS as u64)?;
+
+        file.add_unlimited_dimension("time")?;
+        file.add_dimension("freq", CHANNELS)?;
+
+        let mut freq = file.add_variable::<f64>("freq", &["freq"])?;
+        freq.put_attribute("units", "MHz")?;
+        freq.put(.., freq_plan.freqs().view())?;
+
+        let mut time = file.add_variable::<f64>("time", &["time"])?;
+        time.put_attribute(
+            "units",
+            format!("MJD ({})", time_policy().mjd_standard()).as_str(),
+        )?;
+
+        let mut stokes = file.add_variable::<f32>("stokes_i", &["time", "freq"])?;
+        stokes.put_attribute("long_name", "Downsampled Stokes I")?;
+        stokes.set_chunking(&[chunk, CHANNELS])?;
+        if let Some(level) = compression {
+            stokes.set_compression(level, true)?;
+        }
+
+        let budget = std::time::Duration::from_secs_f64(tsamp);
+        Ok(Self {
+            file,
+            file_path,
+            buffer: Array2::zeros((chunk, CHANNELS)),
+            buffered: 0,
+            spectra_written: 0,
+            tsamp,
+            tstart: None,
+            written: accounting().output("hdf5"),
+            stats: sink_stats("hdf5", budget),
+        })
+    }
+
+    /// Write out whatever spectra are buffered, with their times
+    fn write_chunk(&mut self) -> eyre::Result<()> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+        let write_start = Instant::now();
+        let (start, n) = (self.spectra_written, self.buffered);
+        let tstart = self.tstart.unwrap_or_default();
+        let mjds = Array1::from_shape_fn(n, |i| tstart + (start + i) as f64 * self.tsamp / 86400.0);
+        let mut time = self
+            .file
+            .variable_mut("time")
+            .expect("Time variable is created with the file");
+        time.put(start..start + n, mjds.view())?;
+        let mut stokes = self
+            .file
+            .variable_mut("stokes_i")
+            .expect("Stokes variable is created with the file");
+        stokes.put((start..start + n, ..), self.buffer.slice(s![..n, ..]))?;
+        self.stats.record_write(
+            n * CHANNELS * std::mem::size_of::<f32>(),
+            write_start.elapsed(),
+        );
+        self.spectra_written += n;
+        self.buffered = 0;
+        Ok(())
+    }
+}
+
+impl StokesConsumer for Hdf5Consumer {
+    fn name(&self) -> &str {
+        "hdf5"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        if self.tstart.is_none() {
+            self.stats.set_target(self.file_path.display().to_string());
+            let tstart = time_policy().mjd(exfil_start_time());
+            self.tstart = Some(tstart);
+            self.file.add_attribute("tstart", tstart)?;
+            self.file
+                .add_attribute("tstart_standard", time_policy().mjd_standard())?;
+            self.file
+                .add_attribute("timing_degraded", u8::from(timing::degraded()))?;
+            let blanked: Vec<u64> = channel_mask()
+                .channels()
+                .into_iter()
+                .map(|c| c as u64)
+                .collect();
+            self.file.add_attribute("blanked", blanked)?;
+        }
+        self.buffer
+            .row_mut(self.buffered)
+            .iter_mut()
+            .zip(stokes)
+            .for_each(|(b, s)| *b = *s);
+        self.buffered += 1;
+        self.written.fetch_add(1, Ordering::Relaxed);
+        if self.buffered == self.buffer.nrows() {
+            if let Err(e) = self.write_chunk() {
+                self.stats.record_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        self.write_chunk()
+    }
+}
@@ -0,0 +1,241 @@
+//! Redundant exfil, fanning every spectrum out to several consumers that each write from their own thread
+use super::StokesConsumer;
+use crate::{
+    common::{Stokes, StokesVec, CHANNELS},
+    flags::FlagRun,
+};
+use clap::ValueEnum;
+use eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
+use std::{sync::mpsc, thread::JoinHandle};
+use thingbuf::mpsc::{
+    blocking::{channel, Receiver, Sender},
+    errors::TrySendError,
+};
+use tracing::{error, info, warn};
+
+/// What happens to a spectrum when one consumer's queue is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TeePolicy {
+    /// That consumer misses it, the rest carry on
+    #[default]
+    Drop,
+    /// Wait for room, holding up every consumer (and eventually processing) until the slow one catches up
+    Block,
+}
+
+/// One of the mirrored outputs, with its own backlog
+struct Replica {
+    name: String,
+    /// Stokes I or other polarization products, the writer works out which from the length
+    sender: Option<Sender<StokesVec>>,
+    /// Flag runs are rare and tiny, so they don't need a bounded backlog
+    flag_sender: mpsc::Sender<FlagRun>,
+    writer: Option<JoinHandle<eyre::Result<()>>>,
+    /// Spectra this replica missed because its backlog was full
+    dropped: u64,
+}
+
+impl Replica {
+    /// Stop the writer thread, returning whatever it failed with
+    fn stop(&mut self) -> eyre::Result<()> {
+        drop(self.sender.take());
+        match self.writer.take() {
+            Some(writer) => writer
+                .join()
+                .map_err(|_| eyre!("{} writer thread panicked", self.name))?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the same stream to every inner consumer, carrying on as long as at least one of them is healthy.
+/// A slow replica drops spectra once its backlog fills rather than holding up the others.
+pub struct MirrorConsumer {
+    replicas: Vec<Replica>,
+    policy: TeePolicy,
+}
+
+fn replica_loop(
+    mut consumer: Box<dyn StokesConsumer>,
+    stokes_rcv: Receiver<StokesVec>,
+    flag_rcv: mpsc::Receiver<FlagRun>,
+) -> eyre::Result<()> {
+    while let Some(stokes) = stokes_rcv.recv_ref() {
+        while let Ok(run) = flag_rcv.try_recv() {
+            consumer.flags(&run)?;
+        }
+        if stokes.len() == CHANNELS {
+            consumer.consume(&stokes.iter().copied().collect())?;
+        } else {
+            consumer.consume_full(&stokes)?;
+        }
+    }
+    while let Ok(run) = flag_rcv.try_recv() {
+        consumer.flags(&run)?;
+    }
+    consumer.finish()
+}
+
+impl MirrorConsumer {
+    pub fn new(
+        consumers: Vec<Box<dyn StokesConsumer>>,
+        backlog: usize,
+        policy: TeePolicy,
+    ) -> eyre::Result<Self> {
+        let mut replicas = vec![];
+        for consumer in consumers {
+            let name = consumer.name().to_owned();
+            let (sender, receiver) = channel(backlog);
+            let (flag_sender, flag_receiver) = mpsc::channel();
+            let writer = std::thread::Builder::new()
+                .name(format!("mirror_{name}"))
+                .spawn(move || replica_loop(consumer, receiver, flag_receiver))?;
+            replicas.push(Replica {
+                name,
+                sender: Some(sender),
+                flag_sender,
+                writer: Some(writer),
+                dropped: 0,
+            });
+        }
+        Ok(Self { replicas, policy })
+    }
+
+    /// Hand a spectrum (of whatever polarization products) to every replica, dropping the ones that have failed
+    fn send(&mut self, stokes: &[f32]) -> eyre::Result<()> {
+        let mut failed = vec![];
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+            let Some(sender) = &replica.sender else {
+                continue;
+            };
+            let sent = match self.policy {
+                TeePolicy::Drop => sender.try_send_ref(),
+                TeePolicy::Block => sender.send_ref().map_err(|_| TrySendError::Closed(())),
+            };
+            match sent {
+                Ok(mut slot) => {
+                    slot.clear();
+                    slot.try_extend_from_slice(stokes)?;
+                }
+                Err(TrySendError::Full(_)) => {
+                    if replica.dropped == 0 {
+                        warn!(
+                            replica = replica.name,
+                            "Mirror replica fell behind, dropping spectra"
+                        );
+                    }
+                    replica.dropped += 1;
+                }
+                // The writer thread has exited, which only happens on error
+                Err(_) => failed.push(i),
+            }
+        }
+        for i in failed.into_iter().rev() {
+            let mut replica = self.replicas.remove(i);
+            match replica.stop() {
+                Err(e) => error!(replica = replica.name, "Mirror replica failed - {e}"),
+                Ok(_) => error!(
+                    replica = replica.name,
+                    "Mirror replica stopped unexpectedly"
+                ),
+            }
+        }
+        if self.replicas.is_empty() {
+            bail!("Every mirror replica has failed");
+        }
+        Ok(())
+    }
+}
+
+impl StokesConsumer for MirrorConsumer {
+    fn name(&self) -> &str {
+        "mirror"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        self.send(stokes)
+    }
+
+    fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+        self.send(stokes)
+    }
+
+    fn flags(&mut self, run: &FlagRun) -> eyre::Result<()> {
+        for replica in &self.replicas {
+            // A replica that's gone away will be noticed on the next spectrum
+            let _ = replica.flag_sender.send(*run);
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        let mut healthy = 0;
+        for replica in &mut self.replicas {
+            match replica.stop() {
+                Ok(_) => {
+                    healthy += 1;
+                    info!(
+                        replica = replica.name,
+                        dropped = replica.dropped,
+                        "Mirror replica finished"
+                    );
+                }
+                Err(e) => error!(replica = replica.name, "Mirror replica failed - {e}"),
+            }
+        }
+        if healthy == 0 {
+            bail!("Every mirror replica has failed");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the length of every spectrum, and whether it came as Stokes I
+    struct Recorder(Arc<Mutex<Vec<(bool, usize)>>>);
+
+    impl StokesConsumer for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+            self.0.lock().unwrap().push((true, stokes.len()));
+            Ok(())
+        }
+
+        fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+            self.0.lock().unwrap().push((false, stokes.len()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_polarization_products() {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let mut mirror =
+            MirrorConsumer::new(vec![Box::new(Recorder(seen.clone()))], 4, TeePolicy::Block)
+                .unwrap();
+        let dual: StokesVec = (0..2 * CHANNELS).map(|x| x as f32).collect();
+        let full: StokesVec = (0..4 * CHANNELS).map(|x| x as f32).collect();
+        let i: Stokes = (0..CHANNELS).map(|x| x as f32).collect();
+        mirror.consume_full(&dual).unwrap();
+        mirror.consume_full(&full).unwrap();
+        mirror.consume(&i).unwrap();
+        mirror.finish().unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (false, 2 * CHANNELS),
+                (false, 4 * CHANNELS),
+                (true, CHANNELS)
+            ]
+        );
+    }
+}
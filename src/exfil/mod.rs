@@ -1,7 +1,322 @@
+use crate::{
+    common::{
+        payload_time, Stokes, StokesVec, BLOCK_TIMEOUT, CHANNELS, FIRST_PACKET, PACKET_CADENCE,
+    },
+    flags::FlagRun,
+    profiling::profile,
+    quality::quality_inputs,
+};
+use clap::ValueEnum;
+use hifitime::Epoch;
+use ndarray::Array1;
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{blocking::Receiver, errors::RecvTimeoutError};
+use tokio::sync::broadcast;
+use tracing::info;
+
+pub mod checksum;
 pub mod dada;
+pub mod dedisperse;
+pub mod detect;
 pub mod dummy;
 pub mod filterbank;
+pub mod flaglog;
+pub mod hdf5;
+pub mod mirror;
+pub mod parquet;
+pub mod power;
+pub mod psrfits;
+pub mod relay;
+pub mod requant;
+pub mod ring;
+pub mod routes;
+pub mod spead;
+pub mod stats;
+pub mod stream;
+pub mod supervisor;
+pub mod tap;
+
+/// Ordering of the frequency axis of a block of channels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum ChannelOrder {
+    /// Channel 0 is the highest frequency
+    #[default]
+    Descending,
+    /// Channel 0 is the lowest frequency
+    Ascending,
+}
+
+/// The ordering of the stokes spectra handed to every exfil consumer, normalized once in processing
+pub const STOKES_ORDER: ChannelOrder = ChannelOrder::Descending;
+
+/// Which side of the LO the sky band is mixed down from
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Sideband {
+    /// The band sits below the LO
+    #[default]
+    Lower,
+    /// The band sits above the LO
+    Upper,
+}
+
+/// Mapping of channel index to sky frequency, set by the RF front end and the gateware (all in MHz)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct FrequencyPlan {
+    /// Local oscillator frequency
+    pub lo: f64,
+    /// Total bandwidth across all channels
+    pub bandwidth: f64,
+    pub sideband: Sideband,
+    /// Ordering of the channels this plan describes
+    pub order: ChannelOrder,
+}
+
+impl Default for FrequencyPlan {
+    /// The standard GReX front end
+    fn default() -> Self {
+        Self {
+            lo: 1530.0,
+            bandwidth: 250.0,
+            sideband: Sideband::Lower,
+            order: ChannelOrder::Descending,
+        }
+    }
+}
+
+impl FrequencyPlan {
+    /// The same plan, describing channels in a different order
+    pub fn reordered(self, order: ChannelOrder) -> Self {
+        Self { order, ..self }
+    }
+
+    pub fn low_edge(&self) -> f64 {
+        match self.sideband {
+            Sideband::Lower => self.lo - self.bandwidth,
+            Sideband::Upper => self.lo,
+        }
+    }
+
+    pub fn high_edge(&self) -> f64 {
+        self.low_edge() + self.bandwidth
+    }
+
+    /// Center frequency of the whole band
+    pub fn center(&self) -> f64 {
+        self.low_edge() + self.bandwidth / 2.0
+    }
+
+    /// Center frequency of channel 0
+    pub fn fch1(&self) -> f64 {
+        let half_chan = self.bandwidth / CHANNELS as f64 / 2.0;
+        match self.order {
+            ChannelOrder::Descending => self.high_edge() - half_chan,
+            ChannelOrder::Ascending => self.low_edge() + half_chan,
+        }
+    }
+
+    /// Signed channel spacing
+    pub fn foff(&self) -> f64 {
+        match self.order {
+            ChannelOrder::Descending => -(self.bandwidth / CHANNELS as f64),
+            ChannelOrder::Ascending => self.bandwidth / CHANNELS as f64,
+        }
+    }
+
+    /// Signed bandwidth, negative if frequency decreases with channel index
+    pub fn signed_bandwidth(&self) -> f64 {
+        self.foff() * CHANNELS as f64
+    }
+
+    /// Center frequencies of every channel, in channel order
+    pub fn freqs(&self) -> Array1<f64> {
+        Array1::from_shape_fn(CHANNELS, |i| self.fch1() + i as f64 * self.foff())
+    }
+}
+
+/// Payload count of the first spectrum the running stokes consumers got, if they were switched in partway through
+static EXFIL_FIRST_PACKET: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Start the stokes consumers built from now on at this payload count, rather than the first processed payload
+pub fn set_exfil_first_packet(count: u64) {
+    EXFIL_FIRST_PACKET.store(count, Ordering::Release);
+}
+
+/// Payload count of the first spectrum the running stokes consumers got, which is the first processed payload unless
+/// they were switched in at runtime
+pub fn exfil_first_packet() -> u64 {
+    match EXFIL_FIRST_PACKET.load(Ordering::Acquire) {
+        u64::MAX => FIRST_PACKET.load(Ordering::Acquire),
+        count => count,
+    }
+}
+
+/// Get the Epoch of the first spectrum the running stokes consumers got
+pub fn exfil_start_time() -> Epoch {
+    payload_time(exfil_first_packet())
+}
+
+/// A sink for the downsampled stokes spectra coming out of processing.
+/// Implement this to add a custom exfil format, and register it with [`crate::pipeline::PipelineBuilder::with_consumer`].
+pub trait StokesConsumer: Send {
+    /// Name of this consumer, for logs and accounting
+    fn name(&self) -> &str;
+    /// Consume the next spectrum (in [`STOKES_ORDER`]). Spectra arrive consecutively, starting with the one at
+    /// [`exfil_first_packet`] (the first processed payload, unless the consumer was switched in at runtime).
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()>;
+    /// Consume the next spectrum of polarization products, either all four Stokes parameters (as I, Q, U, then V) or
+    /// the power in each polarization (as A then B). Consumers that only know about Stokes I get that (and only that)
+    /// by default.
+    fn consume_full(&mut self, stokes: &StokesVec) -> eyre::Result<()> {
+        self.consume(&stokes_i_of(stokes))
+    }
+    /// Record a run of data quality flags. Runs arrive in order, but may arrive before the spectra they describe.
+    fn flags(&mut self, _run: &FlagRun) -> eyre::Result<()> {
+        Ok(())
+    }
+    /// Called once when the pipeline is stopping, to flush anything outstanding
+    fn finish(&mut self) -> eyre::Result<()> {
+        Ok(())
+    }
+}
+
+/// The Stokes I of a spectrum of polarization products
+pub fn stokes_i_of(stokes: &StokesVec) -> Stokes {
+    match stokes.len() {
+        // Dual-pol, so the sum of the two
+        n if n == 2 * CHANNELS => stokes[..CHANNELS]
+            .iter()
+            .zip(&stokes[CHANNELS..])
+            .map(|(a, b)| a + b)
+            .collect(),
+        _ => stokes[..CHANNELS].iter().copied().collect(),
+    }
+}
+
+/// Where the consumer task gets its spectra from, either Stokes I or some other polarization products
+pub enum StokesSource {
+    I(Receiver<Stokes>),
+    Full(Receiver<StokesVec>),
+}
+
+impl StokesSource {
+    fn backlog(&self) -> f64 {
+        match self {
+            Self::I(r) => r.len() as f64 / r.capacity() as f64,
+            Self::Full(r) => r.len() as f64 / r.capacity() as f64,
+        }
+    }
+}
+
+/// Hand the consumer every flag run that's come in so far
+fn drain_flags(
+    consumer: &mut dyn StokesConsumer,
+    flag_rcv: &std::sync::mpsc::Receiver<FlagRun>,
+) -> eyre::Result<()> {
+    while let Ok(run) = flag_rcv.try_recv() {
+        consumer.flags(&run)?;
+    }
+    Ok(())
+}
+
+/// How many spectra between updates of the exfil backlog
+const BACKLOG_UPDATE_INTERVAL: usize = 1024;
+
+/// Feed spectra from the exfil channel to a consumer until we're told to stop
+#[allow(clippy::too_many_arguments)]
+pub fn consumer_task(
+    mut consumer: Box<dyn StokesConsumer>,
+    mut relays: Vec<relay::Relay>,
+    mut tap: Option<tap::Tap>,
+    mut detector: Option<detect::PulseDetector>,
+    mut ring: Option<ring::StokesRing>,
+    stokes_rcv: StokesSource,
+    flag_rcv: std::sync::mpsc::Receiver<FlagRun>,
+    downsample_factor: usize,
+    mut supervisor: Option<supervisor::Supervisor>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting {} consumer", consumer.name());
+    let budget = Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64);
+    let stage = profile("exfil", budget);
+    // Sinks record their own writes, but we can catch errors from any of them here
+    let mut sink = stats::sink_stats(consumer.name(), budget);
+    let mut consumed = 0usize;
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Exfil task stopping");
+            break;
+        }
+        if let Some(supervisor) = &mut supervisor {
+            if supervisor.poll(&mut consumer, consumed as u64) {
+                sink = stats::sink_stats(consumer.name(), budget);
+            }
+        }
+        // Relays, the tap, the detector, and the ring only ever see Stokes I
+        let received = match &stokes_rcv {
+            StokesSource::I(r) => r.recv_ref_timeout(BLOCK_TIMEOUT).map(|stokes| {
+                let iter_start = Instant::now();
+                let result = drain_flags(consumer.as_mut(), &flag_rcv)
+                    .and_then(|_| consumer.consume(&stokes));
+                (iter_start, result, (*stokes).clone())
+            }),
+            StokesSource::Full(r) => r.recv_ref_timeout(BLOCK_TIMEOUT).map(|stokes| {
+                let iter_start = Instant::now();
+                let result = drain_flags(consumer.as_mut(), &flag_rcv)
+                    .and_then(|_| consumer.consume_full(&stokes));
+                (iter_start, result, stokes_i_of(&stokes))
+            }),
+        };
+        match received {
+            Ok((iter_start, result, stokes)) => {
+                if let Err(e) = result {
+                    sink.record_error(&e);
+                    return Err(e);
+                }
+                for relay in &mut relays {
+                    relay.push(&stokes);
+                }
+                if let Some(tap) = &mut tap {
+                    tap.push(&stokes);
+                }
+                if let Some(detector) = &mut detector {
+                    detector.push(&stokes);
+                }
+                if let Some(ring) = &mut ring {
+                    ring.push(&stokes);
+                }
+                stage.record(iter_start.elapsed());
+                consumed += 1;
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Closed) => break,
+            Err(_) => unreachable!(),
+        }
+        if consumed.is_multiple_of(BACKLOG_UPDATE_INTERVAL) {
+            quality_inputs().lock().unwrap().exfil_backlog = Some(stokes_rcv.backlog());
+        }
+    }
+    // Processing closes out the last run on its way down
+    while let Ok(run) = flag_rcv.recv_timeout(BLOCK_TIMEOUT) {
+        consumer.flags(&run)?;
+    }
+    consumer.finish()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
 
-// Set by hardware (in MHz)
-pub const HIGHBAND_MID_FREQ: f64 = 1529.93896484375; // Highend of band - half the channel spacing
-pub const BANDWIDTH: f64 = 250.0;
+    #[test]
+    fn test_default_plan() {
+        let plan = FrequencyPlan::default();
+        assert_eq!(plan.fch1(), 1529.93896484375);
+        assert_eq!(plan.center(), 1405.0);
+        assert_eq!(plan.signed_bandwidth(), -250.0);
+        let flipped = plan.reordered(ChannelOrder::Ascending);
+        assert_eq!(flipped.freqs()[0], plan.freqs()[CHANNELS - 1]);
+    }
+}
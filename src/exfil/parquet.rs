@@ -0,0 +1,309 @@
+//! Parquet files of Stokes I, so spectra can be loaded straight into pandas or polars (or anything else with Arrow).
+//! Every row is a spectrum, with its payload count (`count`), MJD in our time standard (`mjd`), and one float column per
+//! channel (`c0000` on, in [`super::STOKES_ORDER`]). Spectra are batched into row groups, and the channel frequencies and
+//! observation metadata go in the file's key-value metadata.
+//!
+//! Files are written uncompressed and without dictionaries, as the spectra hardly repeat. The footer is only written when
+//! a file is closed, so a file that was being written when we died can't be read.
+use super::{
+    exfil_first_packet,
+    stats::{sink_stats, SinkStats},
+    FrequencyPlan, StokesConsumer,
+};
+use crate::{
+    accounting::accounting,
+    common::{payload_time, station_id, Stokes, CHANNELS, PACKET_CADENCE},
+    naming::time_policy,
+};
+use hifitime::prelude::*;
+use parquet::{
+    basic::{Repetition, Type as PhysicalType},
+    data_type::{DoubleType, FloatType, Int64Type},
+    file::{
+        metadata::KeyValue,
+        properties::{WriterProperties, WriterPropertiesPtr},
+        writer::SerializedFileWriter,
+    },
+    schema::types::{Type, TypePtr},
+};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// The columns of every row, a spectrum: its payload count, its MJD, then one per channel
+fn schema() -> eyre::Result<TypePtr> {
+    let column = |name: &str, ty| {
+        Type::primitive_type_builder(name, ty)
+            .with_repetition(Repetition::REQUIRED)
+            .build()
+            .map(Arc::new)
+    };
+    let mut fields = vec![
+        column("count", PhysicalType::INT64)?,
+        column("mjd", PhysicalType::DOUBLE)?,
+    ];
+    for c in 0..CHANNELS {
+        fields.push(column(&format!("c{c:04}"), PhysicalType::FLOAT)?);
+    }
+    Ok(Arc::new(
+        Type::group_type_builder("schema")
+            .with_fields(fields)
+            .build()?,
+    ))
+}
+
+/// Writes Stokes I to Parquet files, in row groups of `batch` spectra, `batches_per_file` to a file
+pub struct ParquetConsumer {
+    file: Option<SerializedFileWriter<File>>,
+    schema: TypePtr,
+    path: PathBuf,
+    /// Files are named after this, and numbered
+    stem: String,
+    freq_plan: FrequencyPlan,
+    downsample_factor: u64,
+    batch: usize,
+    batches_per_file: usize,
+    /// Spectra waiting for their row group to fill, with their payload counts and MJDs
+    pending: Vec<(u64, f64, Stokes)>,
+    /// Row groups written to this file
+    row_groups: usize,
+    files: usize,
+    /// Payload count of the start of the first spectrum, once we've seen it
+    first_count: Option<u64>,
+    spectra: u64,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+}
+
+impl ParquetConsumer {
+    pub fn new(
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        path: &Path,
+        batch: usize,
+        batches_per_file: usize,
+    ) -> eyre::Result<Self> {
+        if batch == 0 || batches_per_file == 0 {
+            eyre::bail!("Parquet files need at least one row group of at least one spectrum");
+        }
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        Ok(Self {
+            file: None,
+            schema: schema()?,
+            path: path.to_owned(),
+            stem: format!("grex-{}", time_policy().filename_stamp(Epoch::now()?)),
+            freq_plan,
+            downsample_factor: downsample_factor as u64,
+            batch,
+            batches_per_file,
+            pending: Vec::with_capacity(batch),
+            row_groups: 0,
+            files: 0,
+            first_count: None,
+            spectra: 0,
+            written: accounting().output("parquet"),
+            stats: sink_stats("parquet", Duration::from_secs_f64(tsamp)),
+        })
+    }
+
+    fn metadata(&self) -> Vec<KeyValue> {
+        let freqs: Vec<_> = self
+            .freq_plan
+            .freqs()
+            .iter()
+            .map(|f| f.to_string())
+            .collect();
+        [
+            ("station".to_owned(), station_id().to_owned()),
+            ("nchans".to_owned(), CHANNELS.to_string()),
+            ("fch1_mhz".to_owned(), self.freq_plan.fch1().to_string()),
+            ("foff_mhz".to_owned(), self.freq_plan.foff().to_string()),
+            ("freqs_mhz".to_owned(), format!("[{}]", freqs.join(","))),
+            (
+                "tsamp_s".to_owned(),
+                (PACKET_CADENCE * self.downsample_factor as f64).to_string(),
+            ),
+            (
+                "downsample_factor".to_owned(),
+                self.downsample_factor.to_string(),
+            ),
+            (
+                "time_standard".to_owned(),
+                time_policy().mjd_standard().to_owned(),
+            ),
+        ]
+        .into_iter()
+        .map(|(key, value)| KeyValue::new(key, value))
+        .collect()
+    }
+
+    fn properties(&self) -> WriterPropertiesPtr {
+        Arc::new(
+            WriterProperties::builder()
+                .set_dictionary_enabled(false)
+                .set_key_value_metadata(Some(self.metadata()))
+                .set_created_by("GReX-T0".to_owned())
+                .build(),
+        )
+    }
+
+    /// Start the next file in the sequence
+    fn open(&mut self) -> eyre::Result<()> {
+        self.files += 1;
+        let file_path = self
+            .path
+            .join(format!("{}_{:04}.parquet", self.stem, self.files));
+        info!("Writing Parquet to {}", file_path.display());
+        self.stats.set_target(file_path.display().to_string());
+        self.file = Some(SerializedFileWriter::new(
+            File::create(&file_path)?,
+            self.schema.clone(),
+            self.properties(),
+        )?);
+        self.row_groups = 0;
+        Ok(())
+    }
+
+    /// Write the footer, which makes the file readable
+    fn close(&mut self) -> eyre::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.close()?;
+        }
+        Ok(())
+    }
+
+    /// Write the pending spectra as a row group, closing the file if it's full
+    fn write_row_group(&mut self) -> eyre::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        if self.file.is_none() {
+            self.open()?;
+        }
+        let write_start = Instant::now();
+        let file = self.file.as_mut().expect("Opened above");
+        let mut row_group = file.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            match index {
+                0 => {
+                    let counts: Vec<_> = self.pending.iter().map(|(c, _, _)| *c as i64).collect();
+                    column
+                        .typed::<Int64Type>()
+                        .write_batch(&counts, None, None)?;
+                }
+                1 => {
+                    let mjds: Vec<_> = self.pending.iter().map(|(_, m, _)| *m).collect();
+                    column
+                        .typed::<DoubleType>()
+                        .write_batch(&mjds, None, None)?;
+                }
+                _ => {
+                    let channel: Vec<_> =
+                        self.pending.iter().map(|(_, _, s)| s[index - 2]).collect();
+                    column
+                        .typed::<FloatType>()
+                        .write_batch(&channel, None, None)?;
+                }
+            }
+            column.close()?;
+            index += 1;
+        }
+        let written = row_group.close()?;
+        self.row_groups += 1;
+        self.stats
+            .record_write(written.compressed_size() as usize, write_start.elapsed());
+        self.stats.record_block();
+        self.pending.clear();
+        if self.row_groups == self.batches_per_file {
+            self.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl StokesConsumer for ParquetConsumer {
+    fn name(&self) -> &str {
+        "parquet"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        let first = *self.first_count.get_or_insert_with(exfil_first_packet);
+        let count = first + self.spectra * self.downsample_factor;
+        self.spectra += 1;
+        self.pending.push((
+            count,
+            time_policy().mjd(payload_time(count)),
+            stokes.clone(),
+        ));
+        self.written.fetch_add(1, Ordering::Relaxed);
+        if self.pending.len() == self.batch {
+            if let Err(e) = self.write_row_group() {
+                self.stats.record_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        self.write_row_group()?;
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parquet::{
+        file::reader::{FileReader, SerializedFileReader},
+        record::RowAccessor,
+    };
+
+    #[test]
+    fn test_readback() {
+        crate::testing::seed_start_time();
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        let mut consumer = ParquetConsumer::new(4, FrequencyPlan::default(), dir, 3, 2).unwrap();
+        // Two full row groups in the first file, and one short one in the second
+        for t in 0..8 {
+            let stokes: Stokes = (0..CHANNELS).map(|c| (t * CHANNELS + c) as f32).collect();
+            consumer.consume(&stokes).unwrap();
+        }
+        consumer.finish().unwrap();
+
+        let open = |n| {
+            let path = dir.join(format!("{}_{n:04}.parquet", consumer.stem));
+            SerializedFileReader::new(File::open(path).unwrap()).unwrap()
+        };
+        let first = open(1);
+        let meta = first.metadata();
+        assert_eq!(meta.num_row_groups(), 2);
+        assert_eq!(meta.file_metadata().num_rows(), 6);
+        assert_eq!(
+            meta.file_metadata().schema_descr().num_columns(),
+            CHANNELS + 2
+        );
+        let kv = meta.file_metadata().key_value_metadata().unwrap();
+        let nchans = kv.iter().find(|kv| kv.key == "nchans").unwrap();
+        assert_eq!(nchans.value.as_deref(), Some("2048"));
+        let rows: Vec<_> = first
+            .get_row_iter(None)
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        for (t, row) in rows.iter().enumerate() {
+            assert_eq!(row.get_long(0).unwrap(), 4 * t as i64);
+            assert_eq!(row.get_float(2 + 100).unwrap(), (t * CHANNELS + 100) as f32);
+        }
+        assert_eq!(open(2).metadata().file_metadata().num_rows(), 2);
+    }
+}
@@ -0,0 +1,65 @@
+//! The total power of the stokes stream, integrated and written out as a light-weight record of the band's health
+use super::{exfil_start_time, StokesConsumer};
+use crate::{
+    common::{Stokes, PACKET_CADENCE},
+    latency::latency_policy,
+    naming::time_policy,
+};
+use hifitime::prelude::*;
+use std::{fs::File, io::Write, path::Path};
+
+/// Writes "<mjd> <mean power>" lines, one per integration, to a text file
+pub struct PowerLog {
+    file: File,
+    acc: f64,
+    /// Spectra per integration
+    integration: usize,
+    /// Spectra accumulated into the current integration
+    n: usize,
+    /// Spectra since the start of the observation
+    spectra: u64,
+    /// Time per input spectrum (s)
+    tsamp: f64,
+}
+
+impl PowerLog {
+    /// Log total power integrated for `integration` seconds to a new file in `path`
+    pub fn new(integration: f64, downsample_factor: usize, path: &Path) -> eyre::Result<Self> {
+        let filename = format!("grex-{}.power", time_policy().filename_stamp(Epoch::now()?));
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        Ok(Self {
+            file: latency_policy().create(&path.join(filename))?,
+            acc: 0.0,
+            integration: ((integration / tsamp).round() as usize).max(1),
+            n: 0,
+            spectra: 0,
+            tsamp,
+        })
+    }
+}
+
+impl StokesConsumer for PowerLog {
+    fn name(&self) -> &str {
+        "total_power"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        self.acc += stokes.iter().map(|&x| f64::from(x)).sum::<f64>() / stokes.len() as f64;
+        self.n += 1;
+        self.spectra += 1;
+        if self.n == self.integration {
+            let start = self.spectra - self.n as u64;
+            let mjd = time_policy()
+                .mjd(exfil_start_time() + Duration::from_seconds(start as f64 * self.tsamp));
+            writeln!(self.file, "{mjd:.10} {}", self.acc / self.n as f64)?;
+            self.acc = 0.0;
+            self.n = 0;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        self.file.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,553 @@
+//! PSRFITS search-mode output, as a primary HDU of observation metadata and a SUBINT binary table of 8-bit Stokes I.
+//! Each subint is quantized against its own per-channel offset and scale (DAT_OFFS and DAT_SCL), and blanked channels
+//! get zero weight. Files are split every so many subints, with NSUBOFFS carrying on the count so they can be stitched.
+use super::{
+    exfil_start_time,
+    stats::{sink_stats, SinkStats},
+    FrequencyPlan, StokesConsumer,
+};
+use crate::{
+    accounting::accounting,
+    common::{station_id, Stokes, CHANNELS, PACKET_CADENCE},
+    naming::time_policy,
+    processing::channel_mask,
+};
+use hifitime::prelude::*;
+use ndarray::{Array1, Array2};
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, info};
+
+/// FITS files are made of blocks of this many bytes
+const BLOCK: usize = 2880;
+/// And headers of cards of this many
+const CARD: usize = 80;
+
+/// A FITS header under construction
+#[derive(Debug, Default)]
+struct Header {
+    cards: Vec<u8>,
+}
+
+impl Header {
+    fn card(&mut self, key: &str, value: &str, comment: &str) -> &mut Self {
+        let mut card = format!("{key:<8}= {value:>20}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        let mut bytes = card.into_bytes();
+        bytes.resize(CARD, b' ');
+        self.cards.extend_from_slice(&bytes);
+        self
+    }
+
+    fn string(&mut self, key: &str, value: &str, comment: &str) -> &mut Self {
+        // Strings are quoted and padded to at least 8 characters, starting in column 11
+        let quoted = format!("'{:<8}'", value.replace('\'', "''"));
+        let mut card = format!("{key:<8}= {quoted:<20}");
+        if !comment.is_empty() {
+            card.push_str(" / ");
+            card.push_str(comment);
+        }
+        let mut bytes = card.into_bytes();
+        bytes.resize(CARD, b' ');
+        self.cards.extend_from_slice(&bytes);
+        self
+    }
+
+    fn int(&mut self, key: &str, value: i64, comment: &str) -> &mut Self {
+        self.card(key, &value.to_string(), comment)
+    }
+
+    fn float(&mut self, key: &str, value: f64, comment: &str) -> &mut Self {
+        self.card(key, &format!("{value:.14E}"), comment)
+    }
+
+    fn logical(&mut self, key: &str, value: bool, comment: &str) -> &mut Self {
+        self.card(key, if value { "T" } else { "F" }, comment)
+    }
+
+    /// Byte offset of the (first) card with this key, to rewrite it later
+    fn offset_of(&self, key: &str) -> Option<usize> {
+        let key = format!("{key:<8}=");
+        self.cards
+            .chunks(CARD)
+            .position(|c| c.starts_with(key.as_bytes()))
+            .map(|i| i * CARD)
+    }
+
+    /// Terminate the header, padded out to a whole block
+    fn finish(mut self) -> Vec<u8> {
+        let mut end = b"END".to_vec();
+        end.resize(CARD, b' ');
+        self.cards.extend_from_slice(&end);
+        let padded = self.cards.len().div_ceil(BLOCK) * BLOCK;
+        self.cards.resize(padded, b' ');
+        self.cards
+    }
+}
+
+/// Quantize a subint of spectra (time x channel) to 8 bits per channel, returning the data and the offsets and scales
+/// that recover it (as `data * scale + offset`)
+fn quantize(spectra: &Array2<f32>) -> (Vec<u8>, Vec<f32>, Vec<f32>) {
+    let mut offsets = vec![0f32; spectra.ncols()];
+    let mut scales = vec![1f32; spectra.ncols()];
+    for (c, chan) in spectra.columns().into_iter().enumerate() {
+        let (lo, hi) = chan
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        if lo.is_finite() && hi > lo {
+            offsets[c] = lo;
+            scales[c] = (hi - lo) / 255.0;
+        } else if lo.is_finite() {
+            offsets[c] = lo;
+        }
+    }
+    let data = spectra
+        .rows()
+        .into_iter()
+        .flat_map(|row| {
+            row.into_iter()
+                .zip(offsets.iter().zip(&scales))
+                .map(|(v, (o, s))| ((v - o) / s).round().clamp(0.0, 255.0) as u8)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    (data, offsets, scales)
+}
+
+/// A UTC time as FITS dates are written
+fn fits_date(epoch: Epoch) -> String {
+    let (y, mo, d, h, mi, s, ns) = epoch.to_gregorian_utc();
+    format!(
+        "{y:04}-{mo:02}-{d:02}T{h:02}:{mi:02}:{s:02}.{:03}",
+        ns / 1_000_000
+    )
+}
+
+fn be_f32s(values: &[f32]) -> Vec<u8> {
+    values.iter().flat_map(|v| v.to_be_bytes()).collect()
+}
+
+/// Writes a subint at a time, so a subint is the most we lose if we go down uncleanly
+pub struct PsrfitsConsumer {
+    file: Option<BufWriter<File>>,
+    path: PathBuf,
+    stem: String,
+    freq_plan: FrequencyPlan,
+    /// Spectra per subint
+    nsblk: usize,
+    /// Subints per file before starting another
+    subints_per_file: usize,
+    /// Seconds per spectrum
+    tbin: f64,
+    /// Spectra waiting for their subint to fill
+    buffer: Array2<f32>,
+    buffered: usize,
+    /// Subints written across every file, and to this one
+    subints: usize,
+    file_subints: usize,
+    files: usize,
+    /// Where NAXIS2 sits in this file's SUBINT header
+    naxis2_offset: u64,
+    /// Time of the first spectrum, once we've seen it
+    start: Option<Epoch>,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+}
+
+impl PsrfitsConsumer {
+    /// Write to files in `path`, in subints of `nsblk` spectra, `subints_per_file` to a file
+    pub fn new(
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        path: &Path,
+        nsblk: usize,
+        subints_per_file: usize,
+    ) -> eyre::Result<Self> {
+        if nsblk == 0 || subints_per_file == 0 {
+            eyre::bail!("PSRFITS files need at least one subint of at least one spectrum");
+        }
+        let tbin = PACKET_CADENCE * downsample_factor as f64;
+        Ok(Self {
+            file: None,
+            path: path.to_owned(),
+            stem: format!("grex-{}", time_policy().filename_stamp(Epoch::now()?)),
+            freq_plan,
+            nsblk,
+            subints_per_file,
+            tbin,
+            buffer: Array2::zeros((nsblk, CHANNELS)),
+            buffered: 0,
+            subints: 0,
+            file_subints: 0,
+            files: 0,
+            naxis2_offset: 0,
+            start: None,
+            written: accounting().output("psrfits"),
+            stats: sink_stats("psrfits", Duration::from_secs_f64(tbin)),
+        })
+    }
+
+    fn row_bytes(&self) -> usize {
+        // TSUBINT, OFFS_SUB, then DAT_FREQ, DAT_WTS, DAT_OFFS, DAT_SCL, then DATA
+        2 * 8 + 4 * CHANNELS * 4 + self.nsblk * CHANNELS
+    }
+
+    fn primary_header(&self, start: Epoch) -> eyre::Result<Vec<u8>> {
+        // PSRFITS start times are always UTC, whatever we name files by
+        let mjd = start.to_mjd_utc_days();
+        let imjd = mjd.floor();
+        let secs = (mjd - imjd) * 86400.0;
+        let smjd = secs.floor();
+        let mut header = Header::default();
+        header
+            .logical("SIMPLE", true, "File conforms to FITS standard")
+            .int("BITPIX", 8, "Bits per data value")
+            .int("NAXIS", 0, "No data in the primary HDU")
+            .logical("EXTEND", true, "Extensions may be present")
+            .string(
+                "FITSTYPE",
+                "PSRFITS",
+                "FITS definition for pulsar data files",
+            )
+            .string("HDRVER", "6.1", "Header version")
+            .string("DATE", &fits_date(Epoch::now()?), "File creation date")
+            .string("OBSERVER", "GReX", "Observer name")
+            .string("PROJID", "GReX", "Project name")
+            .string(
+                "TELESCOP",
+                &format!("GReX-{}", station_id()),
+                "Telescope name",
+            )
+            .string("FRONTEND", "GReX", "Receiver ID")
+            .string("BACKEND", "GReX-T0", "Backend ID")
+            .int("NRCVR", 1, "Number of receiver polarisation channels")
+            .string("FD_POLN", "LIN", "LIN or CIRC")
+            .string("OBS_MODE", "SEARCH", "(PSR, CAL, SEARCH)")
+            .string("DATE-OBS", &fits_date(start), "UTC date of observation")
+            .float("OBSFREQ", self.freq_plan.center(), "[MHz] Centre frequency")
+            .float(
+                "OBSBW",
+                self.freq_plan.signed_bandwidth(),
+                "[MHz] Bandwidth for observation",
+            )
+            .int("OBSNCHAN", CHANNELS as i64, "Number of frequency channels")
+            .string("SRC_NAME", "DRIFT", "Source or scan ID")
+            .string("TRK_MODE", "DRIFT", "Track mode")
+            .int("STT_IMJD", imjd as i64, "Start MJD (UTC days)")
+            .int("STT_SMJD", smjd as i64, "[s] Start time (sec past UTC 00h)")
+            .float("STT_OFFS", secs - smjd, "[s] Start time offset")
+            .float("STT_LST", 0.0, "[s] Start LST");
+        Ok(header.finish())
+    }
+
+    fn subint_header(&self) -> Header {
+        let nchan = CHANNELS as i64;
+        let mut header = Header::default();
+        header
+            .string("XTENSION", "BINTABLE", "FITS binary table")
+            .int("BITPIX", 8, "Binary data")
+            .int("NAXIS", 2, "2-dimensional binary table")
+            .int("NAXIS1", self.row_bytes() as i64, "Width of table in bytes")
+            .int("NAXIS2", 0, "Number of rows")
+            .int("PCOUNT", 0, "Size of special data area")
+            .int("GCOUNT", 1, "One data group")
+            .int("TFIELDS", 7, "Number of fields per row")
+            .string("EXTNAME", "SUBINT", "Name of this binary table extension")
+            .string("INT_TYPE", "TIME", "Time axis")
+            .string("INT_UNIT", "SEC", "Unit of time axis")
+            .string("SCALE", "FluxDen", "Units of data")
+            .int("NPOL", 1, "Number of polarisations")
+            .string("POL_TYPE", "AA+BB", "Polarisation identifier")
+            .float("TBIN", self.tbin, "[s] Time per bin or sample")
+            .int("NBIN", 1, "Nr of bins (PSR/CAL mode; else 1)")
+            .int("NCHAN", nchan, "Number of channels")
+            .float("CHAN_BW", self.freq_plan.foff(), "[MHz] Channel bandwidth")
+            .int("NCHNOFFS", 0, "Channel offset")
+            .int("NSBLK", self.nsblk as i64, "Samples per row")
+            .int("NBITS", 8, "Nr of bits per sample")
+            .int("NSUBOFFS", self.subints as i64, "Subint offset")
+            .float("ZERO_OFF", 0.0, "Zero offset of the data")
+            .string("TTYPE1", "TSUBINT", "Length of subintegration")
+            .string("TFORM1", "1D", "")
+            .string("TUNIT1", "s", "")
+            .string("TTYPE2", "OFFS_SUB", "Offset from start of subint centre")
+            .string("TFORM2", "1D", "")
+            .string("TUNIT2", "s", "")
+            .string(
+                "TTYPE3",
+                "DAT_FREQ",
+                "[MHz] Centre frequency for each channel",
+            )
+            .string("TFORM3", &format!("{nchan}E"), "")
+            .string("TUNIT3", "MHz", "")
+            .string("TTYPE4", "DAT_WTS", "Weights for each channel")
+            .string("TFORM4", &format!("{nchan}E"), "")
+            .string("TTYPE5", "DAT_OFFS", "Data offset for each channel")
+            .string("TFORM5", &format!("{nchan}E"), "")
+            .string("TTYPE6", "DAT_SCL", "Data scale factor for each channel")
+            .string("TFORM6", &format!("{nchan}E"), "")
+            .string("TTYPE7", "DATA", "Subint data table")
+            .string("TFORM7", &format!("{}B", self.nsblk * CHANNELS), "")
+            .string("TDIM7", &format!("(1,{nchan},1,{})", self.nsblk), "")
+            .string("TUNIT7", "Jy", "");
+        header
+    }
+
+    /// Start the next file in the sequence
+    fn open(&mut self, start: Epoch) -> eyre::Result<()> {
+        self.files += 1;
+        let file_path = self
+            .path
+            .join(format!("{}_{:04}.sf", self.stem, self.files));
+        info!("Writing PSRFITS to {}", file_path.display());
+        self.stats.set_target(file_path.display().to_string());
+        let mut file = BufWriter::new(File::create(&file_path)?);
+        let primary = self.primary_header(start)?;
+        file.write_all(&primary)?;
+        let subint = self.subint_header();
+        self.naxis2_offset = (primary.len()
+            + subint
+                .offset_of("NAXIS2")
+                .expect("NAXIS2 is in every SUBINT header")) as u64;
+        file.write_all(&subint.finish())?;
+        self.file = Some(file);
+        self.file_subints = 0;
+        Ok(())
+    }
+
+    /// Pad out the table and make sure the row count covers it
+    fn close(&mut self) -> eyre::Result<()> {
+        let Some(mut file) = self.file.take() else {
+            return Ok(());
+        };
+        let data_len = self.file_subints * self.row_bytes();
+        let padding = data_len.div_ceil(BLOCK) * BLOCK - data_len;
+        file.write_all(&vec![0u8; padding])?;
+        file.flush()?;
+        debug!(subints = self.file_subints, "Closed PSRFITS file");
+        Ok(())
+    }
+
+    /// Write the buffered spectra as a subint (zero-padded if short), splitting the file if it's full
+    fn write_subint(&mut self) -> eyre::Result<()> {
+        if self.buffered == 0 {
+            return Ok(());
+        }
+        let start = self.start.expect("Set with the first spectrum");
+        if self.file.is_none() {
+            self.open(start)?;
+        }
+        let write_start = Instant::now();
+        self.buffer
+            .slice_mut(ndarray::s![self.buffered.., ..])
+            .fill(0.0);
+        let (data, offsets, scales) = quantize(&self.buffer);
+        let blanked = channel_mask().channels();
+        let weights: Vec<f32> = (0..CHANNELS)
+            .map(|c| if blanked.contains(&c) { 0.0 } else { 1.0 })
+            .collect();
+        let freqs: Array1<f32> = self.freq_plan.freqs().mapv(|f| f as f32);
+        let tsubint = self.nsblk as f64 * self.tbin;
+        let offs_sub = (self.subints as f64 + 0.5) * tsubint;
+
+        let mut row = Vec::with_capacity(self.row_bytes());
+        row.extend_from_slice(&tsubint.to_be_bytes());
+        row.extend_from_slice(&offs_sub.to_be_bytes());
+        row.extend(be_f32s(
+            freqs
+                .as_slice()
+                .expect("Freshly made arrays are contiguous"),
+        ));
+        row.extend(be_f32s(&weights));
+        row.extend(be_f32s(&offsets));
+        row.extend(be_f32s(&scales));
+        row.extend(data);
+
+        let file = self.file.as_mut().expect("Opened above");
+        file.write_all(&row)?;
+        self.file_subints += 1;
+        self.subints += 1;
+        // Keep the row count current, so the file is readable up to the last whole subint
+        let end = file.stream_position()?;
+        file.seek(SeekFrom::Start(self.naxis2_offset))?;
+        let mut card = format!(
+            "{:<8}= {:>20} / Number of rows",
+            "NAXIS2", self.file_subints
+        )
+        .into_bytes();
+        card.resize(CARD, b' ');
+        file.write_all(&card)?;
+        file.seek(SeekFrom::Start(end))?;
+        self.stats.record_write(row.len(), write_start.elapsed());
+        self.buffered = 0;
+
+        if self.file_subints == self.subints_per_file {
+            self.close()?;
+        }
+        Ok(())
+    }
+}
+
+impl StokesConsumer for PsrfitsConsumer {
+    fn name(&self) -> &str {
+        "psrfits"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        if self.start.is_none() {
+            self.start = Some(exfil_start_time());
+        }
+        self.buffer
+            .row_mut(self.buffered)
+            .iter_mut()
+            .zip(stokes)
+            .for_each(|(b, s)| *b = *s);
+        self.buffered += 1;
+        self.written.fetch_add(1, Ordering::Relaxed);
+        if self.buffered == self.nsblk {
+            if let Err(e) = self.write_subint() {
+                self.stats.record_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        if self.buffered > 0 {
+            debug!(
+                spectra = self.buffered,
+                "Zero-padding the last PSRFITS subint"
+            );
+        }
+        self.write_subint()?;
+        self.close()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_header_cards() {
+        let mut header = Header::default();
+        header
+            .logical("SIMPLE", true, "")
+            .int("NAXIS2", 12, "Number of rows")
+            .string("EXTNAME", "SUBINT", "");
+        assert_eq!(header.offset_of("NAXIS2"), Some(CARD));
+        let bytes = header.finish();
+        assert_eq!(bytes.len(), BLOCK);
+        let cards: Vec<_> = bytes
+            .chunks(CARD)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+        // Logicals and numbers are right-justified to column 30, strings start in column 11
+        assert_eq!(&cards[0][..30], "SIMPLE  =                    T");
+        assert_eq!(&cards[1][..30], "NAXIS2  =                   12");
+        assert_eq!(&cards[2][..20], "EXTNAME = 'SUBINT  '");
+        assert!(cards[3].starts_with("END "));
+    }
+
+    #[test]
+    fn test_quantize() {
+        let spectra = Array2::from_shape_fn((4, 2), |(t, c)| if c == 0 { t as f32 } else { 3.0 });
+        let (data, offsets, scales) = quantize(&spectra);
+        assert_eq!(offsets, vec![0.0, 3.0]);
+        assert_eq!(data[6], 255);
+        // Constant channels quantize to zero and come back exactly
+        assert_eq!(data[7], 0);
+        assert_eq!(data[7] as f32 * scales[1] + offsets[1], 3.0);
+    }
+
+    /// Read the cards of the header starting at `at` back into (key, value) pairs, returning where the data starts
+    fn read_header(bytes: &[u8], at: usize) -> (std::collections::HashMap<String, String>, usize) {
+        let mut cards = std::collections::HashMap::new();
+        for (i, card) in bytes[at..].chunks(CARD).enumerate() {
+            let card = std::str::from_utf8(card).unwrap();
+            if card.starts_with("END ") {
+                let end = at + (i + 1) * CARD;
+                return (cards, end.div_ceil(BLOCK) * BLOCK);
+            }
+            let (key, rest) = card.split_once('=').unwrap();
+            let value = rest.split(" / ").next().unwrap().trim();
+            cards.insert(
+                key.trim().to_owned(),
+                value.trim_matches('\'').trim().to_owned(),
+            );
+        }
+        panic!("Header has no END card")
+    }
+
+    #[test]
+    fn test_readback() {
+        crate::testing::seed_start_time();
+        let scratch = crate::testing::scratch_dir();
+        let dir = scratch.path();
+        let mut consumer = PsrfitsConsumer::new(1, FrequencyPlan::default(), dir, 4, 2).unwrap();
+        // Three subints, so the first file is full and the second has one
+        for t in 0..12 {
+            let stokes: Stokes = (0..CHANNELS).map(|c| (t % 4 + c) as f32).collect();
+            consumer.consume(&stokes).unwrap();
+        }
+        consumer.finish().unwrap();
+        let path = dir.join(format!("{}_0001.sf", consumer.stem));
+        let bytes = std::fs::read(path).unwrap();
+        assert_eq!(bytes.len() % BLOCK, 0);
+
+        let (primary, subint_start) = read_header(&bytes, 0);
+        assert_eq!(primary["FITSTYPE"], "PSRFITS");
+        let (subint, data_start) = read_header(&bytes, subint_start);
+        assert_eq!(subint["NAXIS2"], "2");
+        assert_eq!(subint["NSBLK"], "4");
+        // The row is as wide as its columns, and DATA is shaped (NBIN, NCHAN, NPOL, NSBLK)
+        let width: usize = (1..=7)
+            .map(|i| {
+                let tform = &subint[&format!("TFORM{i}")];
+                let (count, kind) = tform.split_at(tform.len() - 1);
+                let size = match kind {
+                    "D" => 8,
+                    "E" => 4,
+                    "B" => 1,
+                    _ => panic!("Unexpected column type {kind}"),
+                };
+                count.parse::<usize>().unwrap() * size
+            })
+            .sum();
+        assert_eq!(subint["NAXIS1"], width.to_string());
+        assert_eq!(subint["TDIM7"], format!("(1,{CHANNELS},1,4)"));
+        assert_eq!(subint["TFORM7"], format!("{}B", 4 * CHANNELS));
+
+        // Then the second row's data comes back through its offsets and scales
+        let row = &bytes[data_start + width..data_start + 2 * width];
+        let floats = |at: usize| -> Vec<f32> {
+            row[at..at + 4 * CHANNELS]
+                .chunks(4)
+                .map(|b| f32::from_be_bytes(b.try_into().unwrap()))
+                .collect()
+        };
+        let offsets = floats(16 + 2 * 4 * CHANNELS);
+        let scales = floats(16 + 3 * 4 * CHANNELS);
+        let data = &row[16 + 4 * 4 * CHANNELS..];
+        for t in 0..4 {
+            for c in [0, 100, CHANNELS - 1] {
+                let v = data[t * CHANNELS + c] as f32 * scales[c] + offsets[c];
+                assert!((v - (t + c) as f32).abs() < 1e-3);
+            }
+        }
+    }
+}
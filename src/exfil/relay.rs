@@ -0,0 +1,150 @@
+//! A heavily decimated copy of the stokes stream, sent continuously to a central server for network-wide monitoring
+use super::FrequencyPlan;
+use crate::common::{processed_payload_start_time, Stokes, CHANNELS, PACKET_CADENCE};
+use crate::naming::time_policy;
+use hifitime::Duration;
+use serde::Serialize;
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+};
+use tracing::{info, warn};
+
+/// How many integrations we'll hold on to while the server is unreachable
+const RELAY_BACKLOG: usize = 600;
+/// How long to wait for the server when (re)connecting
+const CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// One integration, sent as a line of JSON
+#[derive(Debug, Serialize)]
+struct RelayMessage {
+    /// Start of the integration (MJD, in the time policy's standard)
+    mjd: f64,
+    /// Length of the integration (s)
+    tsamp: f64,
+    /// Center frequency of the first channel (MHz)
+    fch1: f64,
+    /// Channel spacing (MHz)
+    foff: f64,
+    spectrum: Vec<f32>,
+}
+
+/// Decimates the stokes stream in frequency and time and hands the result to a background sender.
+/// This never blocks or fails the stream it's tapping, integrations are dropped if the server can't keep up.
+pub struct Relay {
+    acc: Vec<f32>,
+    /// Spectra per integration
+    integration: usize,
+    /// Spectra accumulated into the current integration
+    n: usize,
+    /// Spectra since the start of the observation
+    spectra: u64,
+    /// Time per input spectrum (s)
+    tsamp: f64,
+    freq_plan: FrequencyPlan,
+    sender: SyncSender<RelayMessage>,
+}
+
+impl Relay {
+    /// Relay `channels` channels (which must divide [`CHANNELS`]), integrated for `integration` seconds, to `addr`
+    pub fn new(
+        addr: String,
+        channels: usize,
+        integration: f64,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+    ) -> eyre::Result<Self> {
+        if channels == 0 || !CHANNELS.is_multiple_of(channels) {
+            eyre::bail!("Relay channels must divide {CHANNELS}");
+        }
+        let tsamp = PACKET_CADENCE * downsample_factor as f64;
+        let (sender, receiver) = sync_channel(RELAY_BACKLOG);
+        std::thread::Builder::new()
+            .name("relay".to_owned())
+            .spawn(move || sender_loop(&addr, receiver))?;
+        Ok(Self {
+            acc: vec![0.0; channels],
+            integration: ((integration / tsamp).round() as usize).max(1),
+            n: 0,
+            spectra: 0,
+            tsamp,
+            freq_plan,
+            sender,
+        })
+    }
+
+    pub fn push(&mut self, stokes: &Stokes) {
+        let width = CHANNELS / self.acc.len();
+        for (acc, chunk) in self.acc.iter_mut().zip(stokes.chunks_exact(width)) {
+            *acc += chunk.iter().sum::<f32>();
+        }
+        self.n += 1;
+        self.spectra += 1;
+        if self.n == self.integration {
+            let norm = (self.n * width) as f32;
+            let start = self.spectra - self.n as u64;
+            let mjd = time_policy().mjd(
+                processed_payload_start_time() + Duration::from_seconds(start as f64 * self.tsamp),
+            );
+            let msg = RelayMessage {
+                mjd,
+                tsamp: self.n as f64 * self.tsamp,
+                // Center of the first coarse channel
+                fch1: self.freq_plan.fch1() + self.freq_plan.foff() * (width as f64 - 1.0) / 2.0,
+                foff: self.freq_plan.foff() * width as f64,
+                spectrum: self.acc.iter().map(|x| x / norm).collect(),
+            };
+            // If the sender is backed up, this integration is lost
+            let _ = self.sender.try_send(msg);
+            self.acc.fill(0.0);
+            self.n = 0;
+        }
+    }
+}
+
+fn connect(addr: &str) -> std::io::Result<TcpStream> {
+    let mut last_err = std::io::Error::other("Relay address didn't resolve");
+    for sock_addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&sock_addr, CONNECT_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Send integrations to the server as newline-delimited JSON, reconnecting whenever the connection drops
+fn sender_loop(addr: &str, messages: Receiver<RelayMessage>) {
+    info!(addr, "Starting relay");
+    let mut stream = None;
+    let mut reported = false;
+    // Stops when the relay (and so the sender) is dropped
+    while let Ok(msg) = messages.recv() {
+        if stream.is_none() {
+            match connect(addr) {
+                Ok(s) => {
+                    info!(addr, "Relay connected");
+                    reported = false;
+                    stream = Some(s);
+                }
+                Err(e) => {
+                    if !reported {
+                        warn!(
+                            addr,
+                            "Couldn't connect to relay server, dropping integrations - {e}"
+                        );
+                        reported = true;
+                    }
+                    continue;
+                }
+            }
+        }
+        let mut line = serde_json::to_vec(&msg).expect("Relay messages always serialize");
+        line.push(b'\n');
+        if let Some(Err(e)) = stream.as_mut().map(|s| s.write_all(&line)) {
+            warn!(addr, "Lost relay connection - {e}");
+            stream = None;
+        }
+    }
+}
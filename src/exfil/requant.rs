@@ -0,0 +1,100 @@
+//! Requantizing stokes spectra to 8 or 16 bit integers, against a per-channel offset and scale that follow the data.
+//! The statistics are updated with every spectrum, but the offset and scale in use only change every [`REQUANT_BLOCK`]
+//! spectra, so each block can be recovered exactly (as `sample * scale + offset`) from the values recorded for it.
+use crate::common::CHANNELS;
+
+/// Spectra between updates of the offset and scale (and the time constant of the statistics behind them)
+pub const REQUANT_BLOCK: u64 = 1024;
+/// Standard deviations either side of the mean that fit in the output range
+const SIGMAS: f64 = 6.0;
+
+/// Bits per filterbank sample
+pub fn valid_nbits(nbits: u8) -> eyre::Result<u8> {
+    match nbits {
+        8 | 16 | 32 => Ok(nbits),
+        _ => eyre::bail!("Filterbanks can have 8, 16, or 32 bits per sample, not {nbits}"),
+    }
+}
+
+pub struct Requantizer {
+    /// Largest output value
+    max: f64,
+    /// Running mean and variance of each channel
+    mean: Vec<f64>,
+    var: Vec<f64>,
+    observed: u64,
+    /// What's being quantized against at the moment
+    pub offsets: Vec<f32>,
+    pub scales: Vec<f32>,
+}
+
+impl Requantizer {
+    pub fn new(nbits: u8) -> Self {
+        Self {
+            max: ((1u32 << nbits) - 1) as f64,
+            mean: vec![0.0; CHANNELS],
+            var: vec![0.0; CHANNELS],
+            observed: 0,
+            offsets: vec![0.0; CHANNELS],
+            scales: vec![1.0; CHANNELS],
+        }
+    }
+
+    /// Fold a spectrum into the statistics, exactly for the first block and exponentially after that
+    pub fn observe(&mut self, spectrum: &[f32]) {
+        self.observed += 1;
+        let alpha = 1.0 / self.observed.min(REQUANT_BLOCK) as f64;
+        for ((mean, var), &x) in self.mean.iter_mut().zip(&mut self.var).zip(spectrum) {
+            let delta = x as f64 - *mean;
+            *mean += alpha * delta;
+            *var = (1.0 - alpha) * (*var + alpha * delta * delta);
+        }
+    }
+
+    /// Start quantizing against the statistics as they stand
+    pub fn update(&mut self) {
+        for (c, (mean, var)) in self.mean.iter().zip(&self.var).enumerate() {
+            let std = var.sqrt();
+            if std > 0.0 {
+                self.scales[c] = (2.0 * SIGMAS * std / self.max) as f32;
+                self.offsets[c] = (mean - SIGMAS * std) as f32;
+            } else {
+                // Constant (or blanked) channels come back exactly
+                self.scales[c] = 1.0;
+                self.offsets[c] = *mean as f32;
+            }
+        }
+    }
+
+    /// Quantize a spectrum against the current offset and scale
+    pub fn quantize<'a>(&'a self, spectrum: &'a [f32]) -> impl Iterator<Item = u16> + 'a {
+        spectrum
+            .iter()
+            .zip(self.offsets.iter().zip(&self.scales))
+            .map(|(x, (o, s))| ((x - o) / s).round().clamp(0.0, self.max as f32) as u16)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requantize() {
+        let mut rq = Requantizer::new(8);
+        for i in 0..REQUANT_BLOCK {
+            let x = if i % 2 == 0 { 90.0 } else { 110.0 };
+            rq.observe(&[x; CHANNELS]);
+        }
+        rq.update();
+        // Mean of 100 with a standard deviation of 10
+        assert!((rq.offsets[0] - 40.0).abs() < 1e-3);
+        let q: Vec<_> = rq.quantize(&[100.0; CHANNELS]).collect();
+        assert!((127..=128).contains(&q[0]));
+        let back = q[0] as f32 * rq.scales[0] + rq.offsets[0];
+        assert!((back - 100.0).abs() <= rq.scales[0]);
+        // Way out of range saturates
+        assert_eq!(rq.quantize(&[1e6; CHANNELS]).next(), Some(255));
+        assert!(valid_nbits(12).is_err());
+    }
+}
@@ -0,0 +1,328 @@
+//! The last few minutes of the stokes stream, kept time-averaged and quantized in memory so a window of it can be written
+//! out on a trigger or request, long after the voltages around it have been overwritten.
+use super::FrequencyPlan;
+use crate::common::{
+    processed_payload_start_time, CandidateEvent, Stokes, CHANNELS, PACKET_CADENCE,
+};
+use crate::naming::time_policy;
+use sigproc_filterbank::write::WriteFilterbank;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::mpsc::Receiver,
+};
+use tracing::{info, warn};
+
+/// Requests we'll wait on at once, anything more is dropped rather than piling up behind windows that never end
+const MAX_PENDING: usize = 64;
+
+/// Something to write out of the ring
+#[derive(Debug, Clone)]
+pub enum RingRequest {
+    /// Around a candidate, padded by the ring's configured amounts
+    Candidate(CandidateEvent),
+    /// Between two times (MJD, in the time policy's standard)
+    Window {
+        name: String,
+        start_mjd: f64,
+        end_mjd: f64,
+    },
+}
+
+/// A request resolved into the stokes spectra it covers
+#[derive(Debug)]
+struct Pending {
+    name: String,
+    spectra: Range<u64>,
+}
+
+/// Decimated, quantized stokes spectra. Each is stored as u16s scaled to its own peak, so it costs a quarter of the
+/// f32s it came from (and the decimation cuts that down further).
+pub struct StokesRing {
+    /// Stored spectra the ring holds
+    capacity: usize,
+    /// Exfil spectra averaged into each stored one
+    decimation: usize,
+    downsample_factor: usize,
+    freq_plan: FrequencyPlan,
+    path: PathBuf,
+    /// Exfil spectra to include before and after a candidate
+    before: u64,
+    after: u64,
+    /// Payloads to shift every candidate by
+    sample_offset: i64,
+    data: Vec<u16>,
+    scales: Vec<f32>,
+    /// Stored spectra so far
+    stored: u64,
+    /// Exfil spectra so far
+    spectra: u64,
+    acc: Vec<f32>,
+    acc_n: usize,
+    requests: Receiver<RingRequest>,
+    /// Requests waiting for the end of their window to arrive
+    pending: Vec<Pending>,
+}
+
+impl StokesRing {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        minutes: f64,
+        decimation: usize,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        path: &Path,
+        before_s: f64,
+        after_s: f64,
+        sample_offset: i64,
+        requests: Receiver<RingRequest>,
+    ) -> Self {
+        let exfil_tsamp = PACKET_CADENCE * downsample_factor as f64;
+        let capacity = ((minutes * 60.0) / (exfil_tsamp * decimation as f64)).ceil() as usize;
+        info!(
+            capacity,
+            bytes = capacity * CHANNELS * 2,
+            "Keeping a ring of the stokes stream"
+        );
+        Self {
+            capacity,
+            decimation,
+            downsample_factor,
+            freq_plan,
+            path: path.to_owned(),
+            before: (before_s / exfil_tsamp).ceil() as u64,
+            after: (after_s / exfil_tsamp).ceil() as u64,
+            sample_offset,
+            data: vec![0; capacity * CHANNELS],
+            scales: vec![0.0; capacity],
+            stored: 0,
+            spectra: 0,
+            acc: vec![0.0; CHANNELS],
+            acc_n: 0,
+            requests,
+            pending: vec![],
+        }
+    }
+
+    /// Time per exfil spectrum (s)
+    fn exfil_tsamp(&self) -> f64 {
+        PACKET_CADENCE * self.downsample_factor as f64
+    }
+
+    /// Work out which spectra a request covers
+    fn resolve(&self, request: RingRequest) -> Pending {
+        match request {
+            RingRequest::Candidate(event) => {
+                let offset =
+                    self.sample_offset.saturating_add(event.offset) / self.downsample_factor as i64;
+                let center = event.specnum.saturating_add_signed(offset);
+                Pending {
+                    name: event.candname,
+                    spectra: center.saturating_sub(self.before)..center.saturating_add(self.after),
+                }
+            }
+            RingRequest::Window {
+                name,
+                start_mjd,
+                end_mjd,
+            } => {
+                let start = time_policy().mjd(processed_payload_start_time());
+                let to_spectra =
+                    |mjd: f64| ((mjd - start) * 86400.0 / self.exfil_tsamp()).max(0.0) as u64;
+                Pending {
+                    name,
+                    spectra: to_spectra(start_mjd)..to_spectra(end_mjd),
+                }
+            }
+        }
+    }
+
+    pub fn push(&mut self, stokes: &Stokes) {
+        while let Ok(request) = self.requests.try_recv() {
+            let pending = self.resolve(request);
+            if self.pending.len() >= MAX_PENDING {
+                warn!(
+                    name = pending.name,
+                    "Too many stokes ring requests waiting, dropping one"
+                );
+                continue;
+            }
+            self.pending.push(pending);
+        }
+        for (a, s) in self.acc.iter_mut().zip(stokes) {
+            *a += s;
+        }
+        self.acc_n += 1;
+        self.spectra += 1;
+        if self.acc_n == self.decimation {
+            self.store();
+        }
+        // Write out whatever's complete
+        let (ready, waiting) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| p.spectra.end <= self.spectra);
+        self.pending = waiting;
+        for pending in ready {
+            if let Err(e) = self.write(pending) {
+                warn!("Failed to write from the stokes ring - {e}");
+            }
+        }
+    }
+
+    fn store(&mut self) {
+        let slot = (self.stored % self.capacity as u64) as usize;
+        let n = self.acc_n as f32;
+        let peak = self.acc.iter().fold(0f32, |m, v| m.max(v.abs())) / n;
+        let scale = if peak > 0.0 {
+            peak / u16::MAX as f32
+        } else {
+            1.0
+        };
+        for (q, a) in self.data[slot * CHANNELS..(slot + 1) * CHANNELS]
+            .iter_mut()
+            .zip(&self.acc)
+        {
+            *q = (a / n / scale).round().clamp(0.0, u16::MAX as f32) as u16;
+        }
+        self.scales[slot] = scale;
+        self.stored += 1;
+        self.acc.fill(0.0);
+        self.acc_n = 0;
+    }
+
+    /// Stored spectra we still have
+    fn held(&self) -> Range<u64> {
+        self.stored.saturating_sub(self.capacity as u64)..self.stored
+    }
+
+    /// Copy the requested window out of the ring and write it to a filterbank in the background
+    fn write(&self, pending: Pending) -> eyre::Result<()> {
+        let dec = self.decimation as u64;
+        let held = self.held();
+        let start = (pending.spectra.start / dec).max(held.start);
+        let end = pending.spectra.end.div_ceil(dec).min(held.end);
+        if start >= end {
+            warn!(
+                name = pending.name,
+                "Requested window is no longer (or not yet) in the stokes ring"
+            );
+            return Ok(());
+        }
+        if start * dec > pending.spectra.start {
+            warn!(
+                name = pending.name,
+                "Requested window is partly gone from the stokes ring, writing what's left"
+            );
+        }
+        let spectra: Vec<Vec<f32>> = (start..end)
+            .map(|i| {
+                let slot = (i % self.capacity as u64) as usize;
+                let scale = self.scales[slot];
+                self.data[slot * CHANNELS..(slot + 1) * CHANNELS]
+                    .iter()
+                    .map(|q| *q as f32 * scale)
+                    .collect()
+            })
+            .collect();
+        let tsamp = self.exfil_tsamp() * self.decimation as f64;
+        let tstart = time_policy().mjd(processed_payload_start_time())
+            + (start * dec) as f64 * self.exfil_tsamp() / 86400.0;
+        let file_path = self.path.join(format!("grex_stokes-{}.fil", pending.name));
+        let freq_plan = self.freq_plan;
+        std::thread::Builder::new()
+            .name("stokes_ring_write".to_owned())
+            .spawn(move || {
+                match write_filterbank(&file_path, &spectra, freq_plan, tsamp, tstart) {
+                    Ok(_) => info!(path = %file_path.display(), "Wrote window of the stokes ring"),
+                    Err(e) => warn!("Failed to write from the stokes ring - {e}"),
+                }
+            })?;
+        Ok(())
+    }
+}
+
+fn write_filterbank(
+    path: &Path,
+    spectra: &[Vec<f32>],
+    freq_plan: FrequencyPlan,
+    tsamp: f64,
+    tstart: f64,
+) -> eyre::Result<()> {
+    let mut fb = WriteFilterbank::new(CHANNELS, 1);
+    fb.fch1 = Some(freq_plan.fch1());
+    fb.foff = Some(freq_plan.foff());
+    fb.tsamp = Some(tsamp);
+    fb.tstart = Some(tstart);
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&fb.header_bytes())?;
+    for spectrum in spectra {
+        file.write_all(&fb.pack(spectrum))?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_quantization() {
+        let (_, requests) = std::sync::mpsc::sync_channel(1);
+        let mut ring = StokesRing::new(
+            1e-3,
+            2,
+            4,
+            FrequencyPlan::default(),
+            Path::new("."),
+            0.0,
+            0.0,
+            0,
+            requests,
+        );
+        let spectrum = |v: f32| -> Stokes { (0..CHANNELS).map(|i| v * i as f32).collect() };
+        ring.push(&spectrum(1.0));
+        ring.push(&spectrum(3.0));
+        assert_eq!(ring.held(), 0..1);
+        // The average of the two, to within the quantization
+        let restored = ring.data[CHANNELS - 1] as f32 * ring.scales[0];
+        assert!((restored - 2.0 * (CHANNELS - 1) as f32).abs() < 0.1);
+        // It wraps around once it's full
+        for _ in 0..2 * ring.capacity {
+            ring.push(&spectrum(1.0));
+        }
+        assert_eq!(ring.held().end - ring.held().start, ring.capacity as u64);
+    }
+
+    #[test]
+    fn test_pending_bounds() {
+        let (sender, requests) = std::sync::mpsc::sync_channel(2 * MAX_PENDING);
+        let mut ring = StokesRing::new(
+            1e-3,
+            2,
+            4,
+            FrequencyPlan::default(),
+            Path::new("."),
+            1.0,
+            1.0,
+            i64::MAX,
+            requests,
+        );
+        // Offsets and padding that would overflow are pinned at the end of time, so they wait forever
+        for i in 0..2 * MAX_PENDING {
+            let event = CandidateEvent {
+                candname: format!("cand{i}"),
+                specnum: u64::MAX - 1,
+                offset: i64::MAX,
+                ..Default::default()
+            };
+            sender.send(RingRequest::Candidate(event)).unwrap();
+        }
+        ring.push(&(0..CHANNELS).map(|_| 0.0).collect());
+        // But only so many of them
+        assert_eq!(ring.pending.len(), MAX_PENDING);
+        assert_eq!(ring.pending[0].spectra.end, u64::MAX);
+    }
+}
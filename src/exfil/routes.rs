@@ -0,0 +1,742 @@
+//! Where each of our output streams goes, so one station can archive locally, search via DADA, and relay upstream at once.
+//! Loaded from a JSON file like
+//! `{"stokes": [{"sink": "file", "path": "/data"}, {"sink": "dada", "key": "b0ba"}], "flags": [{"sink": "dada", "key": "f1a9"}],
+//! "total_power": [{"sink": "network", "addr": "central:9000"}], "candidates": [{"sink": "file", "path": "/data"}]}`,
+//! or built from the exfil subcommand and any `--tee`s when there isn't one.
+use super::{
+    dada::DadaConsumer,
+    dedisperse::DedisperseConsumer,
+    dummy::DummyConsumer,
+    filterbank::FilterbankConsumer,
+    flaglog::FlagLog,
+    hdf5::Hdf5Consumer,
+    mirror::{MirrorConsumer, TeePolicy},
+    parquet::ParquetConsumer,
+    power::PowerLog,
+    psrfits::PsrfitsConsumer,
+    relay::Relay,
+    requant::valid_nbits,
+    spead::{SpeadConsumer, MAX_PACKET_PAYLOAD},
+    stream::{StreamConsumer, StreamTransport},
+    FrequencyPlan, StokesConsumer, STOKES_ORDER,
+};
+use crate::{args, common::CandidateEvent, latency::latency_policy, naming::time_policy};
+use eyre::{bail, eyre};
+use hifitime::Epoch;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::Write,
+    net::{SocketAddr, UdpSocket},
+    path::{Path, PathBuf},
+};
+use tokio::sync::broadcast;
+use tracing::warn;
+
+/// Default window size of DADA sinks, in time samples
+const DEFAULT_DADA_SAMPLES: usize = 65536;
+/// Default spectra per HDF5 chunk
+const DEFAULT_HDF5_CHUNK: usize = 1024;
+/// Default spectra per PSRFITS subint
+const DEFAULT_NSBLK: usize = 4096;
+/// Default subints per PSRFITS file
+const DEFAULT_SUBINTS_PER_FILE: usize = 256;
+/// Default spectra per Parquet row group
+const DEFAULT_PARQUET_BATCH: usize = 4096;
+/// Default row groups per Parquet file
+const DEFAULT_BATCHES_PER_FILE: usize = 64;
+/// Default bytes of spectrum per SPEAD packet
+const DEFAULT_SPEAD_PAYLOAD: usize = 1024;
+/// Default number of spectra each stokes sink can fall behind by, when there's more than one
+const DEFAULT_BACKLOG: usize = 16384;
+
+fn default_dada_samples() -> usize {
+    DEFAULT_DADA_SAMPLES
+}
+
+fn default_hdf5_chunk() -> usize {
+    DEFAULT_HDF5_CHUNK
+}
+
+fn default_nsblk() -> usize {
+    DEFAULT_NSBLK
+}
+
+fn default_subints_per_file() -> usize {
+    DEFAULT_SUBINTS_PER_FILE
+}
+
+fn default_parquet_batch() -> usize {
+    DEFAULT_PARQUET_BATCH
+}
+
+fn default_batches_per_file() -> usize {
+    DEFAULT_BATCHES_PER_FILE
+}
+
+fn default_spead_payload() -> usize {
+    DEFAULT_SPEAD_PAYLOAD
+}
+
+fn default_backlog() -> usize {
+    DEFAULT_BACKLOG
+}
+
+/// One destination for a stream
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "sink", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Sink {
+    /// Files in a directory
+    File {
+        path: PathBuf,
+        /// Bits per sample, for stokes filterbanks (8, 16, or 32)
+        #[serde(default)]
+        nbits: Option<u8>,
+        /// Seconds per integration, for total power (defaults to `--relay-integration`)
+        #[serde(default)]
+        integration: Option<f64>,
+    },
+    /// Chunked HDF5 files (Stokes I) in a directory
+    Hdf5 {
+        path: PathBuf,
+        /// Spectra per chunk
+        #[serde(default = "default_hdf5_chunk")]
+        chunk: usize,
+        /// Deflate level (1-9), uncompressed if unset
+        #[serde(default)]
+        compression: Option<i32>,
+    },
+    /// PSRFITS search-mode files (Stokes I) in a directory
+    Psrfits {
+        path: PathBuf,
+        /// Spectra per subint
+        #[serde(default = "default_nsblk")]
+        nsblk: usize,
+        /// Subints per file
+        #[serde(default = "default_subints_per_file")]
+        subints_per_file: usize,
+    },
+    /// Parquet files (Stokes I) in a directory
+    Parquet {
+        path: PathBuf,
+        /// Spectra per row group
+        #[serde(default = "default_parquet_batch")]
+        batch: usize,
+        /// Row groups per file
+        #[serde(default = "default_batches_per_file")]
+        batches_per_file: usize,
+    },
+    /// A PSRDADA buffer
+    Dada {
+        /// Hex key
+        key: String,
+        /// Window size in number of time samples
+        #[serde(default = "default_dada_samples")]
+        samples: usize,
+    },
+    /// Sent to a remote host (host:port), as newline-delimited JSON over TCP for spectra or JSON datagrams for candidates
+    Network {
+        addr: String,
+        /// Channels to decimate stokes to (defaults to `--relay-channels`)
+        #[serde(default)]
+        channels: Option<usize>,
+        /// Seconds per integration (defaults to `--relay-integration`)
+        #[serde(default)]
+        integration: Option<f64>,
+    },
+    /// Published (as binary frames) to whoever subscribes on a local address
+    Stream {
+        addr: SocketAddr,
+        #[serde(default)]
+        transport: StreamTransport,
+    },
+    /// Sent to a remote host as SPEAD heaps over UDP
+    Spead {
+        addr: SocketAddr,
+        /// Bytes of the spectrum per packet
+        #[serde(default = "default_spead_payload")]
+        payload: usize,
+    },
+    /// Nowhere, on purpose
+    Null,
+}
+
+/// The sinks for every stream we produce. Streams left empty keep their default behavior,
+/// except for stokes, which isn't saved at all (as with no `--exfil`).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutingTable {
+    /// Downsampled stokes spectra
+    #[serde(default)]
+    pub stokes: Vec<Sink>,
+    /// Run-length encoded data quality flags
+    #[serde(default)]
+    pub flags: Vec<Sink>,
+    /// Stokes I summed over the band
+    #[serde(default)]
+    pub total_power: Vec<Sink>,
+    /// Triggers, as they're dispatched
+    #[serde(default)]
+    pub candidates: Vec<Sink>,
+    /// Voltage dumps (defaults to `--dump-path`)
+    #[serde(default)]
+    pub dumps: Vec<Sink>,
+    /// Spectra each stokes sink can fall behind by, when there's more than one
+    #[serde(default = "default_backlog")]
+    pub backlog: usize,
+    /// What a stokes sink's backlog does when it's full
+    #[serde(default)]
+    pub policy: TeePolicy,
+    /// DM (pc cm^-3) to dedisperse stokes at before any sink gets it, dispersed as it arrives if unset
+    #[serde(default)]
+    pub dedisperse_dm: Option<f64>,
+}
+
+impl Default for RoutingTable {
+    fn default() -> Self {
+        Self {
+            stokes: vec![],
+            flags: vec![],
+            total_power: vec![],
+            candidates: vec![],
+            dumps: vec![],
+            backlog: DEFAULT_BACKLOG,
+            policy: TeePolicy::default(),
+            dedisperse_dm: None,
+        }
+    }
+}
+
+fn dada_key(key: &str) -> eyre::Result<i32> {
+    i32::from_str_radix(key, 16).map_err(|_| eyre!("Invalid DADA key {key:?}"))
+}
+
+impl RoutingTable {
+    /// Read a routing table from a JSON file, checking every route makes sense
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        let table: Self = serde_json::from_reader(File::open(path)?)
+            .map_err(|e| eyre!("Invalid routing table {} - {e}", path.display()))?;
+        table.validate()?;
+        Ok(table)
+    }
+
+    /// The routes equivalent to exfil subcommands (and tees), every one getting the stokes stream
+    pub fn from_exfils(
+        exfils: Vec<args::Exfil>,
+        policy: TeePolicy,
+        filterbank_path: &Path,
+    ) -> eyre::Result<Self> {
+        let mut table = Self {
+            policy,
+            ..Self::default()
+        };
+        for exfil in exfils {
+            match exfil {
+                args::Exfil::Psrdada {
+                    key,
+                    samples,
+                    flag_key,
+                } => {
+                    table.stokes.push(Sink::Dada {
+                        key: format!("{key:x}"),
+                        samples,
+                    });
+                    table.flags.extend(flag_key.map(|key| Sink::Dada {
+                        key: format!("{key:x}"),
+                        samples: DEFAULT_DADA_SAMPLES,
+                    }));
+                }
+                args::Exfil::Filterbank {
+                    mirror,
+                    backlog,
+                    nbits,
+                } => {
+                    table.stokes.extend(
+                        std::iter::once(filterbank_path.to_owned())
+                            .chain(mirror)
+                            .map(|path| Sink::File {
+                                path,
+                                nbits: Some(nbits),
+                                integration: None,
+                            }),
+                    );
+                    table.backlog = backlog;
+                }
+                args::Exfil::Hdf5 { chunk, compression } => table.stokes.push(Sink::Hdf5 {
+                    path: filterbank_path.to_owned(),
+                    chunk,
+                    compression,
+                }),
+                args::Exfil::Psrfits {
+                    nsblk,
+                    subints_per_file,
+                } => table.stokes.push(Sink::Psrfits {
+                    path: filterbank_path.to_owned(),
+                    nsblk,
+                    subints_per_file,
+                }),
+                args::Exfil::Parquet {
+                    batch,
+                    batches_per_file,
+                } => table.stokes.push(Sink::Parquet {
+                    path: filterbank_path.to_owned(),
+                    batch,
+                    batches_per_file,
+                }),
+                args::Exfil::Stream { addr, transport } => {
+                    table.stokes.push(Sink::Stream { addr, transport })
+                }
+                args::Exfil::Spead { addr, payload } => {
+                    table.stokes.push(Sink::Spead { addr, payload })
+                }
+            }
+        }
+        table.validate()?;
+        Ok(table)
+    }
+
+    /// Check that every stream is only routed to sinks that can take it
+    pub fn validate(&self) -> eyre::Result<()> {
+        for sink in self
+            .stokes
+            .iter()
+            .chain(&self.flags)
+            .chain(&self.total_power)
+        {
+            match sink {
+                Sink::Dada { key, .. } => {
+                    dada_key(key)?;
+                }
+                Sink::File {
+                    nbits: Some(nbits), ..
+                } => {
+                    valid_nbits(*nbits)?;
+                }
+                Sink::Hdf5 {
+                    chunk, compression, ..
+                } if *chunk == 0 || compression.is_some_and(|c| !(1..=9).contains(&c)) => {
+                    bail!("HDF5 chunks need at least one spectrum, and compression is from 1 to 9");
+                }
+                Sink::Psrfits {
+                    nsblk,
+                    subints_per_file,
+                    ..
+                } if *nsblk == 0 || *subints_per_file == 0 => {
+                    bail!("PSRFITS files need at least one subint of at least one spectrum");
+                }
+                Sink::Parquet {
+                    batch,
+                    batches_per_file,
+                    ..
+                } if *batch == 0 || *batches_per_file == 0 => {
+                    bail!("Parquet files need at least one row group of at least one spectrum");
+                }
+                Sink::Spead { payload, .. }
+                    if *payload == 0
+                        || !payload.is_multiple_of(4)
+                        || *payload > MAX_PACKET_PAYLOAD =>
+                {
+                    bail!("SPEAD packets carry a multiple of 4 bytes of payload, up to {MAX_PACKET_PAYLOAD}");
+                }
+                _ => (),
+            }
+        }
+        if self
+            .flags
+            .iter()
+            .chain(&self.total_power)
+            .chain(&self.candidates)
+            .chain(&self.dumps)
+            .any(|s| {
+                matches!(
+                    s,
+                    Sink::Hdf5 { .. }
+                        | Sink::Psrfits { .. }
+                        | Sink::Parquet { .. }
+                        | Sink::Stream { .. }
+                        | Sink::Spead { .. }
+                )
+            })
+        {
+            bail!("Only stokes can be routed to HDF5, PSRFITS, Parquet, a stream, or SPEAD");
+        }
+        let stokes_dada = self
+            .stokes
+            .iter()
+            .filter(|s| matches!(s, Sink::Dada { .. }))
+            .count();
+        for sink in &self.flags {
+            match sink {
+                Sink::Network { .. } => bail!("Flags can't be routed to the network"),
+                Sink::Dada { .. } if stokes_dada != 1 => {
+                    bail!("Flags can only be routed to DADA alongside exactly one DADA stokes sink")
+                }
+                _ => (),
+            }
+        }
+        if self
+            .flags
+            .iter()
+            .filter(|s| matches!(s, Sink::Dada { .. }))
+            .count()
+            > 1
+        {
+            bail!("Flags can only be routed to one DADA buffer");
+        }
+        for sink in &self.total_power {
+            match sink {
+                Sink::Dada { .. } => bail!("Total power can't be routed to DADA"),
+                Sink::Network {
+                    channels: Some(c), ..
+                } if *c != 1 => bail!("Total power is a single channel"),
+                _ => (),
+            }
+        }
+        if self
+            .candidates
+            .iter()
+            .any(|s| matches!(s, Sink::Dada { .. }))
+        {
+            bail!("Candidates can't be routed to DADA");
+        }
+        if self
+            .dumps
+            .iter()
+            .any(|s| matches!(s, Sink::Dada { .. } | Sink::Network { .. }))
+        {
+            bail!("Dumps can only be routed to a file or null");
+        }
+        if self.dumps.len() > 1 {
+            bail!("Dumps can only be routed to one place");
+        }
+        // Each of these would write over (or fight for) the same thing as another of its kind
+        let mut outputs = std::collections::HashSet::new();
+        for sink in &self.stokes {
+            let output = match sink {
+                Sink::File { path, .. } => format!("file {}", path.display()),
+                Sink::Hdf5 { path, .. } => format!("hdf5 {}", path.display()),
+                Sink::Psrfits { path, .. } => format!("psrfits {}", path.display()),
+                Sink::Parquet { path, .. } => format!("parquet {}", path.display()),
+                Sink::Dada { key, .. } => format!("dada {:x}", dada_key(key)?),
+                Sink::Stream { addr, .. } => format!("stream {addr}"),
+                Sink::Spead { addr, .. } => format!("spead {addr}"),
+                Sink::Network { .. } | Sink::Null => continue,
+            };
+            if !outputs.insert(output.clone()) {
+                bail!("Stokes is routed to the same {output} more than once");
+            }
+        }
+        if self.backlog == 0 {
+            bail!("Stokes sink backlog must be at least one spectrum");
+        }
+        if self
+            .dedisperse_dm
+            .is_some_and(|dm| !dm.is_finite() || dm < 0.0)
+        {
+            bail!("Stokes can only be dedispersed at a non-negative DM");
+        }
+        Ok(())
+    }
+
+    /// Build the consumer (fanning out to several, if need be) and relays that together take the stokes, flag,
+    /// and total power streams to where they're routed
+    pub fn build_exfil(
+        &self,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        relay_channels: usize,
+        relay_integration: f64,
+        shutdown: &broadcast::Sender<()>,
+    ) -> eyre::Result<(Box<dyn StokesConsumer>, Vec<Relay>)> {
+        self.validate()?;
+        // Processing has already put the spectra in STOKES_ORDER
+        let freq_plan = freq_plan.reordered(STOKES_ORDER);
+        let flag_key = self
+            .flags
+            .iter()
+            .find_map(|s| match s {
+                Sink::Dada { key, .. } => Some(dada_key(key)),
+                _ => None,
+            })
+            .transpose()?;
+        let mut consumers: Vec<Box<dyn StokesConsumer>> = vec![];
+        let mut relays = vec![];
+        let mut filterbanks = 0;
+        for sink in &self.stokes {
+            match sink {
+                Sink::File { path, nbits, .. } => {
+                    let fb = FilterbankConsumer::new(downsample_factor, freq_plan, path)?
+                        .with_nbits(nbits.unwrap_or(32))?;
+                    consumers.push(Box::new(match filterbanks {
+                        0 => fb,
+                        1 => fb.named("filterbank_mirror"),
+                        n => fb.named(&format!("filterbank_mirror_{n}")),
+                    }));
+                    filterbanks += 1;
+                }
+                Sink::Hdf5 {
+                    path,
+                    chunk,
+                    compression,
+                } => consumers.push(Box::new(Hdf5Consumer::new(
+                    downsample_factor,
+                    freq_plan,
+                    path,
+                    *chunk,
+                    *compression,
+                )?)),
+                Sink::Psrfits {
+                    path,
+                    nsblk,
+                    subints_per_file,
+                } => consumers.push(Box::new(PsrfitsConsumer::new(
+                    downsample_factor,
+                    freq_plan,
+                    path,
+                    *nsblk,
+                    *subints_per_file,
+                )?)),
+                Sink::Parquet {
+                    path,
+                    batch,
+                    batches_per_file,
+                } => consumers.push(Box::new(ParquetConsumer::new(
+                    downsample_factor,
+                    freq_plan,
+                    path,
+                    *batch,
+                    *batches_per_file,
+                )?)),
+                Sink::Stream { addr, transport } => consumers.push(Box::new(StreamConsumer::new(
+                    *addr,
+                    *transport,
+                    downsample_factor,
+                )?)),
+                Sink::Spead { addr, payload } => consumers.push(Box::new(SpeadConsumer::new(
+                    *addr,
+                    *payload,
+                    downsample_factor,
+                )?)),
+                Sink::Dada { key, samples } => consumers.push(Box::new(DadaConsumer::new(
+                    dada_key(key)?,
+                    downsample_factor,
+                    freq_plan,
+                    *samples,
+                    flag_key,
+                    shutdown.subscribe(),
+                )?)),
+                Sink::Network {
+                    addr,
+                    channels,
+                    integration,
+                } => relays.push(Relay::new(
+                    addr.clone(),
+                    channels.unwrap_or(relay_channels),
+                    integration.unwrap_or(relay_integration),
+                    downsample_factor,
+                    freq_plan,
+                )?),
+                Sink::Null => (),
+            }
+        }
+        for sink in &self.flags {
+            if let Sink::File { path, .. } = sink {
+                consumers.push(Box::new(FlagLog::new(path)?));
+            }
+        }
+        for sink in &self.total_power {
+            match sink {
+                Sink::File {
+                    path, integration, ..
+                } => consumers.push(Box::new(PowerLog::new(
+                    integration.unwrap_or(relay_integration),
+                    downsample_factor,
+                    path,
+                )?)),
+                Sink::Network {
+                    addr, integration, ..
+                } => relays.push(Relay::new(
+                    addr.clone(),
+                    1,
+                    integration.unwrap_or(relay_integration),
+                    downsample_factor,
+                    freq_plan,
+                )?),
+                Sink::Hdf5 { .. }
+                | Sink::Psrfits { .. }
+                | Sink::Parquet { .. }
+                | Sink::Stream { .. }
+                | Sink::Spead { .. }
+                | Sink::Dada { .. }
+                | Sink::Null => (),
+            }
+        }
+        let consumer: Box<dyn StokesConsumer> = match consumers.len() {
+            0 => return Ok((Box::new(DummyConsumer), relays)),
+            1 => consumers.pop().unwrap(),
+            _ => Box::new(MirrorConsumer::new(consumers, self.backlog, self.policy)?),
+        };
+        let consumer: Box<dyn StokesConsumer> = match self.dedisperse_dm {
+            Some(dm) => Box::new(DedisperseConsumer::new(
+                consumer,
+                dm,
+                downsample_factor,
+                freq_plan,
+            )?),
+            None => consumer,
+        };
+        Ok((consumer, relays))
+    }
+
+    /// Where dumps go, `None` if they're routed to null
+    pub fn dump_path(&self, default: &Path) -> Option<PathBuf> {
+        match self.dumps.first() {
+            Some(Sink::File { path, .. }) => Some(path.clone()),
+            Some(_) => None,
+            None => Some(default.to_owned()),
+        }
+    }
+
+    /// Open the sinks for dispatched candidates
+    pub fn candidate_sinks(&self) -> eyre::Result<Vec<CandidateSink>> {
+        self.candidates
+            .iter()
+            .filter_map(|sink| match sink {
+                Sink::File { path, .. } => Some(CandidateSink::log(path)),
+                Sink::Network { addr, .. } => Some(CandidateSink::forward(addr)),
+                Sink::Hdf5 { .. }
+                | Sink::Psrfits { .. }
+                | Sink::Parquet { .. }
+                | Sink::Stream { .. }
+                | Sink::Spead { .. }
+                | Sink::Dada { .. }
+                | Sink::Null => None,
+            })
+            .collect()
+    }
+}
+
+/// Somewhere to send a copy of every candidate we dispatch
+pub enum CandidateSink {
+    /// Lines of JSON in a file
+    Log(File),
+    /// JSON datagrams (as our own UDP trigger source takes) to another host
+    Forward { socket: UdpSocket, addr: String },
+}
+
+impl CandidateSink {
+    fn log(path: &Path) -> eyre::Result<Self> {
+        let filename = format!(
+            "grex-{}.candidates",
+            time_policy().filename_stamp(Epoch::now()?)
+        );
+        Ok(Self::Log(latency_policy().create(&path.join(filename))?))
+    }
+
+    fn forward(addr: &str) -> eyre::Result<Self> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.set_nonblocking(true)?;
+        Ok(Self::Forward {
+            socket,
+            addr: addr.to_owned(),
+        })
+    }
+
+    /// Send a candidate on, which never holds up the dispatch (only warning if it couldn't)
+    pub fn send(&mut self, event: &CandidateEvent) {
+        let message = serde_json::to_vec(event).expect("Candidates always serialize");
+        let result = match self {
+            Self::Log(file) => file.write_all(&message).and_then(|_| file.write_all(b"\n")),
+            Self::Forward { socket, addr } => socket.send_to(&message, addr.as_str()).map(|_| ()),
+        };
+        if let Err(e) = result {
+            warn!("Couldn't route a candidate - {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_validate() {
+        let table: RoutingTable = serde_json::from_str(
+            r#"{"stokes": [{"sink": "file", "path": "/data"}, {"sink": "dada", "key": "b0ba"}],
+                "flags": [{"sink": "dada", "key": "f1a9"}],
+                "total_power": [{"sink": "network", "addr": "central:9000"}],
+                "dumps": [{"sink": "null"}]}"#,
+        )
+        .unwrap();
+        assert!(table.validate().is_ok());
+        assert_eq!(table.backlog, DEFAULT_BACKLOG);
+        assert_eq!(table.dump_path(Path::new(".")), None);
+        assert!(matches!(
+            table.stokes[1],
+            Sink::Dada {
+                samples: DEFAULT_DADA_SAMPLES,
+                ..
+            }
+        ));
+        // Flags have nowhere to go in DADA without the stokes
+        let bad = RoutingTable {
+            stokes: vec![],
+            ..table.clone()
+        };
+        assert!(bad.validate().is_err());
+        let bad = RoutingTable {
+            dumps: vec![Sink::Network {
+                addr: "central:9000".to_owned(),
+                channels: None,
+                integration: None,
+            }],
+            ..table
+        };
+        assert!(bad.validate().is_err());
+        assert!(serde_json::from_str::<RoutingTable>(r#"{"stoke": []}"#).is_err());
+    }
+
+    #[test]
+    fn test_from_exfil() {
+        let table = RoutingTable::from_exfils(
+            vec![
+                args::Exfil::Psrdada {
+                    key: 0xb0ba,
+                    samples: 1024,
+                    flag_key: Some(0xf1a9),
+                },
+                args::Exfil::Filterbank {
+                    mirror: None,
+                    backlog: DEFAULT_BACKLOG,
+                    nbits: 8,
+                },
+            ],
+            TeePolicy::Block,
+            Path::new("."),
+        )
+        .unwrap();
+        assert_eq!(table.stokes.len(), 2);
+        assert_eq!(table.policy, TeePolicy::Block);
+        assert_eq!(
+            table.flags,
+            vec![Sink::Dada {
+                key: "f1a9".to_owned(),
+                samples: DEFAULT_DADA_SAMPLES
+            }]
+        );
+        assert_eq!(
+            RoutingTable::from_exfils(vec![], TeePolicy::Drop, Path::new(".")).unwrap(),
+            RoutingTable::default()
+        );
+        // Two filterbanks in the same place would write the same file
+        let fb = args::Exfil::Filterbank {
+            mirror: None,
+            backlog: DEFAULT_BACKLOG,
+            nbits: 32,
+        };
+        assert!(
+            RoutingTable::from_exfils(vec![fb.clone(), fb], TeePolicy::Drop, Path::new("."))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,198 @@
+//! Every downsampled spectrum re-exported as a SPEAD heap over UDP, so a station can forward reduced data to a central
+//! aggregator in real time. Packets are SPEAD-64-48 (big-endian item pointers, 15 bit item ids and 48 bit
+//! addresses). No item descriptors are sent, so receivers should expect these items in every packet:
+//!
+//! | id       | kind      | contents                                                        |
+//! |----------|-----------|-----------------------------------------------------------------|
+//! | `0x0001` | immediate | heap counter (spectra since the start of the observation)       |
+//! | `0x0002` | immediate | heap size in bytes                                              |
+//! | `0x0003` | immediate | offset of this packet's payload in the heap                     |
+//! | `0x0004` | immediate | payload length of this packet                                   |
+//! | `0x1600` | immediate | payload count of the first sample in the spectrum               |
+//! | `0x1001` | immediate | number of channels                                              |
+//! | `0x1002` | immediate | payloads averaged into each spectrum                            |
+//! | `0x3300` | address 0 | the spectrum, as little-endian f32s in [`super::STOKES_ORDER`]  |
+use super::{
+    exfil_first_packet,
+    stats::{sink_stats, SinkStats},
+    StokesConsumer,
+};
+use crate::{
+    accounting::accounting,
+    common::{Stokes, PACKET_CADENCE},
+};
+use std::{
+    io::ErrorKind,
+    net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+
+/// SPEAD magic, version, item pointer width (bytes), and heap address width (bytes)
+const SPEAD_HEADER: [u8; 4] = [0x53, 0x04, 2, 6];
+const HEAP_CNT: u16 = 0x0001;
+const HEAP_SIZE: u16 = 0x0002;
+const HEAP_OFFSET: u16 = 0x0003;
+const PAYLOAD_LEN: u16 = 0x0004;
+const TIMESTAMP: u16 = 0x1600;
+const NCHAN: u16 = 0x1001;
+const DOWNSAMPLE: u16 = 0x1002;
+const STOKES: u16 = 0x3300;
+/// Largest heap payload we'll put in one packet (a jumbo frame)
+pub const MAX_PACKET_PAYLOAD: usize = 8192;
+
+fn immediate(id: u16, value: u64) -> u64 {
+    (1 << 63) | (u64::from(id) << 48) | (value & ((1 << 48) - 1))
+}
+
+fn addressed(id: u16, address: u64) -> u64 {
+    (u64::from(id) << 48) | address
+}
+
+/// Split one spectrum into the packets of a heap, each with at most `payload` bytes of it
+fn encode_heap(
+    heap: u64,
+    count: u64,
+    downsample_factor: u64,
+    stokes: &Stokes,
+    payload: usize,
+) -> Vec<Vec<u8>> {
+    let data: Vec<u8> = stokes.iter().flat_map(|x| x.to_le_bytes()).collect();
+    data.chunks(payload)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let items = [
+                immediate(HEAP_CNT, heap),
+                immediate(HEAP_SIZE, data.len() as u64),
+                immediate(HEAP_OFFSET, (i * payload) as u64),
+                immediate(PAYLOAD_LEN, chunk.len() as u64),
+                immediate(TIMESTAMP, count),
+                immediate(NCHAN, stokes.len() as u64),
+                immediate(DOWNSAMPLE, downsample_factor),
+                addressed(STOKES, 0),
+            ];
+            let mut packet = Vec::with_capacity(8 + 8 * items.len() + chunk.len());
+            packet.extend_from_slice(&SPEAD_HEADER);
+            packet.extend_from_slice(&[0, 0]);
+            packet.extend_from_slice(&(items.len() as u16).to_be_bytes());
+            packet.extend(items.iter().flat_map(|item| item.to_be_bytes()));
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect()
+}
+
+/// Sends every spectrum to `addr` as a SPEAD heap, never holding up exfil (packets the socket won't take are dropped)
+pub struct SpeadConsumer {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    /// Heap payload bytes per packet
+    payload: usize,
+    downsample_factor: u64,
+    /// Payload count of the start of the first spectrum, once we've seen it
+    first_count: Option<u64>,
+    heaps: u64,
+    dropped: u64,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+}
+
+impl SpeadConsumer {
+    pub fn new(addr: SocketAddr, payload: usize, downsample_factor: usize) -> eyre::Result<Self> {
+        if payload == 0 || !payload.is_multiple_of(4) || payload > MAX_PACKET_PAYLOAD {
+            eyre::bail!(
+                "SPEAD packets carry a multiple of 4 bytes of payload, up to {MAX_PACKET_PAYLOAD}"
+            );
+        }
+        let socket = UdpSocket::bind(match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })?;
+        socket.set_nonblocking(true)?;
+        let budget = Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64);
+        let stats = sink_stats("spead", budget);
+        stats.set_target(format!("spead {addr}"));
+        info!(%addr, payload, "Sending the stokes stream as SPEAD");
+        Ok(Self {
+            socket,
+            addr,
+            payload,
+            downsample_factor: downsample_factor as u64,
+            first_count: None,
+            heaps: 0,
+            dropped: 0,
+            written: accounting().output("spead"),
+            stats,
+        })
+    }
+}
+
+impl StokesConsumer for SpeadConsumer {
+    fn name(&self) -> &str {
+        "spead"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        let first = *self.first_count.get_or_insert_with(exfil_first_packet);
+        let count = first + self.heaps * self.downsample_factor;
+        let packets = encode_heap(
+            self.heaps,
+            count,
+            self.downsample_factor,
+            stokes,
+            self.payload,
+        );
+        self.heaps += 1;
+        let write_start = Instant::now();
+        let mut bytes = 0;
+        for packet in packets {
+            match self.socket.send_to(&packet, self.addr) {
+                Ok(n) => bytes += n,
+                // A partial heap is no use to anyone, so drop the rest of it
+                Err(e) => {
+                    self.dropped += 1;
+                    if e.kind() != ErrorKind::WouldBlock {
+                        self.stats.record_error(&e.into());
+                    }
+                    if self.dropped.is_power_of_two() {
+                        warn!(
+                            dropped = self.dropped,
+                            "Couldn't send a SPEAD heap, dropping it"
+                        );
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        self.stats.record_write(bytes, write_start.elapsed());
+        self.written.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::CHANNELS;
+
+    #[test]
+    fn test_encode_heap() {
+        let stokes = Stokes::from([1.5; CHANNELS]);
+        let packets = encode_heap(7, 1234, 4, &stokes, 1024);
+        assert_eq!(packets.len(), CHANNELS * 4 / 1024);
+        let second = &packets[1];
+        assert_eq!(second[..4], SPEAD_HEADER);
+        assert_eq!(u16::from_be_bytes([second[6], second[7]]), 8);
+        let item = |n: usize| u64::from_be_bytes(second[8 + 8 * n..16 + 8 * n].try_into().unwrap());
+        assert_eq!(item(0), immediate(HEAP_CNT, 7));
+        assert_eq!(item(2) & ((1 << 48) - 1), 1024);
+        assert_eq!(item(4) & ((1 << 48) - 1), 1234);
+        assert_eq!(item(7) >> 63, 0);
+        assert_eq!(second.len(), 8 + 8 * 8 + 1024);
+        assert_eq!(second[72..76], 1.5f32.to_le_bytes());
+    }
+}
@@ -0,0 +1,130 @@
+//! Output statistics of each exfil sink, so they can be told apart on the dashboards
+use crate::profiling::{profile, StageProfile};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+
+/// Counters and status of a single exfil sink
+#[derive(Debug)]
+pub struct SinkStats {
+    pub bytes: AtomicU64,
+    /// Blocks (or buffers) handed off to whatever is downstream of the sink, if it works in blocks
+    pub blocks: AtomicU64,
+    /// Times the sink was held up on its output for longer than its budget, and for how long in total
+    pub stalls: AtomicU64,
+    pub stalled_ns: AtomicU64,
+    budget: Duration,
+    /// Time taken by each write, including any time spent waiting on the output
+    latency: Arc<StageProfile>,
+    /// Where the sink is currently writing to
+    target: RwLock<Option<String>>,
+    last_error: RwLock<Option<String>>,
+}
+
+/// A point-in-time copy of a sink's statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct SinkSnapshot {
+    pub bytes: u64,
+    pub blocks: u64,
+    pub stalls: u64,
+    pub stalled_ns: u64,
+    pub writes: u64,
+    pub write_p99_ns: u64,
+    pub target: Option<String>,
+    pub last_error: Option<String>,
+}
+
+impl SinkStats {
+    /// Record a write of `bytes` that took `elapsed`, which stalled the sink if that's over budget
+    pub fn record_write(&self, bytes: usize, elapsed: Duration) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.latency.record(elapsed);
+        if elapsed > self.budget {
+            self.record_stall(elapsed);
+        }
+    }
+
+    /// Record time spent waiting for the output to take a write (like waiting on a free block), which stalls the sink
+    /// if that's over budget
+    pub fn record_wait(&self, elapsed: Duration) {
+        if elapsed > self.budget {
+            self.record_stall(elapsed);
+        }
+    }
+
+    /// Record time spent held up by the output outside of a write (like waiting on a full queue)
+    pub fn record_stall(&self, elapsed: Duration) {
+        self.stalls.fetch_add(1, Ordering::Relaxed);
+        self.stalled_ns
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_block(&self) {
+        self.blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn set_target(&self, target: impl Into<String>) {
+        *self.target.write().unwrap() = Some(target.into());
+    }
+
+    pub fn record_error(&self, error: &eyre::Report) {
+        *self.last_error.write().unwrap() = Some(error.to_string());
+    }
+
+    pub fn snapshot(&self) -> SinkSnapshot {
+        let latency = self.latency.snapshot();
+        SinkSnapshot {
+            bytes: self.bytes.load(Ordering::Relaxed),
+            blocks: self.blocks.load(Ordering::Relaxed),
+            stalls: self.stalls.load(Ordering::Relaxed),
+            stalled_ns: self.stalled_ns.load(Ordering::Relaxed),
+            writes: latency.iterations,
+            write_p99_ns: latency.p99_ns,
+            target: self.target.read().unwrap().clone(),
+            last_error: self.last_error.read().unwrap().clone(),
+        }
+    }
+}
+
+fn sinks() -> &'static RwLock<BTreeMap<String, Arc<SinkStats>>> {
+    static SINKS: OnceLock<RwLock<BTreeMap<String, Arc<SinkStats>>>> = OnceLock::new();
+    SINKS.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+/// Get (or create) the statistics of the named sink, whose writes should take less than `budget`.
+/// Sinks should grab this once and record into it directly.
+pub fn sink_stats(name: &str, budget: Duration) -> Arc<SinkStats> {
+    sinks()
+        .write()
+        .unwrap()
+        .entry(name.to_owned())
+        .or_insert_with(|| {
+            Arc::new(SinkStats {
+                bytes: AtomicU64::new(0),
+                blocks: AtomicU64::new(0),
+                stalls: AtomicU64::new(0),
+                stalled_ns: AtomicU64::new(0),
+                budget,
+                latency: profile(&format!("{name}_write"), budget),
+                target: RwLock::new(None),
+                last_error: RwLock::new(None),
+            })
+        })
+        .clone()
+}
+
+/// Snapshots of every sink's statistics
+pub fn snapshot() -> BTreeMap<String, SinkSnapshot> {
+    sinks()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, s)| (name.clone(), s.snapshot()))
+        .collect()
+}
@@ -0,0 +1,239 @@
+//! Every downsampled spectrum published over the network, so a detection pipeline on another machine can consume it
+//! without PSRDADA or shared files. Each frame is a little-endian u64 payload count (of the first sample in the spectrum),
+//! f64 MJD (in our time standard), and u32 channel count, followed by that many little-endian f32s. Over ZeroMQ a frame is
+//! a message on a PUB socket; over plain TCP each frame is preceded by its length as a little-endian u32.
+use super::{
+    exfil_first_packet,
+    stats::{sink_stats, SinkStats},
+    StokesConsumer,
+};
+use crate::{
+    accounting::accounting,
+    common::{payload_time, Stokes, PACKET_CADENCE},
+    naming::time_policy,
+};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{ErrorKind, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{info, warn};
+use zeromq::{PubSocket, Socket, SocketSend};
+
+/// Frames we'll hold while the network catches up, after which they're dropped
+const STREAM_BACKLOG: usize = 4096;
+/// How long a plain TCP subscriber can hold up a write before it's disconnected
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+/// Bytes before the spectrum in every frame
+pub const FRAME_HEADER_BYTES: usize = 8 + 8 + 4;
+
+/// How spectra are published
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamTransport {
+    /// A ZeroMQ PUB socket
+    #[default]
+    Zmq,
+    /// Length-prefixed frames to every connected TCP client
+    Tcp,
+}
+
+/// Pack one spectrum into a frame
+fn encode(count: u64, mjd: f64, stokes: &Stokes) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_BYTES + stokes.len() * 4);
+    frame.extend_from_slice(&count.to_le_bytes());
+    frame.extend_from_slice(&mjd.to_le_bytes());
+    frame.extend_from_slice(&(stokes.len() as u32).to_le_bytes());
+    frame.extend(stokes.iter().flat_map(|x| x.to_le_bytes()));
+    frame
+}
+
+/// Hands each spectrum to a background publisher, never holding up exfil (frames are dropped if the network can't keep up)
+pub struct StreamConsumer {
+    sender: SyncSender<Vec<u8>>,
+    downsample_factor: u64,
+    /// Payload count of the start of the first spectrum, once we've seen it
+    first_count: Option<u64>,
+    spectra: u64,
+    dropped: u64,
+    written: Arc<AtomicU64>,
+    stats: Arc<SinkStats>,
+}
+
+impl StreamConsumer {
+    /// Publish on `addr` over `transport`
+    pub fn new(
+        addr: SocketAddr,
+        transport: StreamTransport,
+        downsample_factor: usize,
+    ) -> eyre::Result<Self> {
+        let budget = Duration::from_secs_f64(PACKET_CADENCE * downsample_factor as f64);
+        let stats = sink_stats("stream", budget);
+        stats.set_target(format!("{transport:?} {addr}").to_lowercase());
+        let (sender, frames) = sync_channel(STREAM_BACKLOG);
+        let publisher_stats = stats.clone();
+        match transport {
+            StreamTransport::Zmq => {
+                // The socket is async, so it gets a little runtime of its own
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                let mut socket = PubSocket::new();
+                runtime.block_on(socket.bind(&format!("tcp://{addr}")))?;
+                std::thread::Builder::new()
+                    .name("stream_zmq".to_owned())
+                    .spawn(move || {
+                        zmq_loop(runtime, socket, frames, &publisher_stats);
+                    })?;
+            }
+            StreamTransport::Tcp => {
+                let listener = TcpListener::bind(addr)?;
+                listener.set_nonblocking(true)?;
+                std::thread::Builder::new()
+                    .name("stream_tcp".to_owned())
+                    .spawn(move || tcp_loop(listener, frames, &publisher_stats))?;
+            }
+        }
+        info!(%addr, ?transport, "Publishing the stokes stream");
+        Ok(Self {
+            sender,
+            downsample_factor: downsample_factor as u64,
+            first_count: None,
+            spectra: 0,
+            dropped: 0,
+            written: accounting().output("stream"),
+            stats,
+        })
+    }
+}
+
+impl StokesConsumer for StreamConsumer {
+    fn name(&self) -> &str {
+        "stream"
+    }
+
+    fn consume(&mut self, stokes: &Stokes) -> eyre::Result<()> {
+        let first = *self.first_count.get_or_insert_with(exfil_first_packet);
+        let count = first + self.spectra * self.downsample_factor;
+        let frame = encode(count, time_policy().mjd(payload_time(count)), stokes);
+        self.spectra += 1;
+        match self.sender.try_send(frame) {
+            Ok(_) => {
+                self.written.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    warn!("Stokes stream fell behind, dropping spectra");
+                }
+                self.dropped += 1;
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                let e = eyre::eyre!("Stokes stream publisher stopped");
+                self.stats.record_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> eyre::Result<()> {
+        if self.dropped > 0 {
+            warn!(
+                dropped = self.dropped,
+                "Stokes stream dropped spectra while falling behind"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Publish frames until the consumer goes away
+fn zmq_loop(
+    runtime: tokio::runtime::Runtime,
+    mut socket: PubSocket,
+    frames: Receiver<Vec<u8>>,
+    stats: &SinkStats,
+) {
+    while let Ok(frame) = frames.recv() {
+        let write_start = Instant::now();
+        let len = frame.len();
+        match runtime.block_on(socket.send(frame.into())) {
+            Ok(_) => stats.record_write(len, write_start.elapsed()),
+            Err(e) => stats.record_error(&eyre::Report::from(e)),
+        }
+    }
+}
+
+/// Write frames to every connected client until the consumer goes away, dropping clients that error or stall
+fn tcp_loop(listener: TcpListener, frames: Receiver<Vec<u8>>, stats: &SinkStats) {
+    let mut clients: Vec<(SocketAddr, TcpStream)> = vec![];
+    while let Ok(frame) = frames.recv() {
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    if let Err(e) = stream
+                        .set_nonblocking(false)
+                        .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                    {
+                        warn!(%peer, "Couldn't set up a stream subscriber - {e}");
+                        continue;
+                    }
+                    info!(%peer, "Stream subscriber connected");
+                    clients.push((peer, stream));
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Failed to accept a stream subscriber - {e}");
+                    break;
+                }
+            }
+        }
+        let write_start = Instant::now();
+        let len = (frame.len() as u32).to_le_bytes();
+        clients.retain_mut(|(peer, stream)| {
+            match stream
+                .write_all(&len)
+                .and_then(|_| stream.write_all(&frame))
+            {
+                Ok(_) => true,
+                Err(e) => {
+                    info!(%peer, "Stream subscriber went away - {e}");
+                    false
+                }
+            }
+        });
+        if !clients.is_empty() {
+            stats.record_write(len.len() + frame.len(), write_start.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common::CHANNELS;
+
+    #[test]
+    fn test_encode() {
+        let stokes = Stokes::from([1.0; CHANNELS]);
+        let frame = encode(42, 60000.5, &stokes);
+        assert_eq!(frame.len(), FRAME_HEADER_BYTES + CHANNELS * 4);
+        assert_eq!(u64::from_le_bytes(frame[..8].try_into().unwrap()), 42);
+        assert_eq!(
+            f64::from_le_bytes(frame[8..16].try_into().unwrap()),
+            60000.5
+        );
+        assert_eq!(
+            u32::from_le_bytes(frame[16..20].try_into().unwrap()),
+            CHANNELS as u32
+        );
+        assert_eq!(f32::from_le_bytes(frame[20..24].try_into().unwrap()), 1.0);
+    }
+}
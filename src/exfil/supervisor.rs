@@ -0,0 +1,175 @@
+//! Switching the stokes sinks while we run, so an operator can start writing filterbanks during an interesting event (or
+//! stop, or write somewhere else) without restarting the pipeline. The exfil task keeps draining the stokes channel and
+//! swaps consumers between spectra, finishing every old sink before the new ones (built from the same routing table, with
+//! the new stokes sinks) see their first spectrum. Flags and total power are rebuilt with them, but relays keep running
+//! as they were started.
+use super::{
+    dummy::DummyConsumer,
+    relay::Relay,
+    routes::{RoutingTable, Sink},
+    set_exfil_first_packet, FrequencyPlan, StokesConsumer,
+};
+use crate::common::FIRST_PACKET;
+use eyre::{bail, eyre};
+use std::sync::{
+    atomic::Ordering,
+    mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+    Arc, Mutex,
+};
+use tokio::sync::broadcast;
+use tracing::{error, info};
+
+/// Switches that can be waiting on the exfil task at once
+const PENDING_SWITCHES: usize = 4;
+
+/// Builds the stokes consumer in the exfil task, and rebuilds it whenever new sinks are asked for
+pub struct Supervisor {
+    routes: RoutingTable,
+    requests: Receiver<Vec<Sink>>,
+    /// The stokes sinks actually running, shared with [`ExfilControl`]
+    current: Arc<Mutex<Vec<Sink>>>,
+    downsample_factor: usize,
+    freq_plan: FrequencyPlan,
+    relay_channels: usize,
+    relay_integration: f64,
+    shutdown: broadcast::Sender<()>,
+}
+
+/// The web server's handle on the [`Supervisor`]
+pub struct ExfilControl {
+    /// Everything but the stokes sinks, to validate switches against
+    routes: RoutingTable,
+    requests: SyncSender<Vec<Sink>>,
+    current: Arc<Mutex<Vec<Sink>>>,
+}
+
+impl Supervisor {
+    pub fn new(
+        routes: RoutingTable,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+        relay_channels: usize,
+        relay_integration: f64,
+        shutdown: broadcast::Sender<()>,
+    ) -> (Self, ExfilControl) {
+        let (s, r) = sync_channel(PENDING_SWITCHES);
+        let current = Arc::new(Mutex::new(routes.stokes.clone()));
+        let control = ExfilControl {
+            routes: routes.clone(),
+            requests: s,
+            current: current.clone(),
+        };
+        (
+            Self {
+                routes,
+                requests: r,
+                current,
+                downsample_factor,
+                freq_plan,
+                relay_channels,
+                relay_integration,
+                shutdown,
+            },
+            control,
+        )
+    }
+
+    /// Build the consumer and relays for the routes we were started with
+    pub fn build(&self) -> eyre::Result<(Box<dyn StokesConsumer>, Vec<Relay>)> {
+        self.routes.build_exfil(
+            self.downsample_factor,
+            self.freq_plan,
+            self.relay_channels,
+            self.relay_integration,
+            &self.shutdown,
+        )
+    }
+
+    /// Swap in a new consumer if a switch has been asked for, `consumed` spectra into the stream.
+    /// If the new sinks can't be built, exfil stops until the next switch.
+    pub fn poll(&mut self, consumer: &mut Box<dyn StokesConsumer>, consumed: u64) -> bool {
+        let Ok(stokes) = self.requests.try_recv() else {
+            return false;
+        };
+        info!("Switching stokes exfil from {}", consumer.name());
+        if let Err(e) = consumer.finish() {
+            error!(
+                "Error finishing {} before switching exfil - {e}",
+                consumer.name()
+            );
+        }
+        // Whatever we build next starts with the spectrum after this one
+        set_exfil_first_packet(
+            FIRST_PACKET.load(Ordering::Acquire) + consumed * self.downsample_factor as u64,
+        );
+        self.routes.stokes = stokes;
+        *consumer = match self.build() {
+            Ok((c, _)) => {
+                info!("Stokes exfil switched to {}", c.name());
+                c
+            }
+            Err(e) => {
+                error!("Couldn't switch stokes exfil, stopping it - {e}");
+                self.routes.stokes.clear();
+                Box::new(DummyConsumer)
+            }
+        };
+        *self.current.lock().unwrap() = self.routes.stokes.clone();
+        true
+    }
+}
+
+impl ExfilControl {
+    /// The stokes sinks running now
+    pub fn current(&self) -> Vec<Sink> {
+        self.current.lock().unwrap().clone()
+    }
+
+    /// Ask the exfil task to switch to these stokes sinks (stopping exfil if there aren't any)
+    pub fn switch(&self, stokes: Vec<Sink>) -> eyre::Result<()> {
+        if stokes.iter().any(|s| matches!(s, Sink::Network { .. })) {
+            bail!("Relays can't be switched at runtime");
+        }
+        RoutingTable {
+            stokes: stokes.clone(),
+            ..self.routes.clone()
+        }
+        .validate()?;
+        self.requests.try_send(stokes).map_err(|e| match e {
+            TrySendError::Full(_) => eyre!("Too many exfil switches pending"),
+            TrySendError::Disconnected(_) => eyre!("Exfil isn't running"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_switch() {
+        let routes = RoutingTable {
+            stokes: vec![Sink::Null],
+            ..RoutingTable::default()
+        };
+        let (shutdown, _) = broadcast::channel(1);
+        let (mut supervisor, control) =
+            Supervisor::new(routes, 4, FrequencyPlan::default(), 64, 1.0, shutdown);
+        let (mut consumer, _) = supervisor.build().unwrap();
+        assert!(!supervisor.poll(&mut consumer, 0));
+        assert!(control
+            .switch(vec![Sink::Network {
+                addr: "central:9000".to_owned(),
+                channels: None,
+                integration: None,
+            }])
+            .is_err());
+        assert!(control.switch(vec![Sink::Null, Sink::Null]).is_ok());
+        assert!(supervisor.poll(&mut consumer, 10));
+        assert_eq!(control.current(), vec![Sink::Null, Sink::Null]);
+        assert_eq!(consumer.name(), "dummy");
+        assert!(control.switch(vec![]).is_ok());
+        assert!(supervisor.poll(&mut consumer, 20));
+        assert!(control.current().is_empty());
+    }
+}
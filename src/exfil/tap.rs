@@ -0,0 +1,215 @@
+//! A read-only copy of the full stokes stream served over TCP, so observer processes can experiment on live data.
+//! Subscribers can never slow down or break the production stream, a subscriber that falls behind just misses spectra.
+use super::FrequencyPlan;
+use crate::common::{processed_payload_start_time, Stokes, CHANNELS, PACKET_CADENCE};
+use crate::naming::time_policy;
+use eyre::eyre;
+use serde::{Deserialize, Serialize};
+use std::{
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, sync_channel, Receiver, SyncSender, TrySendError},
+};
+use tracing::{info, warn};
+
+/// Spectra each subscriber can be behind by before it starts missing them
+const TAP_BACKLOG: usize = 4096;
+
+/// Sent once as a line of JSON when a subscriber connects, followed by frames of a little-endian u64 spectrum index
+/// (counting from the start of the observation) and [`CHANNELS`] little-endian f32s
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TapHeader {
+    /// Start of the observation (MJD, in the time policy's standard)
+    pub start_mjd: f64,
+    /// Time per spectrum (s)
+    pub tsamp: f64,
+    pub downsample_factor: usize,
+    pub channels: usize,
+    /// Center frequency of the first channel (MHz)
+    pub fch1: f64,
+    /// Channel spacing (MHz)
+    pub foff: f64,
+    pub freq_plan: FrequencyPlan,
+}
+
+struct Subscriber {
+    peer: SocketAddr,
+    sender: SyncSender<(u64, Stokes)>,
+    /// Spectra this subscriber missed because it fell behind
+    dropped: u64,
+}
+
+/// Fans the exfil stream out to however many subscribers are connected
+pub struct Tap {
+    connections: Receiver<TcpStream>,
+    subscribers: Vec<Subscriber>,
+    /// Spectra since the start of the observation
+    spectra: u64,
+    downsample_factor: usize,
+    freq_plan: FrequencyPlan,
+}
+
+impl Tap {
+    /// Start accepting subscribers on `addr`
+    pub fn new(
+        addr: SocketAddr,
+        downsample_factor: usize,
+        freq_plan: FrequencyPlan,
+    ) -> eyre::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        info!(%addr, "Serving the stokes tap");
+        let (sender, connections) = channel();
+        std::thread::Builder::new()
+            .name("tap_accept".to_owned())
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    match stream {
+                        Ok(stream) => {
+                            // Stops once the tap is dropped
+                            if sender.send(stream).is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => warn!("Failed to accept a tap subscriber - {e}"),
+                    }
+                }
+            })?;
+        Ok(Self {
+            connections,
+            subscribers: vec![],
+            spectra: 0,
+            downsample_factor,
+            freq_plan,
+        })
+    }
+
+    fn header(&self) -> TapHeader {
+        TapHeader {
+            start_mjd: time_policy().mjd(processed_payload_start_time()),
+            tsamp: PACKET_CADENCE * self.downsample_factor as f64,
+            downsample_factor: self.downsample_factor,
+            channels: CHANNELS,
+            fch1: self.freq_plan.fch1(),
+            foff: self.freq_plan.foff(),
+            freq_plan: self.freq_plan,
+        }
+    }
+
+    /// Start writing to a newly connected subscriber
+    fn subscribe(&mut self, stream: TcpStream) -> eyre::Result<()> {
+        let peer = stream.peer_addr()?;
+        let mut line = serde_json::to_vec(&self.header())?;
+        line.push(b'\n');
+        let (sender, receiver) = sync_channel(TAP_BACKLOG);
+        std::thread::Builder::new()
+            .name(format!("tap_{peer}"))
+            .spawn(move || {
+                if let Err(e) = writer_loop(stream, &line, receiver) {
+                    info!(%peer, "Tap subscriber went away - {e}");
+                }
+            })?;
+        info!(%peer, "Tap subscriber connected");
+        self.subscribers.push(Subscriber {
+            peer,
+            sender,
+            dropped: 0,
+        });
+        Ok(())
+    }
+
+    pub fn push(&mut self, stokes: &Stokes) {
+        while let Ok(stream) = self.connections.try_recv() {
+            if let Err(e) = self.subscribe(stream) {
+                warn!("Couldn't start a tap subscriber - {e}");
+            }
+        }
+        let index = self.spectra;
+        self.spectra += 1;
+        self.subscribers
+            .retain_mut(|sub| match sub.sender.try_send((index, stokes.clone())) {
+                Ok(_) => true,
+                Err(TrySendError::Full(_)) => {
+                    if sub.dropped == 0 {
+                        warn!(peer = %sub.peer, "Tap subscriber fell behind, dropping spectra");
+                    }
+                    sub.dropped += 1;
+                    true
+                }
+                Err(TrySendError::Disconnected(_)) => {
+                    info!(peer = %sub.peer, dropped = sub.dropped, "Tap subscriber disconnected");
+                    false
+                }
+            });
+    }
+}
+
+fn writer_loop(
+    stream: TcpStream,
+    header: &[u8],
+    spectra: Receiver<(u64, Stokes)>,
+) -> std::io::Result<()> {
+    let mut stream = BufWriter::new(stream);
+    stream.write_all(header)?;
+    stream.flush()?;
+    let mut frame = Vec::with_capacity(8 + CHANNELS * 4);
+    let mut write_frame = |stream: &mut BufWriter<TcpStream>, (index, stokes): (u64, Stokes)| {
+        frame.clear();
+        frame.extend_from_slice(&index.to_le_bytes());
+        for v in &stokes {
+            frame.extend_from_slice(&v.to_le_bytes());
+        }
+        stream.write_all(&frame)
+    };
+    while let Ok(next) = spectra.recv() {
+        write_frame(&mut stream, next)?;
+        // Only flush once we've caught up, so a subscriber that's behind gets big writes
+        while let Ok(next) = spectra.try_recv() {
+            write_frame(&mut stream, next)?;
+        }
+        stream.flush()?;
+    }
+    Ok(())
+}
+
+/// The subscriber end of a [`Tap`], for observer processes
+pub struct TapReader {
+    stream: BufReader<TcpStream>,
+    pub header: TapHeader,
+    frame: Vec<u8>,
+}
+
+impl TapReader {
+    pub fn connect(addr: impl ToSocketAddrs) -> eyre::Result<Self> {
+        let mut stream = BufReader::new(TcpStream::connect(addr)?);
+        let mut line = String::new();
+        stream.read_line(&mut line)?;
+        let header: TapHeader = serde_json::from_str(&line)?;
+        if header.channels != CHANNELS {
+            return Err(eyre!(
+                "Tap is serving {} channels, we expect {CHANNELS}",
+                header.channels
+            ));
+        }
+        Ok(Self {
+            stream,
+            header,
+            frame: vec![0; 8 + CHANNELS * 4],
+        })
+    }
+
+    /// The next spectrum and its index, or None once the tap goes away.
+    /// Gaps in the index are spectra we weren't fast enough to receive.
+    pub fn next_spectrum(&mut self) -> eyre::Result<Option<(u64, Stokes)>> {
+        match self.stream.read_exact(&mut self.frame) {
+            Ok(_) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        let index = u64::from_le_bytes(self.frame[..8].try_into().unwrap());
+        let stokes = self.frame[8..]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        Ok(Some((index, stokes)))
+    }
+}
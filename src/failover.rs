@@ -0,0 +1,422 @@
+//! Hot-standby capture: the same packet stream captured on two (or more) NICs, merged back into one by payload count.
+//! Every leg captures on its own as a non-primary stream, and this stage takes the first real copy of each payload,
+//! only waiting on legs that are still delivering, so losing an interface (or just its packets) costs nothing downstream.
+use crate::{
+    accounting::accounting,
+    baseband,
+    capture::{plain_ext_header, ArrivalHistogram, CaptureEvent, Stats, STATS_POLL_DURATION},
+    common::{Payload, FIRST_PACKET, LATEST_PACKET, NEVER_RECEIVED},
+    flags::{flag_marks, Flags},
+    profiling::payload_profile,
+};
+use std::{
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver as StdReceiver, SyncSender},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use thingbuf::mpsc::{
+    blocking::{Receiver, Sender},
+    errors::TryRecvError,
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Payloads we hold while waiting for the slower leg, ~8 ms (far more than two NICs on one switch disagree by)
+pub const MERGE_WINDOW: usize = 1024;
+/// How long we sleep when no leg has anything for us
+const IDLE_SLEEP: Duration = Duration::from_micros(20);
+
+/// How one of the legs is doing, for the metrics
+#[derive(Debug, Clone, Default)]
+pub struct NicHealth {
+    pub name: String,
+    /// Whether it's delivering, and so whether we wait on it
+    pub up: bool,
+    /// Payloads (real or filled by its capture) we got from it
+    pub received: u64,
+    /// Payloads that went downstream from this leg's copy
+    pub used: u64,
+}
+
+/// The latest health of every leg
+pub fn nic_health() -> &'static Mutex<Vec<NicHealth>> {
+    static NIC_HEALTH: OnceLock<Mutex<Vec<NicHealth>>> = OnceLock::new();
+    NIC_HEALTH.get_or_init(|| Mutex::new(vec![]))
+}
+
+/// One interface's capture, as the merge sees it
+pub struct Leg {
+    health: NicHealth,
+    payloads: Receiver<Payload>,
+    stats: StdReceiver<CaptureEvent>,
+    /// The latest stats its capture sent
+    last_stats: Stats,
+    /// Highest count it's given us
+    high: Option<u64>,
+    last_seen: Instant,
+    /// Its capture has stopped for good
+    closed: bool,
+}
+
+impl Leg {
+    pub fn new(
+        name: String,
+        payloads: Receiver<Payload>,
+        stats: StdReceiver<CaptureEvent>,
+    ) -> Self {
+        Self {
+            health: NicHealth {
+                name,
+                up: true,
+                ..Default::default()
+            },
+            payloads,
+            stats,
+            last_stats: Stats::default(),
+            high: None,
+            last_seen: Instant::now(),
+            closed: false,
+        }
+    }
+
+    /// Whether we still need to hear about `count` from this leg before giving up on a real copy of it
+    fn waiting_on(&self, count: u64) -> bool {
+        self.health.up && self.high.is_none_or(|high| high < count)
+    }
+}
+
+/// A payload waiting to go downstream, and which leg it came from
+#[derive(Clone, Copy)]
+struct Slot {
+    payload: Payload,
+    leg: usize,
+}
+
+fn is_real(payload: &Payload) -> bool {
+    payload.recv_latency != NEVER_RECEIVED
+}
+
+/// Merges payloads from the legs into one stream, in order, preferring real payloads to filled ones
+struct Merge {
+    window: Vec<Option<Slot>>,
+    /// The next count to go downstream, once we've seen the first
+    next: Option<u64>,
+    stream: u16,
+    /// Filled payloads that went downstream (as no leg had the real one)
+    drops: usize,
+    /// Real payloads that went downstream
+    processed: usize,
+}
+
+impl Merge {
+    fn new(stream: u16) -> Self {
+        Self {
+            window: vec![None; MERGE_WINDOW],
+            next: None,
+            stream,
+            drops: 0,
+            processed: 0,
+        }
+    }
+
+    /// Hold on to a payload from `leg`, unless we already have it (or already sent it on)
+    fn insert(
+        &mut self,
+        payload: &Payload,
+        leg: usize,
+        legs: &mut [Leg],
+        sender: &Sender<Payload>,
+    ) -> eyre::Result<()> {
+        let next = *self.next.get_or_insert_with(|| {
+            FIRST_PACKET.swap(payload.count, Ordering::Acquire);
+            payload.count
+        });
+        if payload.count < next {
+            // The other leg beat this one to it
+            return Ok(());
+        }
+        let window = MERGE_WINDOW as u64;
+        if payload.count >= next + window {
+            // Too far ahead to hold, which is a capture resync if it's past a whole window of what we're holding
+            self.flush(legs, sender)?;
+            if payload.count >= self.next.unwrap() + window {
+                self.next = Some(payload.count);
+            }
+        }
+        let slot = &mut self.window[(payload.count % window) as usize];
+        match slot {
+            Some(held)
+                if held.payload.count == payload.count
+                    && (is_real(&held.payload) || !is_real(payload)) => {}
+            _ => {
+                *slot = Some(Slot {
+                    payload: *payload,
+                    leg,
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Send on the next payload, if we have it for real or nobody we're waiting on can do better
+    fn drain(&mut self, legs: &mut [Leg], sender: &Sender<Payload>) -> eyre::Result<()> {
+        let Some(mut next) = self.next else {
+            return Ok(());
+        };
+        let window = MERGE_WINDOW as u64;
+        loop {
+            let i = (next % window) as usize;
+            let ready = match &self.window[i] {
+                Some(held) if held.payload.count == next => {
+                    is_real(&held.payload) || !legs.iter().any(|l| l.waiting_on(next))
+                }
+                // Nobody has it, and nobody's going to (a leg resynced past it)
+                _ => !legs.iter().any(|l| l.waiting_on(next)) && legs.iter().any(|l| l.health.up),
+            };
+            if !ready {
+                break;
+            }
+            if let Some(held) = self.window[i].take().filter(|h| h.payload.count == next) {
+                self.emit(&held, legs, sender)?;
+            }
+            next += 1;
+            self.next = Some(next);
+        }
+        Ok(())
+    }
+
+    /// Send on everything we're holding, in order, regardless of who we're waiting on
+    fn flush(&mut self, legs: &mut [Leg], sender: &Sender<Payload>) -> eyre::Result<()> {
+        let Some(next) = self.next else {
+            return Ok(());
+        };
+        let window = MERGE_WINDOW as u64;
+        let mut last = None;
+        for count in next..next + window {
+            if let Some(held) = self.window[(count % window) as usize]
+                .take()
+                .filter(|h| h.payload.count == count)
+            {
+                self.emit(&held, legs, sender)?;
+                last = Some(count);
+            }
+        }
+        self.next = Some(last.map_or(next, |l| l + 1));
+        Ok(())
+    }
+
+    /// What a primary capture would do with a payload, now that we know it's the one going downstream
+    fn emit(
+        &mut self,
+        held: &Slot,
+        legs: &mut [Leg],
+        sender: &Sender<Payload>,
+    ) -> eyre::Result<()> {
+        let payload = &held.payload;
+        // The legs have already acted on their headers, so the recording only has the stream to go on
+        baseband::record(payload, &plain_ext_header(payload.stream));
+        if is_real(payload) {
+            legs[held.leg].health.used += 1;
+            accounting().captured.fetch_add(1, Ordering::Relaxed);
+            self.processed += 1;
+        } else {
+            flag_marks().mark(payload.count..payload.count + 1, Flags::ZERO_FILLED);
+            accounting().filled.fetch_add(1, Ordering::Relaxed);
+            self.drops += 1;
+        }
+        sender.send(*payload)?;
+        LATEST_PACKET.store(payload.count, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Stats for the merged stream, as a primary capture would report them (with the legs' own counters summed)
+    fn stats(&self, legs: &[Leg]) -> Stats {
+        let sum = |f: fn(&Stats) -> usize| legs.iter().map(|l| f(&l.last_stats)).sum();
+        let mut arrivals = ArrivalHistogram::default();
+        for leg in legs {
+            arrivals.merge(&leg.last_stats.arrivals);
+        }
+        Stats {
+            stream: self.stream,
+            drops: self.drops,
+            processed: self.processed,
+            shuffled: sum(|s| s.shuffled),
+            rejected: sum(|s| s.rejected),
+            resyncs: sum(|s| s.resyncs),
+            reordered: sum(|s| s.reordered),
+            faults: sum(|s| s.faults),
+            arrivals,
+        }
+    }
+}
+
+/// Merge the legs capturing `stream` into `cap_send`, failing over to whichever are delivering when one goes quiet
+/// for `timeout`, until shutdown
+pub fn merge_task(
+    mut legs: Vec<Leg>,
+    stream: u16,
+    timeout: Duration,
+    cap_send: Sender<Payload>,
+    stats_send: SyncSender<CaptureEvent>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!(
+        stream,
+        nics = ?legs.iter().map(|l| l.health.name.clone()).collect::<Vec<_>>(),
+        "Starting hot-standby capture merge"
+    );
+    let profile = payload_profile("capture");
+    let mut merge = Merge::new(stream);
+    let mut last_stats = Instant::now();
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Capture merge stopping");
+            break;
+        }
+        let mut idle = true;
+        for i in 0..legs.len() {
+            match legs[i].payloads.try_recv() {
+                Ok(payload) => {
+                    let iter_start = Instant::now();
+                    idle = false;
+                    let leg = &mut legs[i];
+                    leg.health.received += 1;
+                    leg.high = leg.high.max(Some(payload.count));
+                    leg.last_seen = Instant::now();
+                    if !leg.health.up {
+                        info!(nic = leg.health.name, "Interface is delivering again");
+                        leg.health.up = true;
+                    }
+                    merge.insert(&payload, i, &mut legs, &cap_send)?;
+                    merge.drain(&mut legs, &cap_send)?;
+                    profile.record(iter_start.elapsed());
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Closed) => {
+                    if !legs[i].closed {
+                        warn!(
+                            nic = legs[i].health.name,
+                            "Capture on this interface stopped"
+                        );
+                        legs[i].closed = true;
+                        legs[i].health.up = false;
+                        merge.drain(&mut legs, &cap_send)?;
+                    }
+                }
+                Err(_) => unreachable!(),
+            }
+        }
+        // Give up waiting on anyone who's gone quiet (even while the others are busy), which may be what's holding up
+        // the rest
+        let mut failed_over = false;
+        for leg in legs.iter_mut().filter(|l| l.health.up) {
+            if leg.last_seen.elapsed() >= timeout {
+                warn!(
+                    nic = leg.health.name,
+                    "Interface stopped delivering, failing over"
+                );
+                leg.health.up = false;
+                failed_over = true;
+            }
+        }
+        if failed_over {
+            merge.drain(&mut legs, &cap_send)?;
+        }
+        if idle {
+            if legs.iter().all(|l| l.closed) {
+                break;
+            }
+            std::thread::sleep(IDLE_SLEEP);
+        }
+        // The legs report to us, and we report for the stream
+        for leg in legs.iter_mut() {
+            while let Ok(event) = leg.stats.try_recv() {
+                match event {
+                    CaptureEvent::Stats(stats) => leg.last_stats = stats,
+                    resync => {
+                        let _ = stats_send.try_send(resync);
+                    }
+                }
+            }
+        }
+        if last_stats.elapsed() >= STATS_POLL_DURATION {
+            let _ = stats_send.try_send(CaptureEvent::Stats(merge.stats(&legs)));
+            *nic_health().lock().unwrap() = legs.iter().map(|l| l.health.clone()).collect();
+            last_stats = Instant::now();
+        }
+    }
+    merge.flush(&mut legs, &cap_send)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn payload(count: u64, real: bool) -> Payload {
+        Payload {
+            count,
+            recv_latency: if real { 0 } else { NEVER_RECEIVED },
+            ..Default::default()
+        }
+    }
+
+    fn leg(name: &str) -> Leg {
+        let (_, payloads) = thingbuf::mpsc::blocking::channel(1);
+        let (_, stats) = std::sync::mpsc::sync_channel(1);
+        Leg::new(name.to_owned(), payloads, stats)
+    }
+
+    #[test]
+    fn test_merge_prefers_real() {
+        let (send, recv) = thingbuf::mpsc::blocking::channel(16);
+        let mut legs = vec![leg("a"), leg("b")];
+        let mut merge = Merge::new(0);
+        // Leg a lost 1 (and filled it), leg b didn't
+        for (i, p) in [
+            (0, payload(0, true)),
+            (0, payload(1, false)),
+            (0, payload(2, true)),
+        ] {
+            legs[i].high = Some(p.count);
+            merge.insert(&p, i, &mut legs, &send).unwrap();
+            merge.drain(&mut legs, &send).unwrap();
+        }
+        // 0 goes straight through, 1 waits on b
+        assert_eq!(recv.try_recv().unwrap().count, 0);
+        assert!(recv.try_recv().is_err());
+        for p in [payload(0, true), payload(1, true), payload(2, true)] {
+            legs[1].high = Some(p.count);
+            merge.insert(&p, 1, &mut legs, &send).unwrap();
+            merge.drain(&mut legs, &send).unwrap();
+        }
+        let (one, two) = (recv.try_recv().unwrap(), recv.try_recv().unwrap());
+        assert_eq!((one.count, is_real(&one)), (1, true));
+        assert_eq!(two.count, 2);
+        assert!(recv.try_recv().is_err());
+        assert_eq!((legs[0].health.used, legs[1].health.used), (2, 1));
+        assert_eq!(merge.drops, 0);
+    }
+
+    #[test]
+    fn test_merge_fails_over() {
+        let (send, recv) = thingbuf::mpsc::blocking::channel(16);
+        let mut legs = vec![leg("a"), leg("b")];
+        let mut merge = Merge::new(0);
+        for p in [payload(0, true), payload(1, false)] {
+            legs[0].high = Some(p.count);
+            merge.insert(&p, 0, &mut legs, &send).unwrap();
+            merge.drain(&mut legs, &send).unwrap();
+        }
+        assert_eq!(recv.try_recv().unwrap().count, 0);
+        assert!(recv.try_recv().is_err());
+        // Leg b is gone, so the fill is the best we'll get
+        legs[1].health.up = false;
+        merge.drain(&mut legs, &send).unwrap();
+        assert!(!is_real(&recv.try_recv().unwrap()));
+        assert_eq!(merge.drops, 1);
+    }
+}
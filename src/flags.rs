@@ -0,0 +1,185 @@
+//! Per-sample data quality flags, run-length encoded so they can follow the stokes stream into exfil
+use serde::Serialize;
+use std::{
+    fmt,
+    ops::{BitOr, BitOrAssign, Range},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+/// The set of conditions that applied to (any part of) a downsampled spectrum
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Flags(u8);
+
+impl Flags {
+    pub const NONE: Self = Self(0);
+    /// Some channels were blanked for RFI
+    pub const RFI: Self = Self(1);
+    /// Some payloads were zeros filled in for dropped packets
+    pub const ZERO_FILLED: Self = Self(1 << 1);
+    /// A fake pulse was injected
+    pub const INJECTED: Self = Self(1 << 2);
+    /// The noise calibrator was on
+    pub const CAL_ON: Self = Self(1 << 3);
+    /// The FPGA reported a fault (e.g. an ADC or FFT overflow) in a packet header
+    pub const FPGA_STATUS: Self = Self(1 << 4);
+
+    const NAMES: [(Self, &'static str); 5] = [
+        (Self::RFI, "RFI"),
+        (Self::ZERO_FILLED, "ZERO_FILLED"),
+        (Self::INJECTED, "INJECTED"),
+        (Self::CAL_ON, "CAL_ON"),
+        (Self::FPGA_STATUS, "FPGA_STATUS"),
+    ];
+
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Flags {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Flags {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl fmt::Display for Flags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "NONE");
+        }
+        let names: Vec<_> = Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        write!(f, "{}", names.join("|"))
+    }
+}
+
+/// A stretch of consecutive spectra that all carry the same flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FlagRun {
+    /// Index of the first spectrum, counting from the first one exfilled
+    pub start: u64,
+    pub len: u64,
+    pub flags: Flags,
+}
+
+impl FlagRun {
+    /// Fixed-size little-endian record, for binary outputs
+    pub fn to_bytes(&self) -> [u8; 24] {
+        let mut bytes = [0u8; 24];
+        bytes[..8].copy_from_slice(&self.start.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.len.to_le_bytes());
+        bytes[16..].copy_from_slice(&u64::from(self.flags.bits()).to_le_bytes());
+        bytes
+    }
+}
+
+/// Turns per-spectrum flags into runs, only emitting a run once it's finished
+#[derive(Debug, Default)]
+pub struct RunEncoder {
+    current: Option<FlagRun>,
+    next: u64,
+}
+
+impl RunEncoder {
+    /// Add the flags of the next spectrum, returning the previous run if this one ended it
+    pub fn push(&mut self, flags: Flags) -> Option<FlagRun> {
+        let index = self.next;
+        self.next += 1;
+        match &mut self.current {
+            Some(run) if run.flags == flags => {
+                run.len += 1;
+                None
+            }
+            _ => self.current.replace(FlagRun {
+                start: index,
+                len: 1,
+                flags,
+            }),
+        }
+    }
+
+    /// Close out the run in progress
+    pub fn finish(&mut self) -> Option<FlagRun> {
+        self.current.take()
+    }
+}
+
+/// Ranges of payload counts that upstream tasks have flagged, waiting to be picked up by processing
+#[derive(Debug, Default)]
+pub struct FlagMarks {
+    marks: Mutex<Vec<(Range<u64>, Flags)>>,
+    /// Set whenever there are new marks, so processing doesn't need the lock on every payload
+    pending: AtomicBool,
+}
+
+impl FlagMarks {
+    /// Flag a range of payload counts. This has to happen before the payloads are sent downstream.
+    pub fn mark(&self, counts: Range<u64>, flags: Flags) {
+        self.marks.lock().unwrap().push((counts, flags));
+        self.pending.store(true, Ordering::Release);
+    }
+
+    /// Move any new marks into `into`
+    pub fn take(&self, into: &mut Vec<(Range<u64>, Flags)>) {
+        if self.pending.swap(false, Ordering::AcqRel) {
+            into.append(&mut self.marks.lock().unwrap());
+        }
+    }
+}
+
+/// Get the global flag marks
+pub fn flag_marks() -> &'static FlagMarks {
+    static FLAG_MARKS: OnceLock<FlagMarks> = OnceLock::new();
+    FLAG_MARKS.get_or_init(FlagMarks::default)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_run_encoder() {
+        let mut enc = RunEncoder::default();
+        assert_eq!(enc.push(Flags::NONE), None);
+        assert_eq!(enc.push(Flags::NONE), None);
+        assert_eq!(
+            enc.push(Flags::INJECTED),
+            Some(FlagRun {
+                start: 0,
+                len: 2,
+                flags: Flags::NONE
+            })
+        );
+        assert_eq!(
+            enc.finish(),
+            Some(FlagRun {
+                start: 2,
+                len: 1,
+                flags: Flags::INJECTED
+            })
+        );
+        assert_eq!((Flags::RFI | Flags::CAL_ON).to_string(), "RFI|CAL_ON");
+    }
+}
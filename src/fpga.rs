@@ -6,12 +6,14 @@ use casperfpga::transport::{
 use casperfpga_derive::fpga_from_fpg;
 use eyre::bail;
 use fixed::{types::extra::U0, FixedU16};
-use hifitime::{prelude::*, UNIX_REF_EPOCH};
-use rsntp::SynchronizationResult;
+use hifitime::prelude::*;
 use std::net::{Ipv4Addr, SocketAddr};
 use tracing::debug;
 
-use crate::common::PACKET_CADENCE;
+use crate::{
+    common::PACKET_CADENCE,
+    timing::{ClockSample, TimeSync},
+};
 
 fpga_from_fpg!(GrexFpga, "gateware/grex_gateware.fpg");
 
@@ -38,13 +40,38 @@ impl Device {
         Ok(())
     }
 
+    /// Human-readable dump of the registers we'd want to see when debugging a station
+    pub fn register_dump(&mut self) -> eyre::Result<String> {
+        let mut dump = String::new();
+        dump += &format!("fft_shift: {:?}\n", self.fpga.fft_shift.read()?);
+        dump += &format!(
+            "fft_overflow_cnt: {:?}\n",
+            self.fpga.fft_overflow_cnt.read()?
+        );
+        dump += &format!("tx_en: {:?}\n", self.fpga.tx_en.read()?);
+        dump += &format!("pps_cnt: {:?}\n", self.fpga.pps_cnt.read()?);
+        dump += &format!("gbe1_linkup: {:?}\n", self.fpga.gbe1_linkup.read()?);
+        dump += &format!("dest_ip: {:?}\n", self.fpga.dest_ip.read()?);
+        dump += &format!("dest_port: {:?}\n", self.fpga.dest_port.read()?);
+        dump += &format!("spec_vacc_n: {:?}\n", self.fpga.spec_vacc_n.read()?);
+        dump += &format!("stokes_vacc_n: {:?}\n", self.fpga.stokes_vacc_n.read()?);
+        dump += &format!(
+            "temperature: {:?}\n",
+            self.fpga.transport.lock().unwrap().temperature()?
+        );
+        Ok(dump)
+    }
+
+    /// IP address the 10 GbE core sends data from
+    pub const DATA_IP: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 20);
+
     /// Gets the 10 GbE data connection in working order
     pub fn start_networking(&mut self, mac: &[u8; 6]) -> eyre::Result<()> {
         let dest_ip: Ipv4Addr = "192.168.0.1".parse()?;
         let dest_port = 60000u16;
         // Disable
         self.fpga.tx_en.write(false)?;
-        self.fpga.gbe1.set_ip("192.168.0.20".parse()?)?;
+        self.fpga.gbe1.set_ip(Self::DATA_IP)?;
         self.fpga.gbe1.set_gateway(dest_ip)?;
         self.fpga.gbe1.set_netmask("255.255.255.0".parse()?)?;
         self.fpga.gbe1.set_port(dest_port)?;
@@ -69,9 +96,9 @@ impl Device {
 
     /// Send a trigger pulse to start the flow of bytes, returning the true time of the start of packets
     #[allow(clippy::missing_panics_doc)]
-    pub fn trigger(&mut self, time_sync: &SynchronizationResult) -> eyre::Result<Epoch> {
+    pub fn trigger(&mut self, time_sync: &TimeSync) -> eyre::Result<Epoch> {
         // Get the current time, and wait to send the triggers to align the time with a rising PPS edge
-        let now = UNIX_REF_EPOCH + hifitime::Duration::from(time_sync.datetime().unix_timestamp()?);
+        let now = time_sync.now()?;
         let next_sec = now.ceil(1.seconds());
         // If we wait a little past the second second, we have the maximum likleyhood of preventing a fencepost error
         let trigger_time = next_sec + 0.1.seconds();
@@ -102,6 +129,32 @@ impl Device {
         Ok(start_time)
     }
 
+    /// Number of PPS edges the FPGA has seen since it was armed
+    pub fn pps_count(&mut self) -> eyre::Result<u32> {
+        Ok(u32::from(self.fpga.pps_cnt.read()?))
+    }
+
+    /// Read the free-running fabric clock counter and the PPS count back to back, timestamped against the host's monotonic clock
+    pub fn clock_sample(&mut self) -> eyre::Result<ClockSample> {
+        let before = std::time::Instant::now();
+        // The sys block isn't a software register, so we read it off the transport directly (CASPER registers are big-endian)
+        let ticks = u32::from_be_bytes(
+            self.fpga
+                .transport
+                .lock()
+                .unwrap()
+                .read_bytes::<4>("sys_clkcounter", 0)?,
+        );
+        let pps = u32::from(self.fpga.pps_cnt.read()?);
+        let round_trip = before.elapsed();
+        Ok(ClockSample {
+            host: before + round_trip / 2,
+            round_trip,
+            ticks,
+            pps,
+        })
+    }
+
     /// Force a PPS pulse (timing will be inaccurate)
     #[allow(clippy::missing_panics_doc)]
     pub fn force_pps(&mut self) -> eyre::Result<()> {
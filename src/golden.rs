@@ -0,0 +1,122 @@
+//! Bit-exact golden test vectors for the SIMD kernels, generated independently by `test_vectors/generate.py`.
+//! Both the SIMD and scalar paths are checked against the fixtures, so a kernel ported to a new ISA has to agree exactly.
+use crate::{
+    common::{scalar_full_stokes, scalar_stokes, simd_full_stokes, simd_stokes, CHANNELS},
+    injection::{scalar_injection, simd_injection},
+    processing::{accumulate, average},
+};
+
+const STOKES: &[u8] = include_bytes!("../test_vectors/stokes.bin");
+const INJECTION: &[u8] = include_bytes!("../test_vectors/injection.bin");
+const DOWNSAMPLE: &[u8] = include_bytes!("../test_vectors/downsample.bin");
+/// Spectra averaged per downsample vector
+const DOWNSAMPLE_FACTOR: usize = 4;
+
+const POL_BYTES: usize = 2 * CHANNELS;
+const SPECTRUM_BYTES: usize = 4 * CHANNELS;
+
+/// Pulls fixed-size fields out of a fixture in order
+struct Fixture<'a>(&'a [u8]);
+
+impl<'a> Fixture<'a> {
+    fn take(&mut self, n: usize) -> &'a [u8] {
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        head
+    }
+
+    fn i8s<const N: usize>(&mut self) -> [i8; N] {
+        let bytes = self.take(N);
+        std::array::from_fn(|i| bytes[i] as i8)
+    }
+
+    fn spectrum(&mut self) -> [f32; CHANNELS] {
+        let bytes = self.take(SPECTRUM_BYTES);
+        std::array::from_fn(|i| f32::from_le_bytes(bytes[4 * i..4 * i + 4].try_into().unwrap()))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Compare spectra by their bits, so -0.0 != 0.0 and NaNs don't sneak through
+fn assert_bits_eq(a: &[f32; CHANNELS], b: &[f32; CHANNELS]) {
+    for (i, (x, y)) in a.iter().zip(b).enumerate() {
+        assert_eq!(x.to_bits(), y.to_bits(), "Channel {i} differs: {x} vs {y}");
+    }
+}
+
+#[test]
+fn test_stokes_vectors() {
+    let mut fixture = Fixture(STOKES);
+    while !fixture.is_empty() {
+        let a = fixture.i8s::<POL_BYTES>();
+        let b = fixture.i8s::<POL_BYTES>();
+        let expected = fixture.spectrum();
+        let mut simd = [0f32; CHANNELS];
+        let mut scalar = [0f32; CHANNELS];
+        simd_stokes(&mut simd, &a, &b);
+        scalar_stokes(&mut scalar, &a, &b);
+        assert_bits_eq(&simd, &expected);
+        assert_bits_eq(&scalar, &expected);
+    }
+}
+
+#[test]
+fn test_full_stokes_vectors() {
+    // Stokes I of the full product has to match the fixtures too, and the SIMD path the scalar one
+    let mut fixture = Fixture(STOKES);
+    while !fixture.is_empty() {
+        let a = fixture.i8s::<POL_BYTES>();
+        let b = fixture.i8s::<POL_BYTES>();
+        let expected = fixture.spectrum();
+        let mut simd = [[0f32; CHANNELS]; 4];
+        let mut scalar = [[0f32; CHANNELS]; 4];
+        simd_full_stokes(&mut simd, &a, &b);
+        scalar_full_stokes(&mut scalar, &a, &b);
+        assert_bits_eq(&simd[0], &expected);
+        for (s, r) in simd.iter().zip(&scalar) {
+            assert_bits_eq(s, r);
+        }
+    }
+}
+
+#[test]
+fn test_injection_vectors() {
+    let mut fixture = Fixture(INJECTION);
+    while !fixture.is_empty() {
+        let live = fixture.i8s::<POL_BYTES>();
+        let pulse = fixture.i8s::<CHANNELS>();
+        let expected = fixture.i8s::<POL_BYTES>();
+        let mut simd = live;
+        let mut scalar = live;
+        simd_injection(&mut simd, &pulse);
+        scalar_injection(&mut scalar, &pulse);
+        assert_eq!(simd, expected);
+        assert_eq!(scalar, expected);
+    }
+}
+
+#[test]
+fn test_downsample_vectors() {
+    let mut fixture = Fixture(DOWNSAMPLE);
+    while !fixture.is_empty() {
+        let mut simd_acc = [0f32; CHANNELS];
+        let mut scalar_acc = [0f32; CHANNELS];
+        for _ in 0..DOWNSAMPLE_FACTOR {
+            let a = fixture.i8s::<POL_BYTES>();
+            let b = fixture.i8s::<POL_BYTES>();
+            let mut stokes = [0f32; CHANNELS];
+            simd_stokes(&mut stokes, &a, &b);
+            accumulate(&mut simd_acc, &stokes);
+            scalar_stokes(&mut stokes, &a, &b);
+            accumulate(&mut scalar_acc, &stokes);
+        }
+        average(&mut simd_acc, DOWNSAMPLE_FACTOR);
+        average(&mut scalar_acc, DOWNSAMPLE_FACTOR);
+        let expected = fixture.spectrum();
+        assert_bits_eq(&simd_acc, &expected);
+        assert_bits_eq(&scalar_acc, &expected);
+    }
+}
@@ -1,12 +1,21 @@
 //! Task for injecting a fake pulse into the timestream to test/validate downstream components
 use crate::{
-    common::{payload_time, Channel, Payload, BLOCK_TIMEOUT, CHANNELS, FIRST_PACKET},
-    db::InjectionRecord,
+    common::{
+        payload_time, Channel, Payload, BLOCK_TIMEOUT, CHANNELS, FIRST_PACKET, PACKET_CADENCE,
+    },
+    db::{DbEvent, InjectionRecord},
+    exfil::FrequencyPlan,
+    flags::{flag_marks, Flags},
+    naming::time_policy,
+    profiling::payload_profile,
 };
 use byte_slice_cast::AsSliceOf;
+use clap::ValueEnum;
+use eyre::eyre;
 use memmap2::Mmap;
-use ndarray::{s, Array2, ArrayView, ArrayView2};
+use ndarray::{s, Array1, Array2, ArrayView, ArrayView2};
 use pulp::{as_arrays, as_arrays_mut, cast, x86::V3};
+use serde::Deserialize;
 use std::{
     fs::File,
     path::PathBuf,
@@ -14,26 +23,159 @@ use std::{
     time::{Duration, Instant},
 };
 use thingbuf::mpsc::{
-    blocking::{StaticReceiver, StaticSender},
+    blocking::{Receiver, Sender},
     errors::RecvTimeoutError,
 };
 use tokio::sync::broadcast;
 use tracing::info;
-use eyre::eyre;
 
-fn read_pulse(pulse_mmap: &Mmap) -> eyre::Result<ArrayView2<i8>> {
+fn read_pulse(pulse_mmap: &Mmap) -> eyre::Result<ArrayView2<'_, i8>> {
     let raw_bytes = pulse_mmap[..].as_slice_of::<i8>()?;
     let time_samples = raw_bytes.len() / CHANNELS;
     let block = ArrayView::from_shape((time_samples, CHANNELS), raw_bytes)?;
     Ok(block)
 }
 
+/// Dispersion constant for intra-channel smearing (seconds, for MHz channel widths and GHz frequencies)
+const SMEARING_CONSTANT: f64 = 8.3e-6;
+/// Dispersion delay constant (seconds, for MHz frequencies)
+pub(crate) const DISPERSION_CONSTANT: f64 = 4.148808e3;
+/// Ratio of the FWHM to the standard deviation of a gaussian
+const FWHM_PER_SIGMA: f64 = 2.354_820_045;
+
+/// Optional `<pulse>.json` sidecar for pulses that weren't generated at the payload cadence
+#[derive(Debug, Deserialize)]
+struct PulseMeta {
+    /// Sampling time of the pulse file (seconds)
+    tsamp: f64,
+    /// DM of the pulse, to smear each channel as our channelization would
+    #[serde(default)]
+    dm: f64,
+}
+
+/// Resample a pulse to the payload cadence, smearing each channel by the intra-channel dispersion delay at its DM.
+/// This happens in power (the square of the sample), as that's what a real burst conserves.
+fn condition_pulse(pulse: ArrayView2<i8>, meta: &PulseMeta, freqs: &Array1<f64>) -> Array2<i8> {
+    let (samples, channels) = pulse.dim();
+    let chan_bw = (freqs[1] - freqs[0]).abs();
+    // Smearing of each channel, in native samples
+    let widths: Vec<usize> = freqs
+        .iter()
+        .map(|f| {
+            let smear = SMEARING_CONSTANT * meta.dm * chan_bw / (f / 1000.0).powi(3);
+            ((smear / meta.tsamp).ceil() as usize).max(1)
+        })
+        .collect();
+    let max_width = widths.iter().copied().max().unwrap_or(1);
+    let duration = (samples + max_width - 1) as f64 * meta.tsamp;
+    let out_samples = (duration / PACKET_CADENCE).ceil() as usize;
+    let mut out = Array2::<f64>::zeros((out_samples, channels));
+    for (c, width) in widths.into_iter().enumerate() {
+        // Boxcar smear in the native resolution
+        let mut smeared = vec![0f64; samples + width - 1];
+        for (i, x) in pulse.column(c).iter().enumerate() {
+            let power = f64::from(*x).powi(2) / width as f64;
+            smeared[i..i + width].iter_mut().for_each(|s| *s += power);
+        }
+        // Then integrate each native sample into the payloads it overlaps
+        for (i, power) in smeared.into_iter().enumerate() {
+            let (start, stop) = (i as f64 * meta.tsamp, (i + 1) as f64 * meta.tsamp);
+            let mut j = (start / PACKET_CADENCE) as usize;
+            while j < out_samples && (j as f64) * PACKET_CADENCE < stop {
+                let overlap = stop.min((j + 1) as f64 * PACKET_CADENCE)
+                    - start.max(j as f64 * PACKET_CADENCE);
+                out[(j, c)] += power * overlap / PACKET_CADENCE;
+                j += 1;
+            }
+        }
+    }
+    out.mapv(|p| p.sqrt().round().min(f64::from(i8::MAX)) as i8)
+}
+
+/// Spectral envelope of a synthetic pulse
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BandShape {
+    /// Equal fluence in every channel
+    #[default]
+    Flat,
+    /// Fluence following (f / center)^index
+    PowerLaw,
+    /// Fluence in a gaussian band around the center
+    Gaussian,
+}
+
+/// Everything that describes a synthetic pulse
+#[derive(Debug, Clone, Copy)]
+pub struct PulseSpec {
+    /// Dispersion measure (pc cm^-3)
+    pub dm: f64,
+    /// Intrinsic width (FWHM, seconds)
+    pub width: f64,
+    /// Total power (sum of squared samples) deposited in a channel where the envelope is 1
+    pub fluence: f64,
+    pub band_shape: BandShape,
+    pub spectral_index: f64,
+    /// Center of the envelope (MHz)
+    pub band_center: f64,
+    /// FWHM of gaussian envelopes (MHz)
+    pub band_fwhm: f64,
+}
+
+impl PulseSpec {
+    fn envelope(&self, freq: f64) -> f64 {
+        match self.band_shape {
+            BandShape::Flat => 1.0,
+            BandShape::PowerLaw => (freq / self.band_center).powf(self.spectral_index),
+            BandShape::Gaussian => {
+                let sigma = self.band_fwhm / FWHM_PER_SIGMA;
+                (-0.5 * ((freq - self.band_center) / sigma).powi(2)).exp()
+            }
+        }
+    }
+}
+
+/// Generate a dispersed pulse at the payload cadence, in the channel order of `freq_plan` (which is what [`Injections`] reads)
+pub fn synthesize_pulse(spec: &PulseSpec, freq_plan: FrequencyPlan) -> Array2<i8> {
+    let freqs = freq_plan.freqs();
+    let f_top = freqs.iter().copied().fold(f64::MIN, f64::max);
+    let chan_bw = freq_plan.foff().abs();
+    // Arrival time (relative to the top of the band) and width of the pulse in every channel
+    let (delays, sigmas): (Vec<_>, Vec<_>) = freqs
+        .iter()
+        .map(|f| {
+            let delay = DISPERSION_CONSTANT * spec.dm * (f.powi(-2) - f_top.powi(-2));
+            // Intra-channel smearing is a boxcar, add its variance to the intrinsic gaussian
+            let smear = SMEARING_CONSTANT * spec.dm * chan_bw / (f / 1000.0).powi(3);
+            let sigma = ((spec.width / FWHM_PER_SIGMA).powi(2) + smear.powi(2) / 12.0)
+                .sqrt()
+                .max(PACKET_CADENCE / 2.0);
+            (delay, sigma)
+        })
+        .unzip();
+    let max_sigma = sigmas.iter().copied().fold(0.0, f64::max);
+    let max_delay = delays.iter().copied().fold(0.0, f64::max);
+    // Leave room for the tails on either side
+    let t0 = 4.0 * max_sigma;
+    let samples = ((2.0 * t0 + max_delay) / PACKET_CADENCE).ceil() as usize;
+    let mut pulse = Array2::<i8>::zeros((samples, CHANNELS));
+    for (c, ((f, delay), sigma)) in freqs.iter().zip(delays).zip(sigmas).enumerate() {
+        let energy = spec.fluence * spec.envelope(*f);
+        let norm = PACKET_CADENCE / (sigma * (2.0 * std::f64::consts::PI).sqrt());
+        for (j, x) in pulse.column_mut(c).iter_mut().enumerate() {
+            let t = (j as f64 + 0.5) * PACKET_CADENCE - t0 - delay;
+            let power = energy * norm * (-0.5 * (t / sigma).powi(2)).exp();
+            *x = power.sqrt().round().min(f64::from(i8::MAX)) as i8;
+        }
+    }
+    pulse
+}
+
 pub struct Injections {
     pulses: Vec<(String, Array2<i8>)>,
 }
 
 impl Injections {
-    pub fn new(pulse_path: PathBuf) -> eyre::Result<Self> {
+    pub fn new(pulse_path: PathBuf, freq_plan: FrequencyPlan) -> eyre::Result<Self> {
         // Grab all the .dat files in the given directory
         let pulse_files: Vec<_> = std::fs::read_dir(pulse_path)?
             .filter_map(|f| match f {
@@ -52,7 +194,7 @@ impl Injections {
 
         // This could be empty
         if pulse_files.is_empty() {
-            return Err(eyre!("No pulses to inject"))
+            return Err(eyre!("No pulses to inject"));
         }
 
         // Read all the pulses off the disk
@@ -63,9 +205,17 @@ impl Injections {
                 .expect("Invalid file name")
                 .to_string_lossy()
                 .into();
+            let meta_file = file.with_extension("json");
             let mmap = unsafe { Mmap::map(&File::open(file)?)? };
             let pulse_view = read_pulse(&mmap)?;
-            pulses.push((filename, pulse_view.to_owned()));
+            let pulse = if meta_file.exists() {
+                let meta: PulseMeta = serde_json::from_reader(File::open(meta_file)?)?;
+                info!(filename, ?meta, "Resampling pulse to the payload cadence");
+                condition_pulse(pulse_view, &meta, &freq_plan.freqs())
+            } else {
+                pulse_view.to_owned()
+            };
+            pulses.push((filename, pulse));
         }
 
         Ok(Self { pulses })
@@ -120,6 +270,13 @@ pub fn simd_injection(live: &mut [i8; 2 * CHANNELS], injection: &[i8; CHANNELS])
     }
 }
 
+/// Reference implementation of [`simd_injection`], which the SIMD path must match bit-for-bit
+pub fn scalar_injection(live: &mut [i8; 2 * CHANNELS], injection: &[i8; CHANNELS]) {
+    for (re, x) in live.iter_mut().step_by(2).zip(injection) {
+        *re = re.wrapping_add(*x);
+    }
+}
+
 /// Inject this pulse sample into the given payload
 pub fn inject(pl: &mut Payload, sample: &[i8; CHANNELS]) {
     // Safety: These transmutes are safe because Complex<i8> has the same alignment requirements as an i8
@@ -132,11 +289,12 @@ pub fn inject(pl: &mut Payload, sample: &[i8; CHANNELS]) {
 }
 
 pub fn pulse_injection_task(
-    input: StaticReceiver<Payload>,
-    output: StaticSender<Payload>,
-    injection_record_sender: std::sync::mpsc::SyncSender<InjectionRecord>,
+    input: Receiver<Payload>,
+    output: Sender<Payload>,
+    injection_record_sender: std::sync::mpsc::SyncSender<DbEvent>,
     cadence: Duration,
     injections: Injections,
+    stream: u16,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting pulse injection!");
@@ -147,8 +305,7 @@ pub fn pulse_injection_task(
     let mut currently_injecting = false;
     let mut last_injection = Instant::now();
     let mut this_pulse = pulse_cycle.next().unwrap();
-
-    let current_pulse_length = this_pulse.1.shape()[0];
+    let profile = payload_profile("injection");
 
     loop {
         if shutdown.try_recv().is_ok() {
@@ -157,13 +314,18 @@ pub fn pulse_injection_task(
         }
         // Grab payload from packet capture
         match input.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(payload) if payload.stream != stream => {
+                // Pulses only go into the stream we exfil (and flag)
+                output.send(payload)?;
+            }
             Ok(mut payload) => {
+                let iter_start = Instant::now();
                 if last_injection.elapsed() >= cadence {
                     last_injection = Instant::now();
                     currently_injecting = true;
                     i = 0;
                     let record = InjectionRecord {
-                        mjd: payload_time(payload.count).to_mjd_tai_days(),
+                        mjd: time_policy().mjd(payload_time(payload.count)),
                         sample: payload.count - FIRST_PACKET.load(Ordering::Acquire),
                         filename: this_pulse.0.clone(),
                     };
@@ -172,7 +334,11 @@ pub fn pulse_injection_task(
                         mjd = record.mjd,
                         "Injecting pulse"
                     );
-                    let _ = injection_record_sender.send(record);
+                    let _ = injection_record_sender.send(DbEvent::Injection(record));
+                    flag_marks().mark(
+                        payload.count..payload.count + this_pulse.1.shape()[0] as u64,
+                        Flags::INJECTED,
+                    );
                 }
                 if currently_injecting {
                     // Get the slice of fake pulse data and inject
@@ -188,12 +354,13 @@ pub fn pulse_injection_task(
                     );
                     i += 1;
                     // If we've gone through all of it, stop and move to the next pulse
-                    if i == current_pulse_length {
+                    if i == this_pulse.1.shape()[0] {
                         currently_injecting = false;
                         this_pulse = pulse_cycle.next().unwrap();
                     }
                 }
                 output.send(payload)?;
+                profile.record(iter_start.elapsed());
             }
             Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Closed) => break,
@@ -202,3 +369,46 @@ pub fn pulse_injection_task(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_condition_pulse() {
+        // Four native samples per payload, with a single hot sample
+        let mut pulse = Array2::<i8>::zeros((8, CHANNELS));
+        pulse.row_mut(1).fill(8);
+        let meta = PulseMeta {
+            tsamp: PACKET_CADENCE / 4.0,
+            dm: 0.0,
+        };
+        let conditioned = condition_pulse(pulse.view(), &meta, &FrequencyPlan::default().freqs());
+        assert_eq!(conditioned.dim(), (2, CHANNELS));
+        // A quarter of the power lands in the first payload
+        assert!(conditioned.row(0).iter().all(|x| *x == 4));
+        assert!(conditioned.row(1).iter().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn test_synthesize_pulse() {
+        let plan = FrequencyPlan::default();
+        let spec = PulseSpec {
+            dm: 100.0,
+            width: 1e-3,
+            fluence: 2000.0,
+            band_shape: BandShape::Flat,
+            spectral_index: 0.0,
+            band_center: plan.center(),
+            band_fwhm: 50.0,
+        };
+        let pulse = synthesize_pulse(&spec, plan);
+        let peak = |c: usize| {
+            let col = pulse.column(c);
+            (0..col.len()).max_by_key(|i| col[*i]).unwrap()
+        };
+        // The plan is descending, so the last channel is the lowest frequency and arrives last
+        assert!(peak(CHANNELS - 1) > peak(0));
+        assert!(pulse.column(CHANNELS / 2).iter().any(|x| *x > 0));
+    }
+}
@@ -0,0 +1,112 @@
+//! Bounds on how much the pipeline buffers, for commissioning runs where a trigger has to reach the dump stage (and
+//! data has to reach heimdall) within a fixed budget. Set once at startup, like the time policy.
+use crate::{
+    common::PACKET_CADENCE,
+    profiling::{profile, StageProfile},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+/// Most payloads a fast-path channel holds in low-latency mode (~8 ms)
+const LOW_LATENCY_PAYLOADS: usize = 1024;
+/// Most spectra the exfil channel (and the queues of the sinks behind it) hold in low-latency mode
+pub const LOW_LATENCY_SPECTRA: usize = 16;
+
+/// How much latency we'll put up with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyPolicy {
+    /// Bound the buffering at every stage, instead of sizing it to ride out hiccups
+    pub low_latency: bool,
+    /// Time a trigger (or spectrum) has to get through the pipeline
+    pub budget: Duration,
+    /// Open exfil files with O_DSYNC, so a write has hit the disk when it returns
+    pub sync_writes: bool,
+}
+
+impl Default for LatencyPolicy {
+    fn default() -> Self {
+        Self {
+            low_latency: false,
+            budget: Duration::from_millis(100),
+            sync_writes: false,
+        }
+    }
+}
+
+static LATENCY_POLICY: OnceLock<LatencyPolicy> = OnceLock::new();
+
+/// Set the latency policy, once at startup before anything is built
+pub fn set_latency_policy(policy: LatencyPolicy) {
+    let _ = LATENCY_POLICY.set(policy);
+}
+
+/// The latency policy in use
+pub fn latency_policy() -> &'static LatencyPolicy {
+    LATENCY_POLICY.get_or_init(LatencyPolicy::default)
+}
+
+impl LatencyPolicy {
+    /// Capacity of a payload channel, bounded in low-latency mode (but always holding at least `floor`)
+    pub fn payloads(&self, capacity: usize, floor: usize) -> usize {
+        if self.low_latency {
+            capacity.min(LOW_LATENCY_PAYLOADS).max(floor)
+        } else {
+            capacity
+        }
+    }
+
+    /// Capacity of a spectrum channel or queue, bounded in low-latency mode
+    pub fn spectra(&self, capacity: usize) -> usize {
+        if self.low_latency {
+            capacity.min(LOW_LATENCY_SPECTRA)
+        } else {
+            capacity
+        }
+    }
+
+    /// Time `payloads` take to arrive
+    pub fn payload_time(payloads: usize) -> Duration {
+        Duration::from_secs_f64(payloads as f64 * PACKET_CADENCE)
+    }
+
+    /// Create (truncating) an output file, with O_DSYNC if we're asked to
+    pub fn create(&self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        if self.sync_writes {
+            options.custom_flags(libc::O_DSYNC);
+        }
+        options.open(path)
+    }
+}
+
+/// Time from a trigger arriving to the dump stage picking it up, against the budget
+pub fn trigger_profile() -> Arc<StageProfile> {
+    profile("trigger_to_dump", latency_policy().budget)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bounds() {
+        let relaxed = LatencyPolicy::default();
+        assert_eq!(relaxed.payloads(32_768, 64), 32_768);
+        let bounded = LatencyPolicy {
+            low_latency: true,
+            ..Default::default()
+        };
+        assert_eq!(bounded.payloads(32_768, 64), LOW_LATENCY_PAYLOADS);
+        // Never fewer than the floor
+        assert_eq!(bounded.payloads(32_768, 4096), 4096);
+        assert_eq!(bounded.spectra(1024), LOW_LATENCY_SPECTRA);
+        assert_eq!(bounded.spectra(4), 4);
+    }
+}
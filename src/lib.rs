@@ -3,15 +3,40 @@
 #![deny(clippy::all)]
 //#![warn(clippy::pedantic)]
 
+pub mod accounting;
 pub mod args;
+pub mod auth;
+pub mod baseband;
+pub mod calibration;
 pub mod capture;
 pub mod common;
+pub mod correlation;
 pub mod db;
+pub mod dedup;
+pub mod diagnostics;
 pub mod dumps;
 pub mod exfil;
+pub mod failover;
+pub mod flags;
 pub mod fpga;
+#[cfg(test)]
+mod golden;
 pub mod injection;
+pub mod latency;
 pub mod monitoring;
+pub mod naming;
 pub mod pipeline;
+pub mod placement;
+pub mod postprocess;
 pub mod processing;
+pub mod profiling;
+pub mod quality;
+pub mod realtime;
+pub mod summary;
 pub mod telemetry;
+#[cfg(test)]
+mod testing;
+pub mod timing;
+pub mod tools;
+pub mod triggers;
+pub mod tsys;
@@ -1,19 +1,34 @@
-pub use clap::Parser;
-use grex_t0::{args, pipeline::start_pipeline, telemetry::init_tracing_subscriber};
+pub use clap::{Parser, Subcommand};
+use grex_t0::{
+    args, common::set_station_id, diagnostics, pipeline::start_pipeline,
+    telemetry::init_tracing_subscriber, tools,
+};
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> eyre::Result<()> {
     // Setup the error handler
     color_eyre::install()?;
+    // Standalone tools are dispatched before we try to parse the pipeline arguments
+    if std::env::args()
+        .nth(1)
+        .is_some_and(|arg| args::Tool::has_subcommand(&arg))
+    {
+        return tools::run(args::ToolCli::parse().tool);
+    }
     // Get the CLI options
     let cli = args::Cli::parse();
+    // Everything we export is labelled with the station, starting with the telemetry
+    set_station_id(cli.station_id.clone());
     // Setup telemetry (logs, spans, traces, eventually metrics)
     let _guard = init_tracing_subscriber().await;
     // Spawn all the tasks and return the handles
     let handles = start_pipeline(cli).await?;
     // Join them all when we kill the task
     for handle in handles {
-        handle.join().unwrap()?;
+        if let Err(e) = handle.join().unwrap() {
+            diagnostics::write_bundle(&format!("Fatal error: {e:?}"));
+            return Err(e);
+        }
     }
     // Cleanup logging
     opentelemetry::global::shutdown_tracer_provider();
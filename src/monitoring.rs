@@ -1,23 +1,80 @@
-use crate::common::processed_payload_start_time;
-use crate::db::InjectionRecord;
+use crate::accounting::accounting;
+use crate::auth;
+use crate::baseband::baseband_stats;
+use crate::calibration;
+use crate::common::{
+    header_clock, payload_start_time, payload_time, processed_payload_start_time, station_id,
+    CandidateEvent, CHANNELS, FIRST_PACKET, LATEST_PACKET,
+};
+use crate::correlation::{
+    integrated_bandpass, latest_cross_power, BandpassPol, BANDPASS_HISTORY_S,
+};
+use crate::db::{self, AuditRecord, DbEvent};
+use crate::diagnostics;
+use crate::dumps::ring_stats;
+use crate::exfil::{self, dada, ring::RingRequest, routes::Sink, supervisor::ExfilControl};
+use crate::failover::nic_health;
 use crate::fpga::Device;
-use crate::{capture::Stats, common::BLOCK_TIMEOUT};
-use actix_web::{dev::Server, get, App, HttpResponse, HttpServer, Responder};
+use crate::naming::time_policy;
+use crate::placement;
+use crate::processing::channel_mask;
+use crate::profiling;
+use crate::quality::{quality_inputs, rfi_occupancy};
+use crate::summary::RunSummary;
+use crate::timing::{self, ClockFit, TimeSources};
+use crate::triggers;
+use crate::tsys::latest_tsys;
+use crate::{
+    capture::{ArrivalHistogram, CaptureEvent, Stats, ARRIVAL_BUCKETS},
+    common::BLOCK_TIMEOUT,
+};
+use actix_web::{
+    delete, dev::Server, get, http::header, post, put, web, App, HttpRequest, HttpResponse,
+    HttpServer, Responder,
+};
 use paste::paste;
 use prometheus::{
-    register_gauge, register_gauge_vec, register_int_gauge, Gauge, GaugeVec, IntGauge, TextEncoder,
+    core::{Collector, Desc},
+    proto::{Bucket, Histogram, LabelPair, Metric, MetricFamily, MetricType},
+    register_counter_vec, register_gauge, register_gauge_vec, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Counter, CounterVec, Gauge, GaugeVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
 use rusqlite::Connection;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::{
-    mpsc::{Receiver, RecvTimeoutError},
-    OnceLock,
+    atomic::Ordering,
+    mpsc::{Receiver, RecvTimeoutError, SyncSender},
+    Arc, Mutex, OnceLock,
 };
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, oneshot};
 use tracing::{error, info, warn};
 use tracing_actix_web::TracingLogger;
 
 const MONITOR_ACCUMULATIONS: u32 = 1048576; // Around 8 second at 8.192us
 const TEMP_LIMIT_C: f32 = 68.0; // Any higher than this and the system might crash
+const DEVICE_COMMAND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60); // Monitoring can be busy with a VACC for a while
+const HEADER_TIME_TOLERANCE: f64 = 2.0; // Seconds the header time can disagree with the PPS count (we can't read both at once)
+
+/// Timing controls that have to be carried out by the monitor task, as it owns the FPGA
+#[derive(Debug, Clone, Copy)]
+pub enum DeviceCommand {
+    /// Force a PPS pulse, for when the real one has gone missing
+    ForcePps,
+    /// Re-arm the PPS trigger and re-derive the time of packet 0 (only before packets flow)
+    Rearm,
+    /// Re-level the requant gains across the band, pausing DADA exfil around it
+    Recalibrate,
+}
+
+/// A device command along with where to send the outcome
+#[derive(Debug)]
+pub struct DeviceRequest {
+    pub command: DeviceCommand,
+    pub reply: oneshot::Sender<Result<String, String>>,
+}
 
 macro_rules! static_prom {
     ($name:ident, $kind: ty, $create:expr) => {
@@ -30,6 +87,19 @@ macro_rules! static_prom {
     };
 }
 
+/// Bring a prometheus counter up to one of our own running totals
+fn sync_counter(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+/// Bring a prometheus counter of seconds up to one of our own running totals of nanoseconds
+fn sync_seconds(counter: &Counter, total_ns: u64) {
+    let behind = total_ns as f64 / 1e9 - counter.get();
+    if behind > 0.0 {
+        counter.inc_by(behind);
+    }
+}
+
 // Global prometheus state variables
 static_prom!(
     spectrum_gauge,
@@ -46,11 +116,29 @@ static_prom!(
     IntGauge,
     register_int_gauge!("processed_packets", "Number of packets we've processed").unwrap()
 );
+static_prom!(
+    blanked_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "blanked_channels",
+        "Number of channels blanked before exfil"
+    )
+    .unwrap()
+);
 static_prom!(
     drop_gauge,
     IntGauge,
     register_int_gauge!("dropped_packets", "Number of packets we've dropped").unwrap()
 );
+static_prom!(
+    rejected_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "rejected_packets",
+        "Number of datagrams we've rejected for coming from an unexpected source"
+    )
+    .unwrap()
+);
 static_prom!(
     shuffled_gauge,
     IntGauge,
@@ -60,6 +148,33 @@ static_prom!(
     )
     .unwrap()
 );
+static_prom!(
+    resync_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "capture_resyncs",
+        "Number of times capture picked up a new packet sequence instead of filling a gap"
+    )
+    .unwrap()
+);
+static_prom!(
+    reordered_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "reordered_packets",
+        "Number of packets that arrived early and were put back in order"
+    )
+    .unwrap()
+);
+static_prom!(
+    fault_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "capture_fpga_faults",
+        "Number of packets whose header had FPGA status bits set"
+    )
+    .unwrap()
+);
 static_prom!(
     fft_ovlf_gauge,
     IntGauge,
@@ -70,26 +185,748 @@ static_prom!(
     Gauge,
     register_gauge!("fpga_temp", "Internal FPGA temperature").unwrap()
 );
+static_prom!(
+    timing_degraded_gauge,
+    IntGauge,
+    register_int_gauge!(
+        "timing_degraded",
+        "Set if the packet epoch came from the unsynchronized system clock"
+    )
+    .unwrap()
+);
+static_prom!(
+    ntp_offset_gauge,
+    Gauge,
+    register_gauge!(
+        "ntp_offset",
+        "Most recent offset between the system clock and NTP (seconds)"
+    )
+    .unwrap()
+);
+static_prom!(
+    clock_drift_gauge,
+    Gauge,
+    register_gauge!(
+        "clock_drift",
+        "Most recent drift of packet-count time relative to NTP (seconds)"
+    )
+    .unwrap()
+);
+static_prom!(
+    fpga_clock_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "fpga_clock",
+        "FPGA fabric clock rate error against the host clock (ppm), PPS edges gained (+) or lost (-), and read round trip (seconds)",
+        &["stat"]
+    )
+    .unwrap()
+);
 static_prom!(
     adc_rms_gauge,
     GaugeVec,
     register_gauge_vec!("adc_rms", "RMS value of raw adc values", &["channel"]).unwrap()
 );
+static_prom!(
+    cross_power_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "cross_power",
+        "Coherence and phase (radians) of pol A against pol B",
+        &["channel", "component"]
+    )
+    .unwrap()
+);
+static_prom!(
+    standby_nic_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "standby_nic",
+        "Whether each hot-standby capture interface is up, the payloads it delivered, and how many of those we used",
+        &["nic", "component"]
+    )
+    .unwrap()
+);
+static_prom!(
+    tsys_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "tsys",
+        "Estimated system temperature (K) of each sub-band from the noise source cycle",
+        &["subband", "freq"]
+    )
+    .unwrap()
+);
+static_prom!(
+    header_time_error_gauge,
+    Gauge,
+    register_gauge!(
+        "header_time_error",
+        "Time since sync from payload headers minus the FPGA's PPS count (s)"
+    )
+    .unwrap()
+);
+static_prom!(
+    baseband_gauge,
+    IntGaugeVec,
+    register_int_gauge_vec!(
+        "baseband_recording",
+        "Baseband recording payloads recorded and dropped, files started, and files deleted for the budget",
+        &["stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    ring_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "voltage_ring",
+        "Voltage ringbuffer occupancy (payloads and fraction), oldest and newest payload counts, and the time the latest dump took (s)",
+        &["stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    ring_counter,
+    IntCounterVec,
+    register_int_counter_vec!(
+        "voltage_ring_total",
+        "Voltage ringbuffer pushes, non-monotonic resets, resyncs, dumps written, triggers coalesced, dropped, or failed, and rolling segments written, expired, and payloads missed",
+        &["stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    ring_seconds_counter,
+    CounterVec,
+    register_counter_vec!(
+        "voltage_ring_seconds_total",
+        "Seconds the voltage ringbuffer was blocked, and spent dumping",
+        &["stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    sink_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "exfil_sink",
+        "Per exfil sink bytes written, blocks committed, stalls on the output and time stalled (s), and p99 write latency (s)",
+        &["sink", "stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    trigger_source_gauge,
+    IntGaugeVec,
+    register_int_gauge_vec!(
+        "trigger_source",
+        "Per trigger source counts of triggers received, rejected, unauthenticated, ignored (while disabled), and dispatched, and whether it's enabled or failing",
+        &["source", "stat"]
+    )
+    .unwrap()
+);
+static_prom!(
+    sink_info_gauge,
+    IntGaugeVec,
+    register_int_gauge_vec!(
+        "exfil_sink_info",
+        "Where each exfil sink is writing (its last error is on /exfil)",
+        &["sink", "target"]
+    )
+    .unwrap()
+);
+static_prom!(
+    quality_gauge,
+    Gauge,
+    register_gauge!("quality_score", "Composite data quality score (0-100)").unwrap()
+);
+static_prom!(
+    quality_component_gauge,
+    GaugeVec,
+    register_gauge_vec!(
+        "quality_component",
+        "Individual components of the quality score (0-1)",
+        &["component"]
+    )
+    .unwrap()
+);
+
+/// Time between capture datagrams (summed over the streams), exported as a native prometheus histogram.
+/// Capture keeps its own (HDR-style) buckets, so this just reports the latest counts rather than observing.
+#[derive(Clone)]
+struct ArrivalCollector {
+    desc: Desc,
+    latest: Arc<Mutex<ArrivalHistogram>>,
+}
+
+impl ArrivalCollector {
+    fn set(&self, histogram: ArrivalHistogram) {
+        *self.latest.lock().unwrap() = histogram;
+    }
+}
+
+impl Collector for ArrivalCollector {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let latest = self.latest.lock().unwrap();
+        let mut cumulative = 0;
+        let buckets: Vec<_> = latest.counts[..ARRIVAL_BUCKETS]
+            .iter()
+            .enumerate()
+            .map(|(i, count)| {
+                cumulative += count;
+                let mut bucket = Bucket::default();
+                bucket.set_upper_bound(ArrivalHistogram::upper_bound(i) as f64 * 1e-9);
+                bucket.set_cumulative_count(cumulative);
+                bucket
+            })
+            .collect();
+        let mut histogram = Histogram::default();
+        histogram.set_bucket(buckets.into());
+        histogram.set_sample_count(latest.counts.iter().sum());
+        histogram.set_sample_sum(latest.sum_ns as f64 * 1e-9);
+        let mut metric = Metric::default();
+        metric.set_histogram(histogram);
+        let mut family = MetricFamily::default();
+        family.set_name(self.desc.fq_name.clone());
+        family.set_help(self.desc.help.clone());
+        family.set_field_type(MetricType::HISTOGRAM);
+        family.set_metric(vec![metric].into());
+        vec![family]
+    }
+}
+
+static_prom!(arrival_collector, ArrivalCollector, {
+    let collector = ArrivalCollector {
+        desc: Desc::new(
+            "capture_interarrival_seconds".to_owned(),
+            "Time between datagrams as the kernel received them, over every capture stream"
+                .to_owned(),
+            vec![],
+            HashMap::new(),
+        )
+        .unwrap(),
+        latest: Arc::new(Mutex::new(ArrivalHistogram::default())),
+    };
+    prometheus::register(Box::new(collector.clone())).unwrap();
+    collector
+});
+
+/// Every registered metric, each labelled with the station so the central stack doesn't have to relabel them
+pub fn gather() -> Vec<MetricFamily> {
+    let mut families = prometheus::gather();
+    for metric in families.iter_mut().flat_map(|f| f.mut_metric().iter_mut()) {
+        let mut label = LabelPair::default();
+        label.set_name("station".to_owned());
+        label.set_value(station_id().to_owned());
+        metric.mut_label().push(label);
+    }
+    families
+}
 
 #[get("/metrics")]
 async fn metrics() -> impl Responder {
     let encoder = TextEncoder::new();
-    let metric_families = prometheus::gather();
-    HttpResponse::Ok().body(encoder.encode_to_string(&metric_families).unwrap())
+    HttpResponse::Ok().body(encoder.encode_to_string(&gather()).unwrap())
 }
 
 #[get("/start_time")]
 async fn start_time() -> impl Responder {
     let time = processed_payload_start_time();
-    HttpResponse::Ok().body(time.to_mjd_tai_days().to_string())
+    HttpResponse::Ok().body(time_policy().mjd(time).to_string())
+}
+
+#[get("/accounting")]
+async fn accounting_snapshot() -> impl Responder {
+    HttpResponse::Ok().json(accounting().snapshot())
+}
+
+#[get("/quality")]
+async fn quality() -> impl Responder {
+    let inputs = *quality_inputs().lock().unwrap();
+    HttpResponse::Ok().json(inputs.score(TEMP_LIMIT_C.into()))
+}
+
+#[get("/profile")]
+async fn profile() -> impl Responder {
+    HttpResponse::Ok().json(profiling::snapshot())
+}
+
+#[get("/config")]
+async fn config() -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "config": diagnostics::config(),
+        "placement": placement::snapshot(),
+    }))
+}
+
+#[get("/exfil")]
+async fn exfil_sinks() -> impl Responder {
+    HttpResponse::Ok().json(exfil::stats::snapshot())
+}
+
+#[get("/cross_power")]
+async fn cross_power() -> impl Responder {
+    match latest_cross_power().lock().unwrap().as_ref() {
+        Some(xpower) => HttpResponse::Ok().json(serde_json::json!({
+            "integrations": xpower.integrations,
+            "coherence": xpower.coherence(),
+            "phase": xpower.phase(),
+        })),
+        None => HttpResponse::NotFound().body("No cross power integrations yet"),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SpectrumQuery {
+    /// Seconds to integrate over
+    #[serde(default = "default_integration")]
+    integration_s: f64,
+    #[serde(default)]
+    pol: BandpassPol,
+    /// "json" or "csv"
+    #[serde(default)]
+    format: Option<String>,
+}
+
+fn default_integration() -> f64 {
+    10.0
+}
+
+/// The most recent bandpass computed on the host from the voltages we receive, in the gateware channel order so it
+/// lines up with the FPGA's vacc
+#[get("/spectrum")]
+async fn spectrum(query: web::Query<SpectrumQuery>) -> impl Responder {
+    let seconds = query
+        .integration_s
+        .round()
+        .clamp(1.0, BANDPASS_HISTORY_S as f64) as usize;
+    let Some((bandpass, seconds)) = integrated_bandpass(seconds) else {
+        return HttpResponse::NotFound().body("No bandpass integrations yet");
+    };
+    let power = bandpass.average(query.pol);
+    match query.format.as_deref() {
+        Some("csv") => {
+            let mut body = "channel,power\n".to_owned();
+            for (channel, p) in power.iter().enumerate() {
+                body.push_str(&format!("{channel},{p}\n"));
+            }
+            HttpResponse::Ok().content_type("text/csv").body(body)
+        }
+        None | Some("json") => HttpResponse::Ok().json(serde_json::json!({
+            "integration_s": seconds,
+            "pol": query.pol,
+            "payloads": bandpass.payloads,
+            "power": power,
+        })),
+        Some(other) => HttpResponse::BadRequest().body(format!("Unknown format {other}")),
+    }
+}
+
+/// Record who asked for a control action and what happened, in the logs and the DB
+fn audit(
+    req: &HttpRequest,
+    action: &str,
+    outcome: &Result<String, String>,
+    db: &SyncSender<DbEvent>,
+) {
+    let requester = req
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_else(|| "unknown".to_owned());
+    let outcome = match outcome {
+        Ok(s) => s.clone(),
+        Err(e) => format!("error: {e}"),
+    };
+    info!(target: "audit", action, requester, outcome, "Control action");
+    let record = AuditRecord {
+        mjd: hifitime::Epoch::now()
+            .map(|e| time_policy().mjd(e))
+            .unwrap_or_default(),
+        action: action.to_owned(),
+        requester,
+        outcome,
+    };
+    if db.try_send(DbEvent::Audit(record)).is_err() {
+        warn!("Couldn't record control action in the audit log");
+    }
+}
+
+/// Carry out a device command, recording who asked for it and what happened
+async fn device_command(
+    req: HttpRequest,
+    command: DeviceCommand,
+    devices: web::Data<SyncSender<DeviceRequest>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> HttpResponse {
+    let (reply, outcome) = oneshot::channel();
+    let outcome = match devices.try_send(DeviceRequest { command, reply }) {
+        Ok(_) => match tokio::time::timeout(DEVICE_COMMAND_TIMEOUT, outcome).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(_)) => Err("Monitoring task went away".to_owned()),
+            Err(_) => Err("Timed out waiting for the monitoring task".to_owned()),
+        },
+        Err(_) => Err("Monitoring task is busy".to_owned()),
+    };
+    audit(&req, &format!("{command:?}"), &outcome, &db);
+    match outcome {
+        Ok(s) => HttpResponse::Ok().body(s),
+        Err(e) => HttpResponse::Conflict().body(e),
+    }
+}
+
+#[post("/timing/force_pps")]
+async fn force_pps(
+    req: HttpRequest,
+    devices: web::Data<SyncSender<DeviceRequest>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    device_command(req, DeviceCommand::ForcePps, devices, db).await
+}
+
+#[post("/timing/rearm")]
+async fn rearm(
+    req: HttpRequest,
+    devices: web::Data<SyncSender<DeviceRequest>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    device_command(req, DeviceCommand::Rearm, devices, db).await
+}
+
+#[post("/calibration/level")]
+async fn recalibrate(
+    req: HttpRequest,
+    devices: web::Data<SyncSender<DeviceRequest>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    device_command(req, DeviceCommand::Recalibrate, devices, db).await
+}
+
+#[post("/exfil/pause")]
+async fn pause_exfil(req: HttpRequest, db: web::Data<SyncSender<DbEvent>>) -> impl Responder {
+    dada::set_paused(true);
+    let msg = "DADA exfil will pause at the next block boundary".to_owned();
+    audit(&req, "PauseExfil", &Ok(msg.clone()), &db);
+    HttpResponse::Ok().body(msg)
+}
+
+#[post("/exfil/resume")]
+async fn resume_exfil(req: HttpRequest, db: web::Data<SyncSender<DbEvent>>) -> impl Responder {
+    dada::set_paused(false);
+    let msg = "DADA exfil resuming".to_owned();
+    audit(&req, "ResumeExfil", &Ok(msg.clone()), &db);
+    HttpResponse::Ok().body(msg)
+}
+
+#[get("/exfil/stokes")]
+async fn exfil_stokes(control: web::Data<Option<ExfilControl>>) -> impl Responder {
+    match control.as_ref() {
+        Some(control) => HttpResponse::Ok().json(control.current()),
+        None => HttpResponse::NotFound().body("Exfil sinks can't be switched"),
+    }
+}
+
+/// Switch the stokes stream to these sinks (as a routing table lists them), an empty list stopping exfil
+#[put("/exfil/stokes")]
+async fn switch_exfil(
+    req: HttpRequest,
+    sinks: web::Json<Vec<Sink>>,
+    control: web::Data<Option<ExfilControl>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    switch_exfil_to(req, sinks.into_inner(), control, db)
 }
 
-fn update_spec(device: &mut Device) -> eyre::Result<()> {
+#[delete("/exfil/stokes")]
+async fn stop_exfil(
+    req: HttpRequest,
+    control: web::Data<Option<ExfilControl>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    switch_exfil_to(req, vec![], control, db)
+}
+
+/// Ask the exfil task to switch stokes sinks, recording who asked for it
+fn switch_exfil_to(
+    req: HttpRequest,
+    sinks: Vec<Sink>,
+    control: web::Data<Option<ExfilControl>>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> HttpResponse {
+    let Some(control) = control.as_ref() else {
+        return HttpResponse::NotFound().body("Exfil sinks can't be switched");
+    };
+    let outcome = control
+        .switch(sinks.clone())
+        .map(|_| format!("Switching stokes exfil to {sinks:?}"))
+        .map_err(|e| e.to_string());
+    audit(&req, "SwitchExfil", &outcome, &db);
+    match outcome {
+        Ok(msg) => HttpResponse::Accepted().body(msg),
+        Err(e) => HttpResponse::BadRequest().body(e),
+    }
+}
+
+/// The trigger secret, which requests that trigger dumps have to carry as a bearer token if we have one
+#[derive(Debug, Clone)]
+struct TriggerSecret(Option<Vec<u8>>);
+
+impl TriggerSecret {
+    /// Whether `req` may trigger dumps, auditing it as `action` if not
+    fn admits(&self, req: &HttpRequest, action: &str, db: &SyncSender<DbEvent>) -> bool {
+        let Some(secret) = &self.0 else {
+            return true;
+        };
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        let admitted = token.is_some_and(|t| auth::is_secret(secret, t.as_bytes()));
+        if !admitted {
+            audit(req, action, &Err("Unauthorized".to_owned()), db);
+        }
+        admitted
+    }
+}
+
+#[post("/trigger")]
+async fn trigger(
+    req: HttpRequest,
+    event: web::Json<CandidateEvent>,
+    sender: web::Data<tokio::sync::mpsc::Sender<CandidateEvent>>,
+    secret: web::Data<TriggerSecret>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    if !secret.admits(&req, "Trigger", &db) {
+        return HttpResponse::Unauthorized().body("Triggers need the trigger secret");
+    }
+    let mut event = event.into_inner();
+    if let Some(peer) = req.peer_addr() {
+        event.source.get_or_insert_with(|| peer.to_string());
+    }
+    match sender.try_send(event) {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(_) => HttpResponse::ServiceUnavailable().body("Too many triggers waiting"),
+    }
+}
+
+/// Whether manual dumps are turned on
+#[derive(Debug, Clone)]
+struct ManualDumps(bool);
+
+#[derive(Debug, Deserialize)]
+struct ManualDumpQuery {
+    /// Goes in the name of the file
+    name: String,
+    /// Payloads of voltages to dump, if not the usual number
+    ntime: Option<u64>,
+}
+
+/// Dump the voltages around right now, named by the operator
+#[post("/dump/trigger")]
+async fn manual_dump(
+    req: HttpRequest,
+    query: web::Query<ManualDumpQuery>,
+    sender: web::Data<tokio::sync::mpsc::Sender<CandidateEvent>>,
+    manual: web::Data<ManualDumps>,
+    secret: web::Data<TriggerSecret>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    if !manual.0 {
+        return HttpResponse::NotFound().body("Manual dumps are off (see --manual-dumps)");
+    }
+    if !secret.admits(&req, "ManualDump", &db) {
+        return HttpResponse::Unauthorized().body("Manual dumps need the trigger secret");
+    }
+    let ManualDumpQuery { name, ntime } = query.into_inner();
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return HttpResponse::BadRequest().body("Invalid name");
+    }
+    let capacity = ring_stats().capacity.load(Ordering::Relaxed);
+    if ntime.is_some_and(|n| n == 0 || n > capacity) {
+        return HttpResponse::BadRequest().body(format!(
+            "ntime has to be between 1 and the ring's {capacity}"
+        ));
+    }
+    let latest = LATEST_PACKET.load(Ordering::Acquire);
+    if latest == 0 || payload_start_time().lock().unwrap().is_none() {
+        return HttpResponse::ServiceUnavailable().body("No data yet");
+    }
+    // Spectrum zero plus the payloads since, so the window is centered on the newest payload
+    let event = CandidateEvent {
+        candname: name.clone(),
+        specnum: 0,
+        offset: (latest - FIRST_PACKET.load(Ordering::Acquire)) as i64,
+        mjd: Some(time_policy().mjd(payload_time(latest))),
+        source: req.peer_addr().map(|peer| format!("manual ({peer})")),
+        ntime,
+        local: true,
+        ..Default::default()
+    };
+    let outcome = sender
+        .try_send(event)
+        .map(|_| format!("Dumping {name}"))
+        .map_err(|_| "Too many triggers waiting".to_owned());
+    audit(&req, "ManualDump", &outcome, &db);
+    match outcome {
+        Ok(msg) => HttpResponse::Accepted().body(msg),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RecentDumpsQuery {
+    #[serde(default = "default_recent_dumps")]
+    limit: usize,
+}
+
+fn default_recent_dumps() -> usize {
+    20
+}
+
+/// The dumps the database has on record, newest first
+#[get("/dump/recent")]
+async fn recent_dumps(
+    query: web::Query<RecentDumpsQuery>,
+    db_path: web::Data<PathBuf>,
+) -> impl Responder {
+    let (db_path, limit) = (db_path.get_ref().clone(), query.limit);
+    match web::block(move || db::recent_dumps(&db::open_read_only(db_path)?, limit)).await {
+        Ok(Ok(dumps)) => HttpResponse::Ok().json(dumps),
+        Ok(Err(e)) => HttpResponse::InternalServerError().body(e.to_string()),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/triggers")]
+async fn trigger_sources() -> impl Responder {
+    HttpResponse::Ok().json(triggers::snapshot())
+}
+
+/// Turn a trigger source on or off, recording who asked
+fn set_trigger_source(
+    req: &HttpRequest,
+    name: &str,
+    enabled: bool,
+    db: &SyncSender<DbEvent>,
+) -> HttpResponse {
+    let action = if enabled {
+        "EnableTriggerSource"
+    } else {
+        "DisableTriggerSource"
+    };
+    let outcome = if triggers::set_enabled(name, enabled) {
+        Ok(format!("Trigger source {name} enabled: {enabled}"))
+    } else {
+        Err(format!("No trigger source named {name}"))
+    };
+    audit(req, action, &outcome, db);
+    match outcome {
+        Ok(msg) => HttpResponse::Ok().body(msg),
+        Err(e) => HttpResponse::NotFound().body(e),
+    }
+}
+
+#[post("/triggers/{name}/enable")]
+async fn enable_trigger_source(
+    req: HttpRequest,
+    name: web::Path<String>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    set_trigger_source(&req, &name, true, &db)
+}
+
+#[post("/triggers/{name}/disable")]
+async fn disable_trigger_source(
+    req: HttpRequest,
+    name: web::Path<String>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    set_trigger_source(&req, &name, false, &db)
+}
+
+#[derive(Debug, Deserialize)]
+struct RingWindowQuery {
+    /// Goes in the name of the file
+    name: String,
+    start_mjd: f64,
+    end_mjd: f64,
+}
+
+#[post("/stokes_ring")]
+async fn stokes_ring_window(
+    query: web::Query<RingWindowQuery>,
+    ring: web::Data<Option<SyncSender<RingRequest>>>,
+) -> impl Responder {
+    let RingWindowQuery {
+        name,
+        start_mjd,
+        end_mjd,
+    } = query.into_inner();
+    if end_mjd <= start_mjd {
+        return HttpResponse::BadRequest().body("The window has to end after it starts");
+    }
+    if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+        return HttpResponse::BadRequest().body("Invalid name");
+    }
+    let Some(ring) = ring.as_ref() else {
+        return HttpResponse::NotFound().body("Not keeping a stokes ring");
+    };
+    match ring.try_send(RingRequest::Window {
+        name,
+        start_mjd,
+        end_mjd,
+    }) {
+        Ok(_) => HttpResponse::Accepted().finish(),
+        Err(_) => HttpResponse::ServiceUnavailable().body("Stokes ring is busy"),
+    }
+}
+
+#[get("/blanking")]
+async fn blanked_channels() -> impl Responder {
+    HttpResponse::Ok().json(channel_mask().channels())
+}
+
+#[post("/blanking/{channel}")]
+async fn blank_channel(
+    channel: web::Path<usize>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    let channel = channel.into_inner();
+    if channel >= CHANNELS {
+        return HttpResponse::BadRequest().body("Channel out of range");
+    }
+    if channel_mask().blank(channel) {
+        info!(channel, "Blanking channel");
+        if db.try_send(DbEvent::BlankChannel(channel)).is_err() {
+            warn!("Couldn't persist blanked channel to the DB");
+        }
+    }
+    HttpResponse::Ok().json(channel_mask().channels())
+}
+
+#[delete("/blanking/{channel}")]
+async fn unblank_channel(
+    channel: web::Path<usize>,
+    db: web::Data<SyncSender<DbEvent>>,
+) -> impl Responder {
+    let channel = channel.into_inner();
+    if channel_mask().unblank(channel) {
+        info!(channel, "Unblanking channel");
+        if db.try_send(DbEvent::UnblankChannel(channel)).is_err() {
+            warn!("Couldn't persist unblanked channel to the DB");
+        }
+    }
+    HttpResponse::Ok().json(channel_mask().channels())
+}
+
+/// Read the spectra off the FPGA, returning the RFI occupancy of the stokes spectrum
+fn update_spec(device: &mut Device) -> eyre::Result<f64> {
     // Capture the spectrum
     let (a, b, stokes) = device.perform_both_vacc(MONITOR_ACCUMULATIONS)?;
     // And find the mean by dividing by N (and u32 max) to get 0-1
@@ -121,38 +958,234 @@ fn update_spec(device: &mut Device) -> eyre::Result<()> {
             .with_label_values(&[&i.to_string(), "stokes"])
             .set(*v);
     }
-    Ok(())
+    Ok(rfi_occupancy(&stokes_norm))
+}
+
+/// Move the exfil checksums that have piled up into the database
+fn record_checksums(conn: &Connection) {
+    for checksum in exfil::checksum::take() {
+        if let Err(e) = db::insert_checksum(conn, &checksum) {
+            warn!("Couldn't record an exfil checksum - {e}");
+        }
+    }
 }
 
 pub fn db_task(
     conn: Connection,
-    injection_events: Receiver<InjectionRecord>,
+    db_events: Receiver<DbEvent>,
+    summary_path: PathBuf,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     loop {
         // Look for shutdown signal
         if shutdown.try_recv().is_ok() {
-            info!("Monitoring task stopping");
+            info!("DB task stopping");
             break;
         }
-        // If there's a new injection event, process that DB action
-        if let Ok(r) = injection_events.recv() {
-            match r.db_insert(&conn) {
+        // If there's a new event, process that DB action
+        match db_events.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(r) => match r.apply(&conn) {
                 Ok(_) => (),
                 Err(e) => warn!("Error processing DB event - {}", e),
-            }
+            },
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => break,
         }
+        record_checksums(&conn);
     }
+    // Exfil will have checksummed whatever it had left as it finished
+    record_checksums(&conn);
+    // Record where all the samples went for this run
+    let start = processed_payload_start_time();
+    let stop = hifitime::Epoch::now()?;
+    let acc = accounting().snapshot();
+    info!(?acc, "Recording observation accounting");
+    let injections = db::injections_since(&conn, time_policy().mjd(start))?;
+    let summary = RunSummary::collect(start, stop, &acc, injections);
+    match summary.write(&summary_path) {
+        Ok(path) => info!("Wrote run summary to {}", path.display()),
+        Err(e) => warn!("Couldn't write the run summary - {e}"),
+    }
+    db::insert_observation(
+        &conn,
+        time_policy().mjd(start),
+        time_policy().mjd(stop),
+        &acc,
+        &summary.to_json(),
+    )?;
     Ok(())
 }
 
+/// Carry out a timing control on the FPGA
+fn handle_device_command(
+    device: &mut Device,
+    command: DeviceCommand,
+    time_sources: &TimeSources,
+) -> eyre::Result<String> {
+    match command {
+        DeviceCommand::ForcePps => {
+            device.force_pps()?;
+            Ok("Forced a PPS pulse".to_owned())
+        }
+        DeviceCommand::Rearm => {
+            // Re-arming resets the packet count, which would throw everything downstream for a loop mid-run
+            if accounting().captured.load(Ordering::Relaxed) > 0 {
+                eyre::bail!("Packets are already flowing, refusing to re-arm");
+            }
+            let sync = if time_sources.is_empty() {
+                None
+            } else {
+                timing::synchronize(time_sources, 1)
+            };
+            let start = match &sync {
+                Some(sync) => device.trigger(sync)?,
+                None => device.blind_trigger()?,
+            };
+            timing::set_degraded(sync.is_none());
+            *payload_start_time().lock().unwrap() = Some(start);
+            Ok(format!(
+                "Packet 0 is coincident with {} MJD (TAI)",
+                start.to_mjd_tai_days()
+            ))
+        }
+        DeviceCommand::Recalibrate => {
+            unreachable!("Recalibrations run on their own thread")
+        }
+    }
+}
+
+/// Query the FPGA for its health, clocking, and spectra
+fn poll_device(
+    device: &mut Device,
+    clock_fit: &mut ClockFit,
+    clock_fault_reported: &mut bool,
+    header_mismatch_reported: &mut bool,
+) {
+    // Cross-check the FPGA's clock against ours
+    match device.clock_sample() {
+        Ok(sample) => {
+            clock_fit.push(sample);
+            fpga_clock_gauge()
+                .with_label_values(&["round_trip"])
+                .set(sample.round_trip.as_secs_f64());
+            if let Some(ppm) = clock_fit.rate_error_ppm() {
+                fpga_clock_gauge()
+                    .with_label_values(&["rate_error_ppm"])
+                    .set(ppm);
+            }
+            if let Some(slip) = clock_fit.pps_slip() {
+                fpga_clock_gauge()
+                    .with_label_values(&["pps_slip"])
+                    .set(slip as f64);
+            }
+            match clock_fit.fault() {
+                Some(fault) if !*clock_fault_reported => {
+                    error!("FPGA clocking fault, timestamps are at risk - {fault}");
+                    *clock_fault_reported = true;
+                }
+                None if *clock_fault_reported => {
+                    info!("FPGA clocking looks healthy again");
+                    *clock_fault_reported = false;
+                }
+                _ => (),
+            }
+        }
+        Err(e) => warn!("SNAP Error - {e}"),
+    }
+
+    // Update channel data from FPGA
+    match update_spec(device) {
+        Ok(occupancy) => quality_inputs().lock().unwrap().rfi_occupancy = Some(occupancy),
+        Err(e) => warn!("SNAP Error - {e}"),
+    }
+
+    // Metrics from the FPGA
+    match device.fpga.fft_overflow_cnt.read() {
+        Ok(v) => fft_ovlf_gauge().set(u32::from(v).into()),
+        Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+    }
+
+    match device.fpga.transport.lock().unwrap().temperature() {
+        Ok(v) => {
+            // If we get too hot, we really need to bail
+            if v >= TEMP_LIMIT_C {
+                error!("SNAP temperature too hot - powering down");
+                panic!();
+            }
+            fpga_temp().set(v.into());
+            quality_inputs().lock().unwrap().fpga_temp = Some(v.into());
+        }
+        Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+    }
+
+    // Take a snapshot of ADC values and compute RMS value
+    if device.fpga.adc_snap.arm().is_ok() && device.fpga.adc_snap.trigger().is_ok() {
+        match device.fpga.adc_snap.read() {
+            Ok(v) => {
+                let mut rms_a = 0.0;
+                let mut rms_b = 0.0;
+                let mut n = 0;
+                for chunk in v.chunks(4) {
+                    rms_a += f64::powi(f64::from(chunk[0] as i8), 2);
+                    rms_a += f64::powi(f64::from(chunk[1] as i8), 2);
+                    rms_b += f64::powi(f64::from(chunk[2] as i8), 2);
+                    rms_b += f64::powi(f64::from(chunk[3] as i8), 2);
+                    n += 2;
+                }
+                rms_a = ((1.0 / (n as f64)) * rms_a).sqrt();
+                rms_b = ((1.0 / (n as f64)) * rms_b).sqrt();
+                adc_rms_gauge().with_label_values(&["a"]).set(rms_a);
+                adc_rms_gauge().with_label_values(&["b"]).set(rms_b);
+                quality_inputs().lock().unwrap().adc_rms = Some((rms_a, rms_b));
+            }
+            Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+        }
+    }
+
+    // Make sure we're interpreting the payload headers the same way the FPGA keeps time
+    let latest = LATEST_PACKET.load(std::sync::atomic::Ordering::Relaxed);
+    if latest > 0 {
+        match device.pps_count() {
+            Ok(pps) => {
+                let error = header_clock().seconds(latest) - f64::from(pps);
+                header_time_error_gauge().set(error);
+                if error.abs() > HEADER_TIME_TOLERANCE && !*header_mismatch_reported {
+                    *header_mismatch_reported = true;
+                    error!(
+                        error,
+                        "Payload header time disagrees with the FPGA's PPS count - check --count-unit and --epoch-offset"
+                    );
+                }
+            }
+            Err(e) => warn!("SNAP Error - {e}"),
+        }
+    }
+
+    // Keep a register dump handy for diagnostic bundles
+    match device.register_dump() {
+        Ok(dump) => diagnostics::record_registers(dump),
+        Err(e) => warn!("SNAP Error - {e}"),
+    }
+}
+
 /// The monitor task publishes updates about the capture statistics, queries FPGA state, and updates the SQLite database on events
 pub fn monitor_task(
-    mut device: Device,
-    capture_stats: Receiver<Stats>,
+    device: Option<Device>,
+    capture_stats: Receiver<CaptureEvent>,
+    commands: Receiver<DeviceRequest>,
+    time_sources: TimeSources,
+    mut clock_fit: ClockFit,
+    requant_gain: u16,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting monitoring task!");
+    // Shared with recalibrations, which run on their own thread
+    let device = device.map(|d| Arc::new(Mutex::new(d)));
+    let mut recalibration: Option<std::thread::JoinHandle<()>> = None;
+    // Latest stats from each capture stream
+    let mut last_stats: HashMap<u16, Stats> = HashMap::new();
+    let mut header_mismatch_reported = false;
+    let mut clock_fault_reported = false;
     loop {
         // Look for shutdown signal
         if shutdown.try_recv().is_ok() {
@@ -160,75 +1193,314 @@ pub fn monitor_task(
             break;
         }
 
+        // Carry out any timing controls from the API
+        while let Ok(req) = commands.try_recv() {
+            let Some(device) = &device else {
+                let _ = req
+                    .reply
+                    .send(Err("There's no FPGA attached (replaying)".to_owned()));
+                continue;
+            };
+            // Leveling waits on DADA and a long accumulation, which shouldn't hold up the rest of monitoring
+            if let DeviceCommand::Recalibrate = req.command {
+                if recalibration.as_ref().is_some_and(|r| !r.is_finished()) {
+                    let _ = req.reply.send(Err("Already recalibrating".to_owned()));
+                    continue;
+                }
+                let device = device.clone();
+                recalibration = Some(
+                    std::thread::Builder::new()
+                        .name("recalibrate".to_string())
+                        .spawn(move || {
+                            let outcome = calibration::recalibrate(&device, requant_gain)
+                                .map_err(|e| e.to_string());
+                            let _ = req.reply.send(outcome);
+                        })?,
+                );
+                continue;
+            }
+            let outcome =
+                handle_device_command(&mut device.lock().unwrap(), req.command, &time_sources)
+                    .map_err(|e| e.to_string());
+            // Either of these will throw off the PPS count
+            clock_fit.reset();
+            let _ = req.reply.send(outcome);
+        }
+
         // Blocking here is ok, these are infrequent events
         match capture_stats.recv_timeout(BLOCK_TIMEOUT) {
-            Ok(stat) => {
-                packet_gauge().set(stat.processed.try_into().unwrap());
-                drop_gauge().set(stat.drops.try_into().unwrap());
-                shuffled_gauge().set(stat.shuffled.try_into().unwrap());
+            Ok(CaptureEvent::Resync(resync)) => {
+                warn!(
+                    stream = resync.stream,
+                    expected = resync.expected,
+                    count = resync.count,
+                    "Capture resynchronized, the FPGA may have restarted"
+                );
+            }
+            Ok(CaptureEvent::Stats(stat)) => {
+                // Drop rate (of this stream) since its last stats message
+                let last = last_stats
+                    .insert(stat.stream, stat.clone())
+                    .unwrap_or_default();
+                let drops = stat.drops.saturating_sub(last.drops);
+                let total = drops + stat.processed.saturating_sub(last.processed);
+                if total > 0 {
+                    quality_inputs().lock().unwrap().drop_rate = Some(drops as f64 / total as f64);
+                }
+                // The gauges are totals over every stream
+                let sum = |f: fn(&Stats) -> usize| last_stats.values().map(f).sum::<usize>();
+                packet_gauge().set(sum(|s| s.processed).try_into().unwrap());
+                drop_gauge().set(sum(|s| s.drops).try_into().unwrap());
+                shuffled_gauge().set(sum(|s| s.shuffled).try_into().unwrap());
+                rejected_gauge().set(sum(|s| s.rejected).try_into().unwrap());
+                resync_gauge().set(sum(|s| s.resyncs).try_into().unwrap());
+                blanked_gauge().set(channel_mask().channels().len().try_into().unwrap());
+                reordered_gauge().set(sum(|s| s.reordered).try_into().unwrap());
+                fault_gauge().set(sum(|s| s.faults).try_into().unwrap());
+                let mut arrivals = ArrivalHistogram::default();
+                for stats in last_stats.values() {
+                    arrivals.merge(&stats.arrivals);
+                }
+                arrival_collector().set(arrivals);
             }
             Err(RecvTimeoutError::Timeout) => continue,
             Err(RecvTimeoutError::Disconnected) => break,
         }
 
-        // Update channel data from FPGA
-        match update_spec(&mut device) {
-            Ok(_) => (),
-            Err(e) => warn!("SNAP Error - {e}"),
+        // Voltage ringbuffer throughput
+        let ring = ring_stats();
+        let occupancy = ring.occupancy.load(Ordering::Relaxed) as f64;
+        for (stat, value) in [
+            ("occupancy", occupancy),
+            (
+                "fill_fraction",
+                occupancy / ring.capacity.load(Ordering::Relaxed).max(1) as f64,
+            ),
+            ("oldest", ring.oldest.load(Ordering::Relaxed) as f64),
+            ("newest", ring.newest.load(Ordering::Relaxed) as f64),
+            (
+                "last_dump_seconds",
+                ring.last_dump_ns.load(Ordering::Relaxed) as f64 / 1e9,
+            ),
+        ] {
+            ring_gauge().with_label_values(&[stat]).set(value);
+        }
+        for (stat, total) in [
+            ("pushes", &ring.pushes),
+            ("resets", &ring.resets),
+            ("resyncs", &ring.resyncs),
+            ("dumps", &ring.dumps),
+            ("coalesced", &ring.coalesced),
+            ("dropped_triggers", &ring.dropped_triggers),
+            ("failed_triggers", &ring.failed_triggers),
+            ("segments", &ring.segments),
+            ("expired_segments", &ring.expired_segments),
+            ("rolling_gaps", &ring.rolling_gaps),
+            ("missing_manifests", &ring.missing_manifests),
+        ] {
+            sync_counter(
+                &ring_counter().with_label_values(&[stat]),
+                total.load(Ordering::Relaxed),
+            );
+        }
+        for (stat, ns) in [
+            ("blocked", &ring.blocked_ns),
+            ("rolling_copy", &ring.rolling_copy_ns),
+            ("dumping", &ring.dump_ns),
+        ] {
+            sync_seconds(
+                &ring_seconds_counter().with_label_values(&[stat]),
+                ns.load(Ordering::Relaxed),
+            );
         }
 
-        // Metrics from the FPGA
-        match device.fpga.fft_overflow_cnt.read() {
-            Ok(v) => fft_ovlf_gauge().set(u32::from(v).into()),
-            Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+        // Baseband recording, if there is one
+        let baseband = baseband_stats();
+        for (stat, value) in [
+            ("recorded", &baseband.recorded),
+            ("dropped", &baseband.dropped),
+            ("files", &baseband.files),
+            ("expired", &baseband.expired),
+        ] {
+            baseband_gauge()
+                .with_label_values(&[stat])
+                .set(value.load(Ordering::Relaxed) as i64);
         }
 
-        match device.fpga.transport.lock().unwrap().temperature() {
-            Ok(v) => {
-                // If we get too hot, we really need to bail
-                if v >= TEMP_LIMIT_C {
-                    error!("SNAP temperature too hot - powering down");
-                    panic!();
-                }
-                fpga_temp().set(v.into())
-            },
-            Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+        // Timing health
+        timing_degraded_gauge().set(timing::degraded().into());
+        if let Some(offset) = *timing::ntp_offset().lock().unwrap() {
+            ntp_offset_gauge().set(offset);
+        }
+        if let Some(drift) = *timing::clock_drift().lock().unwrap() {
+            clock_drift_gauge().set(drift);
         }
 
-        // Take a snapshot of ADC values and compute RMS value
-        if device.fpga.adc_snap.arm().is_ok() && device.fpga.adc_snap.trigger().is_ok() {
-            match device.fpga.adc_snap.read() {
-                Ok(v) => {
-                    let mut rms_a = 0.0;
-                    let mut rms_b = 0.0;
-                    let mut n = 0;
-                    for chunk in v.chunks(4) {
-                        rms_a += f64::powi(f64::from(chunk[0] as i8), 2);
-                        rms_a += f64::powi(f64::from(chunk[1] as i8), 2);
-                        rms_b += f64::powi(f64::from(chunk[2] as i8), 2);
-                        rms_b += f64::powi(f64::from(chunk[3] as i8), 2);
-                        n += 2;
-                    }
-                    rms_a = ((1.0 / (n as f64)) * rms_a).sqrt();
-                    rms_b = ((1.0 / (n as f64)) * rms_b).sqrt();
-                    adc_rms_gauge().with_label_values(&["a"]).set(rms_a);
-                    adc_rms_gauge().with_label_values(&["b"]).set(rms_b);
-                }
-                Err(e) => warn!("SNAP Error - {e}, {:?}", e),
+        // Everything we learn from the FPGA itself, if we have one (and it isn't busy recalibrating)
+        if let Some(Ok(mut device)) = device.as_ref().map(|d| d.try_lock()) {
+            poll_device(
+                &mut device,
+                &mut clock_fit,
+                &mut clock_fault_reported,
+                &mut header_mismatch_reported,
+            );
+        }
+
+        // Complain about any stage that can't keep up
+        profiling::check_budgets();
+
+        // Health of the individual exfil sinks
+        sink_info_gauge().reset();
+        for (sink, snap) in exfil::stats::snapshot() {
+            for (stat, value) in [
+                ("bytes", snap.bytes as f64),
+                ("blocks", snap.blocks as f64),
+                ("stalls", snap.stalls as f64),
+                ("stalled", snap.stalled_ns as f64 / 1e9),
+                ("write_p99", snap.write_p99_ns as f64 / 1e9),
+            ] {
+                sink_gauge()
+                    .with_label_values(&[sink.as_str(), stat])
+                    .set(value);
+            }
+            sink_info_gauge()
+                .with_label_values(&[sink.as_str(), snap.target.as_deref().unwrap_or("")])
+                .set(1);
+        }
+
+        // Where triggers are coming from
+        for (source, snap) in triggers::snapshot() {
+            for (stat, value) in [
+                ("received", snap.received),
+                ("rejected", snap.rejected),
+                ("unauthenticated", snap.unauthenticated),
+                ("ignored", snap.ignored),
+                ("dispatched", snap.dispatched),
+                ("enabled", snap.enabled.into()),
+                ("failing", snap.error.is_some().into()),
+            ] {
+                trigger_source_gauge()
+                    .with_label_values(&[source.as_str(), stat])
+                    .set(value as i64);
+            }
+        }
+
+        // Cross power from the slow-path correlator
+        if let Some(xpower) = latest_cross_power().lock().unwrap().as_ref() {
+            for (i, (c, p)) in xpower.coherence().iter().zip(xpower.phase()).enumerate() {
+                let channel = i.to_string();
+                cross_power_gauge()
+                    .with_label_values(&[&channel, "coherence"])
+                    .set(*c);
+                cross_power_gauge()
+                    .with_label_values(&[&channel, "phase"])
+                    .set(p);
+            }
+        }
+        for nic in nic_health().lock().unwrap().iter() {
+            for (component, value) in [
+                ("up", f64::from(u8::from(nic.up))),
+                ("received", nic.received as f64),
+                ("used", nic.used as f64),
+            ] {
+                standby_nic_gauge()
+                    .with_label_values(&[&nic.name, component])
+                    .set(value);
+            }
+        }
+        // Sub-bands without a current estimate drop out, rather than holding their last one
+        tsys_gauge().reset();
+        for record in latest_tsys() {
+            if let Some(tsys) = record.tsys {
+                tsys_gauge()
+                    .with_label_values(&[
+                        &record.subband.to_string(),
+                        &format!("{:.3}", record.freq),
+                    ])
+                    .set(tsys);
+            }
+        }
+
+        // Roll everything up into the quality score
+        let scored = quality_inputs().lock().unwrap().score(TEMP_LIMIT_C.into());
+        quality_gauge().set(scored.score);
+        for (component, value) in [
+            ("drops", scored.drops),
+            ("rfi", scored.rfi),
+            ("adc", scored.adc),
+            ("temperature", scored.temperature),
+            ("backlog", scored.backlog),
+        ] {
+            if let Some(v) = value {
+                quality_component_gauge()
+                    .with_label_values(&[component])
+                    .set(v);
             }
         }
     }
     Ok(())
 }
 
-pub fn start_web_server(metrics_port: u16) -> eyre::Result<Server> {
+#[allow(clippy::too_many_arguments)]
+pub fn start_web_server(
+    metrics_port: u16,
+    db_path: PathBuf,
+    db_sender: SyncSender<DbEvent>,
+    device_sender: SyncSender<DeviceRequest>,
+    trigger_sender: tokio::sync::mpsc::Sender<CandidateEvent>,
+    ring_sender: Option<SyncSender<RingRequest>>,
+    exfil_control: Option<ExfilControl>,
+    trigger_secret: Option<Vec<u8>>,
+    manual_dumps: bool,
+) -> eyre::Result<Server> {
     info!("Starting metrics webserver");
+    let db_path = web::Data::new(db_path);
+    let db_sender = web::Data::new(db_sender);
+    let device_sender = web::Data::new(device_sender);
+    let trigger_sender = web::Data::new(trigger_sender);
+    let ring_sender = web::Data::new(ring_sender);
+    let exfil_control = web::Data::new(exfil_control);
+    let trigger_secret = web::Data::new(TriggerSecret(trigger_secret));
+    let manual_dumps = web::Data::new(ManualDumps(manual_dumps));
     // Create the server coroutine
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger::default()) // Tracing middleware
+            .app_data(db_path.clone())
+            .app_data(db_sender.clone())
+            .app_data(device_sender.clone())
+            .app_data(trigger_sender.clone())
+            .app_data(ring_sender.clone())
+            .app_data(exfil_control.clone())
+            .app_data(trigger_secret.clone())
+            .app_data(manual_dumps.clone())
             .service(metrics)
             .service(start_time)
+            .service(config)
+            .service(accounting_snapshot)
+            .service(quality)
+            .service(cross_power)
+            .service(spectrum)
+            .service(profile)
+            .service(exfil_sinks)
+            .service(blanked_channels)
+            .service(blank_channel)
+            .service(unblank_channel)
+            .service(force_pps)
+            .service(rearm)
+            .service(recalibrate)
+            .service(pause_exfil)
+            .service(resume_exfil)
+            .service(exfil_stokes)
+            .service(switch_exfil)
+            .service(stop_exfil)
+            .service(trigger)
+            .service(manual_dump)
+            .service(recent_dumps)
+            .service(trigger_sources)
+            .service(enable_trigger_source)
+            .service(disable_trigger_source)
+            .service(stokes_ring_window)
     })
     .bind(("0.0.0.0", metrics_port))?
     .workers(1)
@@ -0,0 +1,149 @@
+//! How times are written into filenames, headers, and the database. Set once at startup, so every output agrees on
+//! which time standard it's in, instead of some being in TAI and others UTC and cross-matches coming out 37 s apart.
+use clap::ValueEnum;
+use hifitime::{
+    efmt::{Format, Formatter},
+    Epoch,
+};
+use serde::Serialize;
+use std::{str::FromStr, sync::OnceLock};
+
+/// Time standard for everything we write out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeStandard {
+    /// Coordinated universal time
+    Utc,
+    /// International atomic time, which never jumps for leap seconds
+    #[default]
+    Tai,
+    /// The station's local time (only for wall-clock stamps, MJDs are in UTC)
+    Local,
+}
+
+/// Which standard and formats outputs use for their times
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TimePolicy {
+    pub standard: TimeStandard,
+    /// hifitime format of the timestamp in filenames
+    pub filename_format: String,
+}
+
+impl Default for TimePolicy {
+    fn default() -> Self {
+        Self {
+            standard: TimeStandard::Tai,
+            filename_format: "%Y%m%dT%H%M%S".to_owned(),
+        }
+    }
+}
+
+static TIME_POLICY: OnceLock<TimePolicy> = OnceLock::new();
+
+/// Set the time policy, once at startup before anything is written
+pub fn set_time_policy(policy: TimePolicy) {
+    let _ = TIME_POLICY.set(policy);
+}
+
+/// The time policy in use
+pub fn time_policy() -> &'static TimePolicy {
+    TIME_POLICY.get_or_init(TimePolicy::default)
+}
+
+/// Seconds the local wall clock is ahead of UTC at `epoch`
+fn local_offset_s(epoch: Epoch) -> f64 {
+    let t = epoch.to_unix_seconds().floor() as libc::time_t;
+    // Safety: localtime_r only writes into the tm we give it
+    unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        if libc::localtime_r(&t, &mut tm).is_null() {
+            return 0.0;
+        }
+        tm.tm_gmtoff as f64
+    }
+}
+
+impl TimePolicy {
+    pub fn new(standard: TimeStandard, filename_format: &str) -> eyre::Result<Self> {
+        // Catch a bad format now, rather than when we go to name the first file
+        Format::from_str(filename_format)
+            .map_err(|e| eyre::eyre!("Invalid filename time format - {e:?}"))?;
+        Ok(Self {
+            standard,
+            filename_format: filename_format.to_owned(),
+        })
+    }
+
+    /// Name of the standard the MJDs are in, to label headers and attributes with
+    pub fn mjd_standard(&self) -> &'static str {
+        match self.standard {
+            TimeStandard::Tai => "TAI",
+            TimeStandard::Utc | TimeStandard::Local => "UTC",
+        }
+    }
+
+    /// Days since the MJD epoch in our standard
+    pub fn mjd(&self, epoch: Epoch) -> f64 {
+        match self.standard {
+            TimeStandard::Tai => epoch.to_mjd_tai_days(),
+            TimeStandard::Utc | TimeStandard::Local => epoch.to_mjd_utc_days(),
+        }
+    }
+
+    /// The epoch at an MJD in our standard
+    pub fn epoch_at_mjd(&self, mjd: f64) -> Epoch {
+        match self.standard {
+            TimeStandard::Tai => Epoch::from_mjd_tai(mjd),
+            TimeStandard::Utc | TimeStandard::Local => Epoch::from_mjd_utc(mjd),
+        }
+    }
+
+    /// Format `epoch` as the wall clock in `standard` reads, with a hifitime format string
+    pub fn format_in(standard: TimeStandard, epoch: Epoch, fmt: &str) -> String {
+        // Formatting prints the UTC calendar, so shift the epoch by however far the clock we want is ahead of UTC
+        // (seconds since 1900 in TAI are ahead of those in UTC by the leap seconds)
+        let shifted = match standard {
+            TimeStandard::Utc => epoch.to_utc_seconds(),
+            TimeStandard::Tai => epoch.to_tai_seconds(),
+            TimeStandard::Local => epoch.to_utc_seconds() + local_offset_s(epoch),
+        };
+        let fmt = Format::from_str(fmt).unwrap();
+        format!("{}", Formatter::new(Epoch::from_utc_seconds(shifted), fmt))
+    }
+
+    /// Format `epoch` in our standard
+    pub fn stamp(&self, epoch: Epoch, fmt: &str) -> String {
+        Self::format_in(self.standard, epoch, fmt)
+    }
+
+    /// Timestamp to put in a filename
+    pub fn filename_stamp(&self, epoch: Epoch) -> String {
+        self.stamp(epoch, &self.filename_format)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_time_standards() {
+        let epoch = Epoch::from_gregorian_utc_hms(2024, 6, 1, 12, 0, 0);
+        let fmt = "%Y%m%dT%H%M%S";
+        assert_eq!(
+            TimePolicy::format_in(TimeStandard::Utc, epoch, fmt),
+            "20240601T120000"
+        );
+        assert_eq!(
+            TimePolicy::format_in(TimeStandard::Tai, epoch, fmt),
+            "20240601T120037"
+        );
+        let policy = TimePolicy::default();
+        assert!(
+            (policy.epoch_at_mjd(policy.mjd(epoch)) - epoch)
+                .to_seconds()
+                .abs()
+                < 1e-3
+        );
+    }
+}
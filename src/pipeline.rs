@@ -1,40 +1,251 @@
 use crate::{
-    args, capture,
-    common::{payload_start_time, Payload, CHANNELS},
-    db,
+    args, auth, baseband, calibration, capture,
+    common::{self, payload_start_time, Payload, CHANNELS, PACKET_CADENCE},
+    correlation, db, dedup, diagnostics,
     dumps::{self, DumpRing},
-    exfil,
+    exfil::{self, routes::RoutingTable, StokesConsumer},
+    failover,
     fpga::Device,
     injection::{self, Injections},
-    monitoring, processing,
+    latency::{self, LatencyPolicy},
+    monitoring,
+    naming::{self, TimePolicy},
+    placement::Placer,
+    postprocess::{self, DumpPolicy},
+    processing,
+    realtime::{self, Realtime},
+    timing::{self, SyncEpoch},
+    triggers::{self, ChannelSource, TriggerSource, UdpSource, ZmqSource},
+    tsys::{TsysConfig, TsysEstimator},
 };
 pub use clap::Parser;
 use core_affinity::CoreId;
 use eyre::bail;
-use rsntp::SntpClient;
 use std::{thread::JoinHandle, time::Duration};
-use thingbuf::mpsc::{blocking::channel, blocking::StaticChannel};
+use thingbuf::mpsc::blocking::channel;
 use tokio::{
     signal::unix::{signal, SignalKind},
     sync::broadcast,
     try_join,
 };
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-// Setup the static channels
-static CAPTURE_CHAN: StaticChannel<Payload, 32_768> = StaticChannel::new();
-static INJECT_CHAN: StaticChannel<Payload, 32_768> = StaticChannel::new();
-static DUMP_CHAN: StaticChannel<Payload, 32_768> = StaticChannel::new();
+/// Sizes of the fast-path channels, set at startup so memory-constrained hosts can tune them
+#[derive(Debug, Clone, Copy)]
+pub struct ChannelCapacities {
+    /// Capture to injection, and injection to downsampling (payloads)
+    pub payload: usize,
+    /// Downsampling to the voltage ringbuffer (payloads)
+    pub dump: usize,
+    /// Downsampling to exfil (spectra)
+    pub exfil: usize,
+}
+
+impl ChannelCapacities {
+    /// Check the capacities make sense for this configuration
+    pub fn validate(&self, downsample_factor: usize, vbuf_capacity: usize) -> eyre::Result<()> {
+        if self.payload < downsample_factor {
+            bail!(
+                "Payload channels ({}) must hold at least one downsampled spectrum's worth of payloads ({downsample_factor})",
+                self.payload
+            );
+        }
+        // The dump channel covers the stream while a window is copied out of the ring, which can't take longer than
+        // the ring itself covers
+        if self.dump > vbuf_capacity {
+            warn!(
+                "The dump channel ({}) holds more than the voltage buffer ({vbuf_capacity})",
+                self.dump
+            );
+        }
+        let payload_bytes = std::mem::size_of::<Payload>();
+        let total = (2 * self.payload + self.dump) * payload_bytes
+            + self.exfil * CHANNELS * std::mem::size_of::<f32>();
+        info!(
+            capacities = ?self,
+            dump_cover_ms = self.dump as f64 * PACKET_CADENCE * 1e3,
+            exfil_cover_ms = (self.exfil * downsample_factor) as f64 * PACKET_CADENCE * 1e3,
+            "Fast-path channels will take {:.1} MiB",
+            total as f64 / (1024.0 * 1024.0)
+        );
+        Ok(())
+    }
+
+    /// Bound the capacities as the latency policy says (a payload channel still holds a downsampled spectrum's worth)
+    pub fn bounded(self, policy: &LatencyPolicy, downsample_factor: usize) -> Self {
+        Self {
+            payload: policy.payloads(self.payload, downsample_factor),
+            dump: policy.payloads(self.dump, 1),
+            exfil: policy.spectra(self.exfil),
+        }
+    }
+
+    /// Longest a payload can wait in the fast-path channels on its way to exfil, if they're all full
+    pub fn worst_case_delay(&self, downsample_factor: usize) -> Duration {
+        LatencyPolicy::payload_time(2 * self.payload + self.exfil * downsample_factor)
+    }
+}
+
+/// Assembles the pipeline, letting library users swap in their own components
+pub struct PipelineBuilder {
+    cli: args::Cli,
+    consumer: Option<Box<dyn StokesConsumer>>,
+}
+
+impl PipelineBuilder {
+    pub fn new(cli: args::Cli) -> Self {
+        Self {
+            cli,
+            consumer: None,
+        }
+    }
+
+    /// Send the stokes stream to a custom consumer instead of the one selected on the command line
+    pub fn with_consumer(mut self, consumer: impl StokesConsumer + 'static) -> Self {
+        self.consumer = Some(Box::new(consumer));
+        self
+    }
+
+    /// Spawn all the tasks and return the handles
+    pub async fn start(self) -> eyre::Result<Vec<JoinHandle<eyre::Result<()>>>> {
+        run(self.cli, self.consumer).await
+    }
+}
 
-#[tracing::instrument(level = "debug")]
 pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre::Result<()>>>> {
+    PipelineBuilder::new(cli).start().await
+}
+
+#[tracing::instrument(level = "debug", skip(consumer))]
+async fn run(
+    cli: args::Cli,
+    consumer: Option<Box<dyn StokesConsumer>>,
+) -> eyre::Result<Vec<JoinHandle<eyre::Result<()>>>> {
+    let freq_plan = cli.frequency_plan();
+    // Work out where everything goes up front, so a bad routing table fails before we touch the hardware
+    let mut routes = match &cli.routes {
+        Some(_) if !cli.exfils().is_empty() => {
+            bail!("Use either a routing table or exfil subcommands, not both")
+        }
+        Some(path) => RoutingTable::load(path)?,
+        None => RoutingTable::from_exfils(cli.exfils(), cli.tee_policy, &cli.filterbank_path)?,
+    };
+    if cli.dedisperse_dm.is_some() {
+        routes.dedisperse_dm = cli.dedisperse_dm;
+        routes.validate()?;
+    }
+    let candidate_sinks = routes.candidate_sinks()?;
+    if cli.standby_nics.len() == 1 {
+        bail!("Hot-standby capture needs at least two interfaces");
+    }
+    // Set before anything that buffers is built
+    latency::set_latency_policy(cli.latency_policy());
+    let latency = latency::latency_policy();
+    let downsample_factor = 2usize.pow(cli.downsample_power);
+    let capacities = ChannelCapacities {
+        payload: cli.payload_capacity as usize,
+        dump: cli.dump_capacity as usize,
+        exfil: cli.exfil_capacity as usize,
+    }
+    .bounded(latency, downsample_factor);
+    capacities.validate(downsample_factor, cli.vbuf_capacity)?;
+    let dump_window = cli.dump_window();
+    let dump_samples = dump_window.pre.saturating_add(dump_window.post);
+    if dump_samples > cli.vbuf_capacity as u64 {
+        bail!(
+            "The dump window ({dump_samples} payloads) is longer than the voltage buffer ({})",
+            cli.vbuf_capacity
+        );
+    }
+    if latency.low_latency {
+        let worst = capacities.worst_case_delay(downsample_factor);
+        info!(
+            worst_case_ms = worst.as_secs_f64() * 1e3,
+            budget_ms = latency.budget.as_secs_f64() * 1e3,
+            "Running in low-latency mode"
+        );
+        if worst > latency.budget {
+            warn!("Full channels could hold a spectrum past the latency budget, even in low-latency mode");
+        }
+    }
+    for (what, stream) in [("exfil", cli.exfil_stream), ("dump", cli.dump_stream)] {
+        if usize::from(stream) >= cli.cap_port.len() {
+            bail!(
+                "The {what} stream ({stream}) isn't one of the {} capture ports",
+                cli.cap_port.len()
+            );
+        }
+    }
+    if !common::set_header_clock(cli.header_clock()) {
+        warn!("Payload header interpretation was already set, ignoring the configured one");
+    }
+    if !capture::set_packet_format(cli.packet_format) {
+        warn!("Packet format was already set, ignoring the configured one");
+    }
+    if !capture::set_poll_strategy(cli.poll_strategy()) {
+        warn!("Capture poll strategy was already set, ignoring the configured one");
+    }
+    capture::set_watermarking(cli.watermark);
+    if let Some(path) = &cli.dada_header {
+        if !exfil::dada::set_header_template(exfil::dada::load_header_template(path)?) {
+            warn!("DADA header template was already set, ignoring the configured one");
+        }
+    }
+    if let Some(period) = cli.cal_period {
+        let schedule = calibration::CalSchedule::from_seconds(period, cli.cal_on, cli.cal_phase)?;
+        if !calibration::set_cal_schedule(schedule) {
+            warn!("Noise source schedule was already set, ignoring the configured one");
+        }
+    }
+    // Get ready to collect diagnostics if things go wrong
+    diagnostics::configure(cli.diagnostics_path.clone(), format!("{cli:#?}"));
+    diagnostics::install_panic_hook();
+    // Every output writes its times the same way
+    naming::set_time_policy(TimePolicy::new(
+        cli.time_standard,
+        &cli.filename_time_format,
+    )?);
     // Connect to the SQLite database
-    let conn = db::connect_and_create(cli.db_path)?;
+    let conn = db::connect_and_create(cli.db_path.clone())?;
+    // Restore the channels we were blanking last time
+    let mut blanked = db::blanked_channels(&conn)?;
+    if !blanked.is_empty() {
+        info!("Restoring {} blanked channels", blanked.len());
+    }
+    // Along with the ones we're configured to always blank (which aren't persisted, so they follow the config)
+    let mut configured = match &cli.mask_channels {
+        Some(ranges) => processing::parse_channel_ranges(ranges)?,
+        None => vec![],
+    };
+    if let Some(path) = &cli.mask_file {
+        configured.extend(processing::load_mask_file(path)?);
+    }
+    if !configured.is_empty() {
+        info!("Blanking {} configured channels", configured.len());
+    }
+    blanked.extend(configured);
+    processing::channel_mask().set(blanked);
     // Create the dump ring (early in the program lifecycle to give it a chance to allocate)
     info!("Allocating RAM for the voltage ringbuffer!");
-    let ring = DumpRing::new(cli.vbuf_capacity);
+    let touch_threads = cli.vbuf_touch_threads as usize;
+    let ring = match &cli.vbuf_backing {
+        Some(path) => DumpRing::mapped(cli.vbuf_capacity, cli.vbuf_bits, path, touch_threads)?,
+        None if cli.vbuf_hugepages => {
+            DumpRing::hugepages(cli.vbuf_capacity, cli.vbuf_bits, touch_threads)?
+        }
+        None => DumpRing::heap(cli.vbuf_capacity, cli.vbuf_bits, touch_threads),
+    };
+    let ring = if cli.watermark {
+        ring.with_watermarks()
+    } else {
+        ring
+    };
+    // Locking now also faults in the whole ring, rather than on the hot path
+    if cli.mlock {
+        realtime::lock_memory();
+    }
     // Preload all the pulse injection data
-    let injections = Injections::new(cli.pulse_path);
+    let injections = Injections::new(cli.pulse_path.clone(), freq_plan);
     // Setup the exit handler
     let (sd_s, sd_cap_r) = broadcast::channel(1);
     let sd_mon_r = sd_s.subscribe();
@@ -43,7 +254,20 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
     let sd_downsamp_r = sd_s.subscribe();
     let sd_dump_r = sd_s.subscribe();
     let sd_exfil_r = sd_s.subscribe();
+    // Exfil consumers that can block on their output keep an eye out for shutdown themselves
+    let sd_exfil_s = sd_s.clone();
     let sd_trig_r = sd_s.subscribe();
+    let sd_ntp_r = sd_s.subscribe();
+    let sd_drift_r = sd_s.subscribe();
+    let sd_recal_r = sd_s.subscribe();
+    let sd_pp_r = sd_s.subscribe();
+    let sd_baseband_r = sd_s.subscribe();
+    let sd_xcorr_r = sd_s.subscribe();
+    let sd_watchdog_r = sd_s.subscribe();
+    // One for every capture stream beyond the first
+    let mut sd_aux_cap_r: Vec<_> = (1..cli.cap_port.len()).map(|_| sd_s.subscribe()).collect();
+    // And for every hot-standby interface
+    let mut sd_standby_r: Vec<_> = cli.standby_nics.iter().map(|_| sd_s.subscribe()).collect();
     tokio::spawn(async move {
         let mut term = signal(SignalKind::terminate()).unwrap();
         let mut quit = signal(SignalKind::quit()).unwrap();
@@ -56,26 +280,84 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
         info!("Shutting down!");
         sd_s.send(()).unwrap()
     });
-    // Setup NTP
+    // Setup PTP/NTP
+    let time_sources = cli.time_sources();
     let time_sync = if !cli.skip_ntp {
-        info!("Synchronizing time with NTP");
-        let client = SntpClient::new();
-        Some(client.synchronize(cli.ntp_addr).unwrap())
+        info!("Synchronizing time with {:?}", time_sources);
+        let sync = timing::synchronize(&time_sources, cli.ntp_retries);
+        if sync.is_none() {
+            error!(
+                "Every time source failed, falling back to the system clock - TIMING IS DEGRADED"
+            );
+        }
+        sync
     } else {
         info!("Skipping NTP time sync");
         None
     };
-    // Setup the FPGA
-    info!("Setting up SNAP");
-    let mut device = Device::new(cli.fpga_addr);
-    device.reset()?;
-    device.start_networking(&cli.mac)?;
-    let packet_start = if !cli.skip_ntp {
-        info!("Triggering the flow of packets via PPS");
-        device.trigger(&time_sync.unwrap())?
+    timing::set_degraded(time_sync.is_none());
+    // Setup the FPGA, unless something else is standing in for it
+    let replaying = cli.replay.is_some() || cli.simulate.is_some();
+    let mut device = if replaying {
+        None
+    } else {
+        info!("Setting up SNAP");
+        Some(Device::new(cli.fpga_addr))
+    };
+    let packet_start = if replaying {
+        // Payload counts in the recording are taken to be from now, there's no FPGA to ask when it was armed
+        match (&cli.replay, cli.simulate) {
+            (Some(path), _) => {
+                info!(path = %path.display(), "Replaying a recording, leaving the FPGA alone")
+            }
+            (None, overspeed) => info!(?overspeed, "Simulating the FPGA, leaving it alone"),
+        }
+        match &time_sync {
+            Some(sync) => sync.now()?,
+            None => hifitime::Epoch::now()?,
+        }
+    } else if let (Some(device), true) = (device.as_mut(), cli.warm_restart) {
+        // Packets are still flowing from before, so leave the FPGA alone and pick the epoch back up.
+        // FIRST_PACKET needs no saving, capture sets it from the first packet we see after reattaching.
+        info!("Warm restart, reattaching to the running packet stream");
+        let now = match &time_sync {
+            Some(sync) => sync.now()?,
+            None => hifitime::Epoch::now()?,
+        };
+        let pps = device.pps_count()?;
+        let epoch = match SyncEpoch::load(&cli.epoch_path) {
+            Ok(epoch) => {
+                epoch.check(pps, now)?;
+                epoch
+            }
+            Err(e) => {
+                warn!(
+                    "Couldn't load the saved sync epoch ({e}), re-deriving it from the PPS count - timing is only good to the second"
+                );
+                SyncEpoch::from_pps(pps, now, time_sync.is_none())
+            }
+        };
+        timing::set_degraded(time_sync.is_none() || epoch.degraded);
+        epoch.start()
     } else {
-        info!("Blindly triggering (no GPS), timing will be off");
-        device.blind_trigger()?
+        let device = device.as_mut().unwrap();
+        device.reset()?;
+        device.start_networking(&cli.mac)?;
+        let packet_start = match &time_sync {
+            Some(sync) => {
+                info!("Triggering the flow of packets via PPS");
+                device.trigger(sync)?
+            }
+            None => {
+                info!("Blindly triggering (no GPS), timing will be off");
+                device.blind_trigger()?
+            }
+        };
+        // Saved so we can come back after a crash without re-arming
+        if let Err(e) = SyncEpoch::new(packet_start, time_sync.is_none()).save(&cli.epoch_path) {
+            warn!("Couldn't save the sync epoch, a warm restart won't be possible - {e}");
+        }
+        packet_start
     };
     // Move this packet_start time into the global variable that everyone can use
     {
@@ -88,36 +370,109 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
         let mut ps = payload_start_time().lock().unwrap();
         *ps = Some(packet_start);
     }
-    if cli.trig {
-        device.force_pps()?;
+    if let (Some(device), false) = (device.as_mut(), cli.warm_restart) {
+        if cli.trig {
+            device.force_pps()?;
+        }
+        // Set the requantization gains
+        let gain = [cli.requant_gain; CHANNELS];
+        device.set_requant_gains(&gain, &gain)?;
     }
-    // Set the requantization gains
-    let gain = [cli.requant_gain; CHANNELS];
-    device.set_requant_gains(&gain, &gain)?;
 
-    // These may not need to be static
-    let (cap_s, cap_r) = CAPTURE_CHAN.split();
-    let (dump_s, dump_r) = DUMP_CHAN.split();
-    let (inject_s, inject_r) = INJECT_CHAN.split();
     // Fast path channels
-    let (ex_s, ex_r) = channel(1024);
+    let (cap_s, cap_r) = channel(capacities.payload);
+    let (dump_s, dump_r) = channel(capacities.dump);
+    let (inject_s, inject_r) = channel(capacities.payload);
+    let (ex_s, ex_r) = channel(capacities.exfil);
+    // With any polarization products other than Stokes I, spectra go out on this one instead
+    let products = cli.products();
+    let (full_s, full_r) = (products != processing::Products::StokesI)
+        .then(|| channel(capacities.exfil))
+        .unzip();
+    // Data quality flags follow the stokes stream, run-length encoded
+    let (flag_s, flag_r) = std::sync::mpsc::sync_channel(1024);
 
     // Less important channels, these don't have to be static (and we don't need thingbuf)
     let (trig_s, trig_r) = std::sync::mpsc::sync_channel(5);
+    // Without T2 upstream, we have to turn bursts of raw candidates into single triggers ourselves
+    let clusterer = cli.cluster_window.map(|window| {
+        dedup::Clusterer::new(
+            window,
+            cli.cluster_dm_tolerance,
+            Duration::from_secs_f64(cli.cluster_hold),
+        )
+    });
+    // Shared secret triggers must be signed with (or carry, over HTTP), if we're authenticating them
+    let trigger_secret = match &cli.trigger_secret_file {
+        Some(path) => Some(auth::read_secret(path)?),
+        None => None,
+    };
+    dumps::status::set_callback_hosts(cli.callback_hosts.clone());
+    // Windows to write out of the stokes ring, if we're keeping one
+    let (ring_s, ring_r) = match cli.stokes_ring_minutes {
+        Some(_) => {
+            let (s, r) = std::sync::mpsc::sync_channel(16);
+            (Some(s), Some(r))
+        }
+        None => (None, None),
+    };
+    let (http_trigger_source, http_trig_s) =
+        ChannelSource::new("http", 16, cli.trigger_priority("http"));
+    let (internal_trigger_source, internal_trig_s) =
+        ChannelSource::new("internal", 16, cli.trigger_priority("internal"));
+    triggers::set_internal(internal_trig_s);
+    let mut trigger_sources: Vec<Box<dyn TriggerSource>> = vec![
+        Box::new(UdpSource::new(
+            cli.trig_port,
+            clusterer,
+            trigger_secret.clone(),
+            cli.trigger_priority("udp"),
+        )),
+        Box::new(http_trigger_source),
+        Box::new(internal_trigger_source),
+    ];
+    if let Some(endpoint) = &cli.trig_zmq {
+        trigger_sources.push(Box::new(ZmqSource::new(
+            endpoint,
+            cli.trigger_priority("zmq"),
+        )));
+    }
     let (stat_s, stat_r) = std::sync::mpsc::sync_channel(100);
-    let (ir_s, ir_r) = std::sync::mpsc::sync_channel(5);
+    let (aux_cap_s, aux_stat_s) = (cap_s.clone(), stat_s.clone());
+    let (db_s, db_r) = std::sync::mpsc::sync_channel(5);
+    let inject_db_s = db_s.clone();
+    let recal_db_s = db_s.clone();
+    let dump_db_s = db_s.clone();
+    let (xcorr_s, xcorr_r) = std::sync::mpsc::sync_channel(16);
+    let (dev_s, dev_r) = std::sync::mpsc::sync_channel(4);
+    let recal_dev_s = dev_s.clone();
+    // Monitoring needs a time source to re-arm the FPGA on request
+    let mon_time = if cli.skip_ntp {
+        timing::TimeSources::default()
+    } else {
+        time_sources.clone()
+    };
 
-    // Get the CPU core range
-    let mut cpus = cli.core_range;
+    // Hand out the CPU core range
+    let mut placer = Placer::new(cli.placement, cli.core_range.clone());
+    let realtime = Realtime {
+        policy: cli.rt_policy,
+        priority: cli.rt_priority,
+    };
     // Start the threads
     macro_rules! thread_spawn {
             ($(($thread_name:literal, $fcall:expr)), +) => {
-                  vec![$({let cpu = cpus.next().unwrap();
+                  vec![$({let cpu = placer.assign($thread_name)?;
                     std::thread::Builder::new()
                         .name($thread_name.to_string())
                         .spawn( move || {
-                            if !core_affinity::set_for_current(CoreId { id: cpu}) {
-                                bail!("Couldn't set core affinity on thread {}", $thread_name);
+                            if let Some(cpu) = cpu {
+                                if !core_affinity::set_for_current(CoreId { id: cpu}) {
+                                    bail!("Couldn't set core affinity on thread {}", $thread_name);
+                                }
+                            }
+                            if realtime::REALTIME_THREADS.contains(&$thread_name) {
+                                realtime.apply_to_current($thread_name);
                             }
                             $fcall
                         })
@@ -127,6 +482,162 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
 
     let mut handles = vec![];
 
+    // Dump post-processing runs at low priority on whatever core the OS likes
+    let dump_policy = DumpPolicy {
+        compression: cli.dump_compression,
+        channels: cli.dump_channels.clone(),
+    };
+    let dump_policy = if !dump_policy.is_noop() && !cli.dump_format.rewritable() {
+        warn!("Only netcdf dumps can be post-processed, leaving them as they're written");
+        DumpPolicy::default()
+    } else {
+        dump_policy
+    };
+    // Dumps left as they're written get their checksum manifest from the dump writer instead
+    let pp_s = if dump_policy.is_noop() {
+        None
+    } else {
+        let (pp_s, pp_r) = std::sync::mpsc::sync_channel(16);
+        handles.push(
+            std::thread::Builder::new()
+                .name("postprocess".to_string())
+                .spawn(move || postprocess::postprocess_task(dump_policy, pp_r, sd_pp_r))?,
+        );
+        Some(pp_s)
+    };
+
+    // And baseband recording, fed from capture
+    if let Some(config) = cli.baseband_config() {
+        let (raw_s, raw_r) = channel(cli.raw_queue as usize);
+        baseband::set_tee(raw_s);
+        handles.push(
+            std::thread::Builder::new()
+                .name("baseband".to_string())
+                .spawn(move || baseband::baseband_task(config, raw_r, sd_baseband_r))?,
+        );
+    }
+
+    // As does the polarization cross-correlation, which also estimates Tsys when we know the noise source
+    let tsys = calibration::cal_schedule()
+        .zip(cli.tcal)
+        .map(|(schedule, tcal)| {
+            let config = TsysConfig::from_seconds(tcal, cli.tsys_subbands, cli.tsys_interval);
+            TsysEstimator::new(config, schedule, freq_plan)
+        });
+    let tsys_db_s = db_s.clone();
+    handles.push(
+        std::thread::Builder::new()
+            .name("correlation".to_string())
+            .spawn(move || correlation::correlation_task(xcorr_r, tsys, tsys_db_s, sd_xcorr_r))?,
+    );
+
+    // The relay taps the exfil stream
+    let relay = cli
+        .relay_addr
+        .clone()
+        .map(|addr| {
+            exfil::relay::Relay::new(
+                addr,
+                cli.relay_channels,
+                cli.relay_integration,
+                2usize.pow(cli.downsample_power),
+                freq_plan.reordered(exfil::STOKES_ORDER),
+            )
+        })
+        .transpose()?;
+    // And so might observers
+    let tap = cli
+        .tap_addr
+        .map(|addr| {
+            exfil::tap::Tap::new(
+                addr,
+                2usize.pow(cli.downsample_power),
+                freq_plan.reordered(exfil::STOKES_ORDER),
+            )
+        })
+        .transpose()?;
+
+    // And look for bright pulses in it ourselves
+    let detector = cli.detect_threshold.map(|threshold| {
+        exfil::detect::PulseDetector::new(
+            threshold,
+            cli.detect_holdoff,
+            2usize.pow(cli.downsample_power),
+        )
+    });
+
+    // And we might keep the last few minutes of it around
+    let stokes_ring = cli
+        .stokes_ring_minutes
+        .zip(ring_r)
+        .map(|(minutes, requests)| {
+            exfil::ring::StokesRing::new(
+                minutes,
+                cli.stokes_ring_decimation as usize,
+                2usize.pow(cli.downsample_power),
+                freq_plan.reordered(exfil::STOKES_ORDER),
+                &cli.dump_path,
+                cli.stokes_ring_before,
+                cli.stokes_ring_after,
+                cli.trigger_offset,
+                requests,
+            )
+        });
+
+    let exfil_sources = cli.allowed_source.clone();
+
+    // Hot-standby interfaces each capture the exfil stream on their own, for the merge to put back together
+    let mut standby_legs = vec![];
+    for nic in &cli.standby_nics {
+        let (leg_s, leg_r) = channel(capacities.payload);
+        let (leg_stat_s, leg_stat_r) = std::sync::mpsc::sync_channel(100);
+        standby_legs.push(failover::Leg::new(nic.clone(), leg_r, leg_stat_r));
+        let name = format!("capture_{nic}");
+        let cpu = placer.assign(&name)?;
+        let nic = capture::Nic {
+            name: nic.clone(),
+            group: cli.multicast_group,
+        };
+        let port = cli.cap_port[usize::from(cli.exfil_stream)];
+        let (stream, sources) = (cli.exfil_stream, exfil_sources.clone());
+        let (max_gap, recv_batch, reorder_window) = (
+            cli.max_gap,
+            cli.recv_batch as usize,
+            cli.reorder_window as usize,
+        );
+        let shutdown = sd_standby_r.pop().unwrap();
+        handles.push(
+            std::thread::Builder::new()
+                .name(name.clone())
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        if !core_affinity::set_for_current(CoreId { id: cpu }) {
+                            bail!("Couldn't set core affinity on thread {name}");
+                        }
+                    }
+                    realtime.apply_to_current(&name);
+                    capture::cap_task(
+                        Some(nic),
+                        port,
+                        stream,
+                        false,
+                        sources,
+                        max_gap,
+                        recv_batch,
+                        reorder_window,
+                        leg_s,
+                        leg_stat_s,
+                        shutdown,
+                    )
+                })?,
+        );
+    }
+
+    let streams = processing::StreamRoutes {
+        exfil: cli.exfil_stream,
+        dump: cli.dump_stream,
+    };
+
     // We spawn and connect threads a little differently depending on if we're doing pulse injection or not
     match injections {
         Ok(injections) => {
@@ -136,9 +647,10 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
                     injection::pulse_injection_task(
                         cap_r,
                         inject_s,
-                        ir_s,
+                        inject_db_s,
                         Duration::from_secs(cli.injection_cadence),
                         injections,
+                        cli.exfil_stream,
                         sd_inject_r
                     )
                 ),
@@ -147,8 +659,15 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
                     processing::downsample_task(
                         inject_r,
                         ex_s,
+                        full_s,
+                        products,
+                        flag_s,
                         dump_s,
+                        xcorr_s,
+                        streams,
                         cli.downsample_power,
+                        freq_plan,
+                        cli.blank_fill,
                         sd_downsamp_r
                     )
                 )
@@ -162,8 +681,15 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
                 processing::downsample_task(
                     cap_r,
                     ex_s,
+                    full_s,
+                    products,
+                    flag_s,
                     dump_s,
+                    xcorr_s,
+                    streams,
                     cli.downsample_power,
+                    freq_plan,
+                    cli.blank_fill,
                     sd_downsamp_r
                 )
             ));
@@ -171,58 +697,236 @@ pub async fn start_pipeline(cli: args::Cli) -> eyre::Result<Vec<JoinHandle<eyre:
         }
     }
 
+    // Exfil sinks can be switched at runtime, unless we were handed a consumer
+    let (supervisor, exfil_control) = match consumer {
+        Some(_) => (None, None),
+        None => {
+            let (supervisor, control) = exfil::supervisor::Supervisor::new(
+                routes.clone(),
+                2usize.pow(cli.downsample_power),
+                freq_plan,
+                cli.relay_channels,
+                cli.relay_integration,
+                sd_exfil_s,
+            );
+            (Some(supervisor), Some(control))
+        }
+    };
+
+    let exfil_port = cli.cap_port[usize::from(cli.exfil_stream)];
+    let dump_path = routes.dump_path(&cli.dump_path);
+    let rolling = cli.rolling_config();
     // Spawn the rest of the threads
     let mut these_handles = thread_spawn!(
         (
             "collect",
-            monitoring::monitor_task(device, stat_r, sd_mon_r)
+            monitoring::monitor_task(
+                device,
+                stat_r,
+                dev_r,
+                mon_time,
+                timing::ClockFit::new(cli.fpga_clock_rate * 1e6, cli.clock_rate_tolerance),
+                cli.requant_gain,
+                sd_mon_r
+            )
+        ),
+        (
+            "db",
+            monitoring::db_task(conn, db_r, cli.summary_path, sd_db_r)
         ),
-        ("db", monitoring::db_task(conn, ir_r, sd_db_r)),
         (
             "dump",
             dumps::dump_task(
                 ring,
                 dump_r,
                 trig_r,
-                cli.dump_path,
+                dump_path,
                 cli.downsample_power,
+                cli.trigger_offset,
+                dump_window,
+                cli.dump_format.format(),
+                cli.dump_compression,
+                freq_plan,
+                dump_db_s,
+                pp_s,
+                rolling,
                 sd_dump_r
             )
         ),
         (
             "exfil",
-            match cli.exfil {
-                Some(e) => match e {
-                    args::Exfil::Psrdada { key, samples } => exfil::dada::consumer(
-                        key,
-                        ex_r,
-                        2usize.pow(cli.downsample_power),
-                        samples,
-                        sd_exfil_r
-                    ),
-                    args::Exfil::Filterbank => exfil::filterbank::consumer(
-                        ex_r,
-                        2usize.pow(cli.downsample_power),
-                        &cli.filterbank_path,
-                        sd_exfil_r
-                    ),
-                },
-                None => exfil::dummy::consumer(ex_r, sd_exfil_r),
+            match consumer {
+                Some(c) => Ok((c, vec![])),
+                None => supervisor
+                    .as_ref()
+                    .expect("Supervised when there's no consumer")
+                    .build(),
             }
+            .and_then(|(c, relays)| {
+                exfil::consumer_task(
+                    c,
+                    relay.into_iter().chain(relays).collect(),
+                    tap,
+                    detector,
+                    stokes_ring,
+                    match full_r {
+                        Some(full_r) => exfil::StokesSource::Full(full_r),
+                        None => exfil::StokesSource::I(ex_r),
+                    },
+                    flag_r,
+                    2usize.pow(cli.downsample_power),
+                    supervisor,
+                    sd_exfil_r,
+                )
+            })
         ),
         (
             "capture",
-            capture::cap_task(cli.cap_port, cap_s, stat_s, sd_cap_r)
+            match (&cli.replay, cli.simulate) {
+                (Some(path), _) => capture::replay_task(
+                    path,
+                    exfil_port,
+                    cli.exfil_stream,
+                    cli.replay_rate,
+                    cli.max_gap,
+                    cli.recv_batch as usize,
+                    cli.reorder_window as usize,
+                    cap_s,
+                    stat_s,
+                    sd_cap_r
+                ),
+                (None, Some(overspeed)) => capture::simulate_task(
+                    overspeed,
+                    cli.exfil_stream,
+                    cli.max_gap,
+                    cli.recv_batch as usize,
+                    cli.reorder_window as usize,
+                    cap_s,
+                    stat_s,
+                    sd_cap_r
+                ),
+                (None, None) if !standby_legs.is_empty() => failover::merge_task(
+                    standby_legs,
+                    cli.exfil_stream,
+                    Duration::from_millis(cli.failover_timeout),
+                    cap_s,
+                    stat_s,
+                    sd_cap_r
+                ),
+                (None, None) => capture::cap_task(
+                    None,
+                    exfil_port,
+                    cli.exfil_stream,
+                    true,
+                    exfil_sources,
+                    cli.max_gap,
+                    cli.recv_batch as usize,
+                    cli.reorder_window as usize,
+                    cap_s,
+                    stat_s,
+                    sd_cap_r
+                ),
+            }
         )
     );
 
     handles.append(&mut these_handles);
 
+    // Every other board gets a capture thread of its own, feeding the same channel
+    for (stream, port) in cli.cap_port.iter().copied().enumerate() {
+        let stream = stream as u16;
+        if stream == cli.exfil_stream {
+            continue;
+        }
+        // A replay stands in for the exfil stream alone
+        if replaying {
+            warn!(stream, "Not capturing this stream while replaying");
+            continue;
+        }
+        let name = format!("capture_{stream}");
+        let cpu = placer.assign(&name)?;
+        let (cap_s, stat_s) = (aux_cap_s.clone(), aux_stat_s.clone());
+        let sources = cli.allowed_source.clone();
+        let (max_gap, recv_batch, reorder_window) = (
+            cli.max_gap,
+            cli.recv_batch as usize,
+            cli.reorder_window as usize,
+        );
+        let shutdown = sd_aux_cap_r.pop().unwrap();
+        handles.push(
+            std::thread::Builder::new()
+                .name(name.clone())
+                .spawn(move || {
+                    if let Some(cpu) = cpu {
+                        if !core_affinity::set_for_current(CoreId { id: cpu }) {
+                            bail!("Couldn't set core affinity on thread {name}");
+                        }
+                    }
+                    realtime.apply_to_current(&name);
+                    capture::cap_task(
+                        None,
+                        port,
+                        stream,
+                        false,
+                        sources,
+                        max_gap,
+                        recv_batch,
+                        reorder_window,
+                        cap_s,
+                        stat_s,
+                        shutdown,
+                    )
+                })?,
+        );
+    }
+    drop((aux_cap_s, aux_stat_s));
+
     let _ = try_join!(
         // Start the webserver
-        tokio::spawn(monitoring::start_web_server(cli.metrics_port,)?),
-        // Start the trigger watch
-        tokio::spawn(dumps::trigger_task(trig_s, cli.trig_port, sd_trig_r))
+        tokio::spawn(monitoring::start_web_server(
+            cli.metrics_port,
+            cli.db_path,
+            db_s,
+            dev_s,
+            http_trig_s,
+            ring_s.clone(),
+            exfil_control,
+            trigger_secret.clone(),
+            cli.manual_dumps,
+        )?),
+        // Start the trigger sources
+        tokio::spawn(triggers::trigger_task(
+            trigger_sources,
+            trig_s,
+            ring_s,
+            candidate_sinks,
+            sd_trig_r
+        )),
+        // Keep an eye on NTP
+        tokio::spawn(timing::ntp_recheck_task(
+            time_sources.clone(),
+            Duration::from_secs(cli.ntp_recheck_interval),
+            sd_ntp_r
+        )),
+        // Watch for the packet clock drifting
+        tokio::spawn(timing::drift_check_task(
+            time_sources,
+            Duration::from_secs(cli.drift_check_interval),
+            Duration::from_millis(cli.drift_threshold),
+            sd_drift_r
+        )),
+        // Recalibrate on schedule
+        tokio::spawn(calibration::recal_task(
+            cli.recal_at,
+            recal_dev_s,
+            recal_db_s,
+            sd_recal_r
+        )),
+        // Collect diagnostics if the pipeline stalls
+        tokio::spawn(diagnostics::watchdog_task(
+            Duration::from_secs(cli.watchdog_timeout),
+            sd_watchdog_r
+        ))
     )?;
 
     Ok(handles)
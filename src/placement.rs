@@ -0,0 +1,140 @@
+//! Which cores the pinned pipeline threads run on, including on machines with fewer cores than threads
+use crate::realtime::REALTIME_THREADS;
+use clap::ValueEnum;
+use eyre::bail;
+use serde::Serialize;
+use std::{
+    ops::RangeInclusive,
+    sync::{Mutex, OnceLock},
+};
+use tracing::{info, warn};
+
+/// How to place the pinned threads on the configured cores
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlacementPolicy {
+    /// Every thread gets a core to itself, failing if there aren't enough
+    #[default]
+    Exclusive,
+    /// Capture and downsample get cores to themselves, everything else shares what's left
+    Shared,
+    /// Nothing is pinned, the OS puts threads wherever it likes
+    Unpinned,
+}
+
+/// Where a thread ended up
+#[derive(Debug, Clone, Serialize)]
+pub struct ThreadPlacement {
+    pub thread: String,
+    /// None if the thread isn't pinned
+    pub core: Option<usize>,
+    /// Whether the thread has its core to itself
+    pub exclusive: bool,
+}
+
+/// Hands out cores to threads as they're spawned, according to a policy
+#[derive(Debug)]
+pub struct Placer {
+    policy: PlacementPolicy,
+    cores: Vec<usize>,
+    /// Cores handed out so far to threads that get their own
+    next_exclusive: usize,
+    /// Threads placed on the shared cores so far
+    next_shared: usize,
+}
+
+impl Placer {
+    pub fn new(policy: PlacementPolicy, cores: RangeInclusive<usize>) -> Self {
+        Self {
+            policy,
+            cores: cores.collect(),
+            next_exclusive: 0,
+            next_shared: 0,
+        }
+    }
+
+    /// The core (if any) to pin `thread` to
+    pub fn assign(&mut self, thread: &str) -> eyre::Result<Option<usize>> {
+        let placement = match self.policy {
+            PlacementPolicy::Exclusive => {
+                let Some(core) = self.cores.get(self.next_exclusive).copied() else {
+                    bail!(
+                        "Ran out of cores placing thread {thread}, widen the core range or use a shared placement policy"
+                    );
+                };
+                self.next_exclusive += 1;
+                ThreadPlacement {
+                    thread: thread.to_owned(),
+                    core: Some(core),
+                    exclusive: true,
+                }
+            }
+            PlacementPolicy::Shared => {
+                // The hot-path threads get the front of the range, everything else takes turns on the rest
+                let reserved = REALTIME_THREADS.len().min(self.cores.len());
+                if REALTIME_THREADS.contains(&thread) {
+                    let core = self.cores.get(self.next_exclusive).copied();
+                    self.next_exclusive += 1;
+                    if core.is_none() {
+                        warn!(
+                            thread,
+                            "Not enough cores to pin every hot-path thread, leaving it unpinned"
+                        );
+                    }
+                    ThreadPlacement {
+                        thread: thread.to_owned(),
+                        core,
+                        exclusive: core.is_some(),
+                    }
+                } else {
+                    let shared = &self.cores[reserved..];
+                    let core =
+                        (!shared.is_empty()).then(|| shared[self.next_shared % shared.len()]);
+                    self.next_shared += 1;
+                    ThreadPlacement {
+                        thread: thread.to_owned(),
+                        core,
+                        exclusive: false,
+                    }
+                }
+            }
+            PlacementPolicy::Unpinned => ThreadPlacement {
+                thread: thread.to_owned(),
+                core: None,
+                exclusive: false,
+            },
+        };
+        info!(thread, core = ?placement.core, "Placed thread");
+        let core = placement.core;
+        placements().lock().unwrap().push(placement);
+        Ok(core)
+    }
+}
+
+fn placements() -> &'static Mutex<Vec<ThreadPlacement>> {
+    static PLACEMENTS: OnceLock<Mutex<Vec<ThreadPlacement>>> = OnceLock::new();
+    PLACEMENTS.get_or_init(|| Mutex::new(vec![]))
+}
+
+/// Every thread placed so far
+pub fn snapshot() -> Vec<ThreadPlacement> {
+    placements().lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shared_placement() {
+        let mut placer = Placer::new(PlacementPolicy::Shared, 0..=3);
+        assert_eq!(placer.assign("downsample").unwrap(), Some(0));
+        assert_eq!(placer.assign("collect").unwrap(), Some(2));
+        assert_eq!(placer.assign("db").unwrap(), Some(3));
+        assert_eq!(placer.assign("dump").unwrap(), Some(2));
+        assert_eq!(placer.assign("capture").unwrap(), Some(1));
+        let mut placer = Placer::new(PlacementPolicy::Exclusive, 0..=0);
+        assert_eq!(placer.assign("capture").unwrap(), Some(0));
+        assert!(placer.assign("downsample").is_err());
+    }
+}
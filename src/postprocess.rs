@@ -0,0 +1,295 @@
+//! Low-priority post-processing of voltage dumps after they've been written.
+//! Our NetCDF-4 dumps are already valid HDF5, so HDF5 tooling can read them without any conversion.
+use crate::auth;
+use crate::common::BLOCK_TIMEOUT;
+use crate::dumps::{format::VoltageChecksum, ring_stats};
+use netcdf::types::NcVariableType;
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::Ordering,
+        mpsc::{Receiver, RecvTimeoutError},
+    },
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// Number of time samples we'll rewrite at a time, matching the chunking of the dump
+const REWRITE_CHUNK: usize = 2048;
+/// Bytes of a dump read at a time while checksumming it
+const MANIFEST_READ: usize = 1 << 20;
+
+/// What to do to each dump after it's written
+#[derive(Debug, Clone, Default)]
+pub struct DumpPolicy {
+    /// Deflate level the voltages were written with, kept when they're rewritten
+    pub compression: Option<i32>,
+    /// Only keep this range of channels
+    pub channels: Option<Range<usize>>,
+}
+
+impl DumpPolicy {
+    /// Whether this policy would leave dumps untouched (compression is applied as they're written)
+    pub fn is_noop(&self) -> bool {
+        self.channels.is_none()
+    }
+}
+
+/// Drop the priority of the calling thread as far as it will go, so we never compete with capture
+pub(crate) fn lower_priority() {
+    // Safety: setpriority has no memory safety implications, and on linux PRIO_PROCESS with 0 is the calling thread
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        warn!("Couldn't lower the priority of a slow-path thread");
+    }
+}
+
+/// Rewrite the dump at `path` according to the policy, replacing the original
+fn rewrite(policy: &DumpPolicy, path: &Path) -> eyre::Result<()> {
+    let tmp_path = path.with_extension("nc.tmp");
+    let rewritten =
+        rewrite_to(policy, path, &tmp_path).and_then(|_| Ok(std::fs::rename(&tmp_path, path)?));
+    // Don't leave a half-written copy behind, the original is still good
+    if rewritten.is_err() && tmp_path.exists() {
+        if let Err(e) = std::fs::remove_file(&tmp_path) {
+            warn!(?tmp_path, "Couldn't remove a partial rewrite - {e}");
+        }
+    }
+    rewritten
+}
+
+/// Write the rewritten copy of the dump at `path` to `tmp_path`
+fn rewrite_to(policy: &DumpPolicy, path: &Path, tmp_path: &Path) -> eyre::Result<()> {
+    let src = netcdf::open(path)?;
+    let mut dst = netcdf::create(tmp_path)?;
+
+    let ntime = src
+        .dimension("time")
+        .ok_or_else(|| eyre::eyre!("Dump is missing the time dimension"))?
+        .len();
+    let nfreq = src
+        .dimension("freq")
+        .ok_or_else(|| eyre::eyre!("Dump is missing the freq dimension"))?
+        .len();
+    let chans = policy.channels.clone().unwrap_or(0..nfreq);
+    if chans.end > nfreq || chans.is_empty() {
+        eyre::bail!("Channel range {chans:?} doesn't fit in the {nfreq} channels of the dump");
+    }
+
+    // Global attributes
+    for attr in src.attributes() {
+        dst.add_attribute(attr.name(), attr.value()?)?;
+    }
+
+    dst.add_dimension("time", ntime)?;
+    dst.add_dimension("pol", 2)?;
+    dst.add_dimension("freq", chans.len())?;
+    dst.add_dimension("reim", 2)?;
+
+    // Coordinates, carrying over their attributes
+    macro_rules! copy_attrs {
+        ($from:expr, $to:expr) => {
+            for attr in $from.attributes() {
+                $to.put_attribute(attr.name(), attr.value()?)?;
+            }
+        };
+    }
+
+    let src_time = src.variable("time").unwrap();
+    let mut time = dst.add_variable::<f64>("time", &["time"])?;
+    copy_attrs!(src_time, time);
+    time.put(.., src_time.get::<f64, _>(..)?.view())?;
+
+    let src_freq = src.variable("freq").unwrap();
+    let mut freq = dst.add_variable::<f64>("freq", &["freq"])?;
+    copy_attrs!(src_freq, freq);
+    freq.put(.., src_freq.get::<f64, _>(chans.clone())?.view())?;
+
+    for (name, labels) in [("pol", ["a", "b"]), ("reim", ["real", "imaginary"])] {
+        let src_var = src.variable(name).unwrap();
+        let mut var = dst.add_variable_with_type(name, &[name], &NcVariableType::String)?;
+        copy_attrs!(src_var, var);
+        for (i, label) in labels.iter().enumerate() {
+            var.put_string(label, i)?;
+        }
+    }
+
+    // Watermarked dumps carry each sample's receive latency
+    if let Some(src_recv) = src.variable("recv_latency") {
+        let mut recv = dst.add_variable::<i32>("recv_latency", &["time"])?;
+        copy_attrs!(src_recv, recv);
+        recv.put(.., src_recv.get::<i32, _>(..)?.view())?;
+    }
+
+    let src_volts = src.variable("voltages").unwrap();
+    let mut volts = dst.add_variable::<i8>("voltages", &["time", "pol", "freq", "reim"])?;
+    copy_attrs!(src_volts, volts);
+    volts.set_chunking(&[REWRITE_CHUNK, 2, chans.len(), 2])?;
+    if let Some(level) = policy.compression {
+        volts.set_compression(level, true)?;
+    }
+    // Stream through in chunks so we never hold the whole dump in memory
+    let mut checksum = VoltageChecksum::default();
+    for start in (0..ntime).step_by(REWRITE_CHUNK) {
+        let stop = (start + REWRITE_CHUNK).min(ntime);
+        let block = src_volts.get::<i8, _>((start..stop, .., chans.clone(), ..))?;
+        volts.put((start..stop, .., .., ..), block.view())?;
+        checksum.update(block.view());
+    }
+    // Fewer channels, a different checksum
+    volts.put_attribute("crc32c", checksum.crc32c())?;
+
+    dst.sync()?;
+    Ok(())
+}
+
+/// Write a `sha256sum -c` manifest (`<dump>.sha256`) of the dump at `path` and its sidecar, if it has one, so copies
+/// can be checked before the originals are deleted
+pub fn write_manifest(path: &Path) -> eyre::Result<PathBuf> {
+    let mut manifest = String::new();
+    let sidecar = path.with_extension("json");
+    for file in [path, &sidecar] {
+        if file != path && !file.exists() {
+            continue;
+        }
+        let mut sha = Sha256::default();
+        let mut reader = File::open(file)?;
+        let mut buf = vec![0; MANIFEST_READ];
+        loop {
+            match reader.read(&mut buf)? {
+                0 => break,
+                n => sha.update(&buf[..n]),
+            }
+        }
+        let name = file
+            .file_name()
+            .ok_or_else(|| eyre::eyre!("Dump has no file name"))?;
+        manifest.push_str(&format!(
+            "{}  {}\n",
+            auth::to_hex(&sha.finalize()),
+            name.to_string_lossy()
+        ));
+    }
+    let mut manifest_path = path.as_os_str().to_owned();
+    manifest_path.push(".sha256");
+    let mut out = File::create(&manifest_path)?;
+    out.write_all(manifest.as_bytes())?;
+    out.sync_all()?;
+    Ok(manifest_path.into())
+}
+
+/// Apply the dump policy to every file we're sent, at the lowest priority we can, then checksum what's left on disk.
+/// Only run when there's a policy to apply, otherwise the dump writer checksums its own dumps.
+pub fn postprocess_task(
+    policy: DumpPolicy,
+    files: Receiver<PathBuf>,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting dump post-processing task");
+    lower_priority();
+    loop {
+        if shutdown.try_recv().is_ok() {
+            info!("Dump post-processing task stopping");
+            break;
+        }
+        match files.recv_timeout(BLOCK_TIMEOUT) {
+            Ok(path) => {
+                if !policy.is_noop() {
+                    match rewrite(&policy, &path) {
+                        Ok(_) => info!(?path, "Post-processed dump"),
+                        Err(e) => warn!(?path, "Error post-processing dump - {e}"),
+                    }
+                }
+                match write_manifest(&path) {
+                    Ok(manifest) => info!(?manifest, "Wrote dump manifest"),
+                    Err(e) => {
+                        ring_stats()
+                            .missing_manifests
+                            .fetch_add(1, Ordering::Relaxed);
+                        warn!(?path, "Error writing dump manifest - {e}");
+                    }
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        common::{CandidateEvent, CHANNELS},
+        dumps::format::{DumpFileFormat, DumpHeader},
+        exfil::FrequencyPlan,
+    };
+    use ndarray::{array, Array4};
+
+    #[test]
+    fn test_rewrite_channels() {
+        crate::testing::seed_start_time();
+        let dir = crate::testing::scratch_dir();
+        let path = dir.path().join("grex_dump-rewrite.nc");
+        let event = CandidateEvent {
+            candname: "rewrite".to_owned(),
+            ..Default::default()
+        };
+        let header = DumpHeader {
+            event: &event,
+            merged: &[],
+            start_sample: 0,
+            samples: 3,
+            decimation: 1,
+            nbits: 8,
+            freq_plan: FrequencyPlan::default(),
+            watermarked: true,
+            compression: None,
+        };
+        let mut writer = DumpFileFormat::Netcdf
+            .format()
+            .create(&path, &header)
+            .unwrap();
+        let voltages = Array4::from_shape_fn((3, 2, CHANNELS, 2), |(_, _, c, _)| c as i8);
+        let mut checksum = VoltageChecksum::default();
+        checksum.update(voltages.view());
+        let latencies = array![5, -1, 7];
+        writer
+            .write(0, voltages.view(), Some(latencies.view()))
+            .unwrap();
+        writer.finish(&checksum).unwrap();
+
+        let policy = DumpPolicy {
+            compression: None,
+            channels: Some(10..20),
+        };
+        rewrite(&policy, &path).unwrap();
+        let file = netcdf::open(&path).unwrap();
+        let volts = file.variable("voltages").unwrap().get::<i8, _>(..).unwrap();
+        assert_eq!(volts.shape(), [3, 2, 10, 2]);
+        assert_eq!(volts[[0, 0, 0, 0]], 10);
+        // The latencies come along, fill value and all
+        let recv = file.variable("recv_latency").unwrap();
+        assert_eq!(recv.get::<i32, _>(..).unwrap(), array![5, -1, 7].into_dyn());
+        assert!(recv.attribute("_FillValue").is_some());
+        assert!(recv.attribute("units").is_some());
+    }
+
+    #[test]
+    fn test_manifest() {
+        let dir = crate::testing::scratch_dir();
+        let path = dir.path().join("grex_dump-cand.raw");
+        std::fs::write(&path, b"abc").unwrap();
+        std::fs::write(path.with_extension("json"), b"").unwrap();
+        let manifest = std::fs::read_to_string(write_manifest(&path).unwrap()).unwrap();
+        assert_eq!(
+            manifest,
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  grex_dump-cand.raw\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  grex_dump-cand.json\n"
+        );
+    }
+}
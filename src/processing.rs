@@ -1,26 +1,314 @@
 //! Inter-thread processing (downsampling, etc)
-use crate::common::{stokes_i, Payload, Stokes, BLOCK_TIMEOUT, CHANNELS};
+use crate::accounting::accounting;
+use crate::calibration::cal_schedule;
+use crate::common::{full_stokes, stokes_i, Payload, Stokes, StokesVec, BLOCK_TIMEOUT, CHANNELS};
+use crate::correlation::CORRELATION_STRIDE;
+use crate::exfil::{FrequencyPlan, STOKES_ORDER};
+use crate::flags::{flag_marks, FlagRun, Flags, RunEncoder};
+use crate::profiling::payload_profile;
+use clap::ValueEnum;
 use eyre::bail;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::SyncSender,
+        OnceLock, RwLock,
+    },
+    time::Instant,
+};
 use thingbuf::mpsc::{
-    blocking::{Sender, StaticReceiver, StaticSender},
+    blocking::{Receiver, Sender},
     errors::RecvTimeoutError,
 };
 use tokio::sync::broadcast;
-use tracing::info;
+use tracing::{info, warn};
+
+/// What to replace the contents of blanked channels with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum BlankFill {
+    /// Hard zeros
+    #[default]
+    Zeros,
+    /// Gaussian noise matched to the unblanked channels of the same spectrum
+    Noise,
+    /// Gaussian noise matched to each channel's own running mean and variance.
+    /// This also stands in for zero-filled (dropped) packets, so downstream doesn't see the discontinuity.
+    ChannelNoise,
+}
+
+/// Which polarization products come out of processing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Products {
+    /// Summed Stokes I
+    #[default]
+    StokesI,
+    /// All four Stokes parameters, as I, Q, U, then V
+    FullStokes,
+    /// The power in each polarization separately, as A then B
+    DualPol,
+}
+
+/// Turn full stokes (IQUV) into the power in each polarization (A then B), as each is half of I plus or minus Q
+fn dual_pol(full: &mut StokesVec) {
+    let (i, rest) = full.split_at_mut(CHANNELS);
+    for (i, q) in i.iter_mut().zip(&mut rest[..CHANNELS]) {
+        (*i, *q) = ((*i + *q) / 2.0, (*i - *q) / 2.0);
+    }
+    full.truncate(2 * CHANNELS);
+}
+
+/// Number of spectra the running channel statistics are averaged over
+const CHANNEL_STATS_SPECTRA: f32 = 1024.0;
+
+/// Exponentially-weighted running mean and variance of every channel of the downsampled spectra
+#[derive(Debug)]
+pub struct ChannelStats {
+    mean: Box<[f32; CHANNELS]>,
+    var: Box<[f32; CHANNELS]>,
+    /// Spectra seen so far, so the averages settle quickly at startup
+    seen: f32,
+}
+
+impl Default for ChannelStats {
+    fn default() -> Self {
+        Self {
+            mean: Box::new([0.0; CHANNELS]),
+            var: Box::new([0.0; CHANNELS]),
+            seen: 0.0,
+        }
+    }
+}
+
+impl ChannelStats {
+    /// Fold in a spectrum, skipping the blanked channels (which hold nothing real)
+    pub fn update(&mut self, spectrum: &[f32; CHANNELS], mask: &[bool; CHANNELS]) {
+        self.seen += 1.0;
+        let alpha = (1.0 / self.seen).max(1.0 / CHANNEL_STATS_SPECTRA);
+        for (((v, m), mean), var) in spectrum
+            .iter()
+            .zip(mask)
+            .zip(self.mean.iter_mut())
+            .zip(self.var.iter_mut())
+        {
+            if *m {
+                continue;
+            }
+            let delta = v - *mean;
+            *mean += alpha * delta;
+            *var = (1.0 - alpha) * (*var + alpha * delta * delta);
+        }
+    }
+
+    /// Noise for `channel` of a spectrum where `fraction` of the averaged payloads were missing
+    pub fn sample(&self, channel: usize, fraction: f32, rng: &mut impl Rng) -> f32 {
+        fraction * self.mean[channel] + (fraction * self.var[channel]).sqrt() * gaussian(rng)
+    }
+}
+
+/// The set of channels (in gateware order) we're currently blanking, shared between the control API and processing
+#[derive(Debug, Default)]
+pub struct ChannelMask {
+    blanked: RwLock<BTreeSet<usize>>,
+    /// Incremented on every change so readers can cheaply check for updates
+    generation: AtomicU64,
+}
+
+impl ChannelMask {
+    /// Replace the entire set of blanked channels
+    pub fn set(&self, channels: impl IntoIterator<Item = usize>) {
+        *self.blanked.write().unwrap() = channels.into_iter().filter(|c| *c < CHANNELS).collect();
+        self.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// Add a channel to the mask, returning false if it was already blanked or is out of range
+    pub fn blank(&self, channel: usize) -> bool {
+        if channel >= CHANNELS {
+            return false;
+        }
+        let inserted = self.blanked.write().unwrap().insert(channel);
+        self.generation.fetch_add(1, Ordering::Release);
+        inserted
+    }
+
+    /// Remove a channel from the mask, returning false if it wasn't blanked
+    pub fn unblank(&self, channel: usize) -> bool {
+        let removed = self.blanked.write().unwrap().remove(&channel);
+        self.generation.fetch_add(1, Ordering::Release);
+        removed
+    }
+
+    /// The currently blanked channels, in ascending order
+    pub fn channels(&self) -> Vec<usize> {
+        self.blanked.read().unwrap().iter().copied().collect()
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+}
+
+/// Parse a list of channels (in gateware order) like "0-250, 1900-2047, 1024", where ranges are inclusive.
+/// Commas and newlines both separate entries, and anything after a '#' on a line is a comment.
+pub fn parse_channel_ranges(s: &str) -> eyre::Result<Vec<usize>> {
+    let mut channels = BTreeSet::new();
+    for entry in s
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+    {
+        let (start, end): (usize, usize) = match entry.split_once('-') {
+            Some((start, end)) => (start.trim().parse()?, end.trim().parse()?),
+            None => {
+                let c = entry.parse()?;
+                (c, c)
+            }
+        };
+        if start > end || end >= CHANNELS {
+            bail!(
+                "Invalid channel range {entry}, channels go from 0 to {}",
+                CHANNELS - 1
+            );
+        }
+        channels.extend(start..=end);
+    }
+    Ok(channels.into_iter().collect())
+}
+
+/// Read a mask file of channel ranges, as [`parse_channel_ranges`] takes them (one or more per line)
+pub fn load_mask_file(path: &std::path::Path) -> eyre::Result<Vec<usize>> {
+    parse_channel_ranges(&std::fs::read_to_string(path)?)
+}
+
+/// Get the global channel mask
+pub fn channel_mask() -> &'static ChannelMask {
+    static CHANNEL_MASK: OnceLock<ChannelMask> = OnceLock::new();
+    CHANNEL_MASK.get_or_init(ChannelMask::default)
+}
+
+/// Standard normal sample via the Box-Muller transform
+fn gaussian(rng: &mut impl Rng) -> f32 {
+    let u1: f32 = rng.gen_range(f32::EPSILON..1.0);
+    let u2: f32 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f32::consts::PI * u2).cos()
+}
+
+/// Replace the blanked channels of a spectrum according to the fill mode
+fn apply_blanking(
+    spectrum: &mut [f32; CHANNELS],
+    mask: &[bool; CHANNELS],
+    fill: BlankFill,
+    stats: &ChannelStats,
+    rng: &mut impl Rng,
+) {
+    match fill {
+        BlankFill::Zeros => spectrum
+            .iter_mut()
+            .zip(mask)
+            .filter(|(_, m)| **m)
+            .for_each(|(v, _)| *v = 0.0),
+        BlankFill::Noise => {
+            // Statistics of the channels we're keeping
+            let (mut n, mut sum, mut sum_sq) = (0usize, 0f32, 0f32);
+            for (v, _) in spectrum.iter().zip(mask).filter(|(_, m)| !**m) {
+                n += 1;
+                sum += v;
+                sum_sq += v * v;
+            }
+            if n == 0 {
+                spectrum.fill(0.0);
+                return;
+            }
+            let mean = sum / n as f32;
+            let std = (sum_sq / n as f32 - mean * mean).max(0.0).sqrt();
+            spectrum
+                .iter_mut()
+                .zip(mask)
+                .filter(|(_, m)| **m)
+                .for_each(|(v, _)| *v = mean + std * gaussian(rng));
+        }
+        BlankFill::ChannelNoise => spectrum
+            .iter_mut()
+            .enumerate()
+            .zip(mask)
+            .filter(|(_, m)| **m)
+            .for_each(|((c, v), _)| *v = stats.sample(c, 1.0, rng)),
+    }
+}
+
+/// Add a spectrum into the running downsample accumulator
+pub fn accumulate(acc: &mut [f32; CHANNELS], stokes: &[f32; CHANNELS]) {
+    acc.iter_mut().zip(stokes).for_each(|(x, y)| *x += y);
+}
+
+/// Turn the running downsample accumulator of `n` spectra into their average
+pub fn average(acc: &mut [f32; CHANNELS], n: usize) {
+    acc.iter_mut().for_each(|v| *v /= n as f32);
+}
+
+/// Which capture streams go where, as the downsampler demultiplexes them
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StreamRoutes {
+    /// Stream that's downsampled into the exfil stream
+    pub exfil: u16,
+    /// Stream that's sent on to the voltage ringbuffer
+    pub dump: u16,
+}
 
 #[allow(clippy::missing_panics_doc)]
+#[allow(clippy::too_many_arguments)]
 pub fn downsample_task(
-    receiver: StaticReceiver<Payload>,
+    receiver: Receiver<Payload>,
     sender: Sender<Stokes>,
-    to_dumps: StaticSender<Payload>,
+    full_sender: Option<Sender<StokesVec>>,
+    products: Products,
+    to_flags: SyncSender<FlagRun>,
+    to_dumps: Sender<Payload>,
+    to_correlation: SyncSender<Payload>,
+    streams: StreamRoutes,
     downsample_power: u32,
+    freq_plan: FrequencyPlan,
+    blank_fill: BlankFill,
     mut shutdown: broadcast::Receiver<()>,
 ) -> eyre::Result<()> {
     info!("Starting downsample task");
     let downsamp_iters = 2usize.pow(downsample_power);
     let mut downsamp_buf = [0f32; CHANNELS];
     let mut stokes_buf = [0f32; CHANNELS];
+    // With full stokes (or dual-pol, which we get from I and Q), every spectrum goes out on its own channel (in place
+    // of the Stokes I one) and we average Q, U, and V alongside I (which stays in `downsamp_buf`, so blanking and noise
+    // filling only ever deal with I)
+    let mut full_buf = full_sender
+        .as_ref()
+        .map(|_| Box::new([[0f32; CHANNELS]; 4]));
+    let mut quv_buf = full_sender
+        .as_ref()
+        .map(|_| Box::new([[0f32; CHANNELS]; 3]));
     let mut local_downsamp_iters = 0;
+    // The gateware ordering only needs to be corrected here, everything downstream sees STOKES_ORDER
+    let flip = freq_plan.order != STOKES_ORDER;
+    // Local copy of the channel mask, refreshed whenever the control API changes it
+    let mut mask = [false; CHANNELS];
+    let mut any_blanked = false;
+    let mut mask_generation = None;
+    let mut rng = StdRng::from_entropy();
+    let mut channel_stats = ChannelStats::default();
+    // Zero-filled payloads in the spectrum we're accumulating, when they're being replaced with noise
+    let mut missing_iters = 0;
+    let profile = payload_profile("downsample");
+    // Flagged ranges of payloads we haven't gotten past yet, and the flags of the spectrum we're accumulating
+    let mut marks = vec![];
+    let mut spectrum_flags = Flags::NONE;
+    let cal = cal_schedule();
+    let mut encoder = RunEncoder::default();
+    let send_run = |run: FlagRun| {
+        if to_flags.try_send(run).is_err() {
+            warn!(?run, "Flag stream backed up, dropping a flag run");
+        }
+    };
 
     loop {
         if shutdown.try_recv().is_ok() {
@@ -33,33 +321,189 @@ pub fn downsample_task(
             Err(RecvTimeoutError::Closed) => break,
             Err(_) => unreachable!(),
         };
-        // Send payload to dump (non-blocking)
-        if let Err(thingbuf::mpsc::errors::TrySendError::Closed(_)) = to_dumps.try_send(*payload) {
-            bail!("Channel closed");
-        }
-        // Compute Stokes I
-        stokes_i(&mut stokes_buf, &payload);
-        // Add to averaging bufs
-        downsamp_buf
-            .iter_mut()
-            .zip(&stokes_buf)
-            .for_each(|(x, y)| *x += y);
+        let iter_start = Instant::now();
+        // Send payload to dump (non-blocking), if it's from the board we're dumping
+        if payload.stream == streams.dump {
+            match to_dumps.try_send(*payload) {
+                Ok(_) => (),
+                Err(thingbuf::mpsc::errors::TrySendError::Full(_)) => {
+                    accounting().dump_overflow.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(_) => bail!("Channel closed"),
+            }
+        }
+        // Everything past here is only for the board we're exfilling
+        if payload.stream != streams.exfil {
+            continue;
+        }
+        // Every so often, hand a payload to the slow-path correlator (non-blocking, and we don't mind if it's busy)
+        if payload.count % CORRELATION_STRIDE == 0 {
+            let _ = to_correlation.try_send(*payload);
+        }
+        // Pick up any flags for this payload
+        flag_marks().take(&mut marks);
+        let mut payload_flags = Flags::NONE;
+        if !marks.is_empty() {
+            for (counts, flags) in &marks {
+                if counts.contains(&payload.count) {
+                    payload_flags |= *flags;
+                }
+            }
+            marks.retain(|(counts, _)| counts.end > payload.count + 1);
+        }
+        if cal.is_some_and(|cal| cal.is_on(payload.count)) {
+            payload_flags |= Flags::CAL_ON;
+        }
+        spectrum_flags |= payload_flags;
+        if blank_fill == BlankFill::ChannelNoise && payload_flags.contains(Flags::ZERO_FILLED) {
+            // Leave it out of the average, we'll make up for it once the spectrum is done
+            missing_iters += 1;
+        } else if let (Some(full), Some(quv)) = (&mut full_buf, &mut quv_buf) {
+            full_stokes(full, &payload);
+            accumulate(&mut downsamp_buf, &full[0]);
+            for (acc, param) in quv.iter_mut().zip(&full[1..]) {
+                accumulate(acc, param);
+            }
+        } else {
+            // Compute Stokes I
+            stokes_i(&mut stokes_buf, &payload);
+            // Add to averaging bufs
+            accumulate(&mut downsamp_buf, &stokes_buf);
+        }
 
         // Increment the count
         local_downsamp_iters += 1;
+        accounting().downsampled.fetch_add(1, Ordering::Relaxed);
 
         // Check for downsample exit condition
         if local_downsamp_iters == downsamp_iters {
             // Write averages directly into it
-            downsamp_buf
-                .iter_mut()
-                .for_each(|v| *v /= local_downsamp_iters as f32);
-            sender.send(downsamp_buf.into())?;
+            average(&mut downsamp_buf, local_downsamp_iters);
+            // Blank channels before they go anywhere near exfil
+            let generation = channel_mask().generation();
+            if mask_generation != Some(generation) {
+                mask_generation = Some(generation);
+                mask = [false; CHANNELS];
+                for c in channel_mask().channels() {
+                    mask[c] = true;
+                }
+                any_blanked = mask.contains(&true);
+            }
+            if missing_iters > 0 {
+                let fraction = missing_iters as f32 / local_downsamp_iters as f32;
+                for (c, v) in downsamp_buf.iter_mut().enumerate() {
+                    *v += channel_stats.sample(c, fraction, &mut rng);
+                }
+            } else if blank_fill == BlankFill::ChannelNoise {
+                channel_stats.update(&downsamp_buf, &mask);
+            }
+            apply_blanking(
+                &mut downsamp_buf,
+                &mask,
+                blank_fill,
+                &channel_stats,
+                &mut rng,
+            );
+            if any_blanked {
+                spectrum_flags |= Flags::RFI;
+            }
+            if flip {
+                downsamp_buf.reverse();
+            }
+            // Flags go out ahead of (or alongside) their spectra
+            if let Some(run) = encoder.push(spectrum_flags) {
+                send_run(run);
+            }
+            spectrum_flags = Flags::NONE;
+            match (&full_sender, &mut quv_buf) {
+                (Some(full_sender), Some(quv)) => {
+                    // Blanked (or missing) polarization is zero, there's nothing sensible to fill it with
+                    let mut full = StokesVec::new();
+                    full.try_extend_from_slice(&downsamp_buf)?;
+                    for param in quv.iter_mut() {
+                        // Zero-filled payloads were left out of the sums
+                        let kept = local_downsamp_iters - missing_iters;
+                        average(param, kept.max(1));
+                        for (v, &blanked) in param.iter_mut().zip(&mask) {
+                            if blanked {
+                                *v = 0.0;
+                            }
+                        }
+                        if flip {
+                            param.reverse();
+                        }
+                        full.try_extend_from_slice(param.as_slice())?;
+                        param.iter_mut().for_each(|v| *v = 0.0);
+                    }
+                    if products == Products::DualPol {
+                        dual_pol(&mut full);
+                    }
+                    full_sender.send(full)?;
+                }
+                _ => sender.send(downsamp_buf.into())?,
+            }
 
             // And reset averaging
             downsamp_buf.iter_mut().for_each(|v| *v = 0.0);
             local_downsamp_iters = 0;
+            missing_iters = 0;
         }
+        profile.record(iter_start.elapsed());
+    }
+    if let Some(run) = encoder.finish() {
+        send_run(run);
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_channel_ranges() {
+        let channels = parse_channel_ranges("0-3, 10\n# edges\n2046-2047 # top").unwrap();
+        assert_eq!(channels, vec![0, 1, 2, 3, 10, 2046, 2047]);
+        assert!(parse_channel_ranges("5-2").is_err());
+        assert!(parse_channel_ranges("2048").is_err());
+        assert!(parse_channel_ranges("one").is_err());
+    }
+
+    #[test]
+    fn test_dual_pol() {
+        use crate::common::Channel;
+        let mut pl = Payload::default();
+        for c in 0..CHANNELS {
+            pl.pol_a[c] = Channel::new((c % 7) as i8, -3);
+            pl.pol_b[c] = Channel::new(2, (c % 5) as i8);
+        }
+        let mut params = [[0f32; CHANNELS]; 4];
+        full_stokes(&mut params, &pl);
+        let mut full = StokesVec::new();
+        for param in &params {
+            full.try_extend_from_slice(param).unwrap();
+        }
+        dual_pol(&mut full);
+        assert_eq!(full.len(), 2 * CHANNELS);
+        // Each is the stokes I of that polarization by itself
+        let power = |a, b| {
+            let single = Payload {
+                pol_a: a,
+                pol_b: b,
+                ..Default::default()
+            };
+            let mut power = [0f32; CHANNELS];
+            stokes_i(&mut power, &single);
+            power
+        };
+        let zeros = [Channel::new(0, 0); CHANNELS];
+        let (aa, bb) = (power(pl.pol_a, zeros), power(zeros, pl.pol_b));
+        for c in 0..CHANNELS {
+            assert!((full[c] - aa[c]).abs() < 1e-6);
+            assert!((full[CHANNELS + c] - bb[c]).abs() < 1e-6);
+        }
+        // And what Stokes I only consumers get back is their sum
+        let i = crate::exfil::stokes_i_of(&full);
+        assert!(i.iter().zip(&params[0]).all(|(a, b)| (a - b).abs() < 1e-6));
+    }
+}
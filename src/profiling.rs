@@ -0,0 +1,154 @@
+//! Always-on, lightweight timing of each stage's loop iterations
+use crate::common::PACKET_CADENCE;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, OnceLock, RwLock,
+    },
+    time::Duration,
+};
+use tracing::warn;
+
+/// Number of power-of-two buckets, enough for anything up to a few seconds
+const BUCKETS: usize = 32;
+
+/// An exponential histogram of iteration times, where bucket i holds times in [2^(i-1), 2^i) ns
+#[derive(Debug)]
+pub struct StageProfile {
+    buckets: [AtomicU64; BUCKETS],
+    /// How long an iteration can take before we fall behind
+    budget: Duration,
+    /// Whether we've already warned about being over budget
+    over_budget: AtomicBool,
+}
+
+/// A point-in-time copy of a stage's profile
+#[derive(Debug, Clone, Serialize)]
+pub struct ProfileSnapshot {
+    pub iterations: u64,
+    pub p50_ns: u64,
+    pub p99_ns: u64,
+    pub budget_ns: u64,
+    /// Counts per bucket, where bucket i holds times in [2^(i-1), 2^i) ns
+    pub buckets: Vec<u64>,
+}
+
+impl StageProfile {
+    fn new(budget: Duration) -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            budget,
+            over_budget: AtomicBool::new(false),
+        }
+    }
+
+    /// Record the time taken by one iteration
+    pub fn record(&self, elapsed: Duration) {
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        let bucket = ((u64::BITS - ns.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProfileSnapshot {
+        let buckets: Vec<_> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        ProfileSnapshot {
+            iterations: buckets.iter().sum(),
+            p50_ns: quantile(&buckets, 0.5),
+            p99_ns: quantile(&buckets, 0.99),
+            budget_ns: u64::try_from(self.budget.as_nanos()).unwrap_or(u64::MAX),
+            buckets,
+        }
+    }
+}
+
+/// Upper edge of the bucket containing the given quantile (0 if there's nothing recorded)
+fn quantile(buckets: &[u64], q: f64) -> u64 {
+    let total: u64 = buckets.iter().sum();
+    if total == 0 {
+        return 0;
+    }
+    let target = (q * total as f64).ceil() as u64;
+    let mut seen = 0;
+    for (i, count) in buckets.iter().enumerate() {
+        seen += count;
+        if seen >= target {
+            return 1u64 << i;
+        }
+    }
+    1u64 << (buckets.len() - 1)
+}
+
+fn profiles() -> &'static RwLock<BTreeMap<String, Arc<StageProfile>>> {
+    static PROFILES: OnceLock<RwLock<BTreeMap<String, Arc<StageProfile>>>> = OnceLock::new();
+    PROFILES.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+/// Get (or create) the profile of the named stage, which has `budget` per iteration.
+/// Stages should grab this once and record into it directly.
+pub fn profile(name: &str, budget: Duration) -> Arc<StageProfile> {
+    profiles()
+        .write()
+        .unwrap()
+        .entry(name.to_owned())
+        .or_insert_with(|| Arc::new(StageProfile::new(budget)))
+        .clone()
+}
+
+/// Profile of a stage that handles one payload per iteration
+pub fn payload_profile(name: &str) -> Arc<StageProfile> {
+    profile(name, Duration::from_secs_f64(PACKET_CADENCE))
+}
+
+/// Snapshots of every stage's profile
+pub fn snapshot() -> BTreeMap<String, ProfileSnapshot> {
+    profiles()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, p)| (name.clone(), p.snapshot()))
+        .collect()
+}
+
+/// Warn (once, until it recovers) about any stage whose p99 is over its budget
+pub fn check_budgets() {
+    for (name, profile) in profiles().read().unwrap().iter() {
+        let snap = profile.snapshot();
+        let over = snap.p99_ns > snap.budget_ns;
+        if over && !profile.over_budget.swap(true, Ordering::Relaxed) {
+            warn!(
+                stage = name,
+                p99_ns = snap.p99_ns,
+                budget_ns = snap.budget_ns,
+                "Stage is over its time budget"
+            );
+        } else if !over {
+            profile.over_budget.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_quantiles() {
+        let profile = StageProfile::new(Duration::from_micros(8));
+        for _ in 0..99 {
+            profile.record(Duration::from_nanos(1000));
+        }
+        profile.record(Duration::from_millis(1));
+        let snap = profile.snapshot();
+        assert_eq!(snap.iterations, 100);
+        assert_eq!(snap.p50_ns, 1024);
+        assert_eq!(snap.p99_ns, 1024);
+        profile.record(Duration::from_millis(1));
+        assert_eq!(profile.snapshot().p99_ns, 1 << 20);
+    }
+}
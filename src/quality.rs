@@ -0,0 +1,153 @@
+//! A single composite data quality score, so stations can be ranked at a glance
+use serde::Serialize;
+use std::sync::{Mutex, OnceLock};
+
+/// Temperature below which the FPGA is perfectly happy (C)
+const TEMP_COMFORTABLE_C: f64 = 55.0;
+/// Range of ADC RMS values (counts) that make good use of the 8 bits
+const ADC_RMS_GOOD: (f64, f64) = (8.0, 48.0);
+/// Fraction of dropped packets at which the drop component bottoms out
+const DROP_RATE_FLOOR: f64 = 0.01;
+/// Number of MADs above the median a channel needs to be to count as RFI
+const RFI_MAD_THRESHOLD: f64 = 5.0;
+
+/// The raw measurements that feed the score, None if we don't know yet
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QualityInputs {
+    /// Fraction of packets dropped since the last update
+    pub drop_rate: Option<f64>,
+    /// Fraction of channels occupied by RFI
+    pub rfi_occupancy: Option<f64>,
+    /// RMS of each ADC (counts)
+    pub adc_rms: Option<(f64, f64)>,
+    /// FPGA temperature (C)
+    pub fpga_temp: Option<f64>,
+    /// How full the exfil channel is (0-1)
+    pub exfil_backlog: Option<f64>,
+}
+
+/// Component scores (0-1) and the overall weighted score (0-100)
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Quality {
+    pub score: f64,
+    pub drops: Option<f64>,
+    pub rfi: Option<f64>,
+    pub adc: Option<f64>,
+    pub temperature: Option<f64>,
+    pub backlog: Option<f64>,
+}
+
+/// Get the global quality inputs, updated by whichever tasks can see them
+pub fn quality_inputs() -> &'static Mutex<QualityInputs> {
+    static QUALITY_INPUTS: OnceLock<Mutex<QualityInputs>> = OnceLock::new();
+    QUALITY_INPUTS.get_or_init(|| Mutex::new(QualityInputs::default()))
+}
+
+/// Linearly map x from 1 at `good` to 0 at `bad`
+fn ramp(x: f64, good: f64, bad: f64) -> f64 {
+    ((x - bad) / (good - bad)).clamp(0.0, 1.0)
+}
+
+fn adc_score(rms: f64) -> f64 {
+    if rms < ADC_RMS_GOOD.0 {
+        ramp(rms, ADC_RMS_GOOD.0, 0.0)
+    } else {
+        // Clipping happens at 128
+        ramp(rms, ADC_RMS_GOOD.1, 128.0)
+    }
+}
+
+/// Fraction of channels more than RFI_MAD_THRESHOLD MADs above the median
+pub fn rfi_occupancy(spectrum: &[f64]) -> f64 {
+    if spectrum.is_empty() {
+        return 0.0;
+    }
+    let median = |v: &mut Vec<f64>| {
+        v.sort_by(|a, b| a.total_cmp(b));
+        v[v.len() / 2]
+    };
+    let mut sorted = spectrum.to_vec();
+    let med = median(&mut sorted);
+    let mut deviations: Vec<_> = spectrum.iter().map(|x| (x - med).abs()).collect();
+    let mad = median(&mut deviations);
+    let flagged = spectrum
+        .iter()
+        .filter(|x| **x > med + RFI_MAD_THRESHOLD * mad)
+        .count();
+    flagged as f64 / spectrum.len() as f64
+}
+
+impl QualityInputs {
+    /// Combine the inputs into a score, renormalizing the weights over the components we know about
+    pub fn score(&self, temp_limit: f64) -> Quality {
+        let drops = self.drop_rate.map(|r| ramp(r, 0.0, DROP_RATE_FLOOR));
+        let rfi = self.rfi_occupancy.map(|o| 1.0 - o.clamp(0.0, 1.0));
+        let adc = self.adc_rms.map(|(a, b)| adc_score(a).min(adc_score(b)));
+        let temperature = self
+            .fpga_temp
+            .map(|t| ramp(t, TEMP_COMFORTABLE_C, temp_limit));
+        let backlog = self.exfil_backlog.map(|b| 1.0 - b.clamp(0.0, 1.0));
+        let weighted = [
+            (drops, 0.3),
+            (rfi, 0.2),
+            (adc, 0.2),
+            (temperature, 0.15),
+            (backlog, 0.15),
+        ];
+        let (sum, weights) = weighted
+            .iter()
+            .filter_map(|(c, w)| c.map(|c| (c * w, *w)))
+            .fold((0.0, 0.0), |(s, ws), (c, w)| (s + c, ws + w));
+        let score = if weights > 0.0 {
+            100.0 * sum / weights
+        } else {
+            0.0
+        };
+        Quality {
+            score,
+            drops,
+            rfi,
+            adc,
+            temperature,
+            backlog,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_score() {
+        let perfect = QualityInputs {
+            drop_rate: Some(0.0),
+            rfi_occupancy: Some(0.0),
+            adc_rms: Some((20.0, 20.0)),
+            fpga_temp: Some(40.0),
+            exfil_backlog: Some(0.0),
+        };
+        assert_eq!(perfect.score(68.0).score, 100.0);
+        let hot = QualityInputs {
+            fpga_temp: Some(68.0),
+            ..perfect
+        };
+        assert!((hot.score(68.0).score - 85.0).abs() < 1e-9);
+        // With nothing to go on there's no score
+        assert_eq!(QualityInputs::default().score(68.0).score, 0.0);
+        // Unknown components don't count against us
+        let partial = QualityInputs {
+            drop_rate: Some(0.0),
+            ..Default::default()
+        };
+        assert_eq!(partial.score(68.0).score, 100.0);
+    }
+
+    #[test]
+    fn test_rfi_occupancy() {
+        let mut spectrum = vec![1.0; 100];
+        spectrum[3] = 100.0;
+        spectrum[50] = 100.0;
+        assert_eq!(rfi_occupancy(&spectrum), 0.02);
+    }
+}
@@ -0,0 +1,70 @@
+//! Realtime scheduling and memory locking for the hot-path threads, so the OS doesn't get in the way under load
+use clap::ValueEnum;
+use tracing::{info, warn};
+
+/// Threads worth promoting, as they're the ones that drop packets if they get descheduled
+pub const REALTIME_THREADS: [&str; 2] = ["capture", "downsample"];
+
+/// Scheduling policy for the hot-path threads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SchedPolicy {
+    /// The normal time-sharing scheduler
+    #[default]
+    Other,
+    /// First in, first out realtime
+    Fifo,
+    /// Round-robin realtime
+    Rr,
+}
+
+/// How to schedule the hot-path threads
+#[derive(Debug, Clone, Copy)]
+pub struct Realtime {
+    pub policy: SchedPolicy,
+    /// Realtime priority (1-99), ignored for the normal scheduler
+    pub priority: i32,
+}
+
+impl Realtime {
+    /// Apply this policy to the calling thread, carrying on as we were (with a warning) if we're not allowed to.
+    /// Returns whether the policy took effect.
+    pub fn apply_to_current(&self, thread: &str) -> bool {
+        let (policy, priority) = match self.policy {
+            SchedPolicy::Other => return true,
+            SchedPolicy::Fifo => (libc::SCHED_FIFO, self.priority),
+            SchedPolicy::Rr => (libc::SCHED_RR, self.priority),
+        };
+        let param = libc::sched_param {
+            sched_priority: priority,
+        };
+        // SAFETY: pthread_self is always valid for the calling thread, and param outlives the call
+        let ret = unsafe { libc::pthread_setschedparam(libc::pthread_self(), policy, &param) };
+        if ret == 0 {
+            info!(thread, ?self.policy, priority, "Realtime scheduling enabled");
+            true
+        } else {
+            warn!(
+                thread,
+                "Couldn't set realtime scheduling (needs CAP_SYS_NICE or an rtprio limit), running with the normal scheduler - {}",
+                std::io::Error::from_raw_os_error(ret)
+            );
+            false
+        }
+    }
+}
+
+/// Lock every current and future page of the process into RAM, so nothing on the hot path ever page faults to disk.
+/// Returns whether it worked, warning if it didn't.
+pub fn lock_memory() -> bool {
+    // SAFETY: mlockall has no memory safety requirements
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } == 0 {
+        info!("Locked process memory");
+        true
+    } else {
+        warn!(
+            "Couldn't lock process memory (needs CAP_IPC_LOCK or a memlock limit), carrying on without - {}",
+            std::io::Error::last_os_error()
+        );
+        false
+    }
+}
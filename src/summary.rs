@@ -0,0 +1,150 @@
+//! End-of-run digest, so collaborators can see how a night went without digging through the logs
+use crate::{
+    accounting::AccountingSnapshot, common::station_id, diagnostics::alert_counts,
+    dumps::ring_stats, exfil::stats, naming::time_policy,
+};
+use hifitime::Epoch;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::atomic::Ordering,
+};
+
+/// What a single exfil sink produced over the run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SinkSummary {
+    pub spectra: u64,
+    pub bytes: u64,
+    pub last_error: Option<String>,
+}
+
+/// Payloads we lost along the way
+#[derive(Debug, Clone, Serialize)]
+pub struct DropSummary {
+    /// Payloads zero-filled in place of dropped packets
+    pub filled: u64,
+    /// Payloads thrown away for arriving out of order
+    pub shuffled: u64,
+    pub dump_overflow: u64,
+    pub dump_flushed: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RunSummary {
+    pub station: &'static str,
+    pub start_mjd: f64,
+    pub stop_mjd: f64,
+    /// What the MJDs are in
+    pub time_standard: &'static str,
+    pub duration_s: f64,
+    pub captured: u64,
+    pub drops: DropSummary,
+    pub sinks: BTreeMap<String, SinkSummary>,
+    pub injections: u64,
+    pub dumps: u64,
+    pub warnings: u64,
+    pub errors: u64,
+}
+
+impl RunSummary {
+    /// Pull together the run-wide counters for a run spanning `start` to `stop`
+    pub fn collect(start: Epoch, stop: Epoch, acc: &AccountingSnapshot, injections: u64) -> Self {
+        let mut sinks: BTreeMap<String, SinkSummary> = acc
+            .written
+            .iter()
+            .map(|(name, &spectra)| {
+                (
+                    name.clone(),
+                    SinkSummary {
+                        spectra,
+                        ..Default::default()
+                    },
+                )
+            })
+            .collect();
+        for (name, snap) in stats::snapshot() {
+            let sink = sinks.entry(name).or_default();
+            sink.bytes = snap.bytes;
+            sink.last_error = snap.last_error;
+        }
+        Self {
+            station: station_id(),
+            start_mjd: time_policy().mjd(start),
+            stop_mjd: time_policy().mjd(stop),
+            time_standard: time_policy().mjd_standard(),
+            duration_s: (stop - start).to_seconds(),
+            captured: acc.captured,
+            drops: DropSummary {
+                filled: acc.filled,
+                shuffled: acc.shuffled,
+                dump_overflow: acc.dump_overflow,
+                dump_flushed: acc.dump_flushed,
+            },
+            sinks,
+            injections,
+            dumps: ring_stats().dumps.load(Ordering::Relaxed),
+            warnings: alert_counts().warnings.load(Ordering::Relaxed),
+            errors: alert_counts().errors.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    /// Write the summary as both text and JSON into `dir`, returning the path of the text one
+    pub fn write(&self, dir: &Path) -> eyre::Result<PathBuf> {
+        let stem = format!(
+            "grex-summary-{}",
+            time_policy().filename_stamp(Epoch::now()?)
+        );
+        let text_path = dir.join(format!("{stem}.txt"));
+        std::fs::write(&text_path, self.to_string())?;
+        std::fs::write(dir.join(format!("{stem}.json")), self.to_json())?;
+        Ok(text_path)
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "GReX T0 run at {} from MJD {:.6} to {:.6} {} ({:.2} hours)",
+            self.station,
+            self.start_mjd,
+            self.stop_mjd,
+            self.time_standard,
+            self.duration_s / 3600.0
+        )?;
+        writeln!(f, "\nPayloads captured: {}", self.captured)?;
+        writeln!(f, "  zero-filled (dropped packets): {}", self.drops.filled)?;
+        writeln!(f, "  out of order: {}", self.drops.shuffled)?;
+        writeln!(f, "  dump channel overflow: {}", self.drops.dump_overflow)?;
+        writeln!(f, "  flushed after dumps: {}", self.drops.dump_flushed)?;
+        writeln!(f, "\nExfil:")?;
+        if self.sinks.is_empty() {
+            writeln!(f, "  (none)")?;
+        }
+        for (name, sink) in &self.sinks {
+            write!(
+                f,
+                "  {name}: {} spectra, {:.1} MiB",
+                sink.spectra,
+                sink.bytes as f64 / (1 << 20) as f64
+            )?;
+            match &sink.last_error {
+                Some(e) => writeln!(f, ", last error: {e}")?,
+                None => writeln!(f)?,
+            }
+        }
+        writeln!(f, "\nInjections performed: {}", self.injections)?;
+        writeln!(f, "Voltage dumps written: {}", self.dumps)?;
+        writeln!(
+            f,
+            "Alerts raised: {} warnings, {} errors",
+            self.warnings, self.errors
+        )
+    }
+}
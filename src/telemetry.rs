@@ -1,3 +1,4 @@
+use crate::{common::station_id, diagnostics::RecentLogs};
 use opentelemetry::KeyValue;
 use opentelemetry_appender_tracing::layer::OpenTelemetryTracingBridge;
 use opentelemetry_sdk::{
@@ -19,6 +20,7 @@ fn resource() -> Resource {
             KeyValue::new(SERVICE_NAME, env!("CARGO_PKG_NAME")),
             KeyValue::new(SERVICE_VERSION, env!("CARGO_PKG_VERSION")),
             KeyValue::new(DEPLOYMENT_ENVIRONMENT, "production"),
+            KeyValue::new("station.id", station_id()),
         ],
         SCHEMA_URL,
     )
@@ -54,6 +56,7 @@ pub async fn init_tracing_subscriber() {
     tracing_subscriber::registry()
         .with(EnvFilter::from_default_env())
         .with(tracing_subscriber::fmt::layer())
+        .with(RecentLogs)
         .with(trace_layer)
         .with(log_layer)
         .init();
@@ -0,0 +1,19 @@
+//! Fixtures shared by the unit tests
+use crate::common::payload_start_time;
+
+/// A directory of our own to write files into, deleted when it's dropped (even if the test fails)
+pub fn scratch_dir() -> tempfile::TempDir {
+    tempfile::Builder::new()
+        .prefix("grex-")
+        .tempdir()
+        .expect("Couldn't create a scratch directory")
+}
+
+/// Start the observation, for tests that need the times of payloads. It's one start for the whole process, so
+/// every test seeds the same one.
+pub fn seed_start_time() {
+    payload_start_time()
+        .lock()
+        .unwrap()
+        .get_or_insert(hifitime::Epoch::from_mjd_tai(60000.0));
+}
@@ -0,0 +1,452 @@
+//! Synchronizing the packet clock against absolute time
+use crate::common::{payload_time, LATEST_PACKET};
+use eyre::bail;
+use hifitime::Epoch;
+use rsntp::SntpClient;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use tracing::{error, info, warn};
+
+/// Set if the packet epoch was derived from the unsynchronized system clock
+static DEGRADED_TIMING: AtomicBool = AtomicBool::new(false);
+/// Time between attempts when every NTP server failed
+const NTP_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Whether our absolute timing should be considered untrustworthy
+pub fn degraded() -> bool {
+    DEGRADED_TIMING.load(Ordering::Acquire)
+}
+
+pub fn set_degraded(degraded: bool) {
+    DEGRADED_TIMING.store(degraded, Ordering::Release);
+}
+
+/// The most recently measured offset (seconds) between the system clock and NTP, if we have one
+pub fn ntp_offset() -> &'static Mutex<Option<f64>> {
+    static NTP_OFFSET: OnceLock<Mutex<Option<f64>>> = OnceLock::new();
+    NTP_OFFSET.get_or_init(|| Mutex::new(None))
+}
+
+/// The most recently measured drift (seconds) of packet-count time relative to NTP, if we have one
+pub fn clock_drift() -> &'static Mutex<Option<f64>> {
+    static CLOCK_DRIFT: OnceLock<Mutex<Option<f64>>> = OnceLock::new();
+    CLOCK_DRIFT.get_or_init(|| Mutex::new(None))
+}
+
+/// Samples kept in the fit of FPGA ticks against host time
+const CLOCK_FIT_SAMPLES: usize = 64;
+/// Fewest samples we'll fit a rate to
+const CLOCK_FIT_MIN_SAMPLES: usize = 4;
+
+/// A reading of the FPGA's clock counters and when (on the host) it happened
+#[derive(Debug, Clone, Copy)]
+pub struct ClockSample {
+    /// Host monotonic time halfway through the read
+    pub host: Instant,
+    /// How long the read took, which bounds how well `host` lines up with the counters
+    pub round_trip: Duration,
+    /// Free-running fabric clock counter (wraps)
+    pub ticks: u32,
+    /// PPS edges seen since the FPGA was armed
+    pub pps: u32,
+}
+
+/// A running linear fit of FPGA fabric clock ticks against host time, to catch clocking faults from the host side
+#[derive(Debug)]
+pub struct ClockFit {
+    /// Rate the fabric clock is supposed to run at (Hz)
+    nominal_rate: f64,
+    /// Rate error beyond which we consider the clock faulty (ppm)
+    tolerance_ppm: f64,
+    first: Option<ClockSample>,
+    last: Option<ClockSample>,
+    /// Unwrapped ticks since the first sample
+    ticks: u64,
+    /// Host seconds and unwrapped ticks since the first sample
+    samples: VecDeque<(f64, f64)>,
+}
+
+impl ClockFit {
+    pub fn new(nominal_rate: f64, tolerance_ppm: f64) -> Self {
+        Self {
+            nominal_rate,
+            tolerance_ppm,
+            first: None,
+            last: None,
+            ticks: 0,
+            samples: VecDeque::with_capacity(CLOCK_FIT_SAMPLES),
+        }
+    }
+
+    /// Start over, i.e. after the FPGA was re-armed
+    pub fn reset(&mut self) {
+        *self = Self::new(self.nominal_rate, self.tolerance_ppm);
+    }
+
+    pub fn push(&mut self, sample: ClockSample) {
+        let Some(first) = self.first else {
+            self.first = Some(sample);
+            self.last = Some(sample);
+            self.samples.push_back((0.0, 0.0));
+            return;
+        };
+        let last = self.last.unwrap();
+        // The counter wraps every few seconds, so use the host clock to work out how many times it went around
+        let host_dt = (sample.host - last.host).as_secs_f64();
+        let wrapped = u64::from(sample.ticks.wrapping_sub(last.ticks));
+        let wraps = ((host_dt * self.nominal_rate - wrapped as f64) / 2f64.powi(32))
+            .round()
+            .max(0.0);
+        self.ticks += wrapped + ((wraps as u64) << 32);
+        self.last = Some(sample);
+        if self.samples.len() == CLOCK_FIT_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back(((sample.host - first.host).as_secs_f64(), self.ticks as f64));
+    }
+
+    /// Least-squares rate of the fabric clock over the recent samples (Hz)
+    pub fn rate(&self) -> Option<f64> {
+        if self.samples.len() < CLOCK_FIT_MIN_SAMPLES {
+            return None;
+        }
+        let n = self.samples.len() as f64;
+        let (mean_t, mean_x) = self
+            .samples
+            .iter()
+            .fold((0.0, 0.0), |(t, x), (st, sx)| (t + st / n, x + sx / n));
+        let (mut cov, mut var) = (0.0, 0.0);
+        for (t, x) in &self.samples {
+            cov += (t - mean_t) * (x - mean_x);
+            var += (t - mean_t).powi(2);
+        }
+        (var > 0.0).then(|| cov / var)
+    }
+
+    /// Deviation of the fitted rate from nominal, in parts per million
+    pub fn rate_error_ppm(&self) -> Option<f64> {
+        self.rate()
+            .map(|rate| (rate / self.nominal_rate - 1.0) * 1e6)
+    }
+
+    /// PPS edges seen beyond what the fabric clock says should have happened since the first sample.
+    /// Negative means edges went missing, anything more than one (for the edge we may be straddling) means extra edges.
+    pub fn pps_slip(&self) -> Option<i64> {
+        let (first, last) = (self.first?, self.last?);
+        let rate = self.rate().unwrap_or(self.nominal_rate);
+        let expected = (self.ticks as f64 / rate).floor() as i64;
+        let observed = i64::from(last.pps.wrapping_sub(first.pps));
+        Some(observed - expected)
+    }
+
+    /// What's wrong with the FPGA clocking, if anything
+    pub fn fault(&self) -> Option<String> {
+        if let Some(ppm) = self.rate_error_ppm() {
+            if ppm.abs() > self.tolerance_ppm {
+                return Some(format!("fabric clock is off nominal by {ppm:.1} ppm"));
+            }
+        }
+        match self.pps_slip() {
+            Some(slip) if slip < 0 => Some(format!("{} PPS edges went missing", -slip)),
+            Some(slip) if slip > 1 => Some(format!("{} extra PPS edges", slip - 1)),
+            _ => None,
+        }
+    }
+}
+
+/// A PTP hardware clock (disciplined by linuxptp) to read absolute time from
+#[derive(Debug, Clone)]
+pub struct PtpClock {
+    /// PHC character device, i.e. /dev/ptp0
+    pub device: PathBuf,
+    /// Seconds the PHC is ahead of UTC (it runs on the PTP (TAI) timescale)
+    pub utc_offset: i64,
+}
+
+/// Where we get absolute time from. PTP is preferred, falling back to each NTP server in order.
+#[derive(Debug, Clone, Default)]
+pub struct TimeSources {
+    pub ptp: Option<PtpClock>,
+    pub ntp: Vec<String>,
+}
+
+impl TimeSources {
+    pub fn is_empty(&self) -> bool {
+        self.ptp.is_none() && self.ntp.is_empty()
+    }
+}
+
+/// The result of synchronizing against one of our time sources
+#[derive(Debug, Clone)]
+pub struct TimeSync {
+    /// Seconds the true time is ahead of the system clock
+    pub offset: f64,
+    /// Which source we synchronized against
+    pub source: String,
+}
+
+impl TimeSync {
+    /// The true time right now, according to this synchronization
+    pub fn now(&self) -> eyre::Result<Epoch> {
+        Ok(Epoch::now()? + hifitime::Duration::from_seconds(self.offset))
+    }
+}
+
+fn timespec_nanos(ts: &libc::timespec) -> i128 {
+    i128::from(ts.tv_sec) * 1_000_000_000 + i128::from(ts.tv_nsec)
+}
+
+/// Measure the offset (seconds) of UTC from the PHC relative to the system clock
+fn read_ptp(clock: &PtpClock) -> eyre::Result<f64> {
+    let phc = File::open(&clock.device)?;
+    // FD_TO_CLOCKID from the kernel's dynamic POSIX clock interface
+    let clock_id = ((!phc.as_raw_fd()) << 3) | 3;
+    let mut before = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    let (mut phc_time, mut after) = (before, before);
+    // Bracket the PHC read by system clock reads, taking the midpoint
+    // Safety: The timespecs are valid for writes and the clock id comes from a file we're holding open
+    unsafe {
+        libc::clock_gettime(libc::CLOCK_REALTIME, &mut before);
+        if libc::clock_gettime(clock_id, &mut phc_time) != 0 {
+            bail!(
+                "Couldn't read the PTP hardware clock - {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        libc::clock_gettime(libc::CLOCK_REALTIME, &mut after);
+    }
+    let system = (timespec_nanos(&before) + timespec_nanos(&after)) / 2;
+    let utc = timespec_nanos(&phc_time) - i128::from(clock.utc_offset) * 1_000_000_000;
+    Ok((utc - system) as f64 / 1e9)
+}
+
+/// Try PTP, then each NTP server in order, `retries` times over, returning the first successful synchronization
+pub fn synchronize(sources: &TimeSources, retries: usize) -> Option<TimeSync> {
+    let client = SntpClient::new();
+    for attempt in 1..=retries {
+        if let Some(ptp) = &sources.ptp {
+            match read_ptp(ptp) {
+                Ok(offset) => {
+                    let source = ptp.device.display().to_string();
+                    info!(%source, "Synchronized with PTP");
+                    *ntp_offset().lock().unwrap() = Some(offset);
+                    return Some(TimeSync { offset, source });
+                }
+                Err(e) => warn!(attempt, "PTP synchronization failed - {e}"),
+            }
+        }
+        for server in &sources.ntp {
+            match client.synchronize(server) {
+                Ok(res) => {
+                    info!(server, "Synchronized with NTP");
+                    let offset = res.clock_offset().as_secs_f64();
+                    *ntp_offset().lock().unwrap() = Some(offset);
+                    return Some(TimeSync {
+                        offset,
+                        source: server.clone(),
+                    });
+                }
+                Err(e) => warn!(server, attempt, "NTP synchronization failed - {e}"),
+            }
+        }
+        std::thread::sleep(NTP_RETRY_DELAY);
+    }
+    None
+}
+
+/// Periodically re-query our time sources so we keep a record of how far off the system clock (and therefore our timing) is
+pub async fn ntp_recheck_task(
+    sources: TimeSources,
+    interval: Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting NTP recheck task");
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately, and we just synchronized
+    ticker.tick().await;
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("NTP recheck task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                let sources = sources.clone();
+                match tokio::task::spawn_blocking(move || synchronize(&sources, 1)).await? {
+                    Some(sync) => {
+                        if degraded() {
+                            warn!(offset = sync.offset, source = %sync.source, "Time sync is available again, but timing for this run remains degraded");
+                        }
+                    }
+                    None => {
+                        *ntp_offset().lock().unwrap() = None;
+                        error!("Time sync recheck failed on every source");
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Compare the time implied by the latest packet count to NTP time, returning the drift in seconds
+fn measure_drift(sources: &TimeSources) -> Option<f64> {
+    let count = LATEST_PACKET.load(Ordering::Relaxed);
+    if count == 0 {
+        // No packets yet, nothing to compare against
+        return None;
+    }
+    let system_now = Epoch::now().ok()?;
+    let offset = synchronize(sources, 1)?.offset;
+    let true_now = system_now + hifitime::Duration::from_seconds(offset);
+    Some((payload_time(count) - true_now).to_seconds())
+}
+
+/// Periodically check that the packet counter is keeping time with NTP, which catches FPGA clocking faults
+pub async fn drift_check_task(
+    sources: TimeSources,
+    interval: Duration,
+    threshold: Duration,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting clock drift check task");
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Clock drift check task stopping");
+                break;
+            }
+            _ = ticker.tick() => {
+                let sources = sources.clone();
+                let drift = tokio::task::spawn_blocking(move || measure_drift(&sources)).await?;
+                if let Some(drift) = drift {
+                    if drift.abs() > threshold.as_secs_f64() {
+                        error!(drift, "Packet-count time has drifted from NTP, check the FPGA clocking");
+                    }
+                }
+                *clock_drift().lock().unwrap() = drift;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The absolute time of packet 0, saved when we arm the FPGA so a restarted process can pick the stream back up without re-arming
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SyncEpoch {
+    /// Time of packet 0 (MJD, TAI)
+    pub start_mjd_tai: f64,
+    /// Whether the epoch was derived from an unsynchronized clock
+    pub degraded: bool,
+}
+
+impl SyncEpoch {
+    pub fn new(start: Epoch, degraded: bool) -> Self {
+        Self {
+            start_mjd_tai: start.to_mjd_tai_days(),
+            degraded,
+        }
+    }
+
+    pub fn start(&self) -> Epoch {
+        Epoch::from_mjd_tai(self.start_mjd_tai)
+    }
+
+    pub fn save(&self, path: &Path) -> eyre::Result<()> {
+        // Write then rename, so a crash mid-write can't leave us with half an epoch
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(self)?)?;
+        std::fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> eyre::Result<Self> {
+        Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+    }
+
+    /// PPS edges the FPGA should have counted since it was armed for this epoch, at `now`
+    /// (the first edge after arming is the one that starts packet 0)
+    fn expected_pps(&self, now: Epoch) -> i64 {
+        (now - self.start()).to_seconds().floor() as i64 + 1
+    }
+
+    /// Check this epoch against the PPS count the FPGA has now, which would have started over if it was re-armed since
+    pub fn check(&self, pps_count: u32, now: Epoch) -> eyre::Result<()> {
+        let expected = self.expected_pps(now);
+        // We can land either side of an edge
+        if (i64::from(pps_count) - expected).abs() > 1 {
+            bail!(
+                "The FPGA has counted {pps_count} PPS edges, but it should have counted {expected} since the saved epoch, it must have been re-armed"
+            );
+        }
+        Ok(())
+    }
+
+    /// Work the epoch out from the FPGA's PPS count, good to the second if `now` is
+    pub fn from_pps(pps_count: u32, now: Epoch, degraded: bool) -> Self {
+        let start = now.floor(hifitime::Duration::from_seconds(1.0))
+            - hifitime::Duration::from_seconds(f64::from(pps_count.saturating_sub(1)));
+        Self::new(start, degraded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sync_epoch() {
+        let start = Epoch::from_mjd_tai(60000.0);
+        let epoch = SyncEpoch::new(start, false);
+        let now = start + hifitime::Duration::from_seconds(100.5);
+        assert!(epoch.check(101, now).is_ok());
+        assert!(epoch.check(100, now).is_ok());
+        // Re-armed a few seconds ago
+        assert!(epoch.check(3, now).is_err());
+        let derived = SyncEpoch::from_pps(101, now, false);
+        assert!((derived.start() - start).to_seconds().abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_clock_fit() {
+        // A 250 MHz clock wraps every ~17 s, sampled every 10 s
+        let rate = 250e6;
+        let mut fit = ClockFit::new(rate, 50.0);
+        let start = Instant::now();
+        for i in 0..10u64 {
+            fit.push(ClockSample {
+                host: start + Duration::from_secs(10 * i),
+                round_trip: Duration::ZERO,
+                ticks: (10 * i * rate as u64) as u32,
+                pps: (10 * i) as u32,
+            });
+        }
+        assert!(fit.rate_error_ppm().unwrap().abs() < 1e-3);
+        assert!(matches!(fit.pps_slip(), Some(0 | 1)));
+        assert_eq!(fit.fault(), None);
+        // Then we lose a couple of PPS edges
+        fit.push(ClockSample {
+            host: start + Duration::from_secs(100),
+            round_trip: Duration::ZERO,
+            ticks: (100 * rate as u64) as u32,
+            pps: 98,
+        });
+        assert!(fit.fault().is_some());
+    }
+}
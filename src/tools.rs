@@ -0,0 +1,724 @@
+//! Standalone utilities for commissioning and testing stations
+use crate::{
+    args::Tool,
+    auth,
+    common::{payload_start_time, stokes_i, CandidateEvent, Payload, CHANNELS, PACKET_CADENCE},
+    db,
+    dumps::{dump_filename, format::DumpFileFormat, DumpRing},
+    exfil::{
+        dummy::DummyConsumer, filterbank::FilterbankConsumer, tap::TapReader, FrequencyPlan,
+        StokesConsumer,
+    },
+    injection::{inject, synthesize_pulse, BandShape, PulseSpec},
+    processing::accumulate,
+    triggers,
+};
+use byte_slice_cast::AsByteSlice;
+use eyre::{bail, eyre};
+use hifitime::Epoch;
+use ndarray::ArrayD;
+use rusqlite::Connection;
+use serde::Serialize;
+use sigproc_filterbank::write::WriteFilterbank;
+use std::{
+    fs::File,
+    hint::black_box,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+
+/// How far behind the newest data soak triggers ask for, so the whole dump window is in the ring (seconds)
+const SOAK_TRIGGER_LAG: f64 = 3.0;
+
+/// How often the observer reports on the stream
+const OBSERVE_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Run the selected tool to completion
+pub fn run(tool: Tool) -> eyre::Result<()> {
+    match tool {
+        Tool::BenchStages {
+            seconds,
+            downsample_power,
+        } => bench_stages(Duration::from_secs_f64(seconds), downsample_power),
+        Tool::MakePulse {
+            output,
+            dm,
+            width,
+            fluence,
+            band_shape,
+            spectral_index,
+            band_center,
+            band_fwhm,
+            freq,
+        } => {
+            let plan = freq.plan();
+            let spec = PulseSpec {
+                dm,
+                width: width * 1e-3,
+                fluence,
+                band_shape,
+                spectral_index,
+                band_center: band_center.unwrap_or(plan.center()),
+                band_fwhm,
+            };
+            make_pulse(&output, &spec, plan)
+        }
+        Tool::ReplayTriggers {
+            db,
+            target,
+            spacing,
+            since_mjd,
+            compare,
+            dump_path,
+            dump_format,
+            trigger_secret_file,
+        } => replay_triggers(
+            &Connection::open(db)?,
+            target,
+            Duration::from_secs_f64(spacing),
+            since_mjd,
+            compare.as_deref(),
+            &dump_path,
+            dump_format,
+            trigger_secret_file
+                .map(|path| auth::read_secret(&path))
+                .transpose()?
+                .as_deref(),
+        ),
+        Tool::Observe {
+            addr,
+            filterbank_path,
+        } => observe(&addr, filterbank_path.as_deref()),
+        Tool::Soak {
+            hours,
+            overspeed,
+            trigger_interval,
+            injection_cadence,
+            output,
+            station_id,
+            metrics_port,
+            trig_port,
+            downsample_power,
+            pipeline_args,
+        } => {
+            let soak = Soak {
+                duration: Duration::from_secs_f64(hours * 3600.0),
+                overspeed,
+                trigger_interval: Duration::from_secs_f64(trigger_interval),
+                injection_cadence,
+                output,
+                station_id,
+                metrics_port,
+                trig_port,
+                downsample_power,
+            };
+            soak.run(&pipeline_args)
+        }
+        Tool::Recommend {
+            seconds,
+            path,
+            vbuf_capacity,
+            headroom,
+            output,
+        } => recommend(
+            Duration::from_secs_f64(seconds),
+            &path,
+            vbuf_capacity,
+            headroom,
+            output.as_deref(),
+        ),
+    }
+}
+
+/// What this host can keep up with, and the configuration that follows from it
+#[derive(Debug, Serialize)]
+struct Recommendation {
+    /// Payloads per second stokes and downsampling can sustain
+    processing_rate: f64,
+    /// Longest a single payload took to process (s)
+    worst_payload: f64,
+    /// Spectra per second filterbank packing can sustain
+    pack_rate: f64,
+    /// Sustained write bandwidth of the output disk (bytes/s)
+    disk_bandwidth: f64,
+    /// Longest a single write to the output disk took (s)
+    worst_write: f64,
+    /// Smallest downsample power exfil keeps up at, if processing keeps up at all
+    downsample_power: Option<u32>,
+    payload_capacity: u64,
+    dump_capacity: u64,
+    exfil_capacity: u64,
+}
+
+/// The smallest downsample power (of the ones the pipeline supports) whose exfil rate, with `headroom`, fits in `sustainable` spectra per second
+fn min_downsample_power(payload_rate: f64, sustainable: f64, headroom: f64) -> Option<u32> {
+    (1..=9).find(|p| payload_rate / 2f64.powi(*p as i32) * headroom <= sustainable)
+}
+
+/// Payloads to buffer to ride out a stall of `stall` seconds, with `headroom`
+fn cover(stall: f64, per_item: f64, headroom: f64) -> u64 {
+    (stall / per_item * headroom).ceil() as u64
+}
+
+/// Write spectrum-sized chunks to a scratch file in `dir` for `duration`, returning the bandwidth (bytes/s) and the slowest write (s)
+fn disk_bandwidth(duration: Duration, dir: &Path) -> eyre::Result<(f64, f64)> {
+    let scratch = dir.join(".grex-recommend-scratch");
+    let mut file = File::create(&scratch)?;
+    let chunk = vec![0u8; CHANNELS * std::mem::size_of::<f32>()];
+    let (mut bytes, mut worst) = (0u64, Duration::ZERO);
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        let write_start = Instant::now();
+        file.write_all(&chunk)?;
+        worst = worst.max(write_start.elapsed());
+        bytes += chunk.len() as u64;
+    }
+    // Only count what actually made it to the disk
+    file.sync_all()?;
+    let elapsed = start.elapsed().as_secs_f64();
+    drop(file);
+    std::fs::remove_file(&scratch)?;
+    Ok((bytes as f64 / elapsed, worst.as_secs_f64()))
+}
+
+/// Measure what this host can sustain, and print (and optionally export) the configuration that keeps up with margin
+fn recommend(
+    duration: Duration,
+    path: &Path,
+    vbuf_capacity: usize,
+    headroom: f64,
+    output: Option<&Path>,
+) -> eyre::Result<()> {
+    let payload_rate = 1.0 / PACKET_CADENCE;
+    println!("Measuring processing throughput");
+    let payload = Payload::default();
+    let mut spectrum = [0f32; CHANNELS];
+    let mut acc = [0f32; CHANNELS];
+    let mut worst_payload = Duration::ZERO;
+    let processing_rate = throughput(duration, || {
+        let start = Instant::now();
+        stokes_i(&mut spectrum, black_box(&payload));
+        accumulate(&mut acc, black_box(&spectrum));
+        worst_payload = worst_payload.max(start.elapsed());
+    });
+    let fb = WriteFilterbank::<f32>::new(CHANNELS, 1);
+    let pack_rate = throughput(duration, || {
+        black_box(fb.pack(black_box(&spectrum[..])));
+    });
+    println!("Measuring disk bandwidth of {}", path.display());
+    let (disk_bandwidth, worst_write) = disk_bandwidth(duration, path)?;
+
+    let spectrum_bytes = (CHANNELS * std::mem::size_of::<f32>()) as f64;
+    let sustainable = pack_rate.min(disk_bandwidth / spectrum_bytes);
+    let downsample_power = if processing_rate >= payload_rate * headroom {
+        min_downsample_power(payload_rate, sustainable, headroom)
+    } else {
+        None
+    };
+    let downsample_factor = 2u64.pow(downsample_power.unwrap_or(9));
+    // Dumping writes the whole voltage buffer while the dump channel fills
+    let dump_seconds = (vbuf_capacity * std::mem::size_of::<Payload>()) as f64 / disk_bandwidth;
+    let recommendation = Recommendation {
+        processing_rate,
+        worst_payload: worst_payload.as_secs_f64(),
+        pack_rate,
+        disk_bandwidth,
+        worst_write,
+        downsample_power,
+        payload_capacity: cover(worst_payload.as_secs_f64(), PACKET_CADENCE, headroom)
+            .max(downsample_factor),
+        dump_capacity: cover(dump_seconds, PACKET_CADENCE, headroom).max(1),
+        exfil_capacity: cover(
+            worst_write,
+            PACKET_CADENCE * downsample_factor as f64,
+            headroom,
+        )
+        .max(1),
+    };
+
+    println!(
+        "Processing: {:.0} payloads/s ({:.2}x real time), worst payload {:.1} us",
+        processing_rate,
+        processing_rate / payload_rate,
+        recommendation.worst_payload * 1e6
+    );
+    println!(
+        "Disk: {:.1} MiB/s, worst write {:.1} ms",
+        disk_bandwidth / (1024.0 * 1024.0),
+        worst_write * 1e3
+    );
+    match downsample_power {
+        Some(p) => println!("Recommended: --downsample-power {p}"),
+        None => println!(
+            "Warning: this host can't keep up at any downsample power with {headroom}x headroom"
+        ),
+    }
+    println!(
+        "Recommended: --payload-capacity {} --dump-capacity {} --exfil-capacity {}",
+        recommendation.payload_capacity,
+        recommendation.dump_capacity,
+        recommendation.exfil_capacity
+    );
+    if recommendation.dump_capacity > vbuf_capacity as u64 / 2 {
+        println!("Warning: dumps take long enough to write that the voltage buffer can't cover them, consider a faster disk or smaller buffer");
+    }
+    if let Some(output) = output {
+        std::fs::write(output, serde_json::to_vec_pretty(&recommendation)?)?;
+        println!("Wrote {}", output.display());
+    }
+    Ok(())
+}
+
+/// Follow a pipeline's stokes tap, reporting how much we're keeping up and writing a filterbank if asked to
+fn observe(addr: &str, filterbank_path: Option<&Path>) -> eyre::Result<()> {
+    let mut tap = TapReader::connect(addr)?;
+    let header = tap.header.clone();
+    println!(
+        "Attached to {addr}: {} channels from {} MHz, {} s per spectrum",
+        header.channels, header.fch1, header.tsamp
+    );
+    let mut consumer: Box<dyn StokesConsumer> = match filterbank_path {
+        Some(path) => Box::new(FilterbankConsumer::new(
+            header.downsample_factor,
+            header.freq_plan,
+            path,
+        )?),
+        None => Box::new(DummyConsumer),
+    };
+    let (mut received, mut missed, mut last) = (0u64, 0u64, None);
+    let mut last_report = Instant::now();
+    while let Some((index, stokes)) = tap.next_spectrum()? {
+        if last.is_none() {
+            // Our data starts wherever we joined, which is what the consumer should think of as the start
+            *payload_start_time().lock().unwrap() = Some(
+                Epoch::from_mjd_tai(header.start_mjd)
+                    + hifitime::Duration::from_seconds(index as f64 * header.tsamp),
+            );
+        }
+        if let Some(last) = last {
+            missed += index.saturating_sub(last + 1);
+        }
+        last = Some(index);
+        received += 1;
+        consumer.consume(&stokes)?;
+        if last_report.elapsed() >= OBSERVE_REPORT_INTERVAL {
+            println!("Received {received} spectra, missed {missed}");
+            last_report = Instant::now();
+        }
+    }
+    println!("Tap closed after {received} spectra, missed {missed}");
+    consumer.finish()
+}
+
+/// Re-send recorded triggers (signed, if we have the secret), and compare the resulting dumps to a reference set if we
+/// were given one
+#[allow(clippy::too_many_arguments)]
+fn replay_triggers(
+    conn: &Connection,
+    target: SocketAddr,
+    spacing: Duration,
+    since_mjd: f64,
+    reference: Option<&Path>,
+    dump_path: &Path,
+    dump_format: DumpFileFormat,
+    secret: Option<&[u8]>,
+) -> eyre::Result<()> {
+    if reference.is_some() && !dump_format.rewritable() {
+        bail!("Only netcdf dumps can be compared");
+    }
+    let triggers = db::recorded_triggers(conn, since_mjd)?;
+    println!("Replaying {} triggers to {target}", triggers.len());
+    let sock = UdpSocket::bind("0.0.0.0:0")?;
+    for trigger in &triggers {
+        let message = match secret {
+            Some(secret) => triggers::sign_trigger(secret, trigger)?,
+            None => serde_json::to_vec(trigger)?,
+        };
+        sock.send_to(&message, target)?;
+        println!("Sent {} (sample {})", trigger.candname, trigger.specnum);
+        // The dump task queues triggers that arrive while it's dumping, but only so many
+        std::thread::sleep(spacing);
+    }
+    let Some(reference) = reference else {
+        return Ok(());
+    };
+    let mut mismatched = 0;
+    for trigger in &triggers {
+        let name = dump_filename(&trigger.candname, dump_format);
+        match same_voltages(&reference.join(&name), &dump_path.join(&name)) {
+            Ok(true) => println!("{name}: identical"),
+            Ok(false) => {
+                mismatched += 1;
+                println!("{name}: DIFFERENT");
+            }
+            Err(e) => {
+                mismatched += 1;
+                println!("{name}: couldn't compare - {e}");
+            }
+        }
+    }
+    if mismatched > 0 {
+        bail!(
+            "{mismatched} of {} dumps didn't match the reference",
+            triggers.len()
+        );
+    }
+    Ok(())
+}
+
+/// Whether two dumps hold the same voltages (the time axes depend on when the stream was replayed, so they're ignored)
+fn same_voltages(a: &Path, b: &Path) -> eyre::Result<bool> {
+    let read = |path: &Path| -> eyre::Result<ArrayD<i8>> {
+        let file = netcdf::open(path)?;
+        let voltages = file
+            .variable("voltages")
+            .ok_or_else(|| eyre!("No voltages in {}", path.display()))?;
+        Ok(voltages.get::<i8, _>(..)?)
+    };
+    Ok(read(a)? == read(b)?)
+}
+
+/// A burn-in run of the whole pipeline against the simulated source
+struct Soak {
+    duration: Duration,
+    overspeed: f64,
+    trigger_interval: Duration,
+    injection_cadence: u64,
+    output: PathBuf,
+    station_id: String,
+    metrics_port: u16,
+    trig_port: u16,
+    downsample_power: u32,
+}
+
+impl Soak {
+    fn dir(&self, name: &str) -> PathBuf {
+        self.output.join(name)
+    }
+
+    /// Start the pipeline, drive it for the duration, stop it, and check everything it did
+    fn run(&self, pipeline_args: &[String]) -> eyre::Result<()> {
+        for dir in ["dumps", "filterbanks", "summaries", "pulses"] {
+            std::fs::create_dir_all(self.dir(dir))?;
+        }
+        let plan = FrequencyPlan::default();
+        let pulse = PulseSpec {
+            dm: 100.0,
+            width: 1e-3,
+            fluence: 2000.0,
+            band_shape: BandShape::Flat,
+            spectral_index: 0.0,
+            band_center: plan.center(),
+            band_fwhm: 50.0,
+        };
+        make_pulse(&self.dir("pulses").join("soak.dat"), &pulse, plan)?;
+        let mut pipeline = Command::new(std::env::current_exe()?)
+            .arg("--station-id")
+            .arg(&self.station_id)
+            .arg("--simulate")
+            .arg(self.overspeed.to_string())
+            .arg("--skip-ntp")
+            .arg("--dump-path")
+            .arg(self.dir("dumps"))
+            .arg("--filterbank-path")
+            .arg(self.dir("filterbanks"))
+            .arg("--summary-path")
+            .arg(self.dir("summaries"))
+            .arg("--db-path")
+            .arg(self.output.join("soak.db"))
+            .arg("--pulse-path")
+            .arg(self.dir("pulses"))
+            .arg("--injection-cadence")
+            .arg(self.injection_cadence.to_string())
+            .arg("--metrics-port")
+            .arg(self.metrics_port.to_string())
+            .arg("--trig-port")
+            .arg(self.trig_port.to_string())
+            .arg("--downsample-power")
+            .arg(self.downsample_power.to_string())
+            .args(pipeline_args)
+            .arg("filterbank")
+            .spawn()?;
+        println!(
+            "Soaking for {:.1} hours at {}x real time",
+            self.duration.as_secs_f64() / 3600.0,
+            self.overspeed
+        );
+        let driven = self.drive(&mut pipeline);
+        // Stop it the way an operator would, so it writes its summary
+        // Safety: Just sending a signal to our own child
+        unsafe { libc::kill(pipeline.id() as libc::pid_t, libc::SIGTERM) };
+        let status = pipeline.wait()?;
+        let triggers = driven?;
+        if !status.success() {
+            bail!("The pipeline exited with {status}");
+        }
+        self.verify(&triggers)
+    }
+
+    /// Send triggers on the interval until the time is up, returning the names of the ones we sent
+    fn drive(&self, pipeline: &mut Child) -> eyre::Result<Vec<String>> {
+        let sock = UdpSocket::bind("0.0.0.0:0")?;
+        let spectrum_time = PACKET_CADENCE * 2f64.powi(self.downsample_power as i32);
+        // Far enough back that the whole dump window is in the ring
+        let lag = (SOAK_TRIGGER_LAG / spectrum_time) as u64;
+        let start = Instant::now();
+        let mut next_trigger = start + self.trigger_interval;
+        let mut triggers = vec![];
+        while start.elapsed() < self.duration {
+            if let Some(status) = pipeline.try_wait()? {
+                bail!("The pipeline exited early with {status}");
+            }
+            std::thread::sleep(Duration::from_secs(1));
+            if Instant::now() < next_trigger {
+                continue;
+            }
+            next_trigger += self.trigger_interval;
+            let acc = match get_json(self.metrics_port, "/accounting") {
+                Ok(acc) => acc,
+                Err(e) => {
+                    println!("Pipeline isn't answering yet - {e}");
+                    continue;
+                }
+            };
+            let failures = unexpected_drops(&acc);
+            if !failures.is_empty() {
+                bail!("Unexpected drops: {}", failures.join(", "));
+            }
+            let Some(specnum) = acc["downsampled"].as_u64().and_then(|n| n.checked_sub(lag)) else {
+                continue;
+            };
+            let event = CandidateEvent {
+                candname: format!("soak{:05}", triggers.len()),
+                specnum,
+                source: Some("soak".to_owned()),
+                ..Default::default()
+            };
+            sock.send_to(&serde_json::to_vec(&event)?, ("127.0.0.1", self.trig_port))?;
+            println!(
+                "{:.2} h: sent {} (spectrum {specnum})",
+                start.elapsed().as_secs_f64() / 3600.0,
+                event.candname
+            );
+            triggers.push(event.candname);
+        }
+        Ok(triggers)
+    }
+
+    /// Check the run summary and the outputs, reporting everything that's wrong
+    fn verify(&self, triggers: &[String]) -> eyre::Result<()> {
+        let mut failures = vec![];
+        let summary = newest_with_extension(&self.dir("summaries"), "json")?
+            .ok_or_else(|| eyre!("The pipeline didn't write a run summary"))?;
+        let summary: serde_json::Value = serde_json::from_slice(&std::fs::read(summary)?)?;
+        failures.extend(unexpected_drops(&summary["drops"]));
+        let expected_injections = self.duration.as_secs() / self.injection_cadence.max(1);
+        let injections = summary["injections"].as_u64().unwrap_or(0);
+        if expected_injections > 0 && injections == 0 {
+            failures.push(format!(
+                "no pulses injected (expected around {expected_injections})"
+            ));
+        }
+        let dumps = summary["dumps"].as_u64().unwrap_or(0);
+        if dumps != triggers.len() as u64 {
+            failures.push(format!("{dumps} dumps for {} triggers", triggers.len()));
+        }
+        for name in triggers {
+            let path = self
+                .dir("dumps")
+                .join(dump_filename(name, DumpFileFormat::Netcdf));
+            let samples = netcdf::open(&path)
+                .ok()
+                .and_then(|file| file.dimension("time").map(|d| d.len()));
+            if samples.is_none_or(|n| n == 0) {
+                failures.push(format!("{} is missing or empty", path.display()));
+            }
+        }
+        if let Some(sinks) = summary["sinks"].as_object() {
+            for (name, sink) in sinks {
+                if sink["spectra"].as_u64().unwrap_or(0) == 0 {
+                    failures.push(format!("{name} wrote nothing"));
+                }
+                if let Some(e) = sink["last_error"].as_str() {
+                    failures.push(format!("{name} failed - {e}"));
+                }
+            }
+        }
+        match newest_with_extension(&self.dir("filterbanks"), "fil")? {
+            Some(fil) if std::fs::metadata(&fil)?.len() > 0 => (),
+            _ => failures.push("no filterbank written".to_owned()),
+        }
+        println!(
+            "Soak finished: {} triggers, {dumps} dumps, {injections} injections",
+            triggers.len()
+        );
+        if !failures.is_empty() {
+            for failure in &failures {
+                println!("FAILED: {failure}");
+            }
+            bail!("Soak failed {} checks", failures.len());
+        }
+        println!("Soak passed");
+        Ok(())
+    }
+}
+
+/// Payload drops in an accounting snapshot (or run summary) that shouldn't happen on a healthy machine.
+/// Payloads flushed after dumps are expected, the rest aren't.
+fn unexpected_drops(acc: &serde_json::Value) -> Vec<String> {
+    ["filled", "shuffled", "dump_overflow"]
+        .into_iter()
+        .filter_map(|key| match acc[key].as_u64() {
+            Some(0) | None => None,
+            Some(n) => Some(format!("{n} {key}")),
+        })
+        .collect()
+}
+
+/// GET a JSON document from the pipeline's monitoring server
+fn get_json(port: u16, route: &str) -> eyre::Result<serde_json::Value> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    write!(stream, "GET {route} HTTP/1.0\r\nHost: localhost\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (_, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| eyre!("Malformed response to {route}"))?;
+    Ok(serde_json::from_str(body)?)
+}
+
+/// The most recently modified file in `dir` with this extension
+fn newest_with_extension(dir: &Path, extension: &str) -> eyre::Result<Option<PathBuf>> {
+    let mut newest = None;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path.extension().is_some_and(|e| e == extension) {
+            let modified = entry.metadata()?.modified()?;
+            if newest.as_ref().is_none_or(|(_, t)| modified > *t) {
+                newest = Some((path, modified));
+            }
+        }
+    }
+    Ok(newest.map(|(path, _)| path))
+}
+
+/// Write a synthetic pulse in the format pulse injection reads
+fn make_pulse(output: &Path, spec: &PulseSpec, plan: FrequencyPlan) -> eyre::Result<()> {
+    let pulse = synthesize_pulse(spec, plan);
+    let samples = pulse.nrows();
+    let peak = pulse.iter().copied().max().unwrap_or(0);
+    std::fs::write(
+        output,
+        pulse
+            .as_standard_layout()
+            .as_slice()
+            .unwrap()
+            .as_byte_slice(),
+    )?;
+    println!(
+        "Wrote {} samples ({:.1} ms) to {}, peak amplitude {peak}",
+        samples,
+        samples as f64 * PACKET_CADENCE * 1e3,
+        output.display()
+    );
+    if peak == i8::MAX {
+        println!("Warning: the pulse saturated, consider a lower fluence");
+    }
+    Ok(())
+}
+
+/// Call `f` repeatedly for roughly `duration`, returning the achieved calls per second
+fn throughput(duration: Duration, mut f: impl FnMut()) -> f64 {
+    const BATCH: u64 = 1024;
+    let start = Instant::now();
+    let mut n = 0u64;
+    while start.elapsed() < duration {
+        for _ in 0..BATCH {
+            f();
+        }
+        n += BATCH;
+    }
+    n as f64 / start.elapsed().as_secs_f64()
+}
+
+fn report(stage: &str, achieved: f64, required: f64) {
+    let headroom = achieved / required;
+    let verdict = if headroom >= 1.0 { "OK" } else { "TOO SLOW" };
+    println!("{stage:<16} {achieved:>14.0} {required:>14.0} {headroom:>9.2}x  {verdict}");
+}
+
+/// Benchmark each of the hot-path functions and compare them to the rate they must sustain in real time
+fn bench_stages(duration: Duration, downsample_power: u32) -> eyre::Result<()> {
+    let payload_rate = 1.0 / PACKET_CADENCE;
+    let exfil_rate = payload_rate / 2f64.powi(downsample_power as i32);
+
+    println!(
+        "{:<16} {:>14} {:>14} {:>10}",
+        "stage", "achieved (/s)", "required (/s)", "headroom"
+    );
+
+    let payload = Payload::default();
+    let mut spectrum = [0f32; CHANNELS];
+    report(
+        "stokes_i",
+        throughput(duration, || stokes_i(&mut spectrum, black_box(&payload))),
+        payload_rate,
+    );
+
+    let mut payload = Payload::default();
+    let sample = [1i8; CHANNELS];
+    report(
+        "inject",
+        throughput(duration, || inject(&mut payload, black_box(&sample))),
+        payload_rate,
+    );
+
+    // Keep the counts monotonic so we measure the copy and not the reset path
+    let mut ring = DumpRing::new(1024);
+    let mut payload = Payload::default();
+    report(
+        "push_ring",
+        throughput(duration, || {
+            payload.count += 1;
+            ring.push(black_box(&payload));
+        }),
+        payload_rate,
+    );
+
+    let mut acc = [0f32; CHANNELS];
+    let stokes = [1f32; CHANNELS];
+    report(
+        "downsample",
+        throughput(duration, || accumulate(&mut acc, black_box(&stokes))),
+        payload_rate,
+    );
+
+    let fb = WriteFilterbank::<f32>::new(CHANNELS, 1);
+    report(
+        "filterbank_pack",
+        throughput(duration, || {
+            black_box(fb.pack(black_box(&stokes[..])));
+        }),
+        exfil_rate,
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_min_downsample_power() {
+        // 1000 payloads/s into something that takes 300 spectra/s, with 2x headroom, needs 1000/8 * 2 = 250
+        assert_eq!(min_downsample_power(1000.0, 300.0, 2.0), Some(3));
+        assert_eq!(min_downsample_power(1000.0, 1.0, 2.0), None);
+        assert_eq!(cover(0.01, 0.001, 2.0), 20);
+    }
+}
@@ -0,0 +1,1005 @@
+//! Where dump triggers come from. Every source runs on its own, feeding one queue that hands the
+//! highest-priority trigger to the dump task first.
+use crate::auth;
+use crate::common::CandidateEvent;
+use crate::dedup::{self, Clusterer};
+use crate::dumps::status;
+use crate::exfil::{ring::RingRequest, routes::CandidateSink};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BinaryHeap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{SyncSender, TrySendError},
+        Arc, Mutex, OnceLock, RwLock,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc, Notify},
+    task::JoinHandle,
+};
+use tracing::{info, warn};
+use zeromq::{Socket, SocketRecv, SubSocket};
+
+/// How often we look for candidate clusters that are done
+const CLUSTER_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait before offering a trigger to a busy dump task again
+const DISPATCH_RETRY: Duration = Duration::from_millis(10);
+/// Backoff between attempts at (re)connecting to a ZeroMQ publisher, doubling up to the max
+const ZMQ_RETRY: Duration = Duration::from_secs(1);
+const ZMQ_MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// Version of the trigger protocol we speak. Messages that don't say are taken to be this one.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// How far a signed trigger's timestamp can be from our clock, and so how long we remember its nonce for
+const REPLAY_WINDOW: f64 = 30.0;
+
+/// Priority of a source unless told otherwise, what the station itself finds goes first
+pub fn default_priority(source: &str) -> u8 {
+    match source {
+        "internal" => 3,
+        "http" => 2,
+        _ => 1,
+    }
+}
+
+/// Somewhere triggers come from
+pub trait TriggerSource: Send {
+    /// Name for the metrics and the control API, unique among the sources
+    fn name(&self) -> &str;
+    /// Start receiving, handing every trigger to `queue` until shutdown
+    fn spawn(
+        self: Box<Self>,
+        queue: TriggerQueue,
+        shutdown: broadcast::Receiver<()>,
+    ) -> JoinHandle<eyre::Result<()>>;
+}
+
+/// Counters and controls for one trigger source
+#[derive(Debug)]
+pub struct SourceStats {
+    /// Triggers from higher priority sources are dumped first
+    pub priority: u8,
+    enabled: AtomicBool,
+    /// Triggers (or raw candidates) we heard about
+    pub received: AtomicU64,
+    /// Messages we couldn't make sense of
+    pub rejected: AtomicU64,
+    /// Messages without a valid MAC, when we have a shared secret
+    pub unauthenticated: AtomicU64,
+    /// Triggers thrown away while the source was disabled
+    pub ignored: AtomicU64,
+    /// Triggers handed to the dump task
+    pub dispatched: AtomicU64,
+    /// Why the source isn't receiving right now, if it isn't
+    error: Mutex<Option<String>>,
+}
+
+/// A point-in-time copy of a source's statistics
+#[derive(Debug, Clone, Serialize)]
+pub struct SourceSnapshot {
+    pub priority: u8,
+    pub enabled: bool,
+    pub received: u64,
+    pub rejected: u64,
+    pub unauthenticated: u64,
+    pub ignored: u64,
+    pub dispatched: u64,
+    pub error: Option<String>,
+}
+
+impl SourceStats {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Record why the source stopped receiving, or clear it once it's back
+    pub fn set_error(&self, error: Option<String>) {
+        *self.error.lock().unwrap() = error;
+    }
+
+    pub fn snapshot(&self) -> SourceSnapshot {
+        SourceSnapshot {
+            priority: self.priority,
+            enabled: self.enabled(),
+            received: self.received.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+            unauthenticated: self.unauthenticated.load(Ordering::Relaxed),
+            ignored: self.ignored.load(Ordering::Relaxed),
+            dispatched: self.dispatched.load(Ordering::Relaxed),
+            error: self.error.lock().unwrap().clone(),
+        }
+    }
+}
+
+fn sources() -> &'static RwLock<BTreeMap<String, Arc<SourceStats>>> {
+    static SOURCES: OnceLock<RwLock<BTreeMap<String, Arc<SourceStats>>>> = OnceLock::new();
+    SOURCES.get_or_init(|| RwLock::new(BTreeMap::new()))
+}
+
+/// Get (or create) the statistics of the named source. Sources should grab this once and record into it directly.
+pub fn source_stats(name: &str, priority: u8) -> Arc<SourceStats> {
+    sources()
+        .write()
+        .unwrap()
+        .entry(name.to_owned())
+        .or_insert_with(|| {
+            Arc::new(SourceStats {
+                priority,
+                enabled: AtomicBool::new(true),
+                received: AtomicU64::new(0),
+                rejected: AtomicU64::new(0),
+                unauthenticated: AtomicU64::new(0),
+                ignored: AtomicU64::new(0),
+                dispatched: AtomicU64::new(0),
+                error: Mutex::new(None),
+            })
+        })
+        .clone()
+}
+
+/// Snapshots of every source's statistics
+pub fn snapshot() -> BTreeMap<String, SourceSnapshot> {
+    sources()
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(name, s)| (name.clone(), s.snapshot()))
+        .collect()
+}
+
+/// Enable or disable the named source, returning false if there's no such source
+pub fn set_enabled(name: &str, enabled: bool) -> bool {
+    match sources().read().unwrap().get(name) {
+        Some(stats) => {
+            stats.enabled.store(enabled, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// A trigger waiting for the dump task
+#[derive(Debug)]
+struct Queued {
+    priority: u8,
+    /// Order of arrival, so equal priorities go first come first served
+    seq: u64,
+    stats: Arc<SourceStats>,
+    event: CandidateEvent,
+}
+
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for Queued {}
+
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        // The heap pops the greatest, which should be the highest priority and then the oldest
+        self.priority
+            .cmp(&other.priority)
+            .then(other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Debug, Default)]
+struct Waiting {
+    heap: BinaryHeap<Queued>,
+    next_seq: u64,
+}
+
+/// Triggers from every source, waiting to be dumped in priority order
+#[derive(Debug, Clone, Default)]
+pub struct TriggerQueue {
+    waiting: Arc<Mutex<Waiting>>,
+    notify: Arc<Notify>,
+}
+
+impl TriggerQueue {
+    /// Queue a trigger from the source with `stats`, unless that source is disabled
+    pub fn push(&self, stats: &Arc<SourceStats>, mut event: CandidateEvent) {
+        if !stats.enabled() {
+            stats.ignored.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        event.received.get_or_insert_with(Instant::now);
+        let mut waiting = self.waiting.lock().unwrap();
+        let seq = waiting.next_seq;
+        waiting.next_seq += 1;
+        waiting.heap.push(Queued {
+            priority: stats.priority,
+            seq,
+            stats: stats.clone(),
+            event,
+        });
+        self.notify.notify_one();
+    }
+
+    /// Put back a trigger we couldn't dispatch, keeping its place in line
+    fn requeue(&self, queued: Queued) {
+        self.waiting.lock().unwrap().heap.push(queued);
+    }
+
+    fn try_pop(&self) -> Option<Queued> {
+        self.waiting.lock().unwrap().heap.pop()
+    }
+
+    /// Wait for the highest-priority trigger
+    async fn pop(&self) -> Queued {
+        loop {
+            if let Some(queued) = self.try_pop() {
+                return queued;
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiting.lock().unwrap().heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Where dispatched triggers go, and the ID the next one gets
+struct Dispatcher {
+    sender: SyncSender<CandidateEvent>,
+    stokes_ring: Option<SyncSender<RingRequest>>,
+    candidate_sinks: Vec<CandidateSink>,
+    next_id: u64,
+}
+
+/// What became of a trigger we tried to dispatch
+enum Dispatched {
+    Sent,
+    /// The dump task is busy, so the trigger is handed back
+    Busy(Box<Queued>),
+    Closed,
+}
+
+impl Dispatcher {
+    /// Hand one trigger to the dump task (and the stokes ring and candidate sinks), assigning its trigger ID
+    fn dispatch(&mut self, mut queued: Queued) -> Dispatched {
+        queued.event.trigger_id = self.next_id;
+        let ring_request = self
+            .stokes_ring
+            .as_ref()
+            .map(|_| RingRequest::Candidate(queued.event.clone()));
+        let routed = (!self.candidate_sinks.is_empty()).then(|| queued.event.clone());
+        match self.sender.try_send(queued.event) {
+            Ok(_) => {
+                if let Some(event) = routed {
+                    for sink in &mut self.candidate_sinks {
+                        sink.send(&event);
+                    }
+                }
+                if let Some((ring, request)) = self.stokes_ring.as_ref().zip(ring_request) {
+                    if ring.try_send(request).is_err() {
+                        warn!("Stokes ring is busy, skipping its copy of the trigger");
+                    }
+                }
+                queued.stats.dispatched.fetch_add(1, Ordering::Relaxed);
+                self.next_id += 1;
+                Dispatched::Sent
+            }
+            Err(TrySendError::Full(event)) => {
+                Dispatched::Busy(Box::new(Queued { event, ..queued }))
+            }
+            Err(TrySendError::Disconnected(_)) => Dispatched::Closed,
+        }
+    }
+}
+
+/// Hand queued triggers to the dump task one at a time, assigning each its trigger ID.
+/// While the dump task is busy, triggers wait here so a more important one can jump the line.
+/// Every dispatched trigger also asks the stokes ring (if there is one) for the intensity data around it,
+/// and is copied to wherever candidates are routed.
+async fn dispatch_task(
+    queue: &TriggerQueue,
+    dispatcher: &mut Dispatcher,
+    mut shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    info!("Starting trigger dispatch task!");
+    loop {
+        let queued = tokio::select! {
+            _ = shutdown.recv() => {
+                info!("Trigger dispatch task stopping");
+                break;
+            }
+            queued = queue.pop() => queued,
+        };
+        match dispatcher.dispatch(queued) {
+            Dispatched::Sent => (),
+            Dispatched::Busy(queued) => {
+                queue.requeue(*queued);
+                tokio::time::sleep(DISPATCH_RETRY).await;
+            }
+            Dispatched::Closed => break,
+        }
+    }
+    Ok(())
+}
+
+/// Run every source into one queue, dispatching to the dump task, until shutdown.
+/// Whatever the sources were still holding on to when they stopped gets one last chance at dispatch.
+pub async fn trigger_task(
+    sources: Vec<Box<dyn TriggerSource>>,
+    sender: SyncSender<CandidateEvent>,
+    stokes_ring: Option<SyncSender<RingRequest>>,
+    candidate_sinks: Vec<CandidateSink>,
+    shutdown: broadcast::Receiver<()>,
+) -> eyre::Result<()> {
+    let queue = TriggerQueue::default();
+    let mut handles = vec![];
+    for source in sources {
+        info!(source = source.name(), "Adding trigger source");
+        handles.push(source.spawn(queue.clone(), shutdown.resubscribe()));
+    }
+    let mut dispatcher = Dispatcher {
+        sender,
+        stokes_ring,
+        candidate_sinks,
+        next_id: 0,
+    };
+    dispatch_task(&queue, &mut dispatcher, shutdown).await?;
+    for handle in handles {
+        handle.await??;
+    }
+    while let Some(queued) = queue.try_pop() {
+        let candname = queued.event.candname.clone();
+        match dispatcher.dispatch(queued) {
+            Dispatched::Sent => info!(candname, "Dispatched a trigger held at shutdown"),
+            Dispatched::Busy(_) | Dispatched::Closed => {
+                warn!(
+                    candname,
+                    "Dump task is stopping, dropping a trigger held at shutdown"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A trigger as it comes over the wire, a [`CandidateEvent`] plus the protocol version and (if signed) what keeps it
+/// from being replayed
+#[derive(Debug, Deserialize)]
+struct TriggerMessage {
+    #[serde(flatten)]
+    event: CandidateEvent,
+    version: Option<u32>,
+    /// When it was sent (unix seconds), signed messages have to be within [`REPLAY_WINDOW`] of now
+    timestamp: Option<f64>,
+    /// Unique to the message, so a signed one is only ever accepted once
+    nonce: Option<String>,
+}
+
+/// A signed trigger, the [`TriggerMessage`] exactly as it was signed and its MAC
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    /// The JSON trigger message, as text
+    payload: String,
+    /// Hex HMAC-SHA256 of the payload's bytes under the shared secret
+    hmac: String,
+}
+
+/// Checks signed triggers against the shared secret, and that none of them are replays
+#[derive(Debug)]
+pub struct Authenticator {
+    secret: Vec<u8>,
+    /// Nonces of the signed triggers we've accepted within the window, and their timestamps
+    seen: HashMap<String, f64>,
+}
+
+impl Authenticator {
+    pub fn new(secret: Vec<u8>) -> Self {
+        Self {
+            secret,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// The message a signed envelope vouches for, if it's genuine and new to us as of `now` (unix seconds)
+    fn open(&mut self, envelope: Envelope, now: f64) -> Result<TriggerMessage, TriggerError> {
+        if !auth::verify(&self.secret, envelope.payload.as_bytes(), &envelope.hmac) {
+            return Err(TriggerError::Unauthenticated("has a bad MAC"));
+        }
+        let message: TriggerMessage = serde_json::from_str(&envelope.payload)
+            .map_err(|e| TriggerError::Malformed(e.to_string()))?;
+        let (Some(timestamp), Some(nonce)) = (message.timestamp, message.nonce.as_deref()) else {
+            return Err(TriggerError::Unauthenticated(
+                "is signed without a timestamp and nonce",
+            ));
+        };
+        if (now - timestamp).abs() > REPLAY_WINDOW {
+            return Err(TriggerError::Unauthenticated(
+                "is too far from our clock to be sure it's not a replay",
+            ));
+        }
+        self.seen.retain(|_, t| (now - *t).abs() <= REPLAY_WINDOW);
+        if self.seen.insert(nonce.to_owned(), timestamp).is_some() {
+            return Err(TriggerError::Unauthenticated("is a replay"));
+        }
+        Ok(message)
+    }
+}
+
+/// `event` as a trigger message signed under `secret`, stamped with the time and a fresh nonce
+pub fn sign_trigger(secret: &[u8], event: &CandidateEvent) -> eyre::Result<Vec<u8>> {
+    let mut message = serde_json::to_value(event)?;
+    let fields = message
+        .as_object_mut()
+        .ok_or_else(|| eyre::eyre!("Candidate events are JSON objects"))?;
+    fields.insert("version".to_owned(), PROTOCOL_VERSION.into());
+    fields.insert("timestamp".to_owned(), unix_now().into());
+    fields.insert(
+        "nonce".to_owned(),
+        auth::to_hex(&rand::random::<[u8; 16]>()).into(),
+    );
+    let payload = message.to_string();
+    let hmac = auth::to_hex(&auth::hmac_sha256(secret, payload.as_bytes()));
+    Ok(serde_json::to_vec(&Envelope { payload, hmac })?)
+}
+
+fn unix_now() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |d| d.as_secs_f64())
+}
+
+/// Why we turned a trigger message away
+#[derive(thiserror::Error, Debug)]
+pub enum TriggerError {
+    #[error("Malformed trigger message - {0}")]
+    Malformed(String),
+    #[error("Trigger protocol version {0} is newer than ours ({PROTOCOL_VERSION})")]
+    Version(u32),
+    #[error("Trigger message {0}")]
+    Unauthenticated(&'static str),
+}
+
+/// What we send back to a UDP trigger's sender
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum TriggerReply {
+    /// Accepted, with the names of the candidates it carried
+    Ack {
+        version: u32,
+        candnames: Vec<String>,
+    },
+    /// Turned away, and why
+    Nack { version: u32, reason: String },
+}
+
+impl TriggerReply {
+    /// What to tell the sender, if anything. Messages we can't make sense of or can't vouch for get no answer, so
+    /// the socket can't be used to bounce datagrams at someone else.
+    fn new(res: &Result<Vec<CandidateEvent>, TriggerError>) -> Option<Self> {
+        match res {
+            Ok(events) => Some(Self::Ack {
+                version: PROTOCOL_VERSION,
+                candnames: events.iter().map(|e| e.candname.clone()).collect(),
+            }),
+            Err(e @ TriggerError::Version(_)) => Some(Self::Nack {
+                version: PROTOCOL_VERSION,
+                reason: e.to_string(),
+            }),
+            Err(_) => None,
+        }
+    }
+}
+
+/// Decode a trigger message, either a JSON [`TriggerMessage`] (bare, or signed in an [`Envelope`]) or (when we're
+/// clustering) lines of raw heimdall candidates. With an `authenticator`, only genuine signed messages we haven't
+/// seen before are accepted.
+fn parse_trigger(
+    message: &[u8],
+    heimdall: bool,
+    authenticator: Option<&mut Authenticator>,
+) -> Result<Vec<CandidateEvent>, TriggerError> {
+    let value = match serde_json::from_slice::<serde_json::Value>(message) {
+        Ok(v) => v,
+        Err(e) if heimdall => {
+            if authenticator.is_some() {
+                return Err(TriggerError::Unauthenticated(
+                    "isn't JSON, so can't be signed",
+                ));
+            }
+            return std::str::from_utf8(message)
+                .map_err(|e| TriggerError::Malformed(e.to_string()))?
+                .lines()
+                .filter(|l| !l.trim().is_empty())
+                .map(dedup::parse_heimdall)
+                .collect::<eyre::Result<_>>()
+                .map_err(|_| {
+                    TriggerError::Malformed(format!(
+                        "Neither a JSON trigger message ({e}) nor heimdall candidates"
+                    ))
+                });
+        }
+        Err(e) => return Err(TriggerError::Malformed(e.to_string())),
+    };
+    let malformed = |e: serde_json::Error| TriggerError::Malformed(e.to_string());
+    let message = if value.get("payload").is_some() {
+        let envelope: Envelope = serde_json::from_value(value).map_err(malformed)?;
+        match authenticator {
+            Some(authenticator) => authenticator.open(envelope, unix_now())?,
+            // Without a secret there's nothing to check the MAC against
+            None => serde_json::from_str(&envelope.payload).map_err(malformed)?,
+        }
+    } else if authenticator.is_some() {
+        return Err(TriggerError::Unauthenticated("isn't signed"));
+    } else {
+        serde_json::from_value(value).map_err(malformed)?
+    };
+    match message.version {
+        Some(v) if v > PROTOCOL_VERSION => Err(TriggerError::Version(v)),
+        _ => Ok(vec![message.event]),
+    }
+}
+
+/// Drop a callback we won't send to, dumping the trigger all the same
+fn vet_callback(event: &mut CandidateEvent, authenticated: bool) {
+    if let Some(callback) = event
+        .callback
+        .take_if(|c| !status::callback_allowed(c, authenticated))
+    {
+        warn!(
+            callback,
+            candname = event.candname,
+            "Ignoring a callback we won't send dump statuses to"
+        );
+    }
+}
+
+/// Count a message we turned away against the source
+fn count_rejection(stats: &SourceStats, e: &TriggerError) {
+    let counter = match e {
+        TriggerError::Unauthenticated(_) => &stats.unauthenticated,
+        _ => &stats.rejected,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// JSON triggers (or raw heimdall candidates, if we're clustering them) in UDP datagrams.
+/// Triggers we accept (or only turn away for their version) are answered with a [`TriggerReply`].
+pub struct UdpSource {
+    port: u16,
+    clusterer: Option<Clusterer>,
+    authenticator: Option<Authenticator>,
+    stats: Arc<SourceStats>,
+}
+
+impl UdpSource {
+    pub fn new(
+        port: u16,
+        clusterer: Option<Clusterer>,
+        secret: Option<Vec<u8>>,
+        priority: u8,
+    ) -> Self {
+        Self {
+            port,
+            clusterer,
+            authenticator: secret.map(Authenticator::new),
+            stats: source_stats("udp", priority),
+        }
+    }
+}
+
+impl TriggerSource for UdpSource {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    fn spawn(
+        self: Box<Self>,
+        queue: TriggerQueue,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> JoinHandle<eyre::Result<()>> {
+        let Self {
+            port,
+            mut clusterer,
+            mut authenticator,
+            stats,
+        } = *self;
+        tokio::spawn(async move {
+            info!(port, "Starting UDP trigger source!");
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            let sock = UdpSocket::bind(addr).await?;
+            let mut buf = vec![0; 2048];
+            let mut cluster_check = tokio::time::interval(CLUSTER_CHECK_INTERVAL);
+            loop {
+                tokio::select! {
+                    _ = shutdown.recv() => {
+                        info!("UDP trigger source stopping");
+                        // Clusters still being held on to are triggers all the same
+                        for event in clusterer.as_mut().map(Clusterer::flush).unwrap_or_default() {
+                            info!(
+                                candname = event.candname,
+                                members = event.members,
+                                "Flushed a held cluster into a trigger"
+                            );
+                            queue.push(&stats, event);
+                        }
+                        break;
+                    }
+                    // Receive a candidate from the socket and queue it (or hold on to it, if we're clustering)
+                    res = sock.recv_from(&mut buf) => {
+                        let (n, peer) = res?;
+                        let parsed = parse_trigger(&buf[..n], clusterer.is_some(), authenticator.as_mut());
+                        let reply = TriggerReply::new(&parsed).map(|r| serde_json::to_vec(&r)).transpose()?;
+                        match parsed {
+                            Ok(events) => {
+                                for mut event in events {
+                                    stats.received.fetch_add(1, Ordering::Relaxed);
+                                    event.source.get_or_insert_with(|| peer.to_string());
+                                    vet_callback(&mut event, authenticator.is_some());
+                                    match &mut clusterer {
+                                        Some(c) => c.push(event),
+                                        None => queue.push(&stats, event),
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                count_rejection(&stats, &e);
+                                warn!(%peer, "{e}");
+                            }
+                        }
+                        // Senders that aren't listening for it can just ignore the answer
+                        if let Some(reply) = reply {
+                            if let Err(e) = sock.send_to(&reply, peer).await {
+                                warn!(%peer, "Couldn't reply to trigger - {e}");
+                            }
+                        }
+                    }
+                    _ = cluster_check.tick(), if clusterer.is_some() => {
+                        for event in clusterer.as_mut().unwrap().closed() {
+                            info!(
+                                candname = event.candname,
+                                members = event.members,
+                                "Clustered candidates into a trigger"
+                            );
+                            queue.push(&stats, event);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// JSON triggers published over ZeroMQ, which we subscribe to
+pub struct ZmqSource {
+    endpoint: String,
+    stats: Arc<SourceStats>,
+}
+
+impl ZmqSource {
+    pub fn new(endpoint: &str, priority: u8) -> Self {
+        Self {
+            endpoint: endpoint.to_owned(),
+            stats: source_stats("zmq", priority),
+        }
+    }
+}
+
+impl TriggerSource for ZmqSource {
+    fn name(&self) -> &str {
+        "zmq"
+    }
+
+    fn spawn(
+        self: Box<Self>,
+        queue: TriggerQueue,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> JoinHandle<eyre::Result<()>> {
+        let Self { endpoint, stats } = *self;
+        tokio::spawn(async move {
+            info!(endpoint, "Starting ZeroMQ trigger source!");
+            let mut retry = ZMQ_RETRY;
+            loop {
+                // Keep trying to reach the publisher (which may not be up yet), backing off as we go
+                stats.set_error(Some("Waiting for the publisher".to_owned()));
+                let mut sock = SubSocket::new();
+                let connect = async {
+                    sock.connect(&endpoint).await?;
+                    sock.subscribe("").await
+                };
+                let connected = tokio::select! {
+                    _ = shutdown.recv() => break,
+                    res = connect => res,
+                };
+                if let Err(e) = connected {
+                    warn!(
+                        endpoint,
+                        "Couldn't connect to ZeroMQ publisher, retrying in {retry:?} - {e}"
+                    );
+                    stats.set_error(Some(format!("Couldn't connect: {e}")));
+                    tokio::select! {
+                        _ = shutdown.recv() => break,
+                        _ = tokio::time::sleep(retry) => (),
+                    }
+                    retry = (retry * 2).min(ZMQ_MAX_RETRY);
+                    continue;
+                }
+                stats.set_error(None);
+                retry = ZMQ_RETRY;
+                loop {
+                    let msg = tokio::select! {
+                        _ = shutdown.recv() => {
+                            info!("ZeroMQ trigger source stopping");
+                            return Ok(());
+                        }
+                        res = sock.recv() => res,
+                    };
+                    let msg = match msg {
+                        Ok(msg) => msg,
+                        Err(e) => {
+                            warn!(endpoint, "Lost the ZeroMQ publisher, reconnecting - {e}");
+                            stats.set_error(Some(format!("Lost the publisher: {e}")));
+                            break;
+                        }
+                    };
+                    // Every frame of the message is a trigger of its own
+                    for frame in msg.into_vec() {
+                        match parse_trigger(&frame, false, None) {
+                            Ok(events) => {
+                                for mut event in events {
+                                    stats.received.fetch_add(1, Ordering::Relaxed);
+                                    event.source.get_or_insert_with(|| endpoint.clone());
+                                    vet_callback(&mut event, false);
+                                    queue.push(&stats, event);
+                                }
+                            }
+                            Err(e) => {
+                                count_rejection(&stats, &e);
+                                warn!(endpoint, "{e}");
+                            }
+                        }
+                    }
+                }
+            }
+            info!("ZeroMQ trigger source stopping");
+            Ok(())
+        })
+    }
+}
+
+/// Triggers handed to us from elsewhere in the process, like the web API or an in-process detector
+pub struct ChannelSource {
+    name: String,
+    receiver: mpsc::Receiver<CandidateEvent>,
+    stats: Arc<SourceStats>,
+}
+
+impl ChannelSource {
+    /// A source called `name`, and the sender that feeds it
+    pub fn new(name: &str, capacity: usize, priority: u8) -> (Self, mpsc::Sender<CandidateEvent>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        (
+            Self {
+                name: name.to_owned(),
+                receiver,
+                stats: source_stats(name, priority),
+            },
+            sender,
+        )
+    }
+}
+
+impl TriggerSource for ChannelSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn spawn(
+        self: Box<Self>,
+        queue: TriggerQueue,
+        mut shutdown: broadcast::Receiver<()>,
+    ) -> JoinHandle<eyre::Result<()>> {
+        let Self {
+            name,
+            mut receiver,
+            stats,
+        } = *self;
+        tokio::spawn(async move {
+            info!(name, "Starting trigger source!");
+            loop {
+                tokio::select! {
+                    _ = shutdown.recv() => {
+                        info!(name, "Trigger source stopping");
+                        break;
+                    }
+                    event = receiver.recv() => {
+                        let Some(mut event) = event else { break };
+                        stats.received.fetch_add(1, Ordering::Relaxed);
+                        event.source.get_or_insert_with(|| name.clone());
+                        // The web API takes triggers from anyone who can reach it
+                        vet_callback(&mut event, false);
+                        queue.push(&stats, event);
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+static INTERNAL: OnceLock<mpsc::Sender<CandidateEvent>> = OnceLock::new();
+
+/// Set where in-process detectors send their triggers
+pub fn set_internal(sender: mpsc::Sender<CandidateEvent>) {
+    let _ = INTERNAL.set(sender);
+}
+
+/// Where in-process detectors (like the stokes stream's [`PulseDetector`](crate::exfil::detect::PulseDetector)) send
+/// their triggers, if the pipeline has set it up
+pub fn internal() -> Option<&'static mpsc::Sender<CandidateEvent>> {
+    INTERNAL.get()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_priority_queue() {
+        let queue = TriggerQueue::default();
+        let low = source_stats("test_low", 1);
+        let high = source_stats("test_high", 5);
+        let off = source_stats("test_off", 9);
+        set_enabled("test_off", false);
+        let cand = |name: &str| CandidateEvent {
+            candname: name.to_owned(),
+            ..Default::default()
+        };
+        queue.push(&low, cand("first"));
+        queue.push(&high, cand("urgent"));
+        queue.push(&low, cand("second"));
+        queue.push(&off, cand("ignored"));
+        let order: Vec<_> = std::iter::from_fn(|| queue.try_pop())
+            .map(|q| q.event.candname)
+            .collect();
+        assert_eq!(order, ["urgent", "first", "second"]);
+        assert_eq!(off.ignored.load(Ordering::Relaxed), 1);
+    }
+
+    /// `payload` signed under `secret`, how it goes on the wire
+    fn envelope(secret: &[u8], payload: &str) -> String {
+        let hmac = auth::to_hex(&auth::hmac_sha256(secret, payload.as_bytes()));
+        serde_json::json!({ "payload": payload, "hmac": hmac }).to_string()
+    }
+
+    #[test]
+    fn test_authenticated_trigger() {
+        let secret = b"not very secret";
+        let mut authenticator = Authenticator::new(secret.to_vec());
+        let mut parse =
+            |message: &str| parse_trigger(message.as_bytes(), false, Some(&mut authenticator));
+        let now = unix_now();
+        let payload = format!(
+            r#"{{"candname":"cand", "itime":123, "version":1, "timestamp":{now}, "nonce":"a"}}"#
+        );
+        let signed = envelope(secret, &payload);
+        assert_eq!(parse(&signed).unwrap()[0].specnum, 123);
+        // Only once
+        assert!(matches!(
+            parse(&signed),
+            Err(TriggerError::Unauthenticated("is a replay"))
+        ));
+        let unauthenticated = |res| matches!(res, Err(TriggerError::Unauthenticated(_)));
+        // The MAC is over the payload exactly as sent
+        let tampered = envelope(secret, &payload).replace("123", "124");
+        assert!(unauthenticated(parse(&tampered)));
+        assert!(unauthenticated(parse(&payload)));
+        let stale = format!(
+            r#"{{"candname":"cand","itime":1,"timestamp":{},"nonce":"b"}}"#,
+            now - 2.0 * REPLAY_WINDOW
+        );
+        assert!(unauthenticated(parse(&envelope(secret, &stale))));
+        let unstamped = r#"{"candname":"cand","itime":1}"#;
+        assert!(unauthenticated(parse(&envelope(secret, unstamped))));
+        // How replay-triggers signs them
+        let event = CandidateEvent {
+            candname: "replayed".to_owned(),
+            specnum: 456,
+            ..Default::default()
+        };
+        let signed = String::from_utf8(sign_trigger(secret, &event).unwrap()).unwrap();
+        assert_eq!(parse(&signed).unwrap()[0].candname, "replayed");
+        let signed = String::from_utf8(sign_trigger(b"wrong", &event).unwrap()).unwrap();
+        assert!(unauthenticated(parse(&signed)));
+        // Without a secret the MAC is ignored, but the version still has to be one we speak
+        assert!(parse_trigger(signed.as_bytes(), false, None).is_ok());
+        assert!(parse_trigger(payload.as_bytes(), false, None).is_ok());
+        let newer = parse_trigger(br#"{"candname":"cand","itime":1,"version":2}"#, false, None);
+        assert!(matches!(newer, Err(TriggerError::Version(2))));
+        // Which is all we'll answer with a NACK
+        assert!(matches!(
+            TriggerReply::new(&newer),
+            Some(TriggerReply::Nack { .. })
+        ));
+        assert!(TriggerReply::new(&parse_trigger(b"{", false, None)).is_none());
+        assert!(TriggerReply::new(&parse(&tampered)).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_shutdown() {
+        let port = 60_999;
+        let clusterer = Clusterer::new(100, 1.0, Duration::from_secs(3600));
+        let source = UdpSource::new(port, Some(clusterer), None, 1);
+        let (sender, receiver) = std::sync::mpsc::sync_channel(5);
+        let (sd_s, sd_r) = broadcast::channel(1);
+        let task = tokio::spawn(trigger_task(
+            vec![Box::new(source)],
+            sender,
+            None,
+            vec![],
+            sd_r,
+        ));
+        // Wait for the reply so we know the candidate is being held
+        let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        sock.connect(("127.0.0.1", port)).await.unwrap();
+        let mut buf = [0; 256];
+        loop {
+            sock.send(br#"{"candname":"held","itime":123,"dm":50,"snr":9}"#)
+                .await
+                .unwrap();
+            if tokio::time::timeout(Duration::from_millis(100), sock.recv(&mut buf))
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+        assert!(receiver.try_recv().is_err());
+        sd_s.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert_eq!(receiver.try_recv().unwrap().candname, "held");
+    }
+
+    #[tokio::test]
+    async fn test_zmq_retry() {
+        // Something that hangs up on every connection, so the source should keep trying (and say so) rather than give up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((conn, _)) = listener.accept().await {
+                drop(conn);
+            }
+        });
+        let source = Box::new(ZmqSource::new(&endpoint, 1));
+        let (sd_s, sd_r) = broadcast::channel(1);
+        let handle = source.spawn(TriggerQueue::default(), sd_r);
+        let mut error = None;
+        for _ in 0..50 {
+            error = snapshot()["zmq"]
+                .error
+                .clone()
+                .filter(|e| e.starts_with("Couldn't connect"));
+            if error.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(error.is_some());
+        assert!(!handle.is_finished());
+        sd_s.send(()).unwrap();
+        handle.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+//! Online system temperature from the noise source cycle: the Y-factor of cal-on to cal-off power in each sub-band,
+//! giving a continuous sensitivity record for the station
+use crate::{
+    calibration::CalSchedule,
+    common::{payload_time, Payload, CHANNELS, PACKET_CADENCE},
+    db::{DbEvent, TsysRecord},
+    exfil::FrequencyPlan,
+    naming::time_policy,
+};
+use std::{
+    sync::{mpsc::SyncSender, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+use tracing::warn;
+
+/// Payloads either side of a noise source edge we leave out, so the switching transient doesn't bias either state (~1 ms)
+const EDGE_GUARD: u64 = 128;
+
+/// What we need to turn cal-on and cal-off power into a temperature
+#[derive(Debug, Clone, Copy)]
+pub struct TsysConfig {
+    /// Equivalent temperature of the noise source (K)
+    pub tcal: f64,
+    /// Number of equal sub-bands to estimate over
+    pub subbands: usize,
+    /// Payloads (by count) in each estimate
+    pub integration: u64,
+}
+
+impl TsysConfig {
+    pub fn from_seconds(tcal: f64, subbands: usize, seconds: f64) -> Self {
+        Self {
+            tcal,
+            subbands: subbands.clamp(1, CHANNELS),
+            integration: (seconds / PACKET_CADENCE).round() as u64,
+        }
+    }
+}
+
+/// Tsys from the noise source temperature and the power with it on and off, if the noise source made a difference
+pub fn y_factor_tsys(tcal: f64, on: f64, off: f64) -> Option<f64> {
+    (on > off && off > 0.0).then(|| tcal * off / (on - off))
+}
+
+/// Accumulates (Stokes I) power per sub-band by noise source state, producing an estimate every integration
+pub struct TsysEstimator {
+    config: TsysConfig,
+    schedule: CalSchedule,
+    /// Center frequency of each sub-band (MHz)
+    freqs: Vec<f64>,
+    on: Vec<f64>,
+    off: Vec<f64>,
+    n_on: u64,
+    n_off: u64,
+    /// Count of the first payload in this integration
+    start: Option<u64>,
+}
+
+impl TsysEstimator {
+    pub fn new(config: TsysConfig, schedule: CalSchedule, freq_plan: FrequencyPlan) -> Self {
+        let width = CHANNELS / config.subbands;
+        let channel_freqs = freq_plan.freqs();
+        let freqs = (0..config.subbands)
+            .map(|s| {
+                channel_freqs
+                    .slice(ndarray::s![s * width..(s + 1) * width])
+                    .mean()
+                    .unwrap()
+            })
+            .collect();
+        Self {
+            config,
+            schedule,
+            freqs,
+            on: vec![0.0; config.subbands],
+            off: vec![0.0; config.subbands],
+            n_on: 0,
+            n_off: 0,
+            start: None,
+        }
+    }
+
+    /// How long an estimate stands for, after which it's stale (if another hasn't replaced it)
+    pub fn valid_for(&self) -> Duration {
+        Duration::from_secs_f64(2.0 * self.config.integration as f64 * PACKET_CADENCE)
+    }
+
+    /// Add a payload, returning the estimate for each sub-band if that finished an integration
+    pub fn accumulate(&mut self, payload: &Payload) -> Option<Vec<TsysRecord>> {
+        let count = payload.count;
+        let start = *self.start.get_or_insert(count);
+        let on = self.schedule.is_on(count);
+        // Stay clear of the edges
+        if self.schedule.is_on(count.saturating_sub(EDGE_GUARD)) == on
+            && self.schedule.is_on(count + EDGE_GUARD) == on
+        {
+            let (power, n) = if on {
+                (&mut self.on, &mut self.n_on)
+            } else {
+                (&mut self.off, &mut self.n_off)
+            };
+            let width = CHANNELS / self.config.subbands;
+            for (i, (a, b)) in payload.pol_a.iter().zip(&payload.pol_b).enumerate() {
+                if let Some(p) = power.get_mut(i / width) {
+                    *p += f64::from(a.0.re).powi(2)
+                        + f64::from(a.0.im).powi(2)
+                        + f64::from(b.0.re).powi(2)
+                        + f64::from(b.0.im).powi(2);
+                }
+            }
+            *n += 1;
+        }
+        if count < start + self.config.integration {
+            return None;
+        }
+        let records = self.finish(start, count);
+        self.on.fill(0.0);
+        self.off.fill(0.0);
+        (self.n_on, self.n_off) = (0, 0);
+        self.start = None;
+        records
+    }
+
+    /// The estimates for the integration from `start` to `stop`, which are empty if we didn't see both states
+    fn finish(&self, start: u64, stop: u64) -> Option<Vec<TsysRecord>> {
+        let both = self.n_on > 0 && self.n_off > 0;
+        if !both {
+            warn!("No cal-on or no cal-off data in a Tsys integration, is the integration shorter than the cal period?");
+        }
+        let mjd = time_policy().mjd(payload_time(start + (stop - start) / 2));
+        Some(
+            self.on
+                .iter()
+                .zip(&self.off)
+                .zip(&self.freqs)
+                .enumerate()
+                .map(|(subband, ((on, off), freq))| TsysRecord {
+                    mjd,
+                    subband,
+                    freq: *freq,
+                    tsys: both
+                        .then(|| {
+                            y_factor_tsys(
+                                self.config.tcal,
+                                on / self.n_on as f64,
+                                off / self.n_off as f64,
+                            )
+                        })
+                        .flatten(),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The most recent Tsys estimate of each sub-band, and when it goes stale
+fn latest() -> &'static Mutex<(Vec<TsysRecord>, Option<Instant>)> {
+    static LATEST_TSYS: OnceLock<Mutex<(Vec<TsysRecord>, Option<Instant>)>> = OnceLock::new();
+    LATEST_TSYS.get_or_init(|| Mutex::new((vec![], None)))
+}
+
+/// The most recent Tsys estimate of each sub-band, unless it's gone stale
+pub fn latest_tsys() -> Vec<TsysRecord> {
+    match &*latest().lock().unwrap() {
+        (records, Some(stale)) if Instant::now() < *stale => records.clone(),
+        _ => vec![],
+    }
+}
+
+/// Publish a finished estimate (good for `valid_for`), to the metrics and the database
+pub fn publish(records: Vec<TsysRecord>, valid_for: Duration, db: &SyncSender<DbEvent>) {
+    *latest().lock().unwrap() = (records.clone(), Some(Instant::now() + valid_for));
+    for record in records {
+        if db.try_send(DbEvent::Tsys(record)).is_err() {
+            warn!("Couldn't queue a Tsys estimate for the database");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_y_factor() {
+        // Doubling the power with a 10 K cal means a 10 K system
+        assert_eq!(y_factor_tsys(10.0, 2.0, 1.0), Some(10.0));
+        assert_eq!(y_factor_tsys(10.0, 1.25, 1.0), Some(40.0));
+        // The cal didn't do anything
+        assert_eq!(y_factor_tsys(10.0, 1.0, 1.0), None);
+        assert_eq!(y_factor_tsys(10.0, 1.0, 0.0), None);
+    }
+
+    #[test]
+    fn test_accumulate() {
+        use crate::common::Channel;
+        crate::testing::seed_start_time();
+        let schedule = CalSchedule {
+            period: 1024,
+            on: 512,
+            phase: 0,
+        };
+        let config = TsysConfig {
+            tcal: 10.0,
+            subbands: 2,
+            integration: 2048,
+        };
+        let payload = |count| {
+            // 18 on to 8 off is 10 K of cal on 8 K of system
+            let amp = if schedule.is_on(count) { 3 } else { 2 };
+            Payload {
+                count,
+                pol_a: [Channel::new(amp, 0); CHANNELS],
+                pol_b: [Channel::new(0, amp); CHANNELS],
+                ..Default::default()
+            }
+        };
+        let mut estimator = TsysEstimator::new(config, schedule, FrequencyPlan::default());
+        for count in 0..2048 {
+            assert!(estimator.accumulate(&payload(count)).is_none());
+        }
+        let records = estimator.accumulate(&payload(2048)).unwrap();
+        assert_eq!(records.len(), 2);
+        for record in &records {
+            assert!((record.tsys.unwrap() - 8.0).abs() < 1e-9);
+        }
+        assert!(records[0].freq != records[1].freq);
+        // An integration that only sees the cal on still says so, without an estimate
+        let mut estimator = TsysEstimator::new(
+            TsysConfig {
+                integration: 128,
+                ..config
+            },
+            schedule,
+            FrequencyPlan::default(),
+        );
+        let records = (1024..=1152)
+            .find_map(|count| estimator.accumulate(&payload(count)))
+            .unwrap();
+        assert!(records.iter().all(|r| r.tsys.is_none()));
+    }
+}
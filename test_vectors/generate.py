@@ -0,0 +1,75 @@
+"""Regenerate the golden test vectors for the SIMD kernels.
+
+Everything here is computed independently of the Rust code, in exact integer
+(or exactly representable float) arithmetic, so the fixtures pin down the
+bit-exact output any port of the kernels has to reproduce.
+"""
+import random
+import struct
+from pathlib import Path
+
+CHANNELS = 2048
+DOWNSAMPLE = 4
+HERE = Path(__file__).parent
+
+
+def voltages(rng, kind):
+    """Interleaved re/im i8 voltages for one polarization"""
+    if kind == "random":
+        return [rng.randint(-128, 127) for _ in range(2 * CHANNELS)]
+    # Extremes, to catch overflow in the widening multiplies
+    return [rng.choice([-128, 127, -127, 0]) for _ in range(2 * CHANNELS)]
+
+
+def stokes(a, b):
+    return [
+        (a[2 * c] ** 2 + a[2 * c + 1] ** 2 + b[2 * c] ** 2 + b[2 * c + 1] ** 2) / 16384
+        for c in range(CHANNELS)
+    ]
+
+
+def i8s(xs):
+    return struct.pack(f"<{len(xs)}b", *xs)
+
+
+def f32s(xs):
+    return struct.pack(f"<{len(xs)}f", *xs)
+
+
+def wrap(x):
+    return (x + 128) % 256 - 128
+
+
+def main():
+    rng = random.Random(0x6E4)
+    kinds = ["random", "extreme"]
+
+    # stokes.bin: per vector, pol A (4096 i8), pol B (4096 i8), stokes I (2048 f32)
+    with open(HERE / "stokes.bin", "wb") as f:
+        for kind in kinds:
+            a, b = voltages(rng, kind), voltages(rng, kind)
+            f.write(i8s(a) + i8s(b) + f32s(stokes(a, b)))
+
+    # injection.bin: per vector, live pol (4096 i8), pulse sample (2048 i8), injected pol (4096 i8)
+    with open(HERE / "injection.bin", "wb") as f:
+        for kind in kinds:
+            live = voltages(rng, kind)
+            pulse = [rng.randint(-128, 127) for _ in range(CHANNELS)]
+            out = list(live)
+            for c in range(CHANNELS):
+                out[2 * c] = wrap(out[2 * c] + pulse[c])
+            f.write(i8s(live) + i8s(pulse) + i8s(out))
+
+    # downsample.bin: per vector, DOWNSAMPLE x (pol A, pol B), averaged stokes I (2048 f32)
+    with open(HERE / "downsample.bin", "wb") as f:
+        for kind in kinds:
+            acc = [0.0] * CHANNELS
+            for _ in range(DOWNSAMPLE):
+                a, b = voltages(rng, kind), voltages(rng, kind)
+                f.write(i8s(a) + i8s(b))
+                acc = [x + y for x, y in zip(acc, stokes(a, b))]
+            f.write(f32s([x / DOWNSAMPLE for x in acc]))
+
+
+if __name__ == "__main__":
+    main()